@@ -0,0 +1,248 @@
+use std::fmt;
+
+/// Secrets with less than this many bits of estimated entropy are reported as weak, unless
+/// overridden by `min-entropy`.
+pub const WEAK_ENTROPY_BITS: f64 = 50.0;
+
+/// Records that haven't changed in this many seconds (a year) are reported as stale.
+pub const STALE_AGE_SECS: u64 = 365 * SECS_PER_DAY;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How serious an audit finding is.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// The kinds of problems that an audit reports.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum FindingKind {
+    /// A record couldn't be decrypted or parsed.
+    Unreadable,
+
+    /// A secret has too little estimated entropy (see `min-entropy`).
+    Weak,
+
+    /// Multiple records share the same secret.
+    Reused,
+
+    /// A file is accessible by users other than its owner.
+    Permissions,
+
+    /// A record hasn't changed in a long time (see `STALE_AGE_SECS`).
+    Stale,
+}
+
+impl fmt::Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FindingKind::Unreadable => write!(f, "unreadable"),
+            FindingKind::Weak => write!(f, "weak"),
+            FindingKind::Reused => write!(f, "reused"),
+            FindingKind::Permissions => write!(f, "permissions"),
+            FindingKind::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+/// Represents a single problem found by an audit.
+#[derive(Debug)]
+pub struct Finding {
+    /// How serious the problem is.
+    pub severity: Severity,
+
+    /// What kind of problem it is.
+    pub kind: FindingKind,
+
+    /// The labels of the records involved, if any.
+    pub labels: Vec<String>,
+
+    /// A human-readable description of the problem.
+    pub detail: String,
+}
+
+/// The results of auditing a store.
+#[derive(Debug)]
+pub struct AuditReport {
+    /// The number of records audited.
+    pub records: usize,
+
+    /// Every problem found, most severe first.
+    pub findings: Vec<Finding>,
+}
+
+impl AuditReport {
+    /// Creates a new report from the given findings, sorting them by severity (most
+    /// severe first), then kind, then labels.
+    pub fn new(records: usize, mut findings: Vec<Finding>) -> AuditReport {
+        findings.sort_by(|a, b| {
+            b.severity
+                .cmp(&a.severity)
+                .then(a.kind.cmp(&b.kind))
+                .then(a.labels.cmp(&b.labels))
+        });
+
+        AuditReport { records, findings }
+    }
+
+    /// Returns the number of critical findings in the report.
+    pub fn critical(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Critical)
+            .count()
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} records audited, {} findings ({} critical)",
+            self.records,
+            self.findings.len(),
+            self.critical()
+        )?;
+
+        if self.findings.is_empty() {
+            return Ok(());
+        }
+
+        let rows = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let labels = if finding.labels.is_empty() {
+                    "-".into()
+                } else {
+                    finding.labels.join(", ")
+                };
+
+                [
+                    finding.severity.to_string(),
+                    finding.kind.to_string(),
+                    labels,
+                    finding.detail.clone(),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let header = [
+            "SEVERITY".to_string(),
+            "FINDING".into(),
+            "LABELS".into(),
+            "DETAIL".into(),
+        ];
+
+        let mut widths = [0; 3];
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        writeln!(f)?;
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            writeln!(
+                f,
+                "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Estimates the entropy of the given secret, in bits.
+///
+/// The estimate assumes that each character was chosen uniformly from every character
+/// class (lowercase, uppercase, digits, and symbols) that appears in the secret, so it's
+/// an upper bound: dictionary words and patterns are much weaker than it suggests.
+pub fn estimate_entropy(secret: &str) -> f64 {
+    let pool: u32 = [
+        (secret.chars().any(|c| c.is_ascii_lowercase()), 26),
+        (secret.chars().any(|c| c.is_ascii_uppercase()), 26),
+        (secret.chars().any(|c| c.is_ascii_digit()), 10),
+        (secret.chars().any(|c| !c.is_ascii_alphanumeric()), 33),
+    ]
+    .iter()
+    .filter(|(present, _)| *present)
+    .map(|(_, size)| size)
+    .sum();
+
+    if pool == 0 {
+        return 0.0;
+    }
+
+    secret.chars().count() as f64 * f64::from(pool).log2()
+}
+
+/// Returns the number of whole days in the given number of seconds.
+pub fn days(secs: u64) -> u64 {
+    secs / SECS_PER_DAY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_entropy() {
+        assert_eq!(estimate_entropy(""), 0.0);
+        assert_eq!(estimate_entropy("aaaa"), 4.0 * 26f64.log2());
+        assert_eq!(estimate_entropy("aA1!"), 4.0 * 95f64.log2());
+        assert!(estimate_entropy("password") < WEAK_ENTROPY_BITS);
+        assert!(estimate_entropy("123456") < WEAK_ENTROPY_BITS);
+        assert!(estimate_entropy("letmein!") < WEAK_ENTROPY_BITS);
+        assert!(estimate_entropy("xK9#mP2$vL5&nQ8@") > WEAK_ENTROPY_BITS);
+        assert!(estimate_entropy("correct horse battery staple") > WEAK_ENTROPY_BITS);
+    }
+
+    #[test]
+    fn test_report() {
+        let report = AuditReport::new(
+            2,
+            vec![
+                Finding {
+                    severity: Severity::Warning,
+                    kind: FindingKind::Stale,
+                    labels: vec!["a".into()],
+                    detail: "unchanged for 400 days".into(),
+                },
+                Finding {
+                    severity: Severity::Critical,
+                    kind: FindingKind::Reused,
+                    labels: vec!["a".into(), "b".into()],
+                    detail: "2 records share a secret".into(),
+                },
+            ],
+        );
+
+        assert_eq!(report.critical(), 1);
+        assert_eq!(report.findings[0].kind, FindingKind::Reused);
+        assert_eq!(report.findings[1].kind, FindingKind::Stale);
+
+        assert_eq!(
+            AuditReport::new(0, vec![]).to_string(),
+            "0 records audited, 0 findings (0 critical)\n"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Result};
+
+use crate::kbs2::config::RecordFormat;
+use crate::kbs2::record::Record;
+
+/// Represents the operations that all record codecs are capable of.
+///
+/// Codecs are responsible for turning records into bytes (and back) before encryption
+/// (and after decryption).
+pub trait RecordCodec {
+    /// Serializes the given record.
+    fn encode(&self, record: &Record) -> Result<Vec<u8>>;
+
+    /// Deserializes a record previously serialized by this codec.
+    fn decode(&self, encoded: &[u8]) -> Result<Record>;
+}
+
+/// Serializes records as JSON.
+pub struct JsonCodec;
+
+impl RecordCodec for JsonCodec {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(record)?)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record> {
+        Ok(serde_json::from_slice(encoded)?)
+    }
+}
+
+/// Serializes records as TOML.
+pub struct TomlCodec;
+
+impl RecordCodec for TomlCodec {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>> {
+        Ok(toml::to_vec(record)?)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record> {
+        Ok(toml::from_slice(encoded)?)
+    }
+}
+
+/// Serializes records as MessagePack.
+pub struct MsgpackCodec;
+
+impl RecordCodec for MsgpackCodec {
+    fn encode(&self, record: &Record) -> Result<Vec<u8>> {
+        // NOTE: Field names are included, so that reordering Record's fields
+        // doesn't break existing records.
+        Ok(rmp_serde::to_vec_named(record)?)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Record> {
+        Ok(rmp_serde::from_slice(encoded)?)
+    }
+}
+
+/// Returns the codec for the given record format.
+pub fn codec(format: RecordFormat) -> &'static dyn RecordCodec {
+    match format {
+        RecordFormat::Json => &JsonCodec,
+        RecordFormat::Toml => &TomlCodec,
+        RecordFormat::Msgpack => &MsgpackCodec,
+    }
+}
+
+/// Detects the format of the given serialized record.
+///
+/// No marker is needed, since each format's first byte is distinct: a JSON record always
+/// begins with `{`, a MessagePack record with a map marker (`0x80` or above), and a TOML
+/// record with a bare key.
+pub fn detect(encoded: &[u8]) -> Result<RecordFormat> {
+    match encoded.first() {
+        Some(b'{') => Ok(RecordFormat::Json),
+        Some(0x80..=0x8f) | Some(0xde) | Some(0xdf) => Ok(RecordFormat::Msgpack),
+        Some(c) if c.is_ascii_alphabetic() => Ok(RecordFormat::Toml),
+        _ => Err(anyhow!("unrecognized record format")),
+    }
+}
+
+/// Deserializes the given record, in whichever format it was serialized in.
+pub fn decode(encoded: &[u8]) -> Result<Record> {
+    codec(detect(encoded)?).decode(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::login("foo", "bar", "baz"),
+            Record::environment("foo", "BAR", "baz"),
+            Record::unstructured("foo", "bar\nbaz"),
+            Record::unstructured("ünïcödé", "{\"not\": \"json\"}"),
+            Record {
+                extra: vec![
+                    ("recovery-email".into(), "alice@example.com".into()),
+                    ("notes".into(), "multiple\nlines = \"quoted\"".into()),
+                ]
+                .into_iter()
+                .collect(),
+                ..Record::login("foo", "bar", "baz")
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in &[
+            RecordFormat::Json,
+            RecordFormat::Toml,
+            RecordFormat::Msgpack,
+        ] {
+            for record in records() {
+                let encoded = codec(*format).encode(&record).unwrap();
+
+                assert_eq!(detect(&encoded).unwrap(), *format);
+                assert_eq!(codec(*format).decode(&encoded).unwrap(), record);
+                assert_eq!(decode(&encoded).unwrap(), record);
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        assert!(detect(b"").is_err());
+        assert!(detect(b" {}").is_err());
+        assert_eq!(detect(b"{}").unwrap(), RecordFormat::Json);
+        assert_eq!(detect(b"timestamp = 0").unwrap(), RecordFormat::Toml);
+        assert_eq!(detect(&[0x83]).unwrap(), RecordFormat::Msgpack);
+    }
+}
@@ -1,47 +1,86 @@
 use anyhow::{anyhow, Result};
 use atty::Stream;
 use clap::ArgMatches;
+#[cfg(feature = "clipboard")]
 use clipboard::{ClipboardContext, ClipboardProvider};
-use nix::errno::Errno;
-use nix::sys::mman;
 use nix::unistd::{fork, ForkResult};
+use secrecy::SecretString;
 
 use std::env;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::process;
+use std::time::{Duration, UNIX_EPOCH};
 
+use crate::kbs2::backend::{self, Backend, RageLib};
 use crate::kbs2::config;
-use crate::kbs2::generator::Generator;
-use crate::kbs2::input;
-use crate::kbs2::record::{self, FieldKind::*, RecordBody};
+use crate::kbs2::generator;
+use crate::kbs2::import;
+use crate::kbs2::input::{self, Prompt};
+use crate::kbs2::output;
+use crate::kbs2::record::{self, RecordBody};
 use crate::kbs2::session;
 use crate::kbs2::util;
 
+/// The smallest scrypt work factor that `kbs2 bench-kdf` times.
+const BENCH_KDF_MIN_WORK_FACTOR: u8 = 10;
+
+/// The largest scrypt work factor that `kbs2 bench-kdf` times. Each work factor needs
+/// `2^(work factor + 10)` bytes of memory, so this one needs 4 GiB.
+const BENCH_KDF_MAX_WORK_FACTOR: u8 = 22;
+
 /// Implements the `kbs2 init` command.
-pub fn init(matches: &ArgMatches, config_dir: &Path) -> Result<()> {
+pub fn init(matches: &ArgMatches, config_dir: &Path, profile: Option<&str>) -> Result<()> {
     log::debug!("initializing a new config");
 
-    if config_dir.join(config::CONFIG_BASENAME).exists() && !matches.is_present("force") {
+    if config_dir.join(config::config_basename(profile)?).exists() && !matches.is_present("force") {
         return Err(anyhow!(
             "refusing to overwrite your current config without --force"
         ));
     }
 
-    config::initialize(&config_dir, !matches.is_present("insecure-not-wrapped"))
+    let max_work_factor = matches
+        .value_of("wrap-work-factor")
+        .map(|factor| {
+            factor
+                .parse::<u8>()
+                .map_err(|_| anyhow!("invalid wrap work factor: {}", factor))
+        })
+        .transpose()?;
+
+    let public_key = config::initialize(
+        config_dir,
+        profile,
+        !matches.is_present("insecure-not-wrapped"),
+        max_work_factor,
+    )?;
+
+    // NOTE: The public key is printed so that it can immediately be shared, e.g. to
+    // be added to someone else's recipients-file.
+    println!("{}", public_key);
+
+    Ok(())
 }
 
 /// Implements the `kbs2 unlock` command.
-pub fn unlock(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+pub fn unlock(_matches: &ArgMatches, config: &mut config::Config) -> Result<()> {
     log::debug!("unlock requested");
 
     if !config.wrapped {
         return Err(anyhow!("unlock requested but wrapped=false in config"));
     }
 
+    // NOTE: Decrypting an encrypted include may have already unwrapped the key, in which
+    // case we persist that one instead of unwrapping it again.
+    if let Some(mut unwrapped_key) = config.unwrapped_key.take() {
+        unwrapped_key.persist();
+        return Ok(());
+    }
+
     // NOTE(ww): All of the unwrapping happens in unwrap_keyfile.
-    // The unwrapped data is persistent in shared memory once we return successfully.
-    config.unwrap_keyfile()?;
+    // We persist the unwrapped key, so that it remains in shared memory once we return.
+    config.unwrap_keyfile()?.persist();
 
     Ok(())
 }
@@ -54,12 +93,166 @@ pub fn lock(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
         util::warn("config says that key isn't wrapped, trying anyways...");
     }
 
-    let shm_name = config.unwrapped_key_shm_name()?;
-    match mman::shm_unlink(&shm_name) {
-        Ok(()) => Ok(()),
-        Err(nix::Error::Sys(Errno::ENOENT)) => Err(anyhow!("no unwrapped key to remove")),
-        Err(e) => Err(e.into()),
+    // NOTE: Locking is idempotent, so that it's safe to run unconditionally (e.g. from
+    // a screen locker) without checking whether the key is unlocked first.
+    config.remove_unwrapped_key()
+}
+
+/// Implements the `kbs2 wrap-key` command.
+pub fn wrap_key(_matches: &ArgMatches, config: &mut config::Config) -> Result<()> {
+    log::debug!("wrapping the keyfile");
+
+    if config.wrapped {
+        return Err(anyhow!("wrap requested but wrapped=true in config"));
+    }
+
+    let password = util::get_new_password(
+        config.passphrase_entry,
+        &config.pinentry,
+        &input::TerminalPrompt,
+    )?;
+    config.wrap_work_factor = RageLib::wrap_keyfile(Path::new(&config.keyfile), password)?;
+
+    config.wrapped = true;
+    rewrite_config(config)
+}
+
+/// Implements the `kbs2 unwrap-key` command.
+pub fn unwrap_key(_matches: &ArgMatches, config: &mut config::Config) -> Result<()> {
+    log::debug!("unwrapping the keyfile to disk");
+
+    if !config.wrapped {
+        return Err(anyhow!("unwrap requested but wrapped=false in config"));
+    }
+
+    let password = util::get_password(
+        config.passphrase_entry,
+        &config.pinentry,
+        &input::TerminalPrompt,
+    )?;
+    RageLib::unwrap_to_disk(Path::new(&config.keyfile), password)?;
+
+    config.wrapped = false;
+    rewrite_config(config)
+}
+
+/// Implements the `kbs2 rewrap-key` command.
+pub fn rewrap_key(_matches: &ArgMatches, config: &mut config::Config) -> Result<()> {
+    log::debug!("changing the keyfile's password");
+
+    if !config.wrapped {
+        return Err(anyhow!("rewrap requested but wrapped=false in config"));
+    }
+
+    let old_password = util::get_password(
+        config.passphrase_entry,
+        &config.pinentry,
+        &input::TerminalPrompt,
+    )?;
+    let new_password = util::get_new_password(
+        config.passphrase_entry,
+        &config.pinentry,
+        &input::TerminalPrompt,
+    )?;
+    config.wrap_work_factor =
+        RageLib::rewrap_keyfile(Path::new(&config.keyfile), old_password, new_password)?;
+    rewrite_config(config)?;
+
+    // NOTE: Any unwrapped key in shared memory was unlocked with the old password,
+    // so we remove it to ensure that the new one is required from here on out.
+    config.remove_unwrapped_key()
+}
+
+/// Implements the `kbs2 bench-kdf` command.
+pub fn bench_kdf(matches: &ArgMatches) -> Result<()> {
+    log::debug!("benchmarking scrypt work factors");
+
+    let target = matches.value_of("target-ms").unwrap();
+    let target = target
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| anyhow!("invalid target latency: {}", target))?;
+
+    let timings = backend::bench_kdf(BENCH_KDF_MIN_WORK_FACTOR, BENCH_KDF_MAX_WORK_FACTOR, target)?;
+
+    println!("{:<13}TIME", "WORK FACTOR");
+    for (work_factor, elapsed) in &timings {
+        println!("{:<13}{}ms", work_factor, elapsed.as_millis());
+    }
+
+    match backend::recommend_work_factor(&timings, target) {
+        Some(work_factor) => {
+            println!("\nrecommended: wrap-work-factor = {}", work_factor);
+            Ok(())
+        }
+        None => Err(anyhow!(
+            "even a work factor of {} takes longer than {}ms; try a larger --target-ms",
+            BENCH_KDF_MIN_WORK_FACTOR,
+            target.as_millis()
+        )),
+    }
+}
+
+#[doc(hidden)]
+fn rewrite_config(config: &config::Config) -> Result<()> {
+    let config_path = config.config_path()?;
+
+    // NOTE: We only update `wrapped` and `wrap-work-factor` in the config file itself,
+    // rather than serializing the loaded config: the latter also contains any settings
+    // merged in from includes.
+    let mut value = toml::from_str::<toml::Value>(&fs::read_to_string(&config_path)?)?;
+    let table = value
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("malformed config: {}", config_path.display()))?;
+    table.insert("wrapped".into(), config.wrapped.into());
+    if config.wrapped {
+        table.insert(
+            "wrap-work-factor".into(),
+            i64::from(config.wrap_work_factor).into(),
+        );
+    }
+
+    util::write_file_secure(&config_path, toml::to_string(&value)?.as_bytes())
+}
+
+/// Implements the (hidden) `kbs2 __complete` command.
+///
+/// Emits the requested completion data newline-delimited and sorted, without
+/// decrypting anything (and therefore without prompting for the master password).
+pub fn complete(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("completion data requested");
+
+    let mut candidates = match matches.value_of("kind").unwrap() {
+        "labels" => session::store_labels(config)?,
+        "generators" => config
+            .generators
+            .iter()
+            .map(|g| g.as_dyn().name().to_owned())
+            .collect(),
+        _ => unreachable!(),
+    };
+
+    candidates.sort();
+
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+
+    Ok(())
+}
+
+/// Implements `kbs2 --print-config`, printing the effective configuration (see
+/// `Config::effective`) in the given format (`toml` or `json`).
+pub fn print_config(format: &str, config: &config::Config) -> Result<()> {
+    let value = config.effective()?;
+
+    match format {
+        "toml" => print!("{}", toml::to_string(&value)?),
+        "json" => println!("{}", serde_json::to_string_pretty(&value)?),
+        _ => unreachable!(),
     }
+
+    Ok(())
 }
 
 /// Implements the `kbs2 new` command.
@@ -68,7 +261,17 @@ pub fn new(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 
     if let Some(pre_hook) = &session.config.commands.new.pre_hook {
         log::debug!("pre-hook: {}", pre_hook);
-        session.config.call_hook(pre_hook, &[])?;
+        session
+            .config
+            .call_hook(pre_hook, &[], &session.config.commands.new.hook_env)?;
+    }
+
+    if matches.is_present("delta") {
+        return new_from_delta(matches.is_present("force"), session);
+    }
+
+    if matches.is_present("from-stdin") {
+        return new_from_stdin(matches.is_present("force"), session);
     }
 
     let label = matches.value_of("label").unwrap();
@@ -91,110 +294,279 @@ pub fn new(matches: &ArgMatches, session: &session::Session) -> Result<()> {
         None
     };
 
-    // TODO: new_* below is a little silly. This should be de-duped.
-    match matches.value_of("kind").unwrap() {
-        "login" => new_login(label, terse, &session, generator)?,
-        "environment" => new_environment(label, terse, &session, generator)?,
-        "unstructured" => new_unstructured(label, terse, &session, generator)?,
-        _ => unreachable!(),
+    let kind = matches.value_of("kind").unwrap();
+    let fields = input::fields(
+        kind,
+        terse,
+        &session.config,
+        generator,
+        &input::TerminalPrompt,
+    )?;
+    let record = record::Record::with_fields(kind, label, &fields)?;
+    session.config.check_secret_entropy(&record)?;
+
+    if matches.is_present("extra-passphrase") {
+        let passphrase = util::get_record_passphrase(
+            session.config.passphrase_entry,
+            &session.config.pinentry,
+            &input::TerminalPrompt,
+            label,
+            true,
+        )?;
+        session.add_protected_record(&record, passphrase)?;
+    } else {
+        session.add_record(&record)?;
     }
 
     if let Some(post_hook) = &session.config.commands.new.post_hook {
         log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[&label])?;
+        session.config.call_post_hook(
+            post_hook,
+            &[&label],
+            &session.config.commands.new.hook_env,
+            session.config.commands.new.background_post_hook,
+        )?;
     }
 
     Ok(())
 }
 
 #[doc(hidden)]
-fn new_login(
-    label: &str,
-    terse: bool,
-    session: &session::Session,
-    generator: Option<&dyn Generator>,
-) -> Result<()> {
-    let fields = input::fields(
-        &[Insensitive("Username"), Sensitive("Password")],
-        terse,
-        &session.config,
-        generator,
-    )?;
-    let record = record::Record::login(label, &fields[0], &fields[1]);
+fn new_from_delta(force: bool, session: &session::Session) -> Result<()> {
+    let (written, deleted) = session.apply_delta(io::stdin().lock(), force)?;
+
+    for label in written {
+        println!("{}", label);
+
+        if let Some(post_hook) = &session.config.commands.new.post_hook {
+            log::debug!("post-hook: {}", post_hook);
+            session.config.call_post_hook(
+                post_hook,
+                &[&label],
+                &session.config.commands.new.hook_env,
+                session.config.commands.new.background_post_hook,
+            )?;
+        }
+    }
 
-    session.add_record(&record)
+    for label in deleted {
+        println!("deleted: {}", label);
+    }
+
+    Ok(())
 }
 
 #[doc(hidden)]
-fn new_environment(
-    label: &str,
-    terse: bool,
-    session: &session::Session,
-    generator: Option<&dyn Generator>,
-) -> Result<()> {
-    let fields = input::fields(
-        &[Insensitive("Variable"), Sensitive("Value")],
-        terse,
-        &session.config,
-        generator,
-    )?;
-    let record = record::Record::environment(label, &fields[0], &fields[1]);
+fn new_from_stdin(force: bool, session: &session::Session) -> Result<()> {
+    let results = session.add_records_from_json(io::stdin().lock(), force)?;
+
+    let total = results.len();
+    let mut failures = 0;
+    for (line, result) in results {
+        match result {
+            Ok(label) => {
+                println!("{}", label);
+
+                if let Some(post_hook) = &session.config.commands.new.post_hook {
+                    log::debug!("post-hook: {}", post_hook);
+                    session.config.call_post_hook(
+                        post_hook,
+                        &[&label],
+                        &session.config.commands.new.hook_env,
+                        session.config.commands.new.background_post_hook,
+                    )?;
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                util::warn(&format!("line {}: {}", line, e));
+            }
+        }
+    }
 
-    session.add_record(&record)
+    if failures > 0 {
+        Err(anyhow!(
+            "failed to create {} of {} records",
+            failures,
+            total
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Retrieves the record with the given label, prompting for its extra passphrase if
+/// it's protected by one, and records the access (see `Session::touch`).
+#[doc(hidden)]
+fn get_record(label: &str, session: &session::Session) -> Result<record::Record> {
+    get_record_with_passphrase(label, session).map(|(record, _)| record)
 }
 
+/// Like `get_record`, but also returns the record's extra passphrase (if it has one),
+/// so that the record can be written back under the same passphrase.
 #[doc(hidden)]
-fn new_unstructured(
+fn get_record_with_passphrase(
     label: &str,
-    terse: bool,
     session: &session::Session,
-    generator: Option<&dyn Generator>,
-) -> Result<()> {
-    let fields = input::fields(
-        &[Insensitive("Contents")],
-        terse,
-        &session.config,
-        generator,
-    )?;
-    let record = record::Record::unstructured(label, &fields[0]);
+) -> Result<(record::Record, Option<SecretString>)> {
+    let passphrase = if session.is_protected(label)? {
+        Some(util::get_record_passphrase(
+            session.config.passphrase_entry,
+            &session.config.pinentry,
+            &input::TerminalPrompt,
+            label,
+            false,
+        )?)
+    } else {
+        None
+    };
+
+    let record = match &passphrase {
+        Some(passphrase) => session.get_protected_record(label, passphrase.clone())?,
+        None => session.get_record(label)?,
+    };
 
-    session.add_record(&record)
+    if let Err(e) = session.touch(label) {
+        util::warn(&format!("couldn't record access to {}: {}", label, e));
+    }
+
+    Ok((record, passphrase))
 }
 
 /// Implements the `kbs2 list` command.
 pub fn list(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     log::debug!("listing records");
 
-    let (details, filter_kind) = (matches.is_present("details"), matches.is_present("kind"));
+    // NOTE: Stats are taken from the store's files alone, so nothing is decrypted.
+    if matches.is_present("stats") {
+        println!(
+            "{} records ({} bytes)",
+            session.count()?,
+            session.store_size_bytes()?
+        );
+        return Ok(());
+    }
+
+    let (details, filter_kind, json) = (
+        matches.is_present("details"),
+        matches.is_present("kind"),
+        matches.is_present("json"),
+    );
+
+    let now = util::current_timestamp();
+    let since = matches
+        .value_of("since")
+        .map(|since| util::parse_time(since, now))
+        .transpose()?;
+    let until = matches
+        .value_of("until")
+        .map(|until| util::parse_time(until, now))
+        .transpose()?;
+    let filter_time = since.is_some() || until.is_some();
+
+    let show_aliases = matches.is_present("aliases");
+    let with_aliases = |label: String| {
+        if !show_aliases {
+            return label;
+        }
+
+        let aliases = session
+            .config
+            .aliases
+            .iter()
+            .filter(|(_, target)| **target == label)
+            .map(|(alias, _)| alias.as_str())
+            .collect::<Vec<_>>();
+
+        if aliases.is_empty() {
+            label
+        } else {
+            format!("{} (aliases: {})", label, aliases.join(", "))
+        }
+    };
+
+    if !(details || filter_kind || filter_time) {
+        let labels = session.record_labels()?;
+
+        if json {
+            println!("{}", output::labels_json(&labels)?);
+        } else {
+            for label in labels {
+                println!("{}", with_aliases(label));
+            }
+        }
+
+        return Ok(());
+    }
+
+    // NOTE: Details, kind filtering, and time filtering all require us to decrypt
+    // each record, so we spread the work across threads.
+    let records = session.par_map_records(None, |label, record| (label.to_string(), record))?;
+    let mut labels = vec![];
+
+    for (label, record) in records {
+        // NOTE: We don't prompt for each protected record's extra passphrase here,
+        // so they can't be filtered and are listed without their details.
+        if session.is_protected(&label)? {
+            if filter_kind || filter_time {
+                util::warn(&format!("excluding protected record: {}", label));
+            } else if json {
+                labels.push(label);
+            } else {
+                println!("{}\n\tProtected: yes", with_aliases(label));
+            }
+            continue;
+        }
 
-    for label in session.record_labels()? {
-        let mut display = String::new();
+        let record = record?;
 
-        if details || filter_kind {
-            let record = session.get_record(&label)?;
+        if filter_kind {
+            let kind = matches.value_of("kind").unwrap();
+            if record.body.to_string() != kind {
+                continue;
+            }
+        }
 
-            if filter_kind {
-                let kind = matches.value_of("kind").unwrap();
-                if record.body.to_string() != kind {
+        if filter_time {
+            match record.is_within(since, until) {
+                Some(true) => {}
+                Some(false) => continue,
+                None => {
+                    util::warn(&format!("excluding record without a timestamp: {}", label));
                     continue;
                 }
             }
+        }
 
-            display.push_str(&label);
+        if json {
+            labels.push(label);
+            continue;
+        }
 
-            if details {
-                display.push_str(&format!(
-                    "\n\tKind: {}\n\tTimestamp: {}",
-                    record.body, record.timestamp
-                ));
-            }
+        let last_accessed = if details {
+            session.last_accessed(&label)?
         } else {
-            display.push_str(&label);
+            None
+        };
+
+        let mut display = with_aliases(label);
+
+        if details {
+            display.push_str(&format!(
+                "\n\tKind: {}\n\tTimestamp: {}\n\tLast accessed: {}",
+                record.body,
+                record.timestamp,
+                last_accessed.map_or_else(|| "never".into(), |when| when.to_string())
+            ));
         }
 
         println!("{}", display);
     }
 
+    if json {
+        println!("{}", output::labels_json(&labels)?);
+    }
+
     Ok(())
 }
 
@@ -202,12 +574,121 @@ pub fn list(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 pub fn rm(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     log::debug!("removing a record");
 
-    let label = matches.value_of("label").unwrap();
-    session.delete_record(label)?;
+    let pattern = matches.value_of("label").unwrap();
+    let labels = session.delete_matching(pattern, |labels| {
+        if matches.is_present("yes") {
+            return Ok(true);
+        }
+
+        input::TerminalPrompt.confirm(&format!(
+            "{} matches {} records ({}); delete all of them?",
+            pattern,
+            labels.len(),
+            labels.join(", ")
+        ))
+    })?;
 
     if let Some(post_hook) = &session.config.commands.rm.post_hook {
         log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[&label])?;
+        for label in &labels {
+            session.config.call_post_hook(
+                post_hook,
+                &[label.as_str()],
+                &session.config.commands.rm.hook_env,
+                session.config.commands.rm.background_post_hook,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 mv` command.
+pub fn mv(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("moving record(s)");
+
+    let source = matches.value_of("source").unwrap();
+    let destination = matches.value_of("destination").unwrap();
+    session.move_records(source, destination)?;
+
+    if let Some(post_hook) = &session.config.commands.mv.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session.config.call_post_hook(
+            post_hook,
+            &[&source, &destination],
+            &session.config.commands.mv.hook_env,
+            session.config.commands.mv.background_post_hook,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 cp` command.
+pub fn cp(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("copying a record");
+
+    let source = matches.value_of("source").unwrap();
+    let destination = matches.value_of("destination").unwrap();
+
+    let generator = match matches.value_of("regen") {
+        Some(generator_name) => Some(
+            session
+                .config
+                .get_generator(generator_name)
+                .ok_or_else(|| anyhow!("couldn't find a generator named {}", generator_name))?,
+        ),
+        None => None,
+    };
+
+    session.copy_record(source, destination, matches.is_present("force"), generator)?;
+
+    Ok(())
+}
+
+/// Implements the `kbs2 attach` command.
+pub fn attach(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("attaching a file to a record");
+
+    let label = matches.value_of("label").unwrap();
+    let path = Path::new(matches.value_of("file").unwrap());
+
+    let name = match matches.value_of("name") {
+        Some(name) => name,
+        None => path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("couldn't get an attachment name from {}", path.display()))?,
+    };
+
+    let contents =
+        fs::read(path).map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+    session.attach(label, name, &contents)
+}
+
+/// Implements the `kbs2 attachment` command.
+pub fn attachment(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("getting a record's attachment(s)");
+
+    let label = matches.value_of("label").unwrap();
+
+    match (matches.value_of("name"), matches.value_of("output")) {
+        // NOTE: Decrypting straight into the output file would leave partial plaintext
+        // behind if decryption failed partway through, so we stream into a temporary file
+        // that replaces the output only once decryption succeeds.
+        (Some(name), Some(output)) => {
+            util::write_file_secure_with(Path::new(output), |file| {
+                session.read_attachment(label, name, file)
+            })?;
+        }
+        (Some(name), None) => {
+            session.read_attachment(label, name, &mut io::stdout().lock())?;
+        }
+        (None, _) => {
+            for name in session.attachment_names(label)? {
+                println!("{}", name);
+            }
+        }
     }
 
     Ok(())
@@ -217,13 +698,76 @@ pub fn rm(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 pub fn dump(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     log::debug!("dumping a record");
 
+    let json = matches.is_present("json");
+
+    if let Some(since) = matches.value_of("since") {
+        let since = util::parse_time(since, util::current_timestamp())?;
+
+        let count =
+            session.export_since(UNIX_EPOCH + Duration::from_secs(since), io::stdout().lock())?;
+        log::debug!("dumped {} changed record(s)", count);
+
+        return Ok(());
+    }
+
+    if matches.is_present("all") {
+        // NOTE: Records are decrypted and dumped one at a time, so that dumping a large
+        // store doesn't need to hold all of it in memory.
+        let mut failures = 0;
+        for record in session.records()? {
+            match record {
+                Ok(record) => dump_record(record, json)?,
+                Err(e) => {
+                    failures += 1;
+                    util::warn(&e.to_string());
+                }
+            }
+        }
+
+        return if failures > 0 {
+            Err(anyhow!("failed to dump {} record(s)", failures))
+        } else {
+            Ok(())
+        };
+    }
+
     let label = matches.value_of("label").unwrap();
-    let record = session.get_record(&label)?;
 
-    if matches.is_present("json") {
-        println!("{}", serde_json::to_string(&record)?);
+    // NOTE: Raw contents aren't necessarily a record, e.g. when they were encrypted
+    // with the age CLI, so they're written out untouched.
+    if matches.is_present("raw") {
+        let contents = session.get_raw_record(label)?;
+        io::stdout().write_all(&contents)?;
+        return Ok(());
+    }
+
+    let record = get_record(label, session)?;
+
+    if let Some(key) = matches.value_of("extra") {
+        return match record.extra.get(key) {
+            Some(value) => {
+                println!("{}", value);
+                Ok(())
+            }
+            None => Err(anyhow!("no such metadata on {}: {}", label, key)),
+        };
+    }
+
+    match matches.value_of("format") {
+        Some("env") => print!("{}", output::record_env(&record)),
+        Some("dotenv") => print!("{}", output::record_dotenv(&record)),
+        _ => dump_record(record, json)?,
+    }
+
+    Ok(())
+}
+
+#[doc(hidden)]
+fn dump_record(record: record::Record, json: bool) -> Result<()> {
+    if json {
+        println!("{}", output::record_json(&record)?);
     } else {
-        println!("Label: {}\n\tKind: {}", label, record.body);
+        println!("Label: {}\n\tKind: {}", record.label, record.body);
 
         match record.body {
             RecordBody::Login(l) => {
@@ -234,6 +778,13 @@ pub fn dump(matches: &ArgMatches, session: &session::Session) -> Result<()> {
             }
             RecordBody::Unstructured(u) => println!("\tContents: {}", u.contents),
         }
+
+        if !record.extra.is_empty() {
+            println!("\tExtra:");
+            for (key, value) in &record.extra {
+                println!("\t\t{}: {}", key, value);
+            }
+        }
     }
 
     Ok(())
@@ -241,68 +792,144 @@ pub fn dump(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 
 /// Implements the `kbs2 pass` command.
 pub fn pass(matches: &ArgMatches, session: &session::Session) -> Result<()> {
-    log::debug!("getting a login's password");
+    if matches.is_present("clear") {
+        log::debug!("clearing the clipboard");
+        return clear_clipboard(session);
+    }
+
+    log::debug!("getting a record's field");
 
     if let Some(pre_hook) = &session.config.commands.pass.pre_hook {
         log::debug!("pre-hook: {}", pre_hook);
-        session.config.call_hook(pre_hook, &[])?;
+        session
+            .config
+            .call_hook(pre_hook, &[], &session.config.commands.pass.hook_env)?;
     }
 
-    let label = matches.value_of("label").unwrap();
-    let record = session.get_record(&label)?;
+    // NOTE: Peeking only makes sense on a terminal; anywhere else, the secret would
+    // linger (e.g. in a pipe or a log) instead of being cleared.
+    if matches.is_present("peek") && atty::isnt(Stream::Stdout) {
+        return Err(anyhow!("refusing to peek when stdout isn't a terminal"));
+    }
 
-    let login = match record.body {
-        RecordBody::Login(l) => l,
-        _ => return Err(anyhow!("not a login record: {}", label)),
-    };
+    if matches.is_present("qr") && atty::isnt(Stream::Stdout) {
+        return Err(anyhow!(
+            "refusing to print a QR code when stdout isn't a terminal"
+        ));
+    }
+
+    let label = session.matching_label(matches.value_of("label").unwrap())?;
+    let record = get_record(&label, session)?;
+
+    // NOTE: --clipboard and --no-clipboard override each other, so only the last one
+    // given is present. Without --clipboard, neither the clipboard nor the clear-hook is touched.
+    let secret = record
+        .field(matches.value_of("field").unwrap())?
+        .to_string();
+    if let Some(exec) = matches.value_of("exec") {
+        // NOTE: The secret only ever goes to the command's stdin, never its arguments
+        // (where other users could see it) or the clipboard.
+        let (command, args) = util::parse_and_split_args(exec)?;
+        util::run_with_input(&command, &args, secret.as_bytes())?;
+    } else if matches.is_present("clipboard") && clipboard_supported(session) {
+        // NOTE: The osc52 clipboard is prepared before forking, so that a missing
+        // terminal or an oversized secret is reported by the parent's exit status.
+        let osc52 = match session.config.commands.pass.clipboard_backend {
+            config::ClipboardBackend::Osc52 => Some(prepare_osc52(&secret)?),
+            config::ClipboardBackend::Native => None,
+        };
 
-    let password = login.password;
-    if matches.is_present("clipboard") {
         match fork() {
             Ok(ForkResult::Child) => {
-                // NOTE(ww): More dumbness: cfg! gets expanded into a boolean literal,
-                // so it can't be used to conditionally compile code that only exists on
-                // one platform.
-                #[cfg(target_os = "linux")]
-                {
-                    match session.config.commands.pass.x11_clipboard {
-                        // NOTE(ww): Why, might you ask, is clip_primary its own function?
-                        // It's because the clipboard crate has a bad abstraction:
-                        // ClipboardContext is the top-level type, but it's aliased to
-                        // X11Clipboard<Clipboard>. That means we can't produce it on a match.
-                        // The other option would be to create a ClipboardProvider trait object,
-                        // but it doesn't implement Sized. So we have to do things the dumb
-                        // way here. Alternatively, I could just be missing something obvious.
-                        config::X11Clipboard::Primary => clip_primary(password, &session)?,
-                        config::X11Clipboard::Clipboard => clip(password, &session)?,
-                    };
-                }
-
-                #[cfg(target_os = "macos")]
-                {
-                    clip(password, &session)?;
+                if let Some((tty, sequence)) = osc52 {
+                    clip_osc52(tty, &sequence, &session)?;
+                } else {
+                    clip_native(secret, &session)?;
                 }
             }
             Err(_) => return Err(anyhow!("clipboard fork failed")),
             _ => {}
         }
+    } else if matches.is_present("qr") {
+        println!("{}", output::qr(&secret)?);
+    } else if matches.is_present("peek") {
+        peek(&secret, &session)?;
     } else if atty::isnt(Stream::Stdout) {
-        print!("{}", password);
+        print!("{}", secret);
     } else {
-        println!("{}", password);
+        println!("{}", secret);
     }
 
     if let Some(post_hook) = &session.config.commands.pass.post_hook {
         log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[])?;
+        session.config.call_post_hook(
+            post_hook,
+            &[],
+            &session.config.commands.pass.hook_env,
+            session.config.commands.pass.background_post_hook,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns whether the configured clipboard backend is available, warning (so that
+/// `kbs2 pass` falls back to printing the secret) if it isn't.
+///
+/// Only the native clipboard can be missing, when `kbs2` is built without the `clipboard`
+/// feature (e.g. for a headless server without X11).
+#[doc(hidden)]
+fn clipboard_supported(session: &session::Session) -> bool {
+    match session.config.commands.pass.clipboard_backend {
+        config::ClipboardBackend::Osc52 => true,
+        config::ClipboardBackend::Native if cfg!(feature = "clipboard") => true,
+        config::ClipboardBackend::Native => {
+            util::warn("kbs2 was built without clipboard support; printing the secret instead");
+            false
+        }
+    }
+}
+
+#[doc(hidden)]
+#[cfg(feature = "clipboard")]
+fn clip_native(password: String, session: &session::Session) -> Result<()> {
+    // NOTE(ww): More dumbness: cfg! gets expanded into a boolean literal,
+    // so it can't be used to conditionally compile code that only exists on
+    // one platform.
+    #[cfg(target_os = "linux")]
+    {
+        match session.config.commands.pass.clipboard_selection {
+            // NOTE(ww): Why, might you ask, is clip_primary its own function?
+            // It's because the clipboard crate has a bad abstraction:
+            // ClipboardContext is the top-level type, but it's aliased to
+            // X11Clipboard<Clipboard>. That means we can't produce it on a match.
+            // The other option would be to create a ClipboardProvider trait object,
+            // but it doesn't implement Sized. So we have to do things the dumb
+            // way here. Alternatively, I could just be missing something obvious.
+            config::ClipboardSelection::Primary => clip_primary(password, session)?,
+            config::ClipboardSelection::Clipboard => clip(password, session)?,
+        };
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        clip(password, session)?;
     }
 
     Ok(())
 }
 
+/// The native clipboard isn't available without the `clipboard` feature; `kbs2 pass`
+/// checks for this (see `clipboard_supported`) before getting here.
+#[doc(hidden)]
+#[cfg(not(feature = "clipboard"))]
+fn clip_native(_password: String, _session: &session::Session) -> Result<()> {
+    Err(anyhow!("kbs2 was built without clipboard support"))
+}
+
 #[doc(hidden)]
+#[cfg(feature = "clipboard")]
 fn clip(password: String, session: &session::Session) -> Result<()> {
-    let clipboard_duration = session.config.commands.pass.clipboard_duration;
     let clear_after = session.config.commands.pass.clear_after;
 
     let mut ctx: ClipboardContext =
@@ -310,27 +937,23 @@ fn clip(password: String, session: &session::Session) -> Result<()> {
     ctx.set_contents(password)
         .map_err(|_| anyhow!("unable to store to the clipboard"))?;
 
-    std::thread::sleep(std::time::Duration::from_secs(clipboard_duration));
+    session.config.commands.pass.wait_for_clear()?;
 
     if clear_after {
         ctx.set_contents("".to_owned())
             .map_err(|_| anyhow!("unable to clear the clipboard"))?;
 
-        if let Some(clear_hook) = &session.config.commands.pass.clear_hook {
-            log::debug!("clear-hook: {}", clear_hook);
-            session.config.call_hook(clear_hook, &[])?;
-        }
+        call_clear_hook(session)?;
     }
 
     Ok(())
 }
 
 #[doc(hidden)]
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "clipboard", target_os = "linux"))]
 fn clip_primary(password: String, session: &session::Session) -> Result<()> {
     use clipboard::x11_clipboard::{Primary, X11ClipboardContext};
 
-    let clipboard_duration = session.config.commands.pass.clipboard_duration;
     let clear_after = session.config.commands.pass.clear_after;
 
     let mut ctx: X11ClipboardContext<Primary> =
@@ -338,18 +961,139 @@ fn clip_primary(password: String, session: &session::Session) -> Result<()> {
     ctx.set_contents(password)
         .map_err(|_| anyhow!("unable to store to the clipboard"))?;
 
-    std::thread::sleep(std::time::Duration::from_secs(clipboard_duration));
+    session.config.commands.pass.wait_for_clear()?;
 
     if clear_after {
         ctx.set_contents("".to_owned())
             .map_err(|_| anyhow!("unable to clear the clipboard"))?;
 
-        if let Some(clear_hook) = &session.config.commands.pass.clear_hook {
-            log::debug!("clear-hook: {}", clear_hook);
-            session.config.call_hook(clear_hook, &[])?;
+        call_clear_hook(session)?;
+    }
+
+    Ok(())
+}
+
+/// Opens the controlling terminal and builds the OSC 52 sequence for the given secret,
+/// for use by `clip_osc52`.
+#[doc(hidden)]
+fn prepare_osc52(password: &str) -> Result<(fs::File, String)> {
+    // NOTE: The sequence is written to the controlling terminal rather than stdout,
+    // so that it can't end up in a pipe or a file.
+    let tty = fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|_| anyhow!("the osc52 clipboard requires a terminal"))?;
+    let sequence = util::osc52_sequence(password, util::Multiplexer::detect())?;
+
+    Ok((tty, sequence))
+}
+
+#[doc(hidden)]
+fn clip_osc52(mut tty: fs::File, sequence: &str, session: &session::Session) -> Result<()> {
+    let clear_after = session.config.commands.pass.clear_after;
+
+    tty.write_all(sequence.as_bytes())?;
+    tty.flush()?;
+
+    session.config.commands.pass.wait_for_clear()?;
+
+    if clear_after {
+        util::clear_osc52(&mut tty, util::Multiplexer::detect())?;
+
+        call_clear_hook(session)?;
+    }
+
+    Ok(())
+}
+
+/// Clears the configured clipboard right away, then runs the clear-hook (if any).
+#[doc(hidden)]
+fn clear_clipboard(session: &session::Session) -> Result<()> {
+    match session.config.commands.pass.clipboard_backend {
+        config::ClipboardBackend::Osc52 => {
+            let (mut tty, _) = prepare_osc52("")?;
+            util::clear_osc52(&mut tty, util::Multiplexer::detect())?;
         }
+        config::ClipboardBackend::Native => clear_native(session)?,
+    }
+
+    call_clear_hook(session)
+}
+
+#[doc(hidden)]
+#[cfg(feature = "clipboard")]
+fn clear_native(session: &session::Session) -> Result<()> {
+    // NOTE: See the NOTEs in clip_native for why each clipboard is handled separately.
+    #[cfg(target_os = "linux")]
+    {
+        use clipboard::x11_clipboard::{Primary, X11ClipboardContext};
+
+        match session.config.commands.pass.clipboard_selection {
+            config::ClipboardSelection::Primary => {
+                let mut ctx: X11ClipboardContext<Primary> = ClipboardProvider::new()
+                    .map_err(|_| anyhow!("unable to grab the clipboard"))?;
+                ctx.set_contents("".to_owned())
+                    .map_err(|_| anyhow!("unable to clear the clipboard"))?;
+            }
+            config::ClipboardSelection::Clipboard => {
+                let mut ctx: ClipboardContext = ClipboardProvider::new()
+                    .map_err(|_| anyhow!("unable to grab the clipboard"))?;
+                ctx.set_contents("".to_owned())
+                    .map_err(|_| anyhow!("unable to clear the clipboard"))?;
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let mut ctx: ClipboardContext =
+            ClipboardProvider::new().map_err(|_| anyhow!("unable to grab the clipboard"))?;
+        ctx.set_contents("".to_owned())
+            .map_err(|_| anyhow!("unable to clear the clipboard"))?;
+    }
+
+    Ok(())
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "clipboard"))]
+fn clear_native(_session: &session::Session) -> Result<()> {
+    Err(anyhow!("kbs2 was built without clipboard support"))
+}
+
+#[doc(hidden)]
+fn call_clear_hook(session: &session::Session) -> Result<()> {
+    if let Some(clear_hook) = &session.config.commands.pass.clear_hook {
+        log::debug!("clear-hook: {}", clear_hook);
+        session
+            .config
+            .call_hook(clear_hook, &[], &session.config.commands.pass.hook_env)?;
+    }
+
+    Ok(())
+}
+
+#[doc(hidden)]
+fn peek(password: &str, session: &session::Session) -> Result<()> {
+    let clear_after = session.config.commands.pass.clear_after;
+
+    let mut stdout = io::stdout();
+
+    if !clear_after {
+        writeln!(stdout, "{}", password)?;
+        return Ok(());
     }
 
+    // NOTE: No trailing newline, so that the secret stays on the current line
+    // and can be erased by returning to the start of it.
+    write!(stdout, "{}", password)?;
+    stdout.flush()?;
+
+    std::thread::sleep(session.config.commands.pass.clear_duration());
+
+    write!(stdout, "\r\x1b[2K")?;
+    stdout.flush()?;
+
     Ok(())
 }
 
@@ -358,7 +1102,7 @@ pub fn env(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     log::debug!("getting a environment variable");
 
     let label = matches.value_of("label").unwrap();
-    let record = session.get_record(&label)?;
+    let record = get_record(label, session)?;
 
     let environment = match record.body {
         RecordBody::Environment(e) => e,
@@ -380,6 +1124,67 @@ pub fn env(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 pub fn edit(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     log::debug!("editing a record");
 
+    // NOTE: The edited record is written back under its label, so we resolve any
+    // alias up front instead of leaving it to `get_record`.
+    let label = session
+        .config
+        .resolve_alias(matches.value_of("label").unwrap());
+
+    let (original, passphrase) = get_record_with_passphrase(label, session)?;
+
+    let mut record = if matches.is_present("set") || matches.is_present("append-note") {
+        let mut record = original.clone();
+
+        for assignment in matches.values_of("set").into_iter().flatten() {
+            let (name, value) = match assignment.find('=') {
+                Some(index) => (&assignment[..index], &assignment[index + 1..]),
+                None => return Err(anyhow!("expected KEY=VALUE, not: {}", assignment)),
+            };
+
+            record.set(name, value)?;
+        }
+
+        if let Some(note) = matches.value_of("append-note") {
+            record.append_note(note);
+        }
+
+        record
+    } else {
+        edit_with_editor(&original, session)?
+    };
+
+    // Users can't modify these fields, at least not with `kbs2 edit`.
+    record.label = label.into();
+    record.timestamp = util::current_timestamp();
+
+    // NOTE: Only changed secrets are checked, so that a weak secret doesn't get in the
+    // way of unrelated edits (e.g. appending a note) under strict-entropy.
+    if record.body != original.body {
+        session.config.check_secret_entropy(&record)?;
+    }
+
+    match passphrase {
+        Some(passphrase) => session.add_protected_record(&record, passphrase)?,
+        None => session.add_record(&record)?,
+    }
+
+    if let Some(post_hook) = &session.config.commands.edit.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session.config.call_post_hook(
+            post_hook,
+            &[],
+            &session.config.commands.edit.hook_env,
+            session.config.commands.edit.background_post_hook,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Opens the given record in the configured editor, returning the record as it was when
+/// the editor exited.
+#[doc(hidden)]
+fn edit_with_editor(record: &record::Record, session: &session::Session) -> Result<record::Record> {
     let editor = match session
         .config
         .commands
@@ -397,11 +1202,8 @@ pub fn edit(matches: &ArgMatches, session: &session::Session) -> Result<()> {
 
     log::debug!("editor: {}, args: {:?}", editor, editor_args);
 
-    let label = matches.value_of("label").unwrap();
-    let record = session.get_record(&label)?;
-
     let mut file = tempfile::NamedTempFile::new()?;
-    file.write_all(&serde_json::to_vec_pretty(&record)?)?;
+    file.write_all(&serde_json::to_vec_pretty(record)?)?;
 
     if !process::Command::new(&editor)
         .args(&editor_args)
@@ -417,24 +1219,40 @@ pub fn edit(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     let mut record_contents = vec![];
     file.read_to_end(&mut record_contents)?;
 
-    let mut record = serde_json::from_slice::<record::Record>(&record_contents)?;
+    Ok(serde_json::from_slice::<record::Record>(&record_contents)?)
+}
 
-    // Users can't modify these fields, at least not with `kbs2 edit`.
-    record.label = label.into();
-    record.timestamp = util::current_timestamp();
+/// Implements the `kbs2 generate` command.
+pub fn generate(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    // NOTE: An ad-hoc generator starts from the default internal generator's settings,
+    // overridden by whichever flags were given.
+    if matches.is_present("alphabet") || matches.is_present("length") {
+        if matches.occurrences_of("generator") > 0 {
+            return Err(anyhow!(
+                "--alphabet and --length can't be combined with a named generator"
+            ));
+        }
 
-    session.add_record(&record)?;
+        let mut spec = config::GeneratorInternalConfig {
+            name: "ad-hoc".into(),
+            ..Default::default()
+        };
+        if let Some(alphabet) = matches.value_of("alphabet") {
+            spec.alphabet = alphabet.into();
+        }
+        if let Some(length) = matches.value_of("length") {
+            spec.length = length
+                .parse()
+                .map_err(|_| anyhow!("invalid length: {}", length))?;
+        }
 
-    if let Some(post_hook) = &session.config.commands.edit.post_hook {
-        log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[])?;
-    }
+        let adhoc = generator::build_generator(&generator::GeneratorSpec::Internal(spec));
+        adhoc.validate()?;
+        println!("{}", adhoc.secret()?);
 
-    Ok(())
-}
+        return Ok(());
+    }
 
-/// Implements the `kbs2 generate` command.
-pub fn generate(matches: &ArgMatches, session: &session::Session) -> Result<()> {
     let generator = {
         let generator_name = matches.value_of("generator").unwrap();
         match session.config.get_generator(generator_name) {
@@ -452,3 +1270,131 @@ pub fn generate(matches: &ArgMatches, session: &session::Session) -> Result<()>
 
     Ok(())
 }
+
+/// Implements the `kbs2 audit` command.
+pub fn audit(_matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("auditing the store");
+
+    let report = session.audit(util::current_timestamp())?;
+    print!("{}", report);
+
+    match report.critical() {
+        0 => Ok(()),
+        critical => Err(anyhow!("audit found {} critical problem(s)", critical)),
+    }
+}
+
+/// Implements the `kbs2 totp-export` command.
+pub fn totp_export(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("exporting TOTP key URIs");
+
+    if matches.is_present("qr") && atty::isnt(Stream::Stdout) {
+        return Err(anyhow!(
+            "refusing to print a QR code when stdout isn't a terminal"
+        ));
+    }
+
+    // NOTE: Like peeking, exporting is meant for a terminal (e.g. to scan from a new
+    // device), so the secrets are redacted anywhere else unless explicitly forced.
+    let redact = atty::isnt(Stream::Stdout) && !matches.is_present("force");
+
+    let uris = session.totp_uris()?;
+    let periods = uris
+        .iter()
+        .map(|(_, uri)| record::totp_period(uri))
+        .collect::<Result<Vec<_>>>()?;
+
+    // NOTE: A code is only valid for its own window (and maybe the ones next to it), so a
+    // clock that's off by more than half a period is liable to produce rejected codes.
+    if let Some(&period) = periods.iter().min() {
+        match session.config.clock_skew() {
+            Ok(Some(skew)) if skew > period / 2 => util::warn(&format!(
+                "system clock is off by {} seconds, so TOTP codes may be rejected",
+                skew
+            )),
+            Ok(_) => {}
+            Err(e) => util::warn(&format!("couldn't check the system clock: {}", e)),
+        }
+    }
+
+    let now = util::current_timestamp();
+    for ((label, uri), period) in uris.into_iter().zip(periods) {
+        if matches.is_present("window") {
+            let (counter, remaining) = record::totp_window(now, period);
+            println!(
+                "{}: window {}, valid for {} more seconds",
+                label, counter, remaining
+            );
+        } else if matches.is_present("qr") {
+            println!("{}\n{}", label, output::qr(&uri)?);
+        } else if redact {
+            println!("{}", output::redact_uri_secret(&uri));
+        } else {
+            println!("{}", uri);
+        }
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 import` command.
+pub fn import(matches: &ArgMatches, session: &session::Session) -> Result<()> {
+    log::debug!("importing records from a CSV export");
+
+    let columns =
+        import::CsvColumns::with_mappings(matches.values_of("column").into_iter().flatten())?;
+
+    let file = matches.value_of("file").unwrap();
+    let input = if file == "-" {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        input
+    } else {
+        fs::read_to_string(file).map_err(|e| anyhow!("couldn't read {}: {}", file, e))?
+    };
+
+    let outcomes = session.import_csv(&input, &columns, matches.is_present("overwrite"))?;
+
+    let (mut imported, mut skipped, mut failed) = (0, 0, 0);
+    for (line, outcome) in &outcomes {
+        match outcome {
+            import::ImportOutcome::Imported(label) => {
+                imported += 1;
+                println!("{}", label);
+            }
+            import::ImportOutcome::Skipped(label) => {
+                skipped += 1;
+                util::warn(&format!(
+                    "line {}: skipping {}, which already exists (use --overwrite to replace it)",
+                    line, label
+                ));
+            }
+            import::ImportOutcome::Failed(e) => {
+                failed += 1;
+                util::warn(&format!("line {}: {}", line, e));
+            }
+        }
+    }
+
+    // NOTE: Each imported label has already been printed, so the summary is only
+    // worth a warning when some rows weren't imported.
+    if skipped > 0 || failed > 0 {
+        util::warn(&format!(
+            "imported {} of {} rows ({} skipped, {} failed)",
+            imported,
+            outcomes.len(),
+            skipped,
+            failed
+        ));
+    }
+
+    if failed > 0 {
+        Err(anyhow!(
+            "failed to import {} of {} rows",
+            failed,
+            outcomes.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
@@ -1,26 +1,40 @@
 use age::Decryptor;
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Result};
+use atty::Stream;
 use memmap::Mmap;
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
 use nix::sys::mman;
 use nix::sys::stat::Mode;
 use nix::unistd;
+use rand::distributions::{Distribution, Uniform};
+use rand::Rng;
+use secrecy::SecretString;
 use serde::{de, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::env;
+use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::ops::DerefMut;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::kbs2::backend::{Backend, RageLib};
+use crate::kbs2::audit;
+use crate::kbs2::backend::{self, Backend, RageLib};
 use crate::kbs2::generator::Generator;
+use crate::kbs2::input;
+use crate::kbs2::record;
 use crate::kbs2::util;
 
 /// The default base config directory name, placed relative to the user's config
@@ -42,6 +56,47 @@ pub static UNWRAPPED_KEY_SHM_BASENAME: &str = "/_kbs2_uk";
 /// the user's data directory by default.
 pub static STORE_BASEDIR: &str = "kbs2";
 
+/// The header that begins every binary age file.
+pub static AGE_HEADER: &[u8] = b"age-encryption.org/";
+
+/// The header that begins every ASCII-armored age file.
+pub static AGE_ARMOR_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// The default pinentry binary, used when `passphrase-entry` is `"auto"` or `"pinentry"`.
+pub static DEFAULT_PINENTRY: &str = "pinentry";
+
+/// The default number of times that the master password is prompted for before giving up.
+pub const DEFAULT_PASSPHRASE_ATTEMPTS: u32 = 3;
+
+/// The scrypt work factor assumed for wrapped keys when the config doesn't record one.
+///
+/// NOTE: Configs created before `wrap-work-factor` existed always unwrapped with this factor.
+pub const DEFAULT_WRAP_WORK_FACTOR: u8 = 18;
+
+/// How often the `lock-command` is checked on, when the clipboard is cleared on either a lock
+/// or a timer.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait on another `kbs2` process that's midway through unwrapping the key.
+const UNWRAP_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The delay after the first wrong master password; each subsequent one waits longer.
+const PASSPHRASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The default maximum size, in bytes, of a single record attachment.
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The exit code with which a hook cancels the command that called it, without that being
+/// treated as an error (`EX_TEMPFAIL` in `sysexits.h`).
+pub const HOOK_CANCEL_CODE: i32 = 75;
+
+/// The basename of the log file, relative to the configuration directory, that background
+/// hooks write their output to when `background-hook-log` is enabled.
+pub static BACKGROUND_HOOK_LOG_BASENAME: &str = "hooks.log";
+
+/// The configuration key that lists other configuration files to merge in at load time.
+pub static INCLUDE_KEY: &str = "include";
+
 /// The main kbs2 configuration structure.
 /// The fields of this structure correspond directly to the fields
 /// loaded from the configuration file.
@@ -53,10 +108,53 @@ pub struct Config {
     #[serde(skip)]
     pub config_dir: String,
 
+    /// The name of the profile that this configuration was loaded from, if any.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself.
+    #[serde(skip)]
+    pub profile: Option<String>,
+
+    /// The path to the configuration file that this configuration was loaded from, if it
+    /// was chosen directly (see `load_file`) rather than by `config_dir` and `profile`.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself.
+    #[serde(skip)]
+    pub config_file: Option<String>,
+
+    /// The unwrapped key that was opened to decrypt encrypted includes while loading this
+    /// configuration, if any. Holding onto it keeps it in shared memory for as long as
+    /// the configuration is in use, so that the key isn't unwrapped a second time.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself.
+    #[serde(skip)]
+    pub unwrapped_key: Option<UnwrappedKey>,
+
     /// The public component of the keypair.
     #[serde(rename = "public-key")]
     pub public_key: String,
 
+    /// An optional file listing additional age recipients (one per line) to encrypt records
+    /// to, alongside `public-key`. Relative paths are resolved against the configuration
+    /// directory.
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "recipients-file")]
+    #[serde(default)]
+    pub recipients_file: Option<String>,
+
+    /// Rules that encrypt records with matching labels to their own recipients, instead of
+    /// those in `recipients-file`. The first matching rule wins.
+    #[serde(rename = "recipient-policy")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recipient_policy: Vec<RecipientRule>,
+
+    /// An optional SSH private key (e.g. `~/.ssh/id_ed25519`) to decrypt with, alongside the
+    /// keyfile, for records encrypted to its public key. Requires the `ssh` feature.
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "ssh-identity")]
+    #[serde(default)]
+    pub ssh_identity: Option<String>,
+
     /// The path to a file containing the private component of the keypair,
     /// which may be wrapped with a passphrase.
     #[serde(deserialize_with = "deserialize_with_tilde")]
@@ -66,31 +164,168 @@ pub struct Config {
     /// a passphrase.
     pub wrapped: bool,
 
+    /// How the passphrase for a wrapped keypair is entered.
+    #[serde(default)]
+    #[serde(rename = "passphrase-entry")]
+    pub passphrase_entry: PassphraseEntry,
+
+    /// The pinentry binary to use when `passphrase-entry` is `"auto"` or `"pinentry"`.
+    #[serde(default = "default_pinentry")]
+    pub pinentry: String,
+
+    /// The number of times the master password is prompted for when unwrapping a wrapped
+    /// key, before giving up.
+    ///
+    /// NOTE: When stdin isn't a terminal, the password is only ever prompted for once.
+    #[serde(default = "default_passphrase_attempts")]
+    #[serde(rename = "passphrase-attempts")]
+    pub passphrase_attempts: u32,
+
+    /// The scrypt work factor that the wrapped key was wrapped with, which is also the
+    /// largest work factor accepted when unwrapping it.
+    ///
+    /// NOTE: This is recorded whenever `kbs2` wraps a key, so it shouldn't normally need
+    /// to be changed by hand.
+    #[serde(default = "default_wrap_work_factor")]
+    #[serde(rename = "wrap-work-factor")]
+    pub wrap_work_factor: u8,
+
     /// The path to the directory where encrypted records are stored.
+    ///
+    /// **NOTE**: `load` resolves this into an absolute path (relative paths are resolved
+    /// against the configuration directory), so that it's unaffected by the process's
+    /// working directory. See `unresolved_store` for the path as originally configured.
     #[serde(deserialize_with = "deserialize_with_tilde")]
+    #[serde(skip_serializing)]
     pub store: String,
 
-    /// An optional command to run before each `kbs2` subcommand.
+    /// The path to the record store, as it appears in the configuration file.
+    ///
+    /// **NOTE**: This field is only used for display and serialization; use `store`
+    /// for all store operations.
+    #[serde(rename = "store")]
+    #[serde(skip_deserializing)]
+    pub unresolved_store: String,
+
+    /// Whether or not the store is read-only, i.e. whether every command that would change
+    /// it is rejected up front. See `check_store_writable`.
+    #[serde(default)]
+    #[serde(rename = "read-only")]
+    pub read_only: bool,
+
+    /// What to do when the store doesn't exist. See `open_store`.
+    #[serde(default)]
+    #[serde(rename = "on-missing-store")]
+    pub on_missing_store: OnMissingStore,
+
+    /// How record labels are mapped onto filenames within the store.
+    #[serde(default)]
+    #[serde(rename = "label-encoding")]
+    pub label_encoding: LabelEncoding,
+
+    /// How the case of record labels is handled, e.g. on case-insensitive filesystems.
+    #[serde(default)]
+    #[serde(rename = "label-case")]
+    pub label_case: LabelCase,
+
+    /// Whether or not records are stored in age's ASCII-armored format, rather than
+    /// its binary format.
+    ///
+    /// Records in either format are always readable, regardless of this setting.
+    #[serde(default = "default_store_armor")]
+    #[serde(rename = "store-armor")]
+    pub store_armor: bool,
+
+    /// How records are serialized before encryption.
+    ///
+    /// Records in any format are always readable, regardless of this setting.
+    #[serde(default)]
+    #[serde(rename = "record-format")]
+    pub record_format: RecordFormat,
+
+    /// The maximum size, in bytes, of a single record attachment.
+    #[serde(default = "default_max_attachment_size")]
+    #[serde(rename = "max-attachment-size")]
+    pub max_attachment_size: u64,
+
+    /// The number of bits of estimated entropy (see `audit::estimate_entropy`) below which a
+    /// secret is considered weak, both by `kbs2 audit` and when a record is written by
+    /// `kbs2 new` or `kbs2 edit`. See `check_secret_entropy`.
+    #[serde(default = "default_min_entropy")]
+    #[serde(rename = "min-entropy")]
+    pub min_entropy: f64,
+
+    /// Whether or not `kbs2 new` and `kbs2 edit` refuse to write a record with a weak secret,
+    /// rather than only warning about it.
+    #[serde(default)]
+    #[serde(rename = "strict-entropy")]
+    pub strict_entropy: bool,
+
+    /// The level at which `kbs2` logs, unless overridden by `--verbose` or `RUST_LOG`.
+    #[serde(default)]
+    #[serde(rename = "log-level")]
+    pub log_level: LogLevel,
+
+    /// An optional command that prints the current time (in seconds since the Unix epoch)
+    /// according to a trusted source, for checking the system clock against (see
+    /// `clock_skew`).
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "clock-check")]
+    #[serde(default)]
+    pub clock_check: Option<String>,
+
+    /// An optional command to run before each `kbs2` subcommand.
+    #[serde(deserialize_with = "deserialize_optional_hook")]
     #[serde(rename = "pre-hook")]
     #[serde(default)]
     pub pre_hook: Option<String>,
 
     /// An optional command to run after each `kbs2` subcommand, on success.
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(deserialize_with = "deserialize_optional_hook")]
     #[serde(rename = "post-hook")]
     #[serde(default)]
     pub post_hook: Option<String>,
 
+    /// An optional command to run after each `kbs2` subcommand, whether it succeeded or not.
+    /// See `call_finally_hooks`.
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    #[serde(default)]
+    pub finally_hook: Option<String>,
+
+    /// An optional command to run whenever the wrapped key is freshly unwrapped into shared
+    /// memory, but not when an already-unwrapped key is reused. See `call_unwrap_hook`.
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "on-unwrap")]
+    #[serde(default)]
+    pub on_unwrap: Option<String>,
+
     /// Whether or not any hooks are called when a hook itself invokes `kbs2`.
     #[serde(default)]
     #[serde(rename = "reentrant-hooks")]
     pub reentrant_hooks: bool,
 
+    /// Whether or not post-hooks are run in the background, without waiting for them to
+    /// exit, unless overridden by a command's `background-post-hook`. See `call_post_hook`.
+    #[serde(default)]
+    #[serde(rename = "background-hooks")]
+    pub background_hooks: bool,
+
+    /// Whether or not the output of background hooks is appended to a log file in the
+    /// config directory (see `BACKGROUND_HOOK_LOG_BASENAME`), rather than discarded.
+    #[serde(default)]
+    #[serde(rename = "background-hook-log")]
+    pub background_hook_log: bool,
+
     /// Any secret generators configured by the user.
     #[serde(default)]
     pub generators: Vec<GeneratorConfig>,
 
+    /// Short aliases for record labels, as `alias -> label`. See `resolve_alias`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+
     /// Per-command configuration.
     #[serde(default)]
     pub commands: CommandConfigs,
@@ -100,540 +335,4428 @@ impl Config {
     /// Calls a command as a hook, meaning:
     /// * The command is run with the `kbs2` store as its working directory
     /// * The command is run with `KBS2_HOOK=1` in its environment
+    /// * The command is run with `KBS2_HOOK_CONFIG` (see `hook_token`) in its environment
+    /// * The command is run with any variables in `hook_env` in its environment
+    /// * The command inherits `kbs2`'s `PATH`, which a bare command name (e.g. `sync`) is
+    ///   looked up in. Configured hooks have already had tildes and environment variables
+    ///   expanded when they were loaded
     ///
     /// Hooks have the following behavior:
-    /// 1. If `reentrant-hooks` is `true` *or* `KBS2_HOOK` is *not* present in the environment,
-    ///    the hook is run.
-    /// 2. If `reentrant-hooks` is `false` (the default) *and* `KBS2_HOOK` is already present
-    ///    (indicating that we're already in a hook), nothing is run.
-    pub fn call_hook(&self, cmd: &str, args: &[&str]) -> Result<()> {
-        if self.reentrant_hooks || env::var("KBS2_HOOK").is_err() {
-            let success = Command::new(cmd)
-                .args(args)
-                .current_dir(Path::new(&self.store))
-                .env("KBS2_HOOK", "1")
-                .env("KBS2_CONFIG_DIR", &self.config_dir)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .status()
-                .map(|s| s.success())
-                .map_err(|_| anyhow!("failed to run hook: {}", cmd))?;
-
-            if success {
-                Ok(())
-            } else {
-                Err(anyhow!("hook exited with an error code: {}", cmd))
-            }
-        } else {
-            util::warn("nested hook requested without reentrant-hooks; skipping");
-            Ok(())
+    /// 1. If `reentrant-hooks` is `true` *or* we're not already in a hook for this
+    ///    configuration (see `is_nested_hook`), the hook is run.
+    /// 2. If `reentrant-hooks` is `false` (the default) *and* we're already in a hook for
+    ///    this configuration, nothing is run.
+    ///
+    /// A hook that exits with `HOOK_CANCEL_CODE` produces a `HookCancelled` error; any other
+    /// unsuccessful exit produces an ordinary error.
+    ///
+    /// NOTE: The built-in `KBS2_*` variables always take precedence over `hook_env`, since
+    /// reentrancy detection depends on them.
+    pub fn call_hook(&self, cmd: &str, args: &[&str], hook_env: &HookEnv) -> Result<()> {
+        if !self.should_call_hook() {
+            return Ok(());
+        }
+
+        let status = self
+            .hook_command(cmd, args, hook_env)
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|_| anyhow!("failed to run hook: {}", cmd))?;
+
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(HOOK_CANCEL_CODE) => Err(HookCancelled { hook: cmd.into() }.into()),
+            _ => Err(anyhow!("hook exited with an error code: {}", cmd)),
         }
     }
 
-    /// Given the `name` of a configured generator, return that generator
-    /// if it exists.
-    pub fn get_generator(&self, name: &str) -> Option<&dyn Generator> {
-        for generator_config in self.generators.iter() {
-            let generator = generator_config.as_dyn();
-            if generator.name() == name {
-                return Some(generator);
-            }
+    /// Calls a command as a post-hook, i.e. like `call_hook`, unless it's run in the
+    /// background. `background` is the hook's own `background-post-hook` setting, if it has
+    /// one; otherwise, `background-hooks` decides.
+    ///
+    /// A background hook is started in a new session, detached from `kbs2` and its terminal,
+    /// and isn't waited for: `kbs2` can exit while the hook is still running. As a result,
+    /// only a failure to start the hook is reported; its exit status (including
+    /// `HOOK_CANCEL_CODE`) is ignored. Its output is discarded, unless `background-hook-log`
+    /// is set.
+    pub fn call_post_hook(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        hook_env: &HookEnv,
+        background: Option<bool>,
+    ) -> Result<()> {
+        if !background.unwrap_or(self.background_hooks) {
+            return self.call_hook(cmd, args, hook_env);
         }
 
-        None
-    }
+        if !self.should_call_hook() {
+            return Ok(());
+        }
 
-    /// Returns a suitable identifier for a shared memory object that
-    /// can (or already does) store the unwrapped key.
-    pub fn unwrapped_key_shm_name(&self) -> Result<PathBuf> {
-        let canonicalized_keyfile = fs::canonicalize(&self.keyfile)?;
+        let (stdout, stderr) = if self.background_hook_log {
+            let log_path = Path::new(&self.config_dir).join(BACKGROUND_HOOK_LOG_BASENAME);
+            let log = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(&log_path)
+                .map_err(|e| anyhow!("couldn't open {}: {}", log_path.display(), e))?;
 
-        // NOTE(ww): Why do we truncate the shared memory object's name to 31 characters
-        // (i.e., bytes)? Because that's all macOS supports! See PSHMNAMELEN in sys/posix.h.
-        // Hashing the keyfile's path isn't done here for security anyways; it's just to
-        // prevent the same shared memory object from being accessed by separate config
-        // files (and kbs2 consequently barfing when the key doesn't work with one of the
-        // stores).
-        let mut shm_name = format!(
-            "{}_{:x}",
-            UNWRAPPED_KEY_SHM_BASENAME,
-            Sha256::digest(canonicalized_keyfile.as_os_str().as_bytes())
-        );
-        shm_name.truncate(31);
+            (Stdio::from(log.try_clone()?), Stdio::from(log))
+        } else {
+            (Stdio::null(), Stdio::null())
+        };
 
-        Ok(shm_name.into())
-    }
+        let mut command = self.hook_command(cmd, args, hook_env);
+        command.stdout(stdout).stderr(stderr);
 
-    /// Unwraps the configured private key file into its underlying private
-    /// key, returning a `fs::File` that owns an open reference to that key.
-    ///
-    /// NOTE: This function assumes that the key file is wrapped. Calling
-    /// it with a non-wrapped key file will cause an error.
-    pub fn unwrap_keyfile(&self) -> Result<fs::File> {
-        // Unwrapping our password-protected keyfile and returning it as a raw file descriptor
-        // is a multi-step process.
+        // NOTE: setsid detaches the hook from our terminal and process group, so that
+        // it isn't killed alongside kbs2 (e.g. by a ^C after kbs2 has exited).
+        unsafe {
+            command.pre_exec(|| {
+                unistd::setsid()
+                    .map(drop)
+                    .map_err(|_| io::Error::last_os_error())
+            });
+        }
 
-        let shm_name = self.unwrapped_key_shm_name()?;
+        let child = command
+            .spawn()
+            .map_err(|_| anyhow!("failed to run hook: {}", cmd))?;
+        log::debug!("started background hook: {} (pid {})", cmd, child.id());
 
-        log::debug!("shm name: {:?}", shm_name);
+        Ok(())
+    }
 
-        // First, create the shared memory object that we'll eventually use
-        // to stash the unwrapped key. We do this early to allow it to fail ahead
-        // of the password prompt and decryption steps.
-        log::debug!("creating shared memory object");
-        let unwrapped_fd = match mman::shm_open(
-            &shm_name,
-            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
-            Mode::S_IRUSR | Mode::S_IWUSR,
-        ) {
-            Ok(unwrapped_fd) => unwrapped_fd,
-            Err(nix::Error::Sys(Errno::EEXIST)) => {
-                return Err(anyhow!("unwrapped key already exists"))
-            }
-            Err(e) => return Err(e.into()),
-        };
+    /// Returns whether or not hooks should be called, per `reentrant-hooks`, warning if not.
+    fn should_call_hook(&self) -> bool {
+        let nested = self.is_nested_hook(
+            env::var("KBS2_HOOK").ok().as_deref(),
+            env::var("KBS2_HOOK_CONFIG").ok().as_deref(),
+        );
 
-        // Prompt the user for their "master" password (i.e., the one that decrypts their privkey).
-        let password = util::get_password().or_else(|e| {
-            mman::shm_unlink(&shm_name)?;
-            Err(e)
-        })?;
+        if self.reentrant_hooks || !nested {
+            true
+        } else {
+            util::warn("nested hook requested without reentrant-hooks; skipping");
+            false
+        }
+    }
 
-        // Read the wrapped key from disk.
-        let wrapped_key = std::fs::read(&self.keyfile).or_else(|e| {
-            mman::shm_unlink(&shm_name)?;
-            Err(Error::from(e))
-        })?;
+    /// Returns a `Command` for the given hook, with the environment and working directory
+    /// described by `call_hook`.
+    fn hook_command(&self, cmd: &str, args: &[&str], hook_env: &HookEnv) -> Command {
+        let mut command = Command::new(cmd);
+        command
+            .args(args)
+            .current_dir(Path::new(&self.store))
+            .envs(hook_env)
+            .env("KBS2_HOOK", "1")
+            .env("KBS2_HOOK_CONFIG", self.hook_token())
+            .env("KBS2_CONFIG_DIR", &self.config_dir)
+            .envs(self.profile.iter().map(|p| ("KBS2_PROFILE", p)))
+            .stdin(Stdio::null());
 
-        // Create a new decryptor for the wrapped key.
-        let decryptor = match Decryptor::new(wrapped_key.as_slice()) {
-            Ok(Decryptor::Passphrase(d)) => d,
-            Ok(_) => {
-                mman::shm_unlink(&shm_name)?;
-                return Err(anyhow!(
-                    "key unwrap failed; not a password-wrapped keyfile?"
-                ));
-            }
-            Err(e) => {
-                mman::shm_unlink(&shm_name)?;
-                return Err(anyhow!(
-                    "unable to load private key (backend reports: {:?})",
-                    e
-                ));
-            }
+        command
+    }
+
+    /// Runs the `finally-hook`s for the given subcommand, i.e. the subcommand's own and then
+    /// the global one, once the subcommand has completed with the given result.
+    ///
+    /// Each hook is run with `KBS2_COMMAND_STATUS` set to `success` or `failure`; a command
+    /// cancelled by a hook (see `HookCancelled`) counts as a success.
+    ///
+    /// The subcommand's own error always takes precedence: a failing finally-hook is only
+    /// returned as an error when the subcommand succeeded, and is otherwise reported as a
+    /// warning so that it doesn't mask the original error.
+    pub fn call_finally_hooks(&self, command: &str, result: Result<()>) -> Result<()> {
+        let success = match &result {
+            Ok(()) => true,
+            Err(e) => e.is::<HookCancelled>(),
         };
 
-        // ...and decrypt (i.e., unwrap) using the master password supplied above.
-        log::debug!("beginning key unwrap...");
-        let mut unwrapped_key = String::new();
+        let command_hook = match command {
+            "new" => Some((&self.commands.new.finally_hook, &self.commands.new.hook_env)),
+            "pass" => Some((
+                &self.commands.pass.finally_hook,
+                &self.commands.pass.hook_env,
+            )),
+            "edit" => Some((
+                &self.commands.edit.finally_hook,
+                &self.commands.edit.hook_env,
+            )),
+            "rm" => Some((&self.commands.rm.finally_hook, &self.commands.rm.hook_env)),
+            "mv" => Some((&self.commands.mv.finally_hook, &self.commands.mv.hook_env)),
+            _ => None,
+        };
 
-        // NOTE(ww): A work factor of 18 is an educated guess here; rage generated some
-        // encrypted messages that needed this factor.
-        decryptor
-            .decrypt(&password, Some(18))
-            .map_err(|e| anyhow!("unable to decrypt (backend reports: {:?})", e))
-            .and_then(|mut r| {
-                r.read_to_string(&mut unwrapped_key)
-                    .map_err(|_| anyhow!("i/o error while decrypting"))
-            })
-            .or_else(|e| {
-                mman::shm_unlink(&shm_name)?;
-                Err(e)
-            })?;
-        log::debug!("finished key unwrap!");
+        let global_env = HookEnv::new();
+        let hooks = command_hook
+            .into_iter()
+            .chain(std::iter::once((&self.finally_hook, &global_env)))
+            .filter_map(|(hook, hook_env)| hook.as_ref().map(|hook| (hook, hook_env)));
 
-        // Use ftruncate to tell the shared memory region how much space we'd like.
-        // NOTE(ww): as_bytes returns usize, but ftruncate takes an i64.
-        // We're already in big trouble if this conversion fails, so just unwrap.
-        log::debug!("truncating shm obj");
-        unistd::ftruncate(
-            unwrapped_fd,
-            unwrapped_key.as_bytes().len().try_into().unwrap(),
-        )
-        .or_else(|e| {
-            mman::shm_unlink(&shm_name)?;
-            Err(Error::from(e))
-        })?;
+        let mut hook_result = Ok(());
+        for (hook, hook_env) in hooks {
+            log::debug!("finally-hook: {}", hook);
 
-        // NOTE(ww): This is safe, assuming nix::shm_open doesn't lie about
-        // success when returning a file descriptor.
-        let file = unsafe { fs::File::from_raw_fd(unwrapped_fd) };
+            let mut hook_env = hook_env.clone();
+            hook_env.insert(
+                "KBS2_COMMAND_STATUS".into(),
+                if success { "success" } else { "failure" }.into(),
+            );
 
-        // Toss unwrapped_key into our shared memory.
-        // Ideally we'd just call write(2) here, but that only works on Linux.
-        // See the NOTE under RageLib::new() for more ranting about
-        // macOS concessions.
-        log::debug!("writing the unwrapped key");
-        {
-            let mut mmap = unsafe { Mmap::map(&file)? }.make_mut().or_else(|e| {
-                mman::shm_unlink(&shm_name)?;
-                Err(Error::from(e))
-            })?;
+            // NOTE: Every finally-hook runs, even when an earlier one fails.
+            if let Err(e) = self.call_hook(hook, &[], &hook_env) {
+                if !success || hook_result.is_err() {
+                    util::warn(&format!("finally-hook failed: {}", e));
+                } else {
+                    hook_result = Err(e);
+                }
+            }
+        }
 
-            mmap.deref_mut()
-                .write_all(unwrapped_key.as_bytes())
-                .or_else(|e| {
-                    mman::shm_unlink(&shm_name)?;
-                    Err(Error::from(e))
-                })?;
+        result.and(hook_result)
+    }
+
+    /// Returns the path to this configuration's file: `config_file` if it was loaded
+    /// from one directly, or `config_basename(profile)` within `config_dir` otherwise.
+    pub fn config_path(&self) -> Result<PathBuf> {
+        match &self.config_file {
+            Some(config_file) => Ok(config_file.into()),
+            None => Ok(Path::new(&self.config_dir).join(config_basename(self.profile.as_deref())?)),
         }
+    }
 
-        Ok(file)
+    /// Saves this configuration to its configuration file (see `config_path`).
+    ///
+    /// The file is replaced atomically and is only readable by its owner.
+    ///
+    /// **NOTE**: Any settings that were merged in from `include`d files are written into
+    /// the main file, and the `include` key itself is not preserved.
+    pub fn save(&self) -> Result<()> {
+        util::write_file_secure(&self.config_path()?, toml::to_string(self)?.as_bytes())
     }
-}
 
-/// The different types of generators known to `kbs2`.
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum GeneratorConfig {
-    Command(GeneratorCommandConfig),
-    Internal(GeneratorInternalConfig),
-}
+    /// Returns the configuration as it's actually used, i.e. after includes, migrations,
+    /// expansions, and any overrides have been applied.
+    ///
+    /// Unlike `save`, paths that are resolved against the configuration directory (the store
+    /// and `recipients-file`) are given as absolute paths, so that the result means the same
+    /// thing wherever it's loaded from. The keyfile's path is included, but never its contents.
+    pub fn effective(&self) -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(self)?;
 
-impl GeneratorConfig {
-    fn as_dyn(&self) -> &dyn Generator {
-        match self {
-            GeneratorConfig::Command(g) => g as &dyn Generator,
-            GeneratorConfig::Internal(g) => g as &dyn Generator,
+        // NOTE: This unwrap is safe, since a Config always serializes to a table.
+        let table = value.as_table_mut().unwrap();
+        table.insert("store".into(), self.store.clone().into());
+        if let Some(recipients_file) = self.recipients_file_path() {
+            table.insert(
+                "recipients-file".into(),
+                recipients_file
+                    .to_str()
+                    .ok_or_else(|| anyhow!("recipients-file path is not valid UTF-8"))?
+                    .into(),
+            );
         }
+
+        Ok(value)
     }
-}
 
-/// The configuration settings for a "command" generator.
-#[derive(Debug, Deserialize, Serialize)]
-pub struct GeneratorCommandConfig {
-    /// The name of the generator.
-    pub name: String,
+    /// Returns a token that identifies this configuration (and its store) to hooks, via
+    /// `KBS2_HOOK_CONFIG`.
+    ///
+    /// The token is only used to tell configurations apart, so it's just a hash of the
+    /// configuration directory, profile, and store.
+    pub fn hook_token(&self) -> String {
+        let id = format!(
+            "{}\0{}\0{}\0{}",
+            self.config_dir,
+            self.profile.as_deref().unwrap_or_default(),
+            self.config_file.as_deref().unwrap_or_default(),
+            self.store
+        );
 
-    /// The command to run to generate a secret.
-    pub command: String,
-}
+        format!("{:x}", Sha256::digest(id.as_bytes()))
+    }
 
-/// The configuration settings for an "internal" generator.
-#[derive(Debug, Deserialize, Serialize)]
-pub struct GeneratorInternalConfig {
-    /// The name of the generator.
-    pub name: String,
+    /// Returns whether or not a hook for this configuration would be nested within
+    /// another hook for this configuration, given the values of `KBS2_HOOK` and
+    /// `KBS2_HOOK_CONFIG` in the environment.
+    ///
+    /// Hooks for a *different* configuration (e.g., a hook that runs `kbs2` against another
+    /// store) aren't considered nested. A `KBS2_HOOK` without a `KBS2_HOOK_CONFIG` is
+    /// conservatively considered nested.
+    fn is_nested_hook(&self, hook: Option<&str>, hook_config: Option<&str>) -> bool {
+        hook.is_some() && hook_config.map_or(true, |token| token == self.hook_token())
+    }
 
-    /// The alphabet to sample from when generating a secret.
-    pub alphabet: String,
+    /// Overrides the configured store with the given path, after tilde and environment
+    /// variable expansion. Relative paths are resolved against the current directory.
+    ///
+    /// If the given store doesn't exist, it's created (with owner-only permissions) when
+    /// `create` is `true` and an error is returned otherwise.
+    pub fn override_store(&mut self, store: &str, create: bool) -> Result<()> {
+        let expanded =
+            shellexpand::full(store).map_err(|e| anyhow!("couldn't expand store path: {}", e))?;
+        let path = env::current_dir()?.join(expanded.as_ref());
 
-    /// The number of characters to sample from the alphabet.
-    pub length: u32,
-}
+        if !path.exists() {
+            if !create {
+                return Err(anyhow!("store does not exist: {}", path.display()));
+            }
 
-impl Default for GeneratorInternalConfig {
-    fn default() -> Self {
-        GeneratorInternalConfig {
-            name: "default".into(),
-            // NOTE(ww): This alphabet should be a decent default, as it contains
-            // symbols but not commonly blacklisted ones (e.g. %, $).
-            alphabet: "abcdefghijklmnopqrstuvwxyz0123456789(){}[]-_+=".into(),
-            length: 16,
+            log::debug!("creating overridden store: {:?}", path);
+            util::create_dir_secure(&path)?;
         }
-    }
-}
 
-/// The per-command configuration settings known to `kbs2`.
-#[derive(Default, Debug, Deserialize, Serialize)]
-#[serde(default)]
-pub struct CommandConfigs {
-    /// Settings for `kbs2 new`.
-    pub new: NewConfig,
+        if !path.is_dir() {
+            return Err(anyhow!("store is not a directory: {}", path.display()));
+        }
 
-    /// Settings for `kbs2 pass`.
-    pub pass: PassConfig,
+        self.store = fs::canonicalize(&path)?
+            .to_str()
+            .ok_or_else(|| anyhow!("store path is not valid UTF-8"))?
+            .into();
+        self.unresolved_store = expanded.into_owned();
 
-    /// Settings for `kbs2 edit`.
-    pub edit: EditConfig,
+        Ok(())
+    }
 
-    /// Settings for `kbs2 rm`.
-    pub rm: RmConfig,
-}
+    /// Overrides the configured keyfile with the age identity at the given path, after tilde
+    /// and environment variable expansion. Relative paths are resolved against the current
+    /// directory.
+    ///
+    /// The identity may be wrapped or unwrapped; `wrapped` (and `wrap-work-factor`) are
+    /// updated to match it.
+    ///
+    /// NOTE: `public-key` is left as-is, so records written with an overridden identity
+    /// remain readable by the configured keypair.
+    pub fn override_identity(&mut self, identity: &str) -> Result<()> {
+        let expanded = shellexpand::full(identity)
+            .map_err(|e| anyhow!("couldn't expand identity path: {}", e))?;
+        let path = env::current_dir()?.join(expanded.as_ref());
 
-/// Configuration settings for `kbs2 new`.
-#[derive(Debug, Default, Deserialize, Serialize)]
-#[serde(default)]
-pub struct NewConfig {
-    #[serde(rename = "generate-on-empty")]
-    pub generate_on_empty: bool,
-    // TODO(ww): This deserialize_with is ugly. There's probably a better way to do this.
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "pre-hook")]
-    pub pre_hook: Option<String>,
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "post-hook")]
-    pub post_hook: Option<String>,
+        if !path.is_file() {
+            return Err(anyhow!("identity does not exist: {}", path.display()));
+        }
+
+        let contents = fs::read(&path)?;
+        match backend::wrapped_work_factor(&contents) {
+            Ok(work_factor) => {
+                self.wrapped = true;
+                self.wrap_work_factor = work_factor;
+            }
+            Err(_) => self.wrapped = false,
+        }
+
+        self.keyfile = fs::canonicalize(&path)?
+            .to_str()
+            .ok_or_else(|| anyhow!("identity path is not valid UTF-8"))?
+            .into();
+
+        Ok(())
+    }
+
+    /// Checks the configuration for errors that would otherwise only surface later,
+    /// e.g. when a generator is used.
+    pub fn validate(&self) -> Result<()> {
+        for (command, hook_env) in &[
+            ("new", &self.commands.new.hook_env),
+            ("pass", &self.commands.pass.hook_env),
+            ("edit", &self.commands.edit.hook_env),
+            ("rm", &self.commands.rm.hook_env),
+            ("mv", &self.commands.mv.hook_env),
+        ] {
+            if let Some(var) = hook_env.keys().find(|var| var.starts_with("KBS2_")) {
+                return Err(anyhow!(
+                    "commands.{}.hook-env: can't override built-in variable {}",
+                    command,
+                    var
+                ));
+            }
+        }
+
+        if self.commands.pass.clear_trigger != ClearTrigger::Timer
+            && self.commands.pass.lock_command.is_none()
+        {
+            return Err(anyhow!(
+                "commands.pass.clear-trigger requires commands.pass.lock-command"
+            ));
+        }
+
+        if self.commands.pass.clipboard_duration_jitter > self.commands.pass.clipboard_duration {
+            return Err(anyhow!(
+                "commands.pass.clipboard-duration-jitter can't exceed clipboard-duration"
+            ));
+        }
+
+        if self.passphrase_attempts == 0 {
+            return Err(anyhow!("passphrase-attempts must be at least 1"));
+        }
+
+        if self.wrap_work_factor == 0 {
+            return Err(anyhow!("wrap-work-factor must be at least 1"));
+        }
+
+        for generator in self.generators.iter() {
+            generator.as_dyn().validate()?;
+        }
+
+        for kind in self.commands.new.prompts.keys() {
+            self.commands.new.prompts_for(kind)?;
+        }
+
+        for (alias, label) in self.aliases.iter() {
+            if alias.is_empty() || label.is_empty() {
+                return Err(anyhow!("aliases: aliases and their labels can't be empty"));
+            }
+
+            if alias == label {
+                return Err(anyhow!("aliases: {} is an alias for itself", alias));
+            }
+
+            // NOTE: Aliases are resolved exactly once, so an alias for another alias
+            // would silently point at a record that (probably) doesn't exist.
+            if self.aliases.contains_key(label) {
+                return Err(anyhow!(
+                    "aliases: {} refers to another alias ({}), not a label",
+                    alias,
+                    label
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the label that the given label refers to: the aliased label if it's one of
+    /// the configured `aliases`, or the label itself otherwise.
+    pub fn resolve_alias<'a>(&'a self, label: &'a str) -> &'a str {
+        self.aliases.get(label).map_or(label, String::as_str)
+    }
+
+    /// Checks that the keyfile is only accessible by its owner.
+    ///
+    /// Group or world access to a wrapped keyfile produces a warning, since the key is still
+    /// protected by the master password. The same access to an unwrapped keyfile is an error,
+    /// unless `insecure` is `true`. A missing keyfile isn't checked.
+    pub fn check_keyfile_perms(&self, insecure: bool) -> Result<()> {
+        let problem = match self.keyfile_perms_problem()? {
+            Some(problem) => problem,
+            None => return Ok(()),
+        };
+
+        if self.wrapped || insecure {
+            util::warn(&problem);
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} (pass --insecure-keyfile-perms to continue anyways)",
+                problem
+            ))
+        }
+    }
+
+    /// Checks that the store can be changed, failing with "store is read-only" if it's
+    /// configured as `read-only` or lives somewhere that isn't writable (e.g. a read-only
+    /// mount).
+    ///
+    /// This is meant to be called before a command does any work, so that a read-only
+    /// store is reported up front instead of leaving a change half-applied.
+    pub fn check_store_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("store is read-only"));
+        }
+
+        // NOTE: Only errors that mean "not writable" count; anything else (e.g. a
+        // missing store) is left for the command itself to report.
+        match unistd::access(Path::new(&self.store), unistd::AccessFlags::W_OK) {
+            Err(nix::Error::Sys(Errno::EROFS)) | Err(nix::Error::Sys(Errno::EACCES)) => {
+                Err(anyhow!("store is read-only"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the `on-missing-store` policy to the store, returning whether it exists
+    /// afterwards.
+    ///
+    /// A missing store is an error under `error`. Under `create`, it's created (with
+    /// owner-only permissions) if `mutating` is `true`, and otherwise left missing so that
+    /// the command sees an empty store. Under `prompt`, `confirm` is asked whether to create
+    /// it, and declining is an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `mutating` - whether the command about to run changes the store
+    /// * `confirm` - asks the user whether to create the store at the given path
+    pub fn open_store<F>(&self, mutating: bool, confirm: F) -> Result<bool>
+    where
+        F: FnOnce(&Path) -> Result<bool>,
+    {
+        let store = Path::new(&self.store);
+
+        if store.exists() {
+            if !store.is_dir() {
+                return Err(anyhow!("store is not a directory: {}", store.display()));
+            }
+
+            return Ok(true);
+        }
+
+        let create = match self.on_missing_store {
+            OnMissingStore::Error => false,
+            OnMissingStore::Create => {
+                if !mutating {
+                    log::debug!("store is missing, treating it as empty: {:?}", store);
+                    return Ok(false);
+                }
+                true
+            }
+            OnMissingStore::Prompt => confirm(store)?,
+        };
+
+        if !create {
+            return Err(anyhow!("store does not exist: {}", store.display()));
+        }
+
+        log::debug!("creating missing store: {:?}", store);
+        util::create_dir_secure(store)?;
+
+        Ok(true)
+    }
+
+    /// Returns the path to the `recipients-file`, if one is configured, resolved against the
+    /// configuration directory.
+    pub fn recipients_file_path(&self) -> Option<PathBuf> {
+        self.recipients_file
+            .as_ref()
+            .map(|file| Path::new(&self.config_dir).join(file))
+    }
+
+    /// Returns the full set of recipients that data with the given label (or unlabeled data,
+    /// like attachments, if `None`) is encrypted to: the public key, plus the recipients of
+    /// the first matching `recipient-policy` rule or (if none match) those in the
+    /// `recipients-file`, without duplicates.
+    ///
+    /// Every recipient is validated, and the `recipients-file` is read anew on each call.
+    /// See `backend::merge_recipients`, which `RageLib` also encrypts with.
+    pub fn recipients(&self, label: Option<&str>) -> Result<Vec<age::keys::RecipientKey>> {
+        let pubkey = self
+            .public_key
+            .parse::<age::keys::RecipientKey>()
+            .map_err(|e| anyhow!("unable to parse public key (backend reports: {:?})", e))?;
+
+        backend::merge_recipients(
+            &pubkey,
+            self.recipients_file_path().as_deref(),
+            &backend::parse_recipient_policy(&self.recipient_policy)?,
+            label,
+        )
+    }
+
+    /// Returns the path to the keyfile's canary, i.e. a small known plaintext encrypted to
+    /// the public key by `initialize` (see `Session::verify_key`).
+    pub fn canary_path(&self) -> PathBuf {
+        let mut canary_path = self.keyfile.clone();
+        canary_path.push_str(".canary");
+
+        canary_path.into()
+    }
+
+    /// Returns a description of the keyfile's permission problem, if it has one.
+    pub fn keyfile_perms_problem(&self) -> Result<Option<String>> {
+        Ok(util::shared_mode(Path::new(&self.keyfile))?.map(|mode| {
+            format!(
+                "keyfile {} is accessible by other users (mode {:o}); consider chmod 600",
+                self.keyfile, mode
+            )
+        }))
+    }
+
+    /// Given the `name` of a configured generator, return that generator
+    /// if it exists.
+    pub fn get_generator(&self, name: &str) -> Option<&dyn Generator> {
+        for generator_config in self.generators.iter() {
+            let generator = generator_config.as_dyn();
+            if generator.name() == name {
+                return Some(generator);
+            }
+        }
+
+        None
+    }
+
+    /// Returns how far (in seconds, in either direction) the system clock is from the time
+    /// printed by the `clock-check` command, or `None` if there's no `clock-check`.
+    pub fn clock_skew(&self) -> Result<Option<u64>> {
+        let clock_check = match &self.clock_check {
+            Some(clock_check) => clock_check,
+            None => return Ok(None),
+        };
+
+        let (command, args) = util::parse_and_split_args(clock_check)?;
+        let args = args.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+        let output = util::run_with_output(&command, &args, util::DEFAULT_MAX_OUTPUT)?;
+
+        let reference = output.trim().parse::<u64>().map_err(|_| {
+            anyhow!(
+                "expected seconds since the Unix epoch from clock-check, not: {}",
+                output.trim()
+            )
+        })?;
+
+        // NOTE: The local time is taken after the command exits, so that a slow source
+        // doesn't make the local clock look behind.
+        let now = util::current_timestamp();
+        Ok(Some(if reference > now {
+            reference - now
+        } else {
+            now - reference
+        }))
+    }
+
+    /// Checks each sensitive field of the given record (e.g. a login's password) against
+    /// `min-entropy`, warning about any that fall below it.
+    ///
+    /// When `strict-entropy` is set, a weak field is an error instead.
+    pub fn check_secret_entropy(&self, record: &record::Record) -> Result<()> {
+        let kind = record.body.to_string();
+        let fields = record::kind_fields(&kind)?;
+
+        for (field, (name, value)) in fields.iter().zip(record.body.fields()) {
+            if !matches!(field, record::FieldKind::Sensitive(_)) {
+                continue;
+            }
+
+            let bits = audit::estimate_entropy(value);
+            if bits < self.min_entropy {
+                let message = format!(
+                    "{}: {} has about {:.0} bits of estimated entropy (min-entropy is {})",
+                    record.label, name, bits, self.min_entropy
+                );
+
+                if self.strict_entropy {
+                    return Err(anyhow!("{}", message));
+                }
+                util::warn(&message);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a suitable identifier for a shared memory object that
+    /// can (or already does) store the unwrapped key.
+    pub fn unwrapped_key_shm_name(&self) -> Result<PathBuf> {
+        let canonicalized_keyfile = fs::canonicalize(&self.keyfile)?;
+
+        // NOTE(ww): Why do we truncate the shared memory object's name to 31 characters
+        // (i.e., bytes)? Because that's all macOS supports! See PSHMNAMELEN in sys/posix.h.
+        // Hashing the keyfile's path isn't done here for security anyways; it's just to
+        // prevent the same shared memory object from being accessed by separate config
+        // files (and kbs2 consequently barfing when the key doesn't work with one of the
+        // stores).
+        let mut shm_name = format!(
+            "{}_{:x}",
+            UNWRAPPED_KEY_SHM_BASENAME,
+            Sha256::digest(canonicalized_keyfile.as_os_str().as_bytes())
+        );
+        shm_name.truncate(31);
+
+        Ok(shm_name.into())
+    }
+
+    /// Removes the unwrapped key (see `unwrapped_key_shm_name`) from shared memory, so that
+    /// subsequent commands need the master password again.
+    ///
+    /// Processes that are already attached to the key keep their reference to it, but no
+    /// new process can attach. Succeeds if there's no unwrapped key to remove.
+    pub fn remove_unwrapped_key(&self) -> Result<()> {
+        let shm_name = self.unwrapped_key_shm_name()?;
+
+        match util::retry_eintr(|| mman::shm_unlink(&shm_name)) {
+            Ok(()) => Ok(()),
+            Err(nix::Error::Sys(Errno::ENOENT)) => {
+                log::debug!("no unwrapped key to remove");
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Unwraps the configured private key file into its underlying private
+    /// key, returning an `UnwrappedKey` that owns an open reference to that key.
+    ///
+    /// The unwrapped key is removed from shared memory when the returned `UnwrappedKey`
+    /// is dropped, unless `UnwrappedKey::persist` is called first.
+    ///
+    /// NOTE: This function assumes that the key file is wrapped. Calling
+    /// it with a non-wrapped key file will cause an error.
+    pub fn unwrap_keyfile(&self) -> Result<UnwrappedKey> {
+        // Unwrapping our password-protected keyfile and returning it as a raw file descriptor
+        // is a multi-step process.
+
+        let shm_name = self.unwrapped_key_shm_name()?;
+
+        log::debug!("shm name: {:?}", shm_name);
+
+        // First, create the shared memory object that we'll eventually use
+        // to stash the unwrapped key. We do this early to allow it to fail ahead
+        // of the password prompt and decryption steps.
+        log::debug!("creating shared memory object");
+        match open_or_attach_shm(&shm_name)? {
+            ShmHandle::Created(file) => self.unwrap_into(UnwrappedKey {
+                file,
+                shm_name: Some(shm_name),
+            }),
+            ShmHandle::Attached(_) => Err(anyhow!("unwrapped key already exists")),
+        }
+    }
+
+    /// Returns the unwrapped private key, either by attaching to one already in shared
+    /// memory or by unwrapping the configured private key file.
+    ///
+    /// This is safe to call from concurrent `kbs2` processes: exactly one of them unwraps
+    /// the key (see `open_or_attach_shm`), while the others wait for it to finish and then
+    /// attach to the result. A key unwrapped here is removed from shared memory when the
+    /// returned `UnwrappedKey` is dropped, while an attached key is left in place.
+    ///
+    /// NOTE: This function assumes that the key file is wrapped.
+    pub fn open_unwrapped_key(&self) -> Result<UnwrappedKey> {
+        let shm_name = self.unwrapped_key_shm_name()?;
+        let started = Instant::now();
+
+        log::debug!("shm name: {:?}", shm_name);
+
+        loop {
+            match open_or_attach_shm(&shm_name)? {
+                ShmHandle::Created(file) => {
+                    log::debug!("unwrapped key not available, requesting unwrap");
+                    return self.unwrap_into(UnwrappedKey {
+                        file,
+                        shm_name: Some(shm_name),
+                    });
+                }
+                ShmHandle::Attached(file) => {
+                    // NOTE: An empty object means that another process created it and
+                    // hasn't finished unwrapping into it yet (it may still be prompting for
+                    // the master password). We can't keep this descriptor around while we
+                    // wait, since that process removes the object if its unwrap fails; we'd
+                    // be left holding an empty key that nobody will ever fill in.
+                    if file.metadata()?.len() > 0 {
+                        return Ok(UnwrappedKey::persistent(file));
+                    }
+                }
+            }
+
+            if started.elapsed() >= UNWRAP_WAIT_TIMEOUT {
+                return Err(anyhow!(
+                    "timed out waiting for another kbs2 process to unwrap the key"
+                ));
+            }
+
+            log::debug!("waiting on another process to finish unwrapping");
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+    }
+
+    /// Unwraps the configured private key file into the given (freshly created and
+    /// empty) shared memory object.
+    fn unwrap_into(&self, unwrapped_key: UnwrappedKey) -> Result<UnwrappedKey> {
+        // NOTE: From here on out, any early return drops the guard and therefore
+        // removes the shared memory object.
+        let unwrapped_fd = unwrapped_key.as_raw_fd();
+
+        // Read the wrapped key from disk.
+        let wrapped_key = std::fs::read(&self.keyfile)?;
+
+        // Prompt the user for their "master" password (i.e., the one that decrypts their privkey),
+        // and use it to decrypt (i.e., unwrap) the wrapped key.
+        // NOTE: Retrying only makes sense when there's someone around to retype the password.
+        // NOTE: Neither the password nor the unwrapped key is ever logged, at any level.
+        let attempts = if atty::is(Stream::Stdin) {
+            self.passphrase_attempts
+        } else {
+            1
+        };
+
+        log::debug!("beginning key unwrap...");
+        let unwrapped_key_contents = unwrap_with_retries(
+            &wrapped_key,
+            self.wrap_work_factor,
+            attempts,
+            PASSPHRASE_RETRY_DELAY,
+            || {
+                util::get_password(
+                    self.passphrase_entry,
+                    &self.pinentry,
+                    &input::TerminalPrompt,
+                )
+            },
+        )?;
+        log::debug!("finished key unwrap!");
+
+        // Use ftruncate to tell the shared memory region how much space we'd like.
+        // NOTE(ww): as_bytes returns usize, but ftruncate takes an i64.
+        // We're already in big trouble if this conversion fails, so just unwrap.
+        log::debug!("truncating shm obj");
+        let len = unwrapped_key_contents.as_bytes().len().try_into().unwrap();
+        util::retry_eintr(|| unistd::ftruncate(unwrapped_fd, len))?;
+
+        // Toss unwrapped_key into our shared memory.
+        // Ideally we'd just call write(2) here, but that only works on Linux.
+        // See the NOTE under RageLib::new() for more ranting about
+        // macOS concessions.
+        log::debug!("writing the unwrapped key");
+        {
+            let mut mmap = unsafe { Mmap::map(&unwrapped_key)? }.make_mut()?;
+
+            // NOTE: Keep the unwrapped key's pages out of swap (and core dumps) while
+            // we're writing them. This is best-effort; see util::lock_memory.
+            util::lock_memory(&mmap);
+
+            mmap.deref_mut()
+                .write_all(unwrapped_key_contents.as_bytes())?;
+        }
+
+        // NOTE: Only a fresh unwrap reaches this point; processes that attach to an
+        // existing unwrapped key never do, so the hook runs once per shared memory object.
+        self.call_unwrap_hook();
+
+        Ok(unwrapped_key)
+    }
+
+    /// Runs the `on-unwrap` hook, if there is one, with `KBS2_KEY_ID` (the public key
+    /// of the keypair that was unwrapped) in its environment.
+    ///
+    /// The key is already unwrapped by the time this is called, so a failing (or
+    /// cancelling) hook is reported as a warning rather than an error.
+    pub fn call_unwrap_hook(&self) {
+        if let Some(on_unwrap) = &self.on_unwrap {
+            log::debug!("on-unwrap hook: {}", on_unwrap);
+
+            let mut hook_env = HookEnv::new();
+            hook_env.insert("KBS2_KEY_ID".into(), self.public_key.clone());
+
+            if let Err(e) = self.call_hook(on_unwrap, &[], &hook_env) {
+                util::warn(&format!("on-unwrap hook failed: {}", e));
+            }
+        }
+    }
 }
 
-/// Configuration settings for `kbs2 pass`.
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(default)]
-pub struct PassConfig {
-    #[serde(rename = "clipboard-duration")]
-    pub clipboard_duration: u64,
-    #[serde(rename = "clear-after")]
-    pub clear_after: bool,
-    #[serde(rename = "x11-clipboard")]
-    pub x11_clipboard: X11Clipboard,
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "pre-hook")]
-    pub pre_hook: Option<String>,
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "post-hook")]
-    pub post_hook: Option<String>,
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "clear-hook")]
-    pub clear_hook: Option<String>,
+/// The outcome of `open_or_attach_shm`.
+#[derive(Debug)]
+pub enum ShmHandle {
+    /// This call created the shared memory object, which is empty and open for reading
+    /// and writing. The caller is responsible for filling it in (or removing it).
+    Created(fs::File),
+
+    /// The shared memory object already existed, and is open read-only.
+    Attached(fs::File),
+}
+
+/// Opens the named shared memory object, creating it if it doesn't already exist.
+///
+/// The decision between creating and attaching is made by a single `shm_open` with
+/// `O_CREAT | O_EXCL`, never by checking for the object first. As a result, when multiple
+/// processes (or threads) race on the same name, exactly one of them gets
+/// `ShmHandle::Created`; every other caller gets `ShmHandle::Attached` to that same object.
+///
+/// Attaching only guarantees that the object exists, not that its creator has finished
+/// writing to it: an `Attached` object may still be empty.
+pub fn open_or_attach_shm(shm_name: &Path) -> Result<ShmHandle> {
+    loop {
+        match util::retry_eintr(|| {
+            mman::shm_open(
+                shm_name,
+                OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+                Mode::S_IRUSR | Mode::S_IWUSR,
+            )
+        }) {
+            // NOTE(ww): This is safe, assuming nix::shm_open doesn't lie about
+            // success when returning a file descriptor.
+            Ok(fd) => return Ok(ShmHandle::Created(unsafe { fs::File::from_raw_fd(fd) })),
+            Err(nix::Error::Sys(Errno::EEXIST)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        match util::retry_eintr(|| mman::shm_open(shm_name, OFlag::O_RDONLY, Mode::empty())) {
+            Ok(fd) => return Ok(ShmHandle::Attached(unsafe { fs::File::from_raw_fd(fd) })),
+            // NOTE: The object was removed between our two calls, e.g. because its
+            // creator failed to unwrap into it. Start over, so that we get to create it.
+            Err(nix::Error::Sys(Errno::ENOENT)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// An open reference to an unwrapped private key in shared memory.
+///
+/// The shared memory object is removed (i.e., `shm_unlink`ed) when this handle is dropped,
+/// so that the unwrapped key doesn't outlive the command that needed it. Use `persist` to
+/// intentionally leave it in place for subsequent commands, as `kbs2 unlock` does.
+#[derive(Debug)]
+pub struct UnwrappedKey {
+    file: fs::File,
+    shm_name: Option<PathBuf>,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub enum X11Clipboard {
-    Clipboard,
-    Primary,
-}
+impl UnwrappedKey {
+    /// Creates an `UnwrappedKey` for an already-persisted unwrapped key, i.e. one that
+    /// won't be removed from shared memory when dropped.
+    pub fn persistent(file: fs::File) -> UnwrappedKey {
+        UnwrappedKey {
+            file,
+            shm_name: None,
+        }
+    }
+
+    /// Leaves the unwrapped key in shared memory after this handle is dropped.
+    pub fn persist(&mut self) {
+        self.shm_name = None;
+    }
+}
+
+impl std::ops::Deref for UnwrappedKey {
+    type Target = fs::File;
+
+    fn deref(&self) -> &fs::File {
+        &self.file
+    }
+}
+
+impl Drop for UnwrappedKey {
+    fn drop(&mut self) {
+        if let Some(shm_name) = self.shm_name.take() {
+            log::debug!("removing unwrapped key from shared memory");
+            if let Err(e) = util::retry_eintr(|| mman::shm_unlink(&shm_name)) {
+                log::debug!("couldn't remove unwrapped key: {}", e);
+            }
+        }
+    }
+}
+
+/// The schemes by which `kbs2` can map record labels onto filenames.
+///
+/// NOTE: Configs without a `label-encoding` predate percent-encoding, so their stores may
+/// hold filenames (e.g. containing `%`) that only mean the right thing as raw labels.
+/// `initialize` opts new configs into `Percent` explicitly.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelEncoding {
+    /// Percent-encode characters that can't safely appear in a filename.
+    Percent,
+    /// Use labels as filenames directly, rejecting labels that can't be represented.
+    #[default]
+    Raw,
+}
+
+/// The policies for handling the case of record labels.
+///
+/// NOTE: On a case-insensitive filesystem, labels that differ only by case (e.g. `GitHub` and
+/// `github`) share a file, so writing one silently replaces the other.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LabelCase {
+    /// Use labels as given.
+    #[default]
+    Preserve,
+    /// Lowercase the labels of new records, and look up labels case-insensitively.
+    Lower,
+    /// Reject new records whose labels differ only by case from an existing record's.
+    RejectCollisions,
+}
+
+/// The policies for handling a store that doesn't exist.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingStore {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Create the store for commands that change it, and treat it as empty otherwise.
+    Create,
+    /// Ask the user whether to create the store.
+    Prompt,
+}
+
+impl FromStr for OnMissingStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(OnMissingStore::Error),
+            "create" => Ok(OnMissingStore::Create),
+            "prompt" => Ok(OnMissingStore::Prompt),
+            _ => Err(anyhow!("invalid on-missing-store policy: {}", s)),
+        }
+    }
+}
+
+/// The formats that records can be serialized in, before encryption.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// Serialize records as JSON.
+    #[default]
+    Json,
+    /// Serialize records as TOML.
+    Toml,
+    /// Serialize records as MessagePack.
+    Msgpack,
+}
+
+/// The levels at which `kbs2` can log.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Log only errors.
+    #[default]
+    Error,
+    /// Log warnings and errors.
+    Warn,
+    /// Log informational messages, warnings, and errors.
+    Info,
+    /// Log debugging messages and everything above.
+    Debug,
+    /// Log everything.
+    Trace,
+}
+
+impl LogLevel {
+    /// Returns the level requested by the given number of `--verbose` flags, if any.
+    pub fn from_verbosity(verbosity: u64) -> Option<LogLevel> {
+        match verbosity {
+            0 => None,
+            1 => Some(LogLevel::Warn),
+            2 => Some(LogLevel::Info),
+            3 => Some(LogLevel::Debug),
+            _ => Some(LogLevel::Trace),
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// The mechanisms by which `kbs2` can prompt for a passphrase.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PassphraseEntry {
+    /// Prompt via a pinentry binary if one is available, and on the TTY otherwise.
+    #[default]
+    Auto,
+    /// Prompt via a pinentry binary, warning before falling back on the TTY if unavailable.
+    Pinentry,
+    /// Prompt directly on the TTY.
+    Tty,
+}
+
+/// The different types of generators known to `kbs2`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum GeneratorConfig {
+    Command(GeneratorCommandConfig),
+    Internal(GeneratorInternalConfig),
+}
+
+impl GeneratorConfig {
+    /// Returns the configured generator as a `Generator` trait object.
+    pub fn as_dyn(&self) -> &dyn Generator {
+        match self {
+            GeneratorConfig::Command(g) => g as &dyn Generator,
+            GeneratorConfig::Internal(g) => g as &dyn Generator,
+        }
+    }
+}
+
+/// The configuration settings for a "command" generator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GeneratorCommandConfig {
+    /// The name of the generator.
+    pub name: String,
+
+    /// The command to run to generate a secret.
+    pub command: String,
+
+    /// The maximum number of bytes of output accepted from the command.
+    #[serde(default = "default_max_output")]
+    #[serde(rename = "max-output")]
+    pub max_output: usize,
+}
+
+/// The configuration settings for an "internal" generator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GeneratorInternalConfig {
+    /// The name of the generator.
+    pub name: String,
+
+    /// The alphabet to sample from when generating a secret.
+    pub alphabet: String,
+
+    /// The number of characters to sample from the alphabet.
+    pub length: u32,
+
+    /// Optional per-class sampling weights, keyed by character class (`"lowercase"`,
+    /// `"uppercase"`, `"digits"`, or `"symbols"`).
+    ///
+    /// Each character in the alphabet is weighted by its class's weight (1 if its class
+    /// isn't listed), so sampling stays uniform within a class. When empty (the default),
+    /// the alphabet is sampled uniformly.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub weights: BTreeMap<String, u32>,
+}
+
+impl Default for GeneratorInternalConfig {
+    fn default() -> Self {
+        GeneratorInternalConfig {
+            name: "default".into(),
+            // NOTE(ww): This alphabet should be a decent default, as it contains
+            // symbols but not commonly blacklisted ones (e.g. %, $).
+            alphabet: "abcdefghijklmnopqrstuvwxyz0123456789(){}[]-_+=".into(),
+            length: 16,
+            weights: BTreeMap::new(),
+        }
+    }
+}
+
+/// Additional environment variables passed to a command's hooks.
+pub type HookEnv = BTreeMap<String, String>;
+
+/// The error returned by `Config::call_hook` when a hook exits with `HOOK_CANCEL_CODE`.
+///
+/// Commands propagate this like any other error, but `kbs2` exits successfully on it.
+#[derive(Debug)]
+pub struct HookCancelled {
+    /// The hook that cancelled the command.
+    pub hook: String,
+}
+
+impl fmt::Display for HookCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hook cancelled the command: {}", self.hook)
+    }
+}
+
+impl std::error::Error for HookCancelled {}
+
+/// The per-command configuration settings known to `kbs2`.
+#[derive(Default, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommandConfigs {
+    /// Settings for `kbs2 new`.
+    pub new: NewConfig,
+
+    /// Settings for `kbs2 pass`.
+    pub pass: PassConfig,
+
+    /// Settings for `kbs2 edit`.
+    pub edit: EditConfig,
+
+    /// Settings for `kbs2 rm`.
+    pub rm: RmConfig,
+
+    /// Settings for `kbs2 mv`.
+    pub mv: MvConfig,
+}
+
+/// Configuration settings for `kbs2 new`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NewConfig {
+    #[serde(rename = "generate-on-empty")]
+    pub generate_on_empty: bool,
+    // TODO(ww): This deserialize_with is ugly. There's probably a better way to do this.
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "pre-hook")]
+    pub pre_hook: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "post-hook")]
+    pub post_hook: Option<String>,
+    #[serde(rename = "background-post-hook")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_post_hook: Option<bool>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    pub finally_hook: Option<String>,
+    #[serde(rename = "hook-env")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_env: HookEnv,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub prompts: BTreeMap<String, Vec<FieldPrompt>>,
+}
+
+impl NewConfig {
+    /// Returns the prompts for the given record kind's fields, in the order they should be
+    /// prompted for.
+    ///
+    /// Kinds without configured prompts are prompted for in their usual field order.
+    pub fn prompts_for(&self, kind: &str) -> Result<Vec<FieldPrompt>> {
+        let fields = record::kind_fields(kind)
+            .map_err(|e| anyhow!("commands.new.prompts.{}: {}", kind, e))?;
+
+        let prompts = match self.prompts.get(kind) {
+            Some(prompts) => prompts,
+            None => {
+                return Ok(fields
+                    .iter()
+                    .map(|field| FieldPrompt {
+                        field: field.name().into(),
+                        prompt: None,
+                        default: None,
+                    })
+                    .collect())
+            }
+        };
+
+        let err = |msg: String| Err(anyhow!("commands.new.prompts.{}: {}", kind, msg));
+        for (index, prompt) in prompts.iter().enumerate() {
+            match fields.iter().find(|f| f.name() == prompt.field) {
+                None => return err(format!("{} records have no {} field", kind, prompt.field)),
+                Some(record::FieldKind::Sensitive(_)) if prompt.default.is_some() => {
+                    return err(format!("{} can't have a default", prompt.field))
+                }
+                _ => {}
+            }
+
+            if prompts[..index].iter().any(|p| p.field == prompt.field) {
+                return err(format!("{} is prompted for more than once", prompt.field));
+            }
+        }
+
+        if let Some(missing) = fields
+            .iter()
+            .find(|f| !prompts.iter().any(|p| p.field == f.name()))
+        {
+            return err(format!("missing a prompt for {}", missing.name()));
+        }
+
+        Ok(prompts.clone())
+    }
+}
+
+/// A prompt for a single record field, as configured for `kbs2 new`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldPrompt {
+    /// The name of the field to prompt for, e.g. `username`.
+    pub field: String,
+
+    /// The text to prompt with, instead of the field's name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+
+    /// The value to pre-fill the prompt with. Only insensitive fields can have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+/// Configuration settings for `kbs2 pass`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PassConfig {
+    #[serde(rename = "clipboard-duration")]
+    pub clipboard_duration: u64,
+    #[serde(rename = "clipboard-duration-jitter")]
+    pub clipboard_duration_jitter: u64,
+    #[serde(rename = "clear-after")]
+    pub clear_after: bool,
+    #[serde(rename = "clipboard-selection")]
+    pub clipboard_selection: ClipboardSelection,
+    #[serde(rename = "clipboard-backend")]
+    pub clipboard_backend: ClipboardBackend,
+    #[serde(rename = "clear-trigger")]
+    pub clear_trigger: ClearTrigger,
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "lock-command")]
+    pub lock_command: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "pre-hook")]
+    pub pre_hook: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "post-hook")]
+    pub post_hook: Option<String>,
+    #[serde(rename = "background-post-hook")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_post_hook: Option<bool>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    pub finally_hook: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "clear-hook")]
+    pub clear_hook: Option<String>,
+    #[serde(rename = "hook-env")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_env: HookEnv,
+}
+
+/// The X11 selections that the native clipboard backend can copy secrets to.
+///
+/// NOTE: This replaces the old `x11-clipboard` setting; see `migrate`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardSelection {
+    /// The `CLIPBOARD` selection, i.e. the usual copy-and-paste clipboard.
+    #[default]
+    Clipboard,
+    /// The `PRIMARY` selection, i.e. the middle-click clipboard.
+    Primary,
+}
+
+/// A `recipient-policy` rule, which chooses the recipients of records whose labels match
+/// a glob.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecipientRule {
+    /// The glob that labels are matched against, e.g. `"team-a/*"`.
+    pub labels: String,
+
+    /// The recipients that matching records are encrypted to, alongside `public-key`.
+    pub recipients: Vec<String>,
+}
+
+/// The clipboards that `kbs2 pass -c` can copy secrets to.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardBackend {
+    /// The system clipboard (X11 on Linux, the pasteboard on macOS).
+    #[default]
+    Native,
+    /// The terminal's clipboard, via OSC 52 escape sequences (e.g. over SSH).
+    Osc52,
+}
+
+/// The events that can trigger clearing the clipboard after `kbs2 pass -c`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClearTrigger {
+    /// Clear once `clipboard-duration` seconds have passed.
+    #[default]
+    Timer,
+    /// Clear once the `lock-command` exits, i.e. when the screen is locked.
+    Lock,
+    /// Clear on whichever of the above happens first.
+    Both,
+}
+
+impl PassConfig {
+    /// Blocks until the clipboard should be cleared, as determined by `clear-trigger`.
+    ///
+    /// A `lock-command` that fails is treated like a screen lock, so that a broken command
+    /// never leaves a secret on the clipboard indefinitely.
+    pub fn wait_for_clear(&self) -> Result<()> {
+        let duration = self.clear_duration();
+
+        match self.clear_trigger {
+            ClearTrigger::Timer => thread::sleep(duration),
+            ClearTrigger::Lock => {
+                let status = self.spawn_lock_command()?.wait()?;
+                if !status.success() {
+                    util::warn("lock-command exited with an error; clearing the clipboard");
+                }
+            }
+            ClearTrigger::Both => {
+                let mut child = self.spawn_lock_command()?;
+                let start = Instant::now();
+
+                while start.elapsed() < duration {
+                    if child.try_wait()?.is_some() {
+                        return Ok(());
+                    }
+
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+
+                // NOTE: The timer won, so the lock command is no longer needed.
+                child.kill().ok();
+                child.wait()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns how long to wait before clearing a secret: `clipboard-duration`, randomly
+    /// adjusted by up to `clipboard-duration-jitter` in either direction.
+    pub fn clear_duration(&self) -> Duration {
+        self.clear_duration_with_rng(&mut rand::thread_rng())
+    }
+
+    #[doc(hidden)]
+    fn clear_duration_with_rng<R: Rng>(&self, rng: &mut R) -> Duration {
+        // NOTE: The jitter is applied in milliseconds, so that the clear time can't
+        // be predicted to the second either.
+        let duration = self.clipboard_duration.saturating_mul(1000);
+        let jitter = self.clipboard_duration_jitter.saturating_mul(1000);
+
+        if jitter == 0 {
+            return Duration::from_millis(duration);
+        }
+
+        Duration::from_millis(
+            Uniform::new_inclusive(
+                duration.saturating_sub(jitter),
+                duration.saturating_add(jitter),
+            )
+            .sample(rng),
+        )
+    }
+
+    #[doc(hidden)]
+    fn spawn_lock_command(&self) -> Result<Child> {
+        // NOTE: validate ensures that lock-command is set for the lock-based triggers.
+        let lock_command = self
+            .lock_command
+            .as_ref()
+            .ok_or_else(|| anyhow!("no lock-command configured"))?;
+        let (command, args) = util::parse_and_split_args(lock_command)?;
+
+        Command::new(&command)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|_| anyhow!("failed to run lock-command: {}", command))
+    }
+}
+
+impl Default for PassConfig {
+    fn default() -> Self {
+        PassConfig {
+            clipboard_duration: 10,
+            clipboard_duration_jitter: 0,
+            clear_after: true,
+            clipboard_selection: Default::default(),
+            clipboard_backend: Default::default(),
+            clear_trigger: Default::default(),
+            lock_command: None,
+            pre_hook: None,
+            post_hook: None,
+            background_post_hook: None,
+            finally_hook: None,
+            clear_hook: None,
+            hook_env: Default::default(),
+        }
+    }
+}
+
+/// Configuration settings for `kbs2 edit`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EditConfig {
+    pub editor: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "post-hook")]
+    pub post_hook: Option<String>,
+    #[serde(rename = "background-post-hook")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_post_hook: Option<bool>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    pub finally_hook: Option<String>,
+    #[serde(rename = "hook-env")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_env: HookEnv,
+}
+
+/// Configuration settings for `kbs2 rm`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RmConfig {
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "post-hook")]
+    pub post_hook: Option<String>,
+    #[serde(rename = "background-post-hook")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_post_hook: Option<bool>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    pub finally_hook: Option<String>,
+    #[serde(rename = "hook-env")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_env: HookEnv,
+}
+
+/// Configuration settings for `kbs2 mv`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MvConfig {
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "post-hook")]
+    pub post_hook: Option<String>,
+    #[serde(rename = "background-post-hook")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_post_hook: Option<bool>,
+    #[serde(deserialize_with = "deserialize_optional_hook")]
+    #[serde(rename = "finally-hook")]
+    pub finally_hook: Option<String>,
+    #[serde(rename = "hook-env")]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_env: HookEnv,
+}
+
+#[doc(hidden)]
+fn default_pinentry() -> String {
+    DEFAULT_PINENTRY.into()
+}
+
+#[doc(hidden)]
+fn default_passphrase_attempts() -> u32 {
+    DEFAULT_PASSPHRASE_ATTEMPTS
+}
+
+#[doc(hidden)]
+fn default_wrap_work_factor() -> u8 {
+    DEFAULT_WRAP_WORK_FACTOR
+}
+
+#[doc(hidden)]
+fn default_store_armor() -> bool {
+    true
+}
+
+#[doc(hidden)]
+fn default_max_attachment_size() -> u64 {
+    DEFAULT_MAX_ATTACHMENT_SIZE
+}
+
+#[doc(hidden)]
+fn default_min_entropy() -> f64 {
+    audit::WEAK_ENTROPY_BITS
+}
+
+#[doc(hidden)]
+fn default_max_output() -> usize {
+    util::DEFAULT_MAX_OUTPUT
+}
+
+#[doc(hidden)]
+fn deserialize_with_tilde<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let unexpanded: String = Deserialize::deserialize(deserializer)?;
+    Ok(shellexpand::tilde(&unexpanded).into_owned())
+}
+
+#[doc(hidden)]
+fn deserialize_optional_with_tilde<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let unexpanded: Option<String> = Deserialize::deserialize(deserializer)?;
+
+    match unexpanded {
+        Some(unexpanded) => Ok(Some(shellexpand::tilde(&unexpanded).into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// Like `deserialize_optional_with_tilde`, but for hook commands, which also have any
+/// environment variables in them expanded (e.g. `$TOOLS/sync`).
+///
+/// NOTE: Hooks that aren't paths (e.g. `sync`) are left to `PATH`, which hooks inherit.
+#[doc(hidden)]
+fn deserialize_optional_hook<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let unexpanded: Option<String> = Deserialize::deserialize(deserializer)?;
+
+    match unexpanded {
+        Some(unexpanded) => shellexpand::full(&unexpanded)
+            .map(|expanded| Some(expanded.into_owned()))
+            .map_err(|e| de::Error::custom(format!("couldn't expand hook {}: {}", unexpanded, e))),
+        None => Ok(None),
+    }
+}
+
+/// Returns a suitable configuration directory path for `kbs2`.
+///
+/// NOTE: This function always chooses `$HOME/.config/kbs2`, across all platforms.
+pub fn find_config_dir() -> Result<PathBuf> {
+    // TODO(ww): This should respect XDG on Linux.
+    let home = util::home_dir()?;
+    Ok(home.join(".config").join(CONFIG_BASEDIR))
+}
+
+/// Returns a suitable record store directory path for `kbs2`.
+///
+/// NOTE: This function always chooses `$HOME/.local/share/kbs2`, across all platforms.
+fn store_dir() -> Result<PathBuf> {
+    // TODO(ww): This should respect XDG on Linux.
+    let home = util::home_dir()?;
+    Ok(home.join(".local/share").join(STORE_BASEDIR))
+}
+
+/// Returns the basename of the configuration file for the given profile, or for
+/// the default profile if `None`.
+///
+/// The default profile uses `CONFIG_BASENAME`; all others use `<profile>.conf`.
+pub fn config_basename(profile: Option<&str>) -> Result<String> {
+    match profile {
+        None => Ok(CONFIG_BASENAME.into()),
+        Some(profile) => {
+            if profile.is_empty()
+                || profile.starts_with('.')
+                || profile.contains(std::path::is_separator)
+            {
+                return Err(anyhow!("invalid profile name: {:?}", profile));
+            }
+
+            Ok(format!("{}.conf", profile))
+        }
+    }
+}
+
+/// Decrypts the given wrapped key with a password supplied by `prompt`, prompting up to
+/// `attempts` times. Keys wrapped with a scrypt work factor above `work_factor` are rejected.
+///
+/// Each wrong password is followed by an increasing delay (`delay`, then twice `delay`, and
+/// so on) to discourage guessing. Other errors, including a failed prompt, aren't retried.
+fn unwrap_with_retries<F>(
+    wrapped_key: &[u8],
+    work_factor: u8,
+    attempts: u32,
+    delay: Duration,
+    mut prompt: F,
+) -> Result<String>
+where
+    F: FnMut() -> Result<SecretString>,
+{
+    // NOTE: Each of these fails before the first prompt, since no password can help.
+    if wrapped_key.is_empty() {
+        return Err(anyhow!(
+            "key unwrap failed; the keyfile is empty (restore it from a backup?)"
+        ));
+    }
+
+    let mut attempt = 1;
+    loop {
+        // Create a new decryptor for the wrapped key.
+        let decryptor = match Decryptor::new(wrapped_key) {
+            Ok(Decryptor::Passphrase(d)) => d,
+            Ok(_) => {
+                return Err(anyhow!(
+                    "key unwrap failed; the keyfile is encrypted to age recipients, not wrapped \
+                     with a password"
+                ));
+            }
+            Err(_)
+                if wrapped_key.starts_with(b"AGE-SECRET-KEY-")
+                    || wrapped_key.starts_with(b"# created: ") =>
+            {
+                return Err(anyhow!(
+                    "key unwrap failed; the keyfile isn't wrapped (set wrapped = false in the \
+                     config, or wrap it with kbs2 wrap-key)"
+                ));
+            }
+            Err(e) => {
+                return Err(anyhow!(
+                    "key unwrap failed; the keyfile isn't a valid age file, and may be corrupt \
+                     or truncated (backend reports: {:?})",
+                    e
+                ));
+            }
+        };
+
+        let password = prompt()?;
+
+        let mut unwrapped_key_contents = String::new();
+        let result = decryptor
+            .decrypt(&password, Some(work_factor))
+            .map_err(|e| anyhow!("unable to decrypt (backend reports: {:?})", e))
+            .and_then(|mut r| {
+                r.read_to_string(&mut unwrapped_key_contents)
+                    .map_err(|_| anyhow!("i/o error while decrypting"))
+            });
+
+        match result {
+            Ok(_) => return Ok(unwrapped_key_contents),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(_) => {
+                util::warn(&format!(
+                    "wrong master password (attempt {} of {})",
+                    attempt, attempts
+                ));
+                thread::sleep(delay * attempt);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Given a path to a `kbs2` configuration directory, initializes a configuration
+/// file and keypair within it, returning the new public key.
+///
+/// # Arguments
+///
+/// * `config_dir` - The configuration directory to initialize within
+/// * `profile` - The profile to initialize, or `None` for the default profile
+/// * `wrapped` - Whether or not to generate a passphrase-wrapped keypair
+/// * `max_work_factor` - The largest scrypt work factor to wrap the keypair with, if any
+pub fn initialize(
+    config_dir: &Path,
+    profile: Option<&str>,
+    wrapped: bool,
+    max_work_factor: Option<u8>,
+) -> Result<String> {
+    // NOTE: Check the profile name before creating a keypair for it.
+    config_basename(profile)?;
+
+    // NOTE: Each profile gets its own keypair and store, so that profiles
+    // don't clobber each other.
+    let (keyfile, store) = match profile {
+        None => (config_dir.join(DEFAULT_KEY_BASENAME), store_dir()?),
+        Some(profile) => (
+            config_dir.join(format!("{}.{}", profile, DEFAULT_KEY_BASENAME)),
+            store_dir()?.with_file_name(format!("{}-{}", STORE_BASEDIR, profile)),
+        ),
+    };
+
+    let (public_key, wrap_work_factor) = if wrapped {
+        let password = util::get_password(
+            PassphraseEntry::default(),
+            DEFAULT_PINENTRY,
+            &input::TerminalPrompt,
+        )?;
+        RageLib::create_wrapped_keypair(&keyfile, password, max_work_factor)?
+    } else {
+        (RageLib::create_keypair(&keyfile)?, DEFAULT_WRAP_WORK_FACTOR)
+    };
+
+    log::debug!("public key: {}", public_key);
+
+    #[allow(clippy::redundant_field_names)]
+    let config = Config {
+        // NOTE: Not actually serialized; used by `save` to locate the config file.
+        config_dir: config_dir.to_str().unwrap().into(),
+        profile: profile.map(Into::into),
+        config_file: None,
+        unwrapped_key: None,
+        public_key: public_key,
+        recipients_file: None,
+        recipient_policy: vec![],
+        ssh_identity: None,
+        keyfile: keyfile.to_str().unwrap().into(),
+        wrapped: wrapped,
+        passphrase_entry: Default::default(),
+        pinentry: DEFAULT_PINENTRY.into(),
+        passphrase_attempts: DEFAULT_PASSPHRASE_ATTEMPTS,
+        wrap_work_factor: wrap_work_factor,
+        store: store.to_str().unwrap().into(),
+        unresolved_store: store.to_str().unwrap().into(),
+        read_only: false,
+        on_missing_store: Default::default(),
+        label_encoding: LabelEncoding::Percent,
+        label_case: Default::default(),
+        store_armor: true,
+        record_format: Default::default(),
+        max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+        min_entropy: audit::WEAK_ENTROPY_BITS,
+        strict_entropy: false,
+        log_level: Default::default(),
+        clock_check: None,
+        pre_hook: None,
+        post_hook: None,
+        finally_hook: None,
+        on_unwrap: None,
+        reentrant_hooks: false,
+        background_hooks: false,
+        background_hook_log: false,
+        generators: vec![GeneratorConfig::Internal(Default::default())],
+        aliases: BTreeMap::new(),
+        commands: Default::default(),
+    };
+
+    // NOTE: The store is created up front, since the default `on-missing-store`
+    // policy refuses to use a store that doesn't exist.
+    util::create_dir_secure(&store)?;
+    backend::write_canary(&config.canary_path(), config.recipients(None)?)?;
+    config.save()?;
+
+    Ok(config.public_key)
+}
+
+/// Given a path to a `kbs2` configuration directory, loads the configuration
+/// file for the given profile (or the default profile, if `None`) within and returns
+/// the resulting `Config`.
+///
+/// Any files listed under `include` are merged into the configuration first; see
+/// `load_value` for the merging rules.
+pub fn load(config_dir: &Path, profile: Option<&str>) -> Result<Config> {
+    let config_path = config_dir.join(config_basename(profile)?);
+
+    load_path(config_dir, profile, &config_path)
+}
+
+/// Loads the configuration file at the given path, which can be named anything (e.g.
+/// one named by `KBS2_CONFIG`), and returns the resulting `Config`.
+///
+/// The file's parent directory is used as the configuration directory, and no profile
+/// is used.
+pub fn load_file(config_file: &Path) -> Result<Config> {
+    let config_file = fs::canonicalize(config_file)
+        .map_err(|e| anyhow!("couldn't read {}: {}", config_file.display(), e))?;
+
+    // NOTE: This unwrap is safe, since a canonicalized file always has a parent.
+    let mut config = load_path(config_file.parent().unwrap(), None, &config_file)?;
+    config.config_file = Some(
+        config_file
+            .to_str()
+            .ok_or_else(|| anyhow!("config path is not valid UTF-8"))?
+            .into(),
+    );
+
+    Ok(config)
+}
+
+#[doc(hidden)]
+fn load_path(config_dir: &Path, profile: Option<&str>, config_path: &Path) -> Result<Config> {
+    // NOTE: Encrypted includes are decrypted with the key named by the main config
+    // file, so the backend is only created (and the key possibly unwrapped) once
+    // an encrypted include is actually encountered.
+    let mut backend = None;
+    let mut unwrapped_key = None;
+    let mut decrypt = |encrypted: &[u8]| -> Result<Vec<u8>> {
+        if backend.is_none() {
+            let contents = fs::read_to_string(config_path)
+                .map_err(|e| anyhow!("couldn't read {}: {}", config_path.display(), e))?;
+            let (stub, key) = stub_backend(config_dir, profile, &contents)?;
+            backend = Some(stub);
+            unwrapped_key = key;
+        }
+        backend.as_ref().unwrap().decrypt_bytes(encrypted)
+    };
+    let value = load_value(config_dir, config_path, &mut vec![], &mut decrypt)?;
+
+    let mut config = config_from_value(config_dir, profile, value)?;
+    config.unwrapped_key = unwrapped_key;
+
+    Ok(config)
+}
+
+/// Loads a configuration from the given reader (e.g. stdin, for `--config -`), and
+/// returns the resulting `Config`.
+///
+/// Since the configuration has no file of its own, the given configuration directory is
+/// used as the base for its relative paths: `store`, `keyfile`, and any includes.
+pub fn load_reader<R: Read>(mut reader: R, config_dir: &Path) -> Result<Config> {
+    let mut contents = String::new();
+    reader
+        .read_to_string(&mut contents)
+        .map_err(|e| anyhow!("couldn't read config: {}", e))?;
+
+    if is_encrypted(contents.as_bytes()) {
+        return Err(anyhow!(
+            "config is encrypted, but the main config must be plaintext (move the settings to encrypt into an included file)"
+        ));
+    }
+
+    let mut backend = None;
+    let mut unwrapped_key = None;
+    let mut decrypt = |encrypted: &[u8]| -> Result<Vec<u8>> {
+        if backend.is_none() {
+            let (stub, key) = stub_backend(config_dir, None, &contents)?;
+            backend = Some(stub);
+            unwrapped_key = key;
+        }
+        backend.as_ref().unwrap().decrypt_bytes(encrypted)
+    };
+
+    // NOTE: The stack starts with a placeholder for the config itself, so that its
+    // includes aren't mistaken for the main config (which is never decrypted). Canonical
+    // paths are always absolute, so the placeholder can't be part of a real cycle.
+    let value = merge_includes(
+        config_dir,
+        &contents,
+        &mut vec![PathBuf::from("-")],
+        &mut decrypt,
+    )?;
+
+    let mut config = config_from_value(config_dir, None, value)?;
+    config.unwrapped_key = unwrapped_key;
+    if Path::new(&config.keyfile).is_relative() {
+        config.keyfile = fs::canonicalize(config_dir)?
+            .join(&config.keyfile)
+            .to_str()
+            .ok_or_else(|| anyhow!("keyfile path is not valid UTF-8"))?
+            .into();
+    }
+
+    Ok(config)
+}
+
+#[doc(hidden)]
+fn config_from_value(
+    config_dir: &Path,
+    profile: Option<&str>,
+    mut value: toml::Value,
+) -> Result<Config> {
+    migrate(&mut value)?;
+    resolve_generators(&mut value)?;
+
+    let mut config = Config {
+        config_dir: config_dir.to_str().unwrap().into(),
+        profile: profile.map(Into::into),
+        ..value
+            .try_into()
+            .map_err(|e| anyhow!("config loading error: {}", e))?
+    };
+
+    config.unresolved_store = config.store.clone();
+    config.store = resolve_store(config_dir, &config.store)?
+        .to_str()
+        .ok_or_else(|| anyhow!("store path is not valid UTF-8"))?
+        .into();
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+/// Resolves `extends` in the given (fully merged) configuration value's generators: a
+/// generator that extends another inherits each of its settings (besides `name`) that it
+/// doesn't set itself, including those that it inherited in turn. Table settings (like
+/// `weights`) are merged key by key, with the extending generator's keys taking precedence.
+///
+/// NOTE: Generators are resolved before they're deserialized, so that an extending generator
+/// doesn't have to repeat settings that its type requires (e.g. `alphabet`). A saved
+/// configuration contains the resolved generators, without `extends`.
+fn resolve_generators(value: &mut toml::Value) -> Result<()> {
+    let generators = match value
+        .get_mut("generators")
+        .and_then(toml::Value::as_array_mut)
+    {
+        Some(generators) => generators,
+        None => return Ok(()),
+    };
+
+    let tables = generators
+        .iter()
+        .map(|generator| {
+            generator
+                .as_table()
+                .cloned()
+                .ok_or_else(|| anyhow!("config loading error: generators must be tables"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    *generators = (0..tables.len())
+        .map(|index| resolve_generator(&tables, index, &mut vec![]).map(toml::Value::Table))
+        .collect::<Result<_>>()?;
+
+    Ok(())
+}
+
+#[doc(hidden)]
+fn resolve_generator(
+    tables: &[toml::value::Table],
+    index: usize,
+    chain: &mut Vec<String>,
+) -> Result<toml::value::Table> {
+    let name_of = |table: &toml::value::Table| {
+        table
+            .get("name")
+            .and_then(toml::Value::as_str)
+            .map(ToString::to_string)
+    };
+
+    let mut table = tables[index].clone();
+    let parent = match table.remove("extends") {
+        Some(parent) => parent,
+        None => return Ok(table),
+    };
+
+    let name = name_of(&table).unwrap_or_else(|| "(unnamed)".into());
+    let parent = parent.as_str().ok_or_else(|| {
+        anyhow!(
+            "config loading error: generator {} must extend a generator by name",
+            name
+        )
+    })?;
+
+    chain.push(name.clone());
+    if chain.iter().any(|n| n == parent) {
+        chain.push(parent.into());
+        return Err(anyhow!(
+            "config loading error: generator inheritance cycle: {}",
+            chain.join(" -> ")
+        ));
+    }
+
+    let parent_index = tables
+        .iter()
+        .position(|t| name_of(t).as_deref() == Some(parent))
+        .ok_or_else(|| {
+            anyhow!(
+                "config loading error: generator {} extends a nonexistent generator: {}",
+                name,
+                parent
+            )
+        })?;
+
+    for (key, value) in resolve_generator(tables, parent_index, chain)? {
+        if key == "name" {
+            continue;
+        }
+
+        match table.get_mut(&key) {
+            Some(toml::Value::Table(own)) => {
+                if let toml::Value::Table(inherited) = value {
+                    for (key, value) in inherited {
+                        if !own.contains_key(&key) {
+                            own.insert(key, value);
+                        }
+                    }
+                }
+            }
+            Some(_) => {}
+            None => {
+                table.insert(key, value);
+            }
+        }
+    }
+
+    Ok(table)
+}
+
+/// Rewrites deprecated settings in the given (fully merged) configuration value into
+/// their replacements, warning about each one found.
+///
+/// Settings that are already present take precedence over migrated ones.
+fn migrate(value: &mut toml::Value) -> Result<()> {
+    let pass = match value
+        .get_mut("commands")
+        .and_then(|commands| commands.get_mut("pass"))
+        .and_then(toml::Value::as_table_mut)
+    {
+        Some(pass) => pass,
+        None => return Ok(()),
+    };
+
+    if let Some(x11_clipboard) = pass.remove("x11-clipboard") {
+        util::warn(
+            "commands.pass.x11-clipboard is deprecated; use commands.pass.clipboard-selection instead",
+        );
+
+        let selection = match x11_clipboard.as_str() {
+            Some("Clipboard") => "clipboard",
+            Some("Primary") => "primary",
+            _ => {
+                return Err(anyhow!(
+                    "config loading error: invalid commands.pass.x11-clipboard: {}",
+                    x11_clipboard
+                ))
+            }
+        };
+
+        if !pass.contains_key("clipboard-selection") {
+            pass.insert("clipboard-selection".into(), selection.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the configuration file at the given path as a TOML value, merging in each
+/// file listed in its `include` key.
+///
+/// Included paths are tilde and environment variable expanded, and relative paths are
+/// resolved against the given configuration directory. Includes are merged in the order
+/// they're listed, followed by the including file itself, so later files override keys
+/// from earlier ones (arrays, like `generators`, are appended instead).
+///
+/// Included files may be age-encrypted, in which case they're passed to `decrypt` before
+/// being parsed. The main file itself (the first one loaded) is never decrypted, since
+/// it names the key that everything else is decrypted with.
+///
+/// `stack` holds the files currently being loaded, and is used to reject include cycles.
+fn load_value(
+    config_dir: &Path,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    decrypt: &mut dyn FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<toml::Value> {
+    let canonical_path =
+        fs::canonicalize(path).map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+    if stack.contains(&canonical_path) {
+        return Err(anyhow!("config include cycle detected: {}", path.display()));
+    }
+
+    let contents =
+        fs::read(path).map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+    let contents = if is_encrypted(&contents) {
+        if stack.is_empty() {
+            return Err(anyhow!(
+                "{} is encrypted, but the main config file must be plaintext (move the settings to encrypt into an included file)",
+                path.display()
+            ));
+        }
+
+        log::debug!("decrypting config: {}", path.display());
+        decrypt(&contents).map_err(|e| anyhow!("couldn't decrypt {}: {}", path.display(), e))?
+    } else {
+        contents
+    };
+    let contents = String::from_utf8(contents)
+        .map_err(|_| anyhow!("config loading error: {} isn't valid UTF-8", path.display()))?;
+
+    stack.push(canonical_path);
+    let merged = merge_includes(config_dir, &contents, stack, decrypt);
+    stack.pop();
+
+    merged
+}
+
+/// Parses the given configuration contents as a TOML value, merging in each file listed
+/// in its `include` key (see `load_value`).
+fn merge_includes(
+    config_dir: &Path,
+    contents: &str,
+    stack: &mut Vec<PathBuf>,
+    decrypt: &mut dyn FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<toml::Value> {
+    let mut value = toml::from_str::<toml::Value>(contents)
+        .map_err(|e| anyhow!("config loading error: {}", e))?;
+
+    let includes = match value.as_table_mut().and_then(|t| t.remove(INCLUDE_KEY)) {
+        None => vec![],
+        Some(toml::Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                toml::Value::String(include) => Ok(include),
+                _ => Err(anyhow!(
+                    "config loading error: include entries must be paths"
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(_) => {
+            return Err(anyhow!(
+                "config loading error: include must be an array of paths"
+            ))
+        }
+    };
+
+    let mut merged = toml::Value::Table(Default::default());
+    for include in includes {
+        let expanded = shellexpand::full(&include)
+            .map_err(|e| anyhow!("couldn't expand include path: {}", e))?;
+        log::debug!("including config: {}", expanded);
+
+        let included = load_value(
+            config_dir,
+            &config_dir.join(expanded.as_ref()),
+            stack,
+            decrypt,
+        )?;
+        merge_values(&mut merged, included);
+    }
+    merge_values(&mut merged, value);
+
+    Ok(merged)
+}
+
+/// Returns whether the given file contents are age-encrypted, in either the binary or
+/// the ASCII-armored format.
+fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(AGE_HEADER) || contents.starts_with(AGE_ARMOR_HEADER)
+}
+
+/// Creates a backend from the key settings in the given contents of the main config file,
+/// for decrypting encrypted includes.
+///
+/// Only the main file itself is used (not its includes), so the key settings
+/// (`public-key`, `keyfile`, `wrapped`, and the passphrase settings) must be in it.
+///
+/// If the key is wrapped, the unwrapped key is returned alongside the backend. The caller
+/// should hold onto it until it's done with the key, so that later backends attach to it
+/// instead of unwrapping (and prompting for) the key again.
+fn stub_backend(
+    config_dir: &Path,
+    profile: Option<&str>,
+    contents: &str,
+) -> Result<(RageLib, Option<UnwrappedKey>)> {
+    let mut value = toml::from_str::<toml::Value>(contents)
+        .map_err(|e| anyhow!("config loading error: {}", e))?;
+
+    // NOTE: `store` is the only required setting that the key doesn't depend on,
+    // so it's allowed to live in an encrypted include. Fill in a placeholder for it.
+    // NOTE: Generators don't matter to the key either, and may extend generators that
+    // only exist in the (not yet loaded) includes, so they're dropped.
+    if let Some(table) = value.as_table_mut() {
+        if !table.contains_key("store") {
+            table.insert("store".into(), "".into());
+        }
+        table.remove("generators");
+    }
+
+    let stub = Config {
+        config_dir: config_dir.to_str().unwrap().into(),
+        profile: profile.map(Into::into),
+        ..value
+            .try_into()
+            .map_err(|e| anyhow!("config loading error: {}", e))?
+    };
+
+    let unwrapped_key = if stub.wrapped {
+        Some(stub.open_unwrapped_key()?)
+    } else {
+        None
+    };
+
+    Ok((RageLib::new(&stub)?, unwrapped_key))
+}
+
+/// Merges `other` into `base`: tables are merged recursively, arrays are appended, and
+/// everything else in `other` replaces its counterpart in `base`.
+fn merge_values(base: &mut toml::Value, other: toml::Value) {
+    match (base, other) {
+        (toml::Value::Table(base), toml::Value::Table(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base), toml::Value::Array(other)) => base.extend(other),
+        (base, other) => *base = other,
+    }
+}
+
+/// Resolves the given store path into an absolute path, resolving relative paths against
+/// the given configuration directory.
+///
+/// If the store already exists, the resolved path is also canonicalized (resolving any
+/// symlinks); otherwise, it's returned as-is so that it can be created later.
+fn resolve_store(config_dir: &Path, store: &str) -> Result<PathBuf> {
+    let config_dir = fs::canonicalize(config_dir)?;
+    let store = config_dir.join(store);
+
+    if store.exists() {
+        Ok(fs::canonicalize(&store)?)
+    } else {
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    fn dummy_config() -> Config {
+        Config {
+            config_dir: "/not/a/real/dir".into(),
+            profile: None,
+            config_file: None,
+            unwrapped_key: None,
+            public_key: "not a real public key".into(),
+            recipients_file: None,
+            recipient_policy: vec![],
+            ssh_identity: None,
+            keyfile: "not a real private key file".into(),
+            wrapped: false,
+            passphrase_entry: PassphraseEntry::Tty,
+            pinentry: DEFAULT_PINENTRY.into(),
+            passphrase_attempts: DEFAULT_PASSPHRASE_ATTEMPTS,
+            wrap_work_factor: DEFAULT_WRAP_WORK_FACTOR,
+            store: "/tmp".into(),
+            unresolved_store: "/tmp".into(),
+            read_only: false,
+            on_missing_store: Default::default(),
+            label_encoding: Default::default(),
+            label_case: Default::default(),
+            store_armor: true,
+            record_format: Default::default(),
+            max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+            min_entropy: audit::WEAK_ENTROPY_BITS,
+            strict_entropy: false,
+            log_level: Default::default(),
+            clock_check: None,
+            pre_hook: Some("true".into()),
+            post_hook: Some("false".into()),
+            finally_hook: None,
+            on_unwrap: None,
+            reentrant_hooks: false,
+            background_hooks: false,
+            background_hook_log: false,
+            generators: vec![GeneratorConfig::Internal(Default::default())],
+            aliases: BTreeMap::new(),
+            commands: CommandConfigs {
+                rm: RmConfig {
+                    post_hook: Some("this-command-does-not-exist".into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_find_config_dir() {
+        let dir = find_config_dir().unwrap();
+        // NOTE: We can't check whether the main config dir exists since we create it if it
+        // doesn't; instead, we just check that it isn't something weird like a regular file.
+        assert!(!dir.is_file());
+
+        let parent = dir.parent().unwrap();
+
+        assert!(parent.exists());
+        assert!(parent.is_dir());
+    }
+
+    #[test]
+    fn test_initialize() {
+        // TODO: Figure out how to test initialization with a wrapped key.
+        // The current API requires graphical interaction.
+        // {
+        //     let dir = tempdir().unwrap();
+        //     assert!(initialize(dir.path(), true).is_ok());
+        // }
+
+        {
+            let dir = tempdir().unwrap();
+            let public_key = initialize(dir.path(), None, false, None).unwrap();
+            assert!(public_key.parse::<age::keys::RecipientKey>().is_ok());
+            assert_eq!(load(dir.path(), None).unwrap().public_key, public_key);
+
+            let path = dir.path();
+            assert!(path.exists());
+            assert!(path.is_dir());
+
+            assert!(path.join(CONFIG_BASENAME).exists());
+            assert!(path.join(CONFIG_BASENAME).is_file());
+
+            assert!(path.join(DEFAULT_KEY_BASENAME).exists());
+            assert!(path.join(DEFAULT_KEY_BASENAME).is_file());
+
+            let config = load(path, None).unwrap();
+            let canary = fs::read(config.canary_path()).unwrap();
+            let backend = RageLib::new(&config).unwrap();
+            assert_eq!(
+                backend.decrypt_bytes(&canary).unwrap(),
+                backend::CANARY_PLAINTEXT
+            );
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            assert!(load(dir.path(), None).is_ok());
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(dir.path().to_str().unwrap(), config.config_dir);
+        }
+    }
+
+    #[test]
+    fn test_load_migrates_x11_clipboard() {
+        let load_with_pass = |pass: &str| {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let config_path = dir.path().join(CONFIG_BASENAME);
+            let contents = fs::read_to_string(&config_path)
+                .unwrap()
+                .replace("clipboard-selection = \"clipboard\"", pass);
+            fs::write(&config_path, contents).unwrap();
+
+            load(dir.path(), None)
+        };
+
+        {
+            let config = load_with_pass("x11-clipboard = \"Primary\"").unwrap();
+            assert_eq!(
+                config.commands.pass.clipboard_selection,
+                ClipboardSelection::Primary
+            );
+        }
+
+        {
+            let config = load_with_pass("x11-clipboard = \"Clipboard\"").unwrap();
+            assert_eq!(
+                config.commands.pass.clipboard_selection,
+                ClipboardSelection::Clipboard
+            );
+        }
+
+        {
+            // The new setting wins over the old one.
+            let config =
+                load_with_pass("x11-clipboard = \"Clipboard\"\nclipboard-selection = \"primary\"")
+                    .unwrap();
+            assert_eq!(
+                config.commands.pass.clipboard_selection,
+                ClipboardSelection::Primary
+            );
+        }
+
+        {
+            let err = load_with_pass("x11-clipboard = \"Secondary\"").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "config loading error: invalid commands.pass.x11-clipboard: \"Secondary\""
+            );
+        }
+    }
+
+    #[test]
+    fn test_save() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mode = fs::metadata(dir.path().join(CONFIG_BASENAME))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), Some("work"), false, None).unwrap();
+
+            let mut config = load(dir.path(), Some("work")).unwrap();
+            config.store = resolve_store(dir.path(), "store")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .into();
+            config.unresolved_store = "store".into();
+            config.label_encoding = LabelEncoding::Raw;
+            config.label_case = LabelCase::RejectCollisions;
+            config.store_armor = false;
+            config.max_attachment_size = 1234;
+            config.pre_hook = Some("pre".into());
+            config.post_hook = Some("post".into());
+            config.reentrant_hooks = true;
+            config.commands.edit.editor = Some("vim".into());
+            config.save().unwrap();
+
+            let loaded = load(dir.path(), Some("work")).unwrap();
+            assert_eq!(loaded.config_dir, config.config_dir);
+            assert_eq!(loaded.profile, config.profile);
+            assert_eq!(loaded.store, config.store);
+            assert_eq!(
+                toml::to_string(&loaded).unwrap(),
+                toml::to_string(&config).unwrap()
+            );
+
+            // NOTE: Saving a loaded config shouldn't change it.
+            loaded.save().unwrap();
+            assert_eq!(
+                toml::to_string(&load(dir.path(), Some("work")).unwrap()).unwrap(),
+                toml::to_string(&config).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_include() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(
+                0,
+                "include = [\"generators.toml\", \"hooks.toml\", \"more/hooks.toml\"]\n",
+            );
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            fs::write(
+                dir.path().join("generators.toml"),
+                "[[generators]]\nname = \"pwgen\"\ncommand = \"echo hunter2\"\n",
+            )
+            .unwrap();
+            fs::write(
+                dir.path().join("hooks.toml"),
+                "pre-hook = \"first\"\npost-hook = \"first\"\n[commands.edit]\neditor = \"vim\"\n",
+            )
+            .unwrap();
+            fs::create_dir(dir.path().join("more")).unwrap();
+            fs::write(
+                dir.path().join("more/hooks.toml"),
+                "post-hook = \"second\"\n",
+            )
+            .unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+
+            // Arrays are appended, with the including file's entries last.
+            let names = config
+                .generators
+                .iter()
+                .map(|g| g.as_dyn().name())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["pwgen", "default"]);
+
+            // Later includes override earlier ones, and nested tables are merged.
+            assert_eq!(config.pre_hook.as_deref(), Some("first"));
+            assert_eq!(config.post_hook.as_deref(), Some("second"));
+            assert_eq!(config.commands.edit.editor.as_deref(), Some("vim"));
+            assert_eq!(config.commands.pass.clipboard_duration, 10);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            // The including file takes precedence over its includes.
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = [\"wrapped.toml\"]\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+            fs::write(dir.path().join("wrapped.toml"), "wrapped = true\n").unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert!(!config.wrapped);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = [\"a.toml\"]\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+            fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+            fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert!(err.to_string().starts_with("config include cycle detected"));
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = [\"kbs2.conf\"]\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert!(err.to_string().starts_with("config include cycle detected"));
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = \"generators.toml\"\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "config loading error: include must be an array of paths"
+            );
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = [\"missing.toml\"]\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert!(err.to_string().starts_with("couldn't read"));
+        }
+    }
+
+    #[test]
+    fn test_passphrase_entry() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(config.passphrase_entry, PassphraseEntry::Auto);
+            assert_eq!(config.pinentry, DEFAULT_PINENTRY);
+        }
+
+        {
+            let config: Config = toml::from_str(
+                r#"
+                public-key = "not a real public key"
+                keyfile = "not a real private key file"
+                wrapped = true
+                store = "/tmp"
+                passphrase-entry = "tty"
+                pinentry = "pinentry-curses"
+                "#,
+            )
+            .unwrap();
+
+            assert_eq!(config.passphrase_entry, PassphraseEntry::Tty);
+            assert_eq!(config.pinentry, "pinentry-curses");
+        }
+    }
+
+    #[test]
+    fn test_hook_expansion() {
+        let parse = |hook: &str| {
+            toml::from_str::<Config>(&format!(
+                r#"
+                public-key = "not a real public key"
+                keyfile = "not a real private key file"
+                wrapped = false
+                store = "/tmp"
+                pre-hook = "{}"
+                "#,
+                hook
+            ))
+        };
+
+        {
+            let config = parse("~/bin/sync").unwrap();
+            assert_eq!(
+                Path::new(config.pre_hook.as_deref().unwrap()),
+                util::home_dir().unwrap().join("bin/sync")
+            );
+        }
+
+        {
+            let config = parse("sync").unwrap();
+            assert_eq!(config.pre_hook.as_deref(), Some("sync"));
+        }
+
+        {
+            assert!(parse("$KBS2_TEST_THIS_VARIABLE_DOES_NOT_EXIST/sync").is_err());
+        }
+
+        // A hook given via an environment variable resolves to a runnable command.
+        {
+            let dir = tempdir().unwrap();
+            let hook = dir.path().join("hook.sh");
+            fs::write(&hook, "#!/bin/sh\nexit 0\n").unwrap();
+            fs::set_permissions(&hook, fs::Permissions::from_mode(0o700)).unwrap();
+            env::set_var("KBS2_TEST_HOOK_DIR", dir.path());
+
+            let mut config = parse("$KBS2_TEST_HOOK_DIR/hook.sh").unwrap();
+            config.store = dir.path().to_str().unwrap().into();
+            assert_eq!(Path::new(config.pre_hook.as_deref().unwrap()), hook);
+            assert!(config
+                .call_hook(config.pre_hook.as_ref().unwrap(), &[], &Default::default())
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        {
+            let config = dummy_config();
+            assert_eq!(config.clock_skew().unwrap(), None);
+        }
+
+        {
+            let mut config = dummy_config();
+            config.clock_check = Some("date +%s".into());
+            assert!(config.clock_skew().unwrap().unwrap() <= 1);
+        }
+
+        {
+            let mut config = dummy_config();
+            config.clock_check = Some("echo 0".into());
+            let skew = config.clock_skew().unwrap().unwrap();
+            assert!(skew + 1 >= util::current_timestamp());
+        }
+
+        {
+            let mut config = dummy_config();
+            config.clock_check = Some("echo yesterday".into());
+            let err = config.clock_skew().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "expected seconds since the Unix epoch from clock-check, not: yesterday"
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(LogLevel::from_verbosity(0), None);
+        assert_eq!(LogLevel::from_verbosity(1), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::from_verbosity(3), Some(LogLevel::Debug));
+        assert_eq!(LogLevel::from_verbosity(10), Some(LogLevel::Trace));
+
+        assert_eq!(
+            log::LevelFilter::from(LogLevel::default()),
+            log::LevelFilter::Error
+        );
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let config_path = dir.path().join(CONFIG_BASENAME);
+            let contents = fs::read_to_string(&config_path)
+                .unwrap()
+                .replace("log-level = \"error\"", "log-level = \"info\"");
+            fs::write(&config_path, contents).unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(config.log_level, LogLevel::Info);
+        }
+    }
+
+    #[test]
+    fn test_config_basename() {
+        assert_eq!(config_basename(None).unwrap(), CONFIG_BASENAME);
+        assert_eq!(config_basename(Some("work")).unwrap(), "work.conf");
+
+        for bad in &["", ".hidden", "../work", "a/b"] {
+            assert!(config_basename(Some(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_load_encrypted_include() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            // The stub keeps the key settings, and everything else (including the store)
+            // moves into an encrypted include.
+            let backend = RageLib::new(&load(dir.path(), None).unwrap()).unwrap();
+            let encrypted = backend
+                .encrypt_bytes(b"store = \"/tmp/secret-store\"\n[commands.edit]\neditor = \"ed\"\n")
+                .unwrap();
+            fs::write(dir.path().join("secret.toml.age"), encrypted).unwrap();
+
+            let contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME))
+                .unwrap()
+                .lines()
+                .filter(|line| !line.starts_with("store = "))
+                .collect::<Vec<_>>()
+                .join("\n");
+            fs::write(
+                dir.path().join(CONFIG_BASENAME),
+                format!("include = [\"secret.toml.age\"]\n{}\n", contents),
+            )
+            .unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(config.store, "/tmp/secret-store");
+            assert_eq!(config.commands.edit.editor.as_deref(), Some("ed"));
+
+            // The key isn't wrapped, so there's no unwrapped key to hold onto.
+            assert!(config.unwrapped_key.is_none());
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            // The main config file can't be encrypted.
+            let backend = RageLib::new(&load(dir.path(), None).unwrap()).unwrap();
+            let contents = fs::read(dir.path().join(CONFIG_BASENAME)).unwrap();
+            fs::write(
+                dir.path().join(CONFIG_BASENAME),
+                backend.encrypt_bytes(&contents).unwrap(),
+            )
+            .unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("the main config file must be plaintext"));
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let other = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+            initialize(other.path(), None, false, None).unwrap();
+
+            // An include encrypted to a different key can't be decrypted.
+            let backend = RageLib::new(&load(other.path(), None).unwrap()).unwrap();
+            fs::write(
+                dir.path().join("secret.toml.age"),
+                backend.encrypt_bytes(b"pre-hook = \"nope\"\n").unwrap(),
+            )
+            .unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.insert_str(0, "include = [\"secret.toml.age\"]\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert!(err.to_string().starts_with("couldn't decrypt"));
+        }
+    }
+
+    #[test]
+    fn test_load_profile() {
+        let dir = tempdir().unwrap();
+        initialize(dir.path(), None, false, None).unwrap();
+        initialize(dir.path(), Some("work"), false, None).unwrap();
+
+        assert!(dir.path().join(CONFIG_BASENAME).is_file());
+        assert!(dir.path().join("work.conf").is_file());
+        assert!(dir.path().join("work.key").is_file());
+
+        let default = load(dir.path(), None).unwrap();
+        let work = load(dir.path(), Some("work")).unwrap();
+
+        assert_eq!(default.profile, None);
+        assert_eq!(work.profile.as_deref(), Some("work"));
+        assert_eq!(default.config_dir, work.config_dir);
+        assert_ne!(default.public_key, work.public_key);
+        assert_ne!(default.keyfile, work.keyfile);
+        assert_ne!(default.store, work.store);
+        assert_ne!(
+            default.unwrapped_key_shm_name().unwrap(),
+            work.unwrapped_key_shm_name().unwrap()
+        );
+
+        assert!(load(dir.path(), Some("nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = tempdir().unwrap();
+        initialize(dir.path(), None, false, None).unwrap();
+
+        let config_file = dir.path().join("whatever.conf");
+        fs::rename(dir.path().join(CONFIG_BASENAME), &config_file).unwrap();
+        assert!(load(dir.path(), None).is_err());
+
+        let config = load_file(&config_file).unwrap();
+        let config_file = fs::canonicalize(&config_file).unwrap();
+
+        assert_eq!(
+            Path::new(&config.config_dir),
+            fs::canonicalize(dir.path()).unwrap()
+        );
+        assert_eq!(config.profile, None);
+        assert_eq!(config.config_path().unwrap(), config_file);
+
+        // Saving goes back to the same file, rather than the config directory's default.
+        config.save().unwrap();
+        assert!(!dir.path().join(CONFIG_BASENAME).exists());
+        assert_eq!(
+            load_file(&config_file).unwrap().public_key,
+            config.public_key
+        );
+
+        assert!(load_file(&dir.path().join("nonexistent.conf")).is_err());
+    }
+
+    #[test]
+    fn test_load_reader() {
+        let dir = tempdir().unwrap();
+        initialize(dir.path(), None, false, None).unwrap();
+        let base = fs::canonicalize(dir.path()).unwrap();
+
+        let mut value = toml::from_str::<toml::Value>(
+            &fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap(),
+        )
+        .unwrap();
+        let table = value.as_table_mut().unwrap();
+        table.insert("keyfile".into(), DEFAULT_KEY_BASENAME.into());
+        table.insert("store".into(), "elsewhere".into());
+        table.insert(
+            INCLUDE_KEY.into(),
+            toml::Value::Array(vec!["hooks.toml".into()]),
+        );
+        let contents = toml::to_string(&value).unwrap();
+
+        fs::write(dir.path().join("hooks.toml"), "pre-hook = \"sync\"\n").unwrap();
+
+        {
+            let config = load_reader(contents.as_bytes(), dir.path()).unwrap();
+
+            // Relative paths are resolved against the given directory.
+            assert_eq!(Path::new(&config.config_dir), dir.path());
+            assert_eq!(Path::new(&config.keyfile), base.join(DEFAULT_KEY_BASENAME));
+            assert_eq!(Path::new(&config.store), base.join("elsewhere"));
+            assert_eq!(config.unresolved_store, "elsewhere");
+            assert_eq!(config.pre_hook.as_deref(), Some("sync"));
+            assert_eq!(config.config_file, None);
+
+            // The key is usable, since the keyfile was found.
+            assert!(RageLib::new(&config).is_ok());
+        }
+
+        {
+            let err = load_reader("not = [valid".as_bytes(), dir.path()).unwrap_err();
+            assert!(err.to_string().starts_with("config loading error: "));
+        }
+
+        {
+            let err =
+                load_reader(&b"-----BEGIN AGE ENCRYPTED FILE-----\n"[..], dir.path()).unwrap_err();
+            assert!(err.to_string().starts_with("config is encrypted"));
+        }
+    }
+
+    #[test]
+    fn test_effective() {
+        let dir = tempdir().unwrap();
+        initialize(dir.path(), None, false, None).unwrap();
+
+        let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+        contents = format!("recipients-file = \"recipients.txt\"\n{}", contents);
+        contents.push_str("\n[aliases]\ngh = \"github.com/personal\"\n");
+        fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+        let config = load(dir.path(), None).unwrap();
+        let effective = config.effective().unwrap();
+
+        assert_eq!(
+            effective["recipients-file"].as_str().unwrap(),
+            dir.path().join("recipients.txt").to_str().unwrap()
+        );
+        assert_eq!(effective["store"].as_str().unwrap(), config.store);
+        assert_eq!(effective["keyfile"].as_str().unwrap(), config.keyfile);
+
+        // Reloading the printed config, even from elsewhere, gives the same config back.
+        let elsewhere = tempdir().unwrap();
+        let config_file = elsewhere.path().join("effective.toml");
+        fs::write(&config_file, toml::to_string(&effective).unwrap()).unwrap();
+
+        let reloaded = load_file(&config_file).unwrap();
+        assert_eq!(reloaded.effective().unwrap(), effective);
+        assert_eq!(reloaded.store, config.store);
+        assert_eq!(reloaded.resolve_alias("gh"), "github.com/personal");
+
+        // The JSON form holds the same values.
+        let json = serde_json::to_value(&effective).unwrap();
+        assert_eq!(json["public-key"], config.public_key.as_str());
+        assert_eq!(json["aliases"]["gh"], "github.com/personal");
+    }
+
+    #[test]
+    fn test_load_store() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(config.store, config.unresolved_store);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            fs::create_dir(dir.path().join("store")).unwrap();
+            std::os::unix::fs::symlink(dir.path().join("store"), dir.path().join("link")).unwrap();
+
+            for (store, resolved) in &[("store", "store"), ("./link", "store"), ("new", "new")] {
+                fs::write(
+                    dir.path().join(CONFIG_BASENAME),
+                    format!(
+                        "public-key = \"x\"\nkeyfile = \"x\"\nwrapped = false\nstore = \"{}\"\n",
+                        store
+                    ),
+                )
+                .unwrap();
+
+                let config = load(dir.path(), None).unwrap();
+                assert_eq!(
+                    Path::new(&config.store),
+                    fs::canonicalize(dir.path()).unwrap().join(resolved)
+                );
+                assert_eq!(&config.unresolved_store, store);
+
+                // The original store path should survive a round-trip.
+                let serialized = toml::to_string(&config).unwrap();
+                let reloaded: Config = toml::from_str(&serialized).unwrap();
+                assert_eq!(&reloaded.store, store);
+            }
+        }
+    }
+
+    #[test]
+    fn test_override_store() {
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+
+            config
+                .override_store(dir.path().to_str().unwrap(), false)
+                .unwrap();
+            assert_eq!(
+                Path::new(&config.store),
+                fs::canonicalize(dir.path()).unwrap()
+            );
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let store = dir.path().join("store");
+            let mut config = dummy_config();
+
+            let err = config
+                .override_store(store.to_str().unwrap(), false)
+                .unwrap_err();
+            assert!(err.to_string().starts_with("store does not exist"));
+            assert_eq!(config.store, "/tmp");
+
+            config
+                .override_store(store.to_str().unwrap(), true)
+                .unwrap();
+            assert!(store.is_dir());
+            assert_eq!(
+                fs::metadata(&store).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+        }
+
+        {
+            let mut config = dummy_config();
+            config.override_store("~", false).unwrap();
+
+            assert_eq!(
+                Path::new(&config.store),
+                fs::canonicalize(util::home_dir().unwrap()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_store() {
+        let never = |_: &Path| -> Result<bool> { panic!("unexpected prompt") };
+
+        // An existing store is always opened, regardless of the policy.
+        for policy in &[
+            OnMissingStore::Error,
+            OnMissingStore::Create,
+            OnMissingStore::Prompt,
+        ] {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.store = dir.path().to_str().unwrap().into();
+            config.on_missing_store = *policy;
+
+            assert!(config.open_store(true, never).unwrap());
+            assert!(config.open_store(false, never).unwrap());
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let store = dir.path().join("store");
+            let mut config = dummy_config();
+            config.store = store.to_str().unwrap().into();
+
+            for mutating in &[true, false] {
+                let err = config.open_store(*mutating, never).unwrap_err();
+                assert!(err.to_string().starts_with("store does not exist"));
+                assert!(!store.exists());
+            }
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let store = dir.path().join("store");
+            let mut config = dummy_config();
+            config.store = store.to_str().unwrap().into();
+            config.on_missing_store = OnMissingStore::Create;
+
+            assert!(!config.open_store(false, never).unwrap());
+            assert!(!store.exists());
+
+            assert!(config.open_store(true, never).unwrap());
+            assert!(store.is_dir());
+            assert_eq!(
+                fs::metadata(&store).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let store = dir.path().join("store");
+            let mut config = dummy_config();
+            config.store = store.to_str().unwrap().into();
+            config.on_missing_store = OnMissingStore::Prompt;
+
+            let err = config
+                .open_store(false, |path| {
+                    assert_eq!(path, store);
+                    Ok(false)
+                })
+                .unwrap_err();
+            assert!(err.to_string().starts_with("store does not exist"));
+            assert!(!store.exists());
+
+            assert!(config.open_store(false, |_| Ok(true)).unwrap());
+            assert!(store.is_dir());
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let store = dir.path().join("store");
+            fs::write(&store, "not a store").unwrap();
+            let mut config = dummy_config();
+            config.store = store.to_str().unwrap().into();
+            config.on_missing_store = OnMissingStore::Create;
+
+            let err = config.open_store(true, never).unwrap_err();
+            assert!(err.to_string().starts_with("store is not a directory"));
+        }
+
+        {
+            assert_eq!(
+                "prompt".parse::<OnMissingStore>().unwrap(),
+                OnMissingStore::Prompt
+            );
+            assert!("maybe".parse::<OnMissingStore>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_override_identity() {
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+            let config = load(dir.path(), None).unwrap();
+
+            // A backup of the configured key can decrypt the same records.
+            let backup = dir.path().join("backup-key");
+            fs::copy(&config.keyfile, &backup).unwrap();
+
+            let mut overridden = load(dir.path(), None).unwrap();
+            overridden
+                .override_identity(backup.to_str().unwrap())
+                .unwrap();
+            assert_eq!(
+                Path::new(&overridden.keyfile),
+                fs::canonicalize(&backup).unwrap()
+            );
+            assert!(!overridden.wrapped);
+
+            let record = crate::kbs2::record::Record::login("foo", "bar", "baz");
+            let encrypted = RageLib::new(&config).unwrap().encrypt(&record).unwrap();
+            assert_eq!(
+                RageLib::new(&overridden)
+                    .unwrap()
+                    .decrypt(&encrypted)
+                    .unwrap(),
+                record
+            );
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let keyfile = dir.path().join("wrapped-key");
+            let (_, work_factor) =
+                RageLib::create_wrapped_keypair(&keyfile, SecretString::new("pass".into()), None)
+                    .unwrap();
+
+            let mut config = dummy_config();
+            config.override_identity(keyfile.to_str().unwrap()).unwrap();
+            assert!(config.wrapped);
+            assert_eq!(config.wrap_work_factor, work_factor);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+
+            let err = config
+                .override_identity(dir.path().join("missing").to_str().unwrap())
+                .unwrap_err();
+            assert!(err.to_string().starts_with("identity does not exist"));
+            assert_eq!(config.keyfile, "not a real private key file");
+
+            // Directories aren't identities either.
+            assert!(config
+                .override_identity(dir.path().to_str().unwrap())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_nested_hook() {
+        let config = dummy_config();
+        let token = config.hook_token();
+
+        assert!(!config.is_nested_hook(None, None));
+        assert!(config.is_nested_hook(Some("1"), None));
+        assert!(config.is_nested_hook(Some("1"), Some(&token)));
+
+        // A hook for a different config, profile, or store isn't nested.
+        for other in &[
+            Config {
+                config_dir: "/some/other/dir".into(),
+                ..dummy_config()
+            },
+            Config {
+                profile: Some("work".into()),
+                ..dummy_config()
+            },
+            Config {
+                store: "/some/other/store".into(),
+                ..dummy_config()
+            },
+        ] {
+            assert_ne!(other.hook_token(), token);
+            assert!(!config.is_nested_hook(Some("1"), Some(&other.hook_token())));
+            assert!(!other.is_nested_hook(Some("1"), Some(&token)));
+        }
+    }
+
+    #[test]
+    fn test_recipients() {
+        let keys = (0..3)
+            .map(|_| age::SecretKey::generate().to_public().to_string())
+            .collect::<Vec<_>>();
+
+        let dir = tempdir().unwrap();
+        let mut config = dummy_config();
+        config.config_dir = dir.path().to_str().unwrap().into();
+        config.public_key = keys[0].clone();
+
+        let recipients = |config: &Config, label| {
+            config
+                .recipients(label)
+                .unwrap()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        {
+            assert_eq!(recipients(&config, None), vec![keys[0].clone()]);
+            assert_eq!(recipients(&config, Some("foo")), vec![keys[0].clone()]);
+        }
+
+        // The recipients file is resolved against the config directory, and duplicates
+        // (including of the public key) are dropped.
+        {
+            fs::write(
+                dir.path().join("recipients"),
+                format!("{}\n{}\n# again\n{}\n", keys[1], keys[0], keys[1]),
+            )
+            .unwrap();
+            config.recipients_file = Some("recipients".into());
+
+            assert_eq!(
+                recipients(&config, None),
+                vec![keys[0].clone(), keys[1].clone()]
+            );
+            assert_eq!(
+                recipients(&config, Some("foo")),
+                vec![keys[0].clone(), keys[1].clone()]
+            );
+        }
+
+        // A matching policy rule replaces the recipients file, but unlabeled data is always
+        // encrypted to the default recipients.
+        {
+            config.recipient_policy = vec![RecipientRule {
+                labels: "team/*".into(),
+                recipients: vec![keys[2].clone(), keys[0].clone(), keys[2].clone()],
+            }];
+
+            assert_eq!(
+                recipients(&config, Some("team/db")),
+                vec![keys[0].clone(), keys[2].clone()]
+            );
+            assert_eq!(
+                recipients(&config, Some("foo")),
+                vec![keys[0].clone(), keys[1].clone()]
+            );
+            assert_eq!(
+                recipients(&config, None),
+                vec![keys[0].clone(), keys[1].clone()]
+            );
+        }
+
+        // Every recipient is validated.
+        {
+            config.recipient_policy[0].recipients.push("garbage".into());
+            let err = config.recipients(Some("foo")).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("invalid recipient-policy for team/*"));
+
+            config.recipient_policy.clear();
+            fs::write(dir.path().join("recipients"), "garbage\n").unwrap();
+            let err = config.recipients(None).unwrap_err();
+            assert!(err.to_string().contains("line 1: invalid recipient"));
+
+            config.public_key = "garbage".into();
+            assert!(config.recipients(None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_call_hook() {
+        let config = dummy_config();
+
+        {
+            assert!(config
+                .call_hook(config.pre_hook.as_ref().unwrap(), &[], &Default::default())
+                .is_ok());
+        }
+
+        {
+            let err = config
+                .call_hook(
+                    config.commands.rm.post_hook.as_ref().unwrap(),
+                    &[],
+                    &Default::default(),
+                )
+                .unwrap_err();
+
+            assert_eq!(
+                err.to_string(),
+                "failed to run hook: this-command-does-not-exist"
+            );
+        }
+
+        {
+            let err = config
+                .call_hook(config.post_hook.as_ref().unwrap(), &[], &Default::default())
+                .unwrap_err();
+
+            assert_eq!(err.to_string(), "hook exited with an error code: false");
+        }
+
+        {
+            let err = config
+                .call_hook(
+                    "sh",
+                    &["-c", &format!("exit {}", HOOK_CANCEL_CODE)],
+                    &Default::default(),
+                )
+                .unwrap_err();
+
+            assert!(err.is::<HookCancelled>());
+            assert_eq!(err.to_string(), "hook cancelled the command: sh");
+        }
+
+        {
+            let err = config
+                .call_hook("sh", &["-c", "exit 1"], &Default::default())
+                .unwrap_err();
+
+            assert!(!err.is::<HookCancelled>());
+        }
+
+        {
+            let hook_env = vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("KBS2_HOOK".to_string(), "0".to_string()),
+            ]
+            .into_iter()
+            .collect();
+
+            assert!(config
+                .call_hook("sh", &["-c", "test \"$FOO\" = bar"], &hook_env)
+                .is_ok());
+
+            assert!(config
+                .call_hook(
+                    "sh",
+                    &[
+                        "-c",
+                        &format!("test \"$KBS2_HOOK_CONFIG\" = {}", config.hook_token()),
+                    ],
+                    &hook_env,
+                )
+                .is_ok());
+
+            // Built-in variables can't be clobbered by hook-env.
+            assert!(config
+                .call_hook("sh", &["-c", "test \"$KBS2_HOOK\" = 1"], &hook_env)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_call_post_hook() {
+        {
+            // Post-hooks are synchronous by default.
+            let config = dummy_config();
+            let post_hook = config.post_hook.as_ref().unwrap();
+            let err = config
+                .call_post_hook(post_hook, &[], &Default::default(), None)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "hook exited with an error code: false");
+
+            // ...unless they opt out of it individually.
+            config
+                .call_post_hook(post_hook, &[], &Default::default(), Some(true))
+                .unwrap();
+        }
+
+        {
+            // A hook can also opt out of background-hooks.
+            let mut config = dummy_config();
+            config.background_hooks = true;
+            let post_hook = config.post_hook.as_ref().unwrap();
+
+            assert!(config
+                .call_post_hook(post_hook, &[], &Default::default(), Some(false))
+                .is_err());
+            config
+                .call_post_hook(post_hook, &[], &Default::default(), None)
+                .unwrap();
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.config_dir = dir.path().to_str().unwrap().into();
+            config.store = dir.path().to_str().unwrap().into();
+            config.background_hooks = true;
+            config.background_hook_log = true;
+
+            // A background hook doesn't block the caller, and its failures aren't reported.
+            let start = Instant::now();
+            config
+                .call_post_hook(
+                    "sh",
+                    &["-c", "echo started; sleep 5; touch finished; exit 1"],
+                    &Default::default(),
+                    None,
+                )
+                .unwrap();
+            assert!(start.elapsed() < Duration::from_secs(4));
+            assert!(!dir.path().join("finished").exists());
+
+            // Its output ends up in the log, once it gets around to writing it.
+            let log_path = dir.path().join(BACKGROUND_HOOK_LOG_BASENAME);
+            let deadline = Instant::now() + Duration::from_secs(4);
+            while fs::read_to_string(&log_path).unwrap_or_default() != "started\n" {
+                assert!(Instant::now() < deadline, "background hook never logged");
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            // Failing to start the hook is still an error.
+            let err = config
+                .call_post_hook(
+                    "this-command-does-not-exist",
+                    &[],
+                    &Default::default(),
+                    None,
+                )
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to run hook: this-command-does-not-exist"
+            );
+
+            // NOTE: The hook is still running in the temporary directory, which can't be
+            // removed out from under it, so we wait for it to finish.
+            let deadline = Instant::now() + Duration::from_secs(30);
+            while !dir.path().join("finished").exists() {
+                assert!(Instant::now() < deadline, "background hook never finished");
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    #[test]
+    fn test_call_finally_hooks() {
+        // Each hook appends its name and the command's status to a file in the store.
+        let hook = |dir: &Path, name: &str| {
+            let path = dir.join(format!("{}.sh", name));
+            fs::write(
+                &path,
+                format!(
+                    "#!/bin/sh\necho \"{} $KBS2_COMMAND_STATUS\" >> status\n",
+                    name
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+            Some(path.to_str().unwrap().to_string())
+        };
+        let statuses = |dir: &Path| fs::read_to_string(dir.join("status")).unwrap_or_default();
+
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.store = dir.path().to_str().unwrap().into();
+            config.finally_hook = hook(dir.path(), "global");
+            config.commands.rm.finally_hook = hook(dir.path(), "rm");
+
+            assert!(config.call_finally_hooks("rm", Ok(())).is_ok());
+            assert_eq!(statuses(dir.path()), "rm success\nglobal success\n");
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.store = dir.path().to_str().unwrap().into();
+            config.finally_hook = hook(dir.path(), "global");
+            config.commands.rm.finally_hook = hook(dir.path(), "rm");
+
+            // Only the global finally-hook runs for other commands.
+            let err = config
+                .call_finally_hooks("list", Err(anyhow!("boom")))
+                .unwrap_err();
+            assert_eq!(err.to_string(), "boom");
+            assert_eq!(statuses(dir.path()), "global failure\n");
+        }
+
+        // A failing finally-hook doesn't mask the command's own error...
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.store = dir.path().to_str().unwrap().into();
+            config.finally_hook = hook(dir.path(), "global");
+            config.commands.rm.finally_hook = Some("false".into());
+
+            let err = config
+                .call_finally_hooks("rm", Err(anyhow!("boom")))
+                .unwrap_err();
+            assert_eq!(err.to_string(), "boom");
+            assert_eq!(statuses(dir.path()), "global failure\n");
+        }
+
+        // ...but is an error when the command succeeded.
+        {
+            let mut config = dummy_config();
+            config.finally_hook = Some("false".into());
+
+            let err = config.call_finally_hooks("rm", Ok(())).unwrap_err();
+            assert_eq!(err.to_string(), "hook exited with an error code: false");
+        }
+
+        // Cancelled commands count as successful.
+        {
+            let dir = tempdir().unwrap();
+            let mut config = dummy_config();
+            config.store = dir.path().to_str().unwrap().into();
+            config.finally_hook = hook(dir.path(), "global");
+
+            let err = config
+                .call_finally_hooks("rm", Err(HookCancelled { hook: "pre".into() }.into()))
+                .unwrap_err();
+            assert!(err.is::<HookCancelled>());
+            assert_eq!(statuses(dir.path()), "global success\n");
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        {
+            let config = dummy_config();
+            assert!(config.validate().is_ok());
+        }
+
+        {
+            let mut config = dummy_config();
+            config.commands.pass.clear_trigger = ClearTrigger::Both;
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "commands.pass.clear-trigger requires commands.pass.lock-command"
+            );
+
+            config.commands.pass.lock_command = Some("true".into());
+            assert!(config.validate().is_ok());
+        }
+
+        {
+            let mut config = dummy_config();
+            config.passphrase_attempts = 0;
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "passphrase-attempts must be at least 1"
+            );
+        }
+
+        for (length, ok) in &[
+            (0, false),
+            (1, true),
+            (16, true),
+            (4096, true),
+            (4097, false),
+        ] {
+            let mut config = dummy_config();
+            config
+                .generators
+                .push(GeneratorConfig::Internal(GeneratorInternalConfig {
+                    name: "custom".into(),
+                    alphabet: "abc".into(),
+                    length: *length,
+                    weights: BTreeMap::new(),
+                }));
+
+            assert_eq!(config.validate().is_ok(), *ok);
+        }
+
+        for (var, ok) in &[
+            ("GIT_DIR", true),
+            ("KBS2_HOOK", false),
+            ("KBS2_STORE", false),
+        ] {
+            let mut config = dummy_config();
+            config
+                .commands
+                .rm
+                .hook_env
+                .insert(var.to_string(), "value".into());
+
+            assert_eq!(config.validate().is_ok(), *ok);
+        }
+
+        for (class, ok) in &[("digits", true), ("symbols", true), ("vowels", false)] {
+            let mut config = dummy_config();
+            config
+                .generators
+                .push(GeneratorConfig::Internal(GeneratorInternalConfig {
+                    name: "custom".into(),
+                    alphabet: "abc".into(),
+                    length: 16,
+                    weights: vec![(class.to_string(), 2)].into_iter().collect(),
+                }));
+
+            assert_eq!(config.validate().is_ok(), *ok);
+        }
+
+        for (command, ok) in &[
+            ("echo hunter2", true),
+            ("/bin/sh -c 'echo hunter2'", true),
+            ("kbs2-not-a-real-generator 16", false),
+            ("/not/a/real/generator", false),
+        ] {
+            let mut config = dummy_config();
+            config
+                .generators
+                .push(GeneratorConfig::Command(GeneratorCommandConfig {
+                    name: "custom".into(),
+                    command: command.to_string(),
+                    max_output: util::DEFAULT_MAX_OUTPUT,
+                }));
+
+            assert_eq!(config.validate().is_ok(), *ok);
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents
+                .push_str("\n[[generators]]\nname = \"empty\"\nalphabet = \"abc\"\nlength = 0\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let err = load(dir.path(), None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator empty: length must be at least 1"
+            );
+        }
+
+        {
+            let mut config = dummy_config();
+            config.aliases.insert("gh".into(), "github.com".into());
+            config.aliases.insert("gl".into(), "gitlab.com".into());
+            assert!(config.validate().is_ok());
+
+            config.aliases.insert("git".into(), "gh".into());
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "aliases: git refers to another alias (gh), not a label"
+            );
+        }
+
+        {
+            let mut config = dummy_config();
+            config.aliases.insert("gh".into(), "gh".into());
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "aliases: gh is an alias for itself"
+            );
+        }
+
+        {
+            let mut config = dummy_config();
+            config.aliases.insert("gh".into(), "".into());
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "aliases: aliases and their labels can't be empty"
+            );
+        }
+
+        {
+            let dir = tempdir().unwrap();
+            initialize(dir.path(), None, false, None).unwrap();
+
+            let mut contents = fs::read_to_string(dir.path().join(CONFIG_BASENAME)).unwrap();
+            contents.push_str("\n[aliases]\ngh = \"github.com/personal\"\n");
+            fs::write(dir.path().join(CONFIG_BASENAME), contents).unwrap();
+
+            let config = load(dir.path(), None).unwrap();
+            assert_eq!(config.resolve_alias("gh"), "github.com/personal");
+            assert_eq!(config.resolve_alias("github.com"), "github.com");
+        }
+    }
+
+    #[test]
+    fn test_check_keyfile_perms() {
+        let dir = tempdir().unwrap();
+        initialize(dir.path(), None, false, None).unwrap();
+        let mut config = load(dir.path(), None).unwrap();
+        let keyfile = Path::new(&config.keyfile).to_path_buf();
+
+        // A fresh keyfile is only accessible by its owner.
+        assert!(config.keyfile_perms_problem().unwrap().is_none());
+        assert!(config.check_keyfile_perms(false).is_ok());
+
+        fs::set_permissions(&keyfile, fs::Permissions::from_mode(0o644)).unwrap();
+
+        // An unwrapped keyfile with bad permissions is an error, unless explicitly allowed.
+        assert!(config.keyfile_perms_problem().unwrap().is_some());
+        let err = config.check_keyfile_perms(false).unwrap_err();
+        assert!(err.to_string().contains("mode 644"));
+        assert!(config.check_keyfile_perms(true).is_ok());
+
+        // A wrapped keyfile with bad permissions only produces a warning.
+        config.wrapped = true;
+        assert!(config.keyfile_perms_problem().unwrap().is_some());
+        assert!(config.check_keyfile_perms(false).is_ok());
+
+        // A missing keyfile isn't checked.
+        fs::remove_file(&keyfile).unwrap();
+        assert!(config.keyfile_perms_problem().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unwrap_with_retries() {
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("key");
+        let (_, work_factor) =
+            RageLib::create_wrapped_keypair(&keyfile, SecretString::new("correct".into()), None)
+                .unwrap();
+        let wrapped_key = fs::read(&keyfile).unwrap();
+
+        // NOTE: Each mocked prompt hands out the next password, recording how many were asked for.
+        let prompt = |passwords: &[&str], prompts: &mut usize| -> Result<SecretString> {
+            let password = passwords[*prompts];
+            *prompts += 1;
+            Ok(SecretString::new(password.into()))
+        };
+
+        {
+            let mut prompts = 0;
+            let key =
+                unwrap_with_retries(&wrapped_key, work_factor, 3, Duration::from_secs(0), || {
+                    prompt(&["wrong", "correct"], &mut prompts)
+                })
+                .unwrap();
+
+            assert!(key.starts_with("AGE-SECRET-KEY-"));
+            assert_eq!(prompts, 2);
+        }
+
+        {
+            let mut prompts = 0;
+            let err =
+                unwrap_with_retries(&wrapped_key, work_factor, 2, Duration::from_secs(0), || {
+                    prompt(&["wrong", "wrong", "correct"], &mut prompts)
+                })
+                .unwrap_err();
+
+            assert!(err.to_string().starts_with("unable to decrypt"));
+            assert_eq!(prompts, 2);
+        }
+
+        {
+            let mut prompts = 0;
+            let err =
+                unwrap_with_retries(&wrapped_key, work_factor, 3, Duration::from_secs(0), || {
+                    prompts += 1;
+                    Err(anyhow!("prompt cancelled"))
+                })
+                .unwrap_err();
+
+            assert_eq!(err.to_string(), "prompt cancelled");
+            assert_eq!(prompts, 1);
+        }
+
+        // The same retries, prompting through get_password (as unwrap_keyfile does).
+        {
+            let prompt =
+                input::ScriptedPrompt::new(&[("Password", "wrong"), ("Password", "correct")]);
+            let key =
+                unwrap_with_retries(&wrapped_key, work_factor, 3, Duration::from_secs(0), || {
+                    util::get_password(PassphraseEntry::Tty, DEFAULT_PINENTRY, &prompt)
+                })
+                .unwrap();
+
+            assert!(key.starts_with("AGE-SECRET-KEY-"));
+            assert!(prompt.is_exhausted());
+        }
+
+        // The key unwraps with the work factor it was wrapped with, but not a lower one.
+        {
+            assert_eq!(
+                backend::wrapped_work_factor(&wrapped_key).unwrap(),
+                work_factor
+            );
+
+            let mut prompts = 0;
+            let err = unwrap_with_retries(
+                &wrapped_key,
+                work_factor - 1,
+                1,
+                Duration::from_secs(0),
+                || prompt(&["correct"], &mut prompts),
+            )
+            .unwrap_err();
+
+            assert!(err.to_string().starts_with("unable to decrypt"));
+        }
+    }
+
+    #[test]
+    fn test_unwrap_with_retries_malformed() {
+        let dir = tempdir().unwrap();
+
+        let unwrapped_keyfile = dir.path().join("unwrapped");
+        RageLib::create_keypair(&unwrapped_keyfile).unwrap();
+        let unwrapped_key = fs::read(&unwrapped_keyfile).unwrap();
+
+        let wrapped_keyfile = dir.path().join("wrapped");
+        RageLib::create_wrapped_keypair(&wrapped_keyfile, SecretString::new("pass".into()), None)
+            .unwrap();
+        let wrapped_key = fs::read(&wrapped_keyfile).unwrap();
+
+        let recipient_encrypted = {
+            let key = age::SecretKey::generate();
+            let mut encrypted = vec![];
+            let mut writer = age::Encryptor::with_recipients(vec![key.to_public()])
+                .wrap_output(&mut encrypted, age::Format::AsciiArmor)
+                .unwrap();
+            writer.write_all(b"not a key").unwrap();
+            writer.finish().unwrap();
+            encrypted
+        };
+
+        for (input, message) in &[
+            (&b""[..], "the keyfile is empty"),
+            (&unwrapped_key[..], "the keyfile isn't wrapped"),
+            (
+                &recipient_encrypted[..],
+                "the keyfile is encrypted to age recipients",
+            ),
+            (
+                &wrapped_key[..wrapped_key.len() / 4],
+                "the keyfile isn't a valid age file",
+            ),
+            (&b"garbage"[..], "the keyfile isn't a valid age file"),
+        ] {
+            // NOTE: None of these should get as far as prompting for a password.
+            let err = unwrap_with_retries(
+                input,
+                DEFAULT_WRAP_WORK_FACTOR,
+                3,
+                Duration::from_secs(0),
+                || panic!("unexpected password prompt"),
+            )
+            .unwrap_err();
+
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("key unwrap failed; {}", message)),
+                "unexpected error: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_clear_duration() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        {
+            let config = PassConfig::default();
+
+            assert_eq!(config.clear_duration(), Duration::from_secs(10));
+        }
+
+        {
+            let config = PassConfig {
+                clipboard_duration: 10,
+                clipboard_duration_jitter: 3,
+                ..Default::default()
+            };
+
+            let mut rng = StdRng::seed_from_u64(0);
+            let durations = (0..1000)
+                .map(|_| config.clear_duration_with_rng(&mut rng))
+                .collect::<Vec<_>>();
+
+            assert!(durations
+                .iter()
+                .all(|d| *d >= Duration::from_secs(7) && *d <= Duration::from_secs(13)));
+            assert!(durations.iter().any(|d| *d < Duration::from_secs(10)));
+            assert!(durations.iter().any(|d| *d > Duration::from_secs(10)));
+        }
+
+        {
+            let mut config = dummy_config();
+            config.commands.pass.clipboard_duration_jitter = 11;
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "commands.pass.clipboard-duration-jitter can't exceed clipboard-duration"
+            );
+        }
+    }
+
+    #[test]
+    fn test_wait_for_clear() {
+        {
+            // The default timer trigger waits out the full duration.
+            let config = PassConfig {
+                clipboard_duration: 1,
+                ..Default::default()
+            };
+
+            let start = Instant::now();
+            config.wait_for_clear().unwrap();
+            assert!(start.elapsed() >= Duration::from_secs(1));
+        }
+
+        {
+            let config = PassConfig {
+                clipboard_duration: 60,
+                clear_trigger: ClearTrigger::Lock,
+                lock_command: Some("sh -c 'exit 1'".into()),
+                ..Default::default()
+            };
+
+            // A failing lock command still counts as a lock.
+            let start = Instant::now();
+            config.wait_for_clear().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(60));
+        }
+
+        {
+            let config = PassConfig {
+                clipboard_duration: 60,
+                clear_trigger: ClearTrigger::Both,
+                lock_command: Some("true".into()),
+                ..Default::default()
+            };
+
+            // The lock comes before the timer...
+            let start = Instant::now();
+            config.wait_for_clear().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(60));
+        }
 
-impl Default for PassConfig {
-    fn default() -> Self {
-        PassConfig {
-            clipboard_duration: 10,
-            clear_after: true,
-            x11_clipboard: X11Clipboard::Clipboard,
-            pre_hook: None,
-            post_hook: None,
-            clear_hook: None,
+        {
+            let config = PassConfig {
+                clipboard_duration: 1,
+                clear_trigger: ClearTrigger::Both,
+                lock_command: Some("sleep 60".into()),
+                ..Default::default()
+            };
+
+            // ...and the timer before the lock.
+            let start = Instant::now();
+            config.wait_for_clear().unwrap();
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            assert!(start.elapsed() < Duration::from_secs(60));
+        }
+
+        {
+            let config = PassConfig {
+                clear_trigger: ClearTrigger::Lock,
+                lock_command: Some("this-command-does-not-exist".into()),
+                ..Default::default()
+            };
+
+            assert_eq!(
+                config.wait_for_clear().unwrap_err().to_string(),
+                "failed to run lock-command: this-command-does-not-exist"
+            );
         }
     }
-}
 
-/// Configuration settings for `kbs2 edit`.
-#[derive(Debug, Default, Deserialize, Serialize)]
-#[serde(default)]
-pub struct EditConfig {
-    pub editor: Option<String>,
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "post-hook")]
-    pub post_hook: Option<String>,
-}
+    #[test]
+    fn test_new_prompts() {
+        let prompts = |config: &Config, kind| {
+            config
+                .commands
+                .new
+                .prompts_for(kind)
+                .map(|ps| ps.into_iter().map(|p| p.field).collect::<Vec<_>>())
+        };
 
-/// Configuration settings for `kbs2 rm`.
-#[derive(Debug, Default, Deserialize, Serialize)]
-#[serde(default)]
-pub struct RmConfig {
-    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
-    #[serde(rename = "post-hook")]
-    pub post_hook: Option<String>,
-}
+        {
+            let config = dummy_config();
 
-#[doc(hidden)]
-fn deserialize_with_tilde<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
-where
-    D: de::Deserializer<'de>,
-{
-    let unexpanded: &str = Deserialize::deserialize(deserializer)?;
-    Ok(shellexpand::tilde(unexpanded).into_owned())
-}
+            assert_eq!(prompts(&config, "login").unwrap(), ["username", "password"]);
+            assert_eq!(prompts(&config, "unstructured").unwrap(), ["contents"]);
+            assert!(prompts(&config, "nonexistent").is_err());
+        }
 
-#[doc(hidden)]
-fn deserialize_optional_with_tilde<'de, D>(
-    deserializer: D,
-) -> std::result::Result<Option<String>, D::Error>
-where
-    D: de::Deserializer<'de>,
-{
-    let unexpanded: Option<&str> = Deserialize::deserialize(deserializer)?;
+        {
+            let mut config = dummy_config();
+            config.commands.new = toml::from_str(
+                r#"
+                [[prompts.login]]
+                field = "password"
+                prompt = "Passphrase"
 
-    match unexpanded {
-        Some(unexpanded) => Ok(Some(shellexpand::tilde(unexpanded).into_owned())),
-        None => Ok(None),
-    }
-}
+                [[prompts.login]]
+                field = "username"
+                default = "alice@example.com"
+                "#,
+            )
+            .unwrap();
 
-/// Returns a suitable configuration directory path for `kbs2`.
-///
-/// NOTE: This function always chooses `$HOME/.config/kbs2`, across all platforms.
-pub fn find_config_dir() -> Result<PathBuf> {
-    // TODO(ww): This should respect XDG on Linux.
-    let home = util::home_dir()?;
-    Ok(home.join(".config").join(CONFIG_BASEDIR))
-}
+            assert!(config.validate().is_ok());
+            assert_eq!(prompts(&config, "login").unwrap(), ["password", "username"]);
+            assert_eq!(
+                prompts(&config, "environment").unwrap(),
+                ["variable", "value"]
+            );
 
-/// Returns a suitable record store directory path for `kbs2`.
-///
-/// NOTE: This function always chooses `$HOME/.local/share/kbs2`, across all platforms.
-fn store_dir() -> Result<PathBuf> {
-    // TODO(ww): This should respect XDG on Linux.
-    let home = util::home_dir()?;
-    Ok(home.join(".local/share").join(STORE_BASEDIR))
-}
+            let login = config.commands.new.prompts_for("login").unwrap();
+            assert_eq!(login[0].prompt.as_deref(), Some("Passphrase"));
+            assert_eq!(login[1].default.as_deref(), Some("alice@example.com"));
+        }
 
-/// Given a path to a `kbs2` configuration directory, initializes a configuration
-/// file and keypair within it.
-///
-/// # Arguments
-///
-/// * `config_dir` - The configuration directory to initialize within
-/// * `wrapped` - Whether or not to generate a passphrase-wrapped keypair
-pub fn initialize(config_dir: &Path, wrapped: bool) -> Result<()> {
-    let keyfile = config_dir.join(DEFAULT_KEY_BASENAME);
+        for (prompts, message) in &[
+            (
+                r#"[[prompts.login]]
+                field = "username""#,
+                "commands.new.prompts.login: missing a prompt for password",
+            ),
+            (
+                r#"[[prompts.login]]
+                field = "email""#,
+                "commands.new.prompts.login: login records have no email field",
+            ),
+            (
+                r#"[[prompts.unstructured]]
+                field = "contents"
+                [[prompts.unstructured]]
+                field = "contents""#,
+                "commands.new.prompts.unstructured: contents is prompted for more than once",
+            ),
+            (
+                r#"[[prompts.environment]]
+                field = "variable"
+                [[prompts.environment]]
+                field = "value"
+                default = "hunter2""#,
+                "commands.new.prompts.environment: value can't have a default",
+            ),
+            (
+                r#"[[prompts.note]]
+                field = "contents""#,
+                "commands.new.prompts.note: unknown record kind: note",
+            ),
+        ] {
+            let mut config = dummy_config();
+            config.commands.new = toml::from_str(prompts).unwrap();
 
-    let public_key = if wrapped {
-        RageLib::create_wrapped_keypair(&keyfile)?
-    } else {
-        RageLib::create_keypair(&keyfile)?
-    };
+            assert_eq!(config.validate().unwrap_err().to_string(), *message);
+        }
+    }
 
-    log::debug!("public key: {}", public_key);
+    #[test]
+    fn test_get_generator() {
+        let config = dummy_config();
 
-    #[allow(clippy::redundant_field_names)]
-    let serialized = toml::to_string(&Config {
-        // NOTE(ww): Not actually serialized; just here to make the compiler happy.
-        config_dir: config_dir.to_str().unwrap().into(),
-        public_key: public_key,
-        keyfile: keyfile.to_str().unwrap().into(),
-        wrapped: wrapped,
-        store: store_dir()?.to_str().unwrap().into(),
-        pre_hook: None,
-        post_hook: None,
-        reentrant_hooks: false,
-        generators: vec![GeneratorConfig::Internal(Default::default())],
-        commands: Default::default(),
-    })?;
+        assert!(config.get_generator("default").is_some());
+        assert!(config.get_generator("nonexistent-generator").is_none());
+    }
 
-    fs::write(config_dir.join(CONFIG_BASENAME), serialized)?;
+    #[test]
+    fn test_check_secret_entropy() {
+        let mut config = dummy_config();
 
-    Ok(())
-}
+        // Weak secrets are only warned about by default.
+        assert!(config
+            .check_secret_entropy(&record::Record::login("foo", "bar", "password"))
+            .is_ok());
 
-/// Given a path to a `kbs2` configuration directory, loads the configuration
-/// file within and returns the resulting `Config`.
-pub fn load(config_dir: &Path) -> Result<Config> {
-    let config_path = config_dir.join(CONFIG_BASENAME);
-    let contents = fs::read_to_string(config_path)?;
+        config.strict_entropy = true;
 
-    Ok(Config {
-        config_dir: config_dir.to_str().unwrap().into(),
-        ..toml::from_str(&contents).map_err(|e| anyhow!("config loading error: {}", e))?
-    })
-}
+        assert!(config
+            .check_secret_entropy(&record::Record::login("foo", "bar", "xK9#mP2$vL5&nQ8@"))
+            .is_ok());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
+        // Insensitive fields aren't checked.
+        assert!(config
+            .check_secret_entropy(&record::Record::unstructured("foo", "a"))
+            .is_ok());
 
-    fn dummy_config() -> Config {
-        Config {
-            config_dir: "/not/a/real/dir".into(),
-            public_key: "not a real public key".into(),
-            keyfile: "not a real private key file".into(),
-            wrapped: false,
-            store: "/tmp".into(),
-            pre_hook: Some("true".into()),
-            post_hook: Some("false".into()),
-            reentrant_hooks: false,
-            generators: vec![GeneratorConfig::Internal(Default::default())],
-            commands: CommandConfigs {
-                rm: RmConfig {
-                    post_hook: Some("this-command-does-not-exist".into()),
-                },
-                ..Default::default()
-            },
+        for secret in &["password", "123456", ""] {
+            let err = config
+                .check_secret_entropy(&record::Record::login("foo", "bar", secret))
+                .unwrap_err();
+            assert!(err.to_string().starts_with("foo: password has about"));
+        }
+
+        {
+            let err = config
+                .check_secret_entropy(&record::Record::login("foo", "bar", "123456"))
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "foo: password has about 20 bits of estimated entropy (min-entropy is 50)"
+            );
         }
+
+        config.min_entropy = 10.0;
+        assert!(config
+            .check_secret_entropy(&record::Record::login("foo", "bar", "123456"))
+            .is_ok());
     }
 
     #[test]
-    fn test_find_config_dir() {
-        let dir = find_config_dir().unwrap();
-        // NOTE: We can't check whether the main config dir exists since we create it if it
-        // doesn't; instead, we just check that it isn't something weird like a regular file.
-        assert!(!dir.is_file());
+    fn test_resolve_generators() {
+        let resolve = |generators: &str| {
+            let mut value = toml::from_str::<toml::Value>(generators).unwrap();
+            resolve_generators(&mut value).map(|_| value)
+        };
 
-        let parent = dir.parent().unwrap();
+        {
+            let value = resolve(
+                r#"
+                [[generators]]
+                name = "base"
+                alphabet = "abc123"
+                length = 16
+                weights = { digits = 2, lowercase = 3 }
 
-        assert!(parent.exists());
-        assert!(parent.is_dir());
-    }
+                [[generators]]
+                name = "child"
+                extends = "base"
+                length = 32
+                weights = { digits = 5 }
 
-    #[test]
-    fn test_initialize() {
-        // TODO: Figure out how to test initialization with a wrapped key.
-        // The current API requires graphical interaction.
-        // {
-        //     let dir = tempdir().unwrap();
-        //     assert!(initialize(dir.path(), true).is_ok());
-        // }
+                [[generators]]
+                name = "grandchild"
+                extends = "child"
+                alphabet = "xyz789"
+                "#,
+            )
+            .unwrap();
+
+            let generators = value["generators"]
+                .clone()
+                .try_into::<Vec<GeneratorConfig>>()
+                .unwrap();
+            let internal = |index: usize| match &generators[index] {
+                GeneratorConfig::Internal(g) => g,
+                _ => panic!("expected an internal generator"),
+            };
+
+            // The parent is untouched.
+            assert_eq!(internal(0).name, "base");
+            assert_eq!(internal(0).length, 16);
+            assert_eq!(internal(0).weights["digits"], 2);
+
+            // The child's own settings win, and the rest are inherited.
+            assert_eq!(internal(1).name, "child");
+            assert_eq!(internal(1).alphabet, "abc123");
+            assert_eq!(internal(1).length, 32);
+            assert_eq!(internal(1).weights["digits"], 5);
+            assert_eq!(internal(1).weights["lowercase"], 3);
+
+            // Inheritance is transitive, and `extends` doesn't survive resolution.
+            assert_eq!(internal(2).name, "grandchild");
+            assert_eq!(internal(2).alphabet, "xyz789");
+            assert_eq!(internal(2).length, 32);
+            assert_eq!(internal(2).weights["digits"], 5);
+            assert!(value["generators"][2].get("extends").is_none());
+        }
 
         {
-            let dir = tempdir().unwrap();
-            assert!(initialize(dir.path(), false).is_ok());
+            // Without any generators, there's nothing to resolve.
+            assert!(resolve("wrapped = false").is_ok());
+        }
 
-            let path = dir.path();
-            assert!(path.exists());
-            assert!(path.is_dir());
+        {
+            let err = resolve(
+                r#"
+                [[generators]]
+                name = "a"
+                extends = "b"
 
-            assert!(path.join(CONFIG_BASENAME).exists());
-            assert!(path.join(CONFIG_BASENAME).is_file());
+                [[generators]]
+                name = "b"
+                extends = "a"
+                "#,
+            )
+            .unwrap_err();
 
-            assert!(path.join(DEFAULT_KEY_BASENAME).exists());
-            assert!(path.join(DEFAULT_KEY_BASENAME).is_file());
+            assert_eq!(
+                err.to_string(),
+                "config loading error: generator inheritance cycle: a -> b -> a"
+            );
         }
-    }
 
-    #[test]
-    fn test_load() {
         {
-            let dir = tempdir().unwrap();
-            initialize(dir.path(), false).unwrap();
+            let err = resolve(
+                r#"
+                [[generators]]
+                name = "a"
+                extends = "a"
+                "#,
+            )
+            .unwrap_err();
+
+            assert_eq!(
+                err.to_string(),
+                "config loading error: generator inheritance cycle: a -> a"
+            );
+        }
+
+        {
+            let err = resolve(
+                r#"
+                [[generators]]
+                name = "orphan"
+                extends = "nonexistent"
+                "#,
+            )
+            .unwrap_err();
 
-            assert!(load(dir.path()).is_ok());
+            assert_eq!(
+                err.to_string(),
+                "config loading error: generator orphan extends a nonexistent generator: nonexistent"
+            );
         }
 
         {
-            let dir = tempdir().unwrap();
-            initialize(dir.path(), false).unwrap();
+            let err = resolve(
+                r#"
+                [[generators]]
+                name = "numbered"
+                extends = 1
+                "#,
+            )
+            .unwrap_err();
 
-            let config = load(dir.path()).unwrap();
-            assert_eq!(dir.path().to_str().unwrap(), config.config_dir);
+            assert_eq!(
+                err.to_string(),
+                "config loading error: generator numbered must extend a generator by name"
+            );
         }
     }
 
     #[test]
-    fn test_call_hook() {
-        let config = dummy_config();
+    fn test_unwrapped_key() {
+        let shm_name = PathBuf::from(format!("/_kbs2_test_{}", std::process::id()));
+        let create = || {
+            mman::shm_open(
+                &shm_name,
+                OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+                Mode::S_IRUSR | Mode::S_IWUSR,
+            )
+            .map(|fd| unsafe { fs::File::from_raw_fd(fd) })
+        };
 
         {
-            assert!(config
-                .call_hook(config.pre_hook.as_ref().unwrap(), &[])
-                .is_ok());
+            let key = UnwrappedKey {
+                file: create().unwrap(),
+                shm_name: Some(shm_name.clone()),
+            };
+
+            // Still present, so we can't exclusively create it again.
+            assert!(matches!(create(), Err(nix::Error::Sys(Errno::EEXIST))));
+
+            drop(key);
+            let key = UnwrappedKey {
+                file: create().unwrap(),
+                shm_name: Some(shm_name.clone()),
+            };
+            drop(key);
         }
 
         {
-            let err = config
-                .call_hook(config.commands.rm.post_hook.as_ref().unwrap(), &[])
-                .unwrap_err();
+            let mut key = UnwrappedKey {
+                file: create().unwrap(),
+                shm_name: Some(shm_name.clone()),
+            };
+            key.persist();
+            drop(key);
+
+            assert!(matches!(create(), Err(nix::Error::Sys(Errno::EEXIST))));
+            mman::shm_unlink(&shm_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_remove_unwrapped_key() {
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("key");
+        fs::write(&keyfile, "not a real key").unwrap();
+
+        let mut config = dummy_config();
+        config.keyfile = keyfile.to_str().unwrap().into();
+
+        let shm_name = config.unwrapped_key_shm_name().unwrap();
+        let create = || {
+            mman::shm_open(
+                &shm_name,
+                OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+                Mode::S_IRUSR | Mode::S_IWUSR,
+            )
+            .map(|fd| unsafe { fs::File::from_raw_fd(fd) })
+        };
+
+        // Nothing is unwrapped yet, which isn't an error.
+        config.remove_unwrapped_key().unwrap();
+
+        let mut key = UnwrappedKey {
+            file: create().unwrap(),
+            shm_name: Some(shm_name.clone()),
+        };
+        key.persist();
+        drop(key);
+        assert!(matches!(create(), Err(nix::Error::Sys(Errno::EEXIST))));
+
+        // Once removed, the key can be exclusively created (i.e., unwrapped) again.
+        config.remove_unwrapped_key().unwrap();
+        drop(create().unwrap());
+        mman::shm_unlink(&shm_name).unwrap();
+
+        config.remove_unwrapped_key().unwrap();
+    }
+
+    #[test]
+    fn test_open_or_attach_shm() {
+        let shm_name = PathBuf::from(format!("/_kbs2_test_race_{}", std::process::id()));
+
+        for _ in 0..50 {
+            let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let racers = (0..2)
+                .map(|_| {
+                    let barrier = barrier.clone();
+                    let shm_name = shm_name.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        open_or_attach_shm(&shm_name).unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let handles = racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .collect::<Vec<_>>();
 
             assert_eq!(
-                err.to_string(),
-                "failed to run hook: this-command-does-not-exist"
+                handles
+                    .iter()
+                    .filter(|h| matches!(h, ShmHandle::Created(_)))
+                    .count(),
+                1
             );
+            mman::shm_unlink(&shm_name).unwrap();
         }
 
         {
-            let err = config
-                .call_hook(config.post_hook.as_ref().unwrap(), &[])
-                .unwrap_err();
+            let created = open_or_attach_shm(&shm_name).unwrap();
+            let attached = open_or_attach_shm(&shm_name).unwrap();
 
-            assert_eq!(err.to_string(), "hook exited with an error code: false");
+            match (created, attached) {
+                (ShmHandle::Created(mut created), ShmHandle::Attached(mut attached)) => {
+                    created.set_len(3).unwrap();
+                    created.write_all(b"key").unwrap();
+
+                    let mut contents = vec![];
+                    attached.read_to_end(&mut contents).unwrap();
+                    assert_eq!(contents, b"key");
+                }
+                handles => panic!("unexpected handles: {:?}", handles),
+            }
+            mman::shm_unlink(&shm_name).unwrap();
         }
     }
 
     #[test]
-    fn test_get_generator() {
-        let config = dummy_config();
+    fn test_call_unwrap_hook() {
+        let dir = tempdir().unwrap();
+        let hook = dir.path().join("on-unwrap.sh");
+        fs::write(&hook, "#!/bin/sh\necho \"$KBS2_KEY_ID\" >> unwraps\n").unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o700)).unwrap();
 
-        assert!(config.get_generator("default").is_some());
-        assert!(config.get_generator("nonexistent-generator").is_none());
+        let keyfile = dir.path().join("key");
+        fs::write(&keyfile, "not a real wrapped key").unwrap();
+
+        let mut config = dummy_config();
+        config.store = dir.path().to_str().unwrap().into();
+        config.keyfile = keyfile.to_str().unwrap().into();
+        config.on_unwrap = Some(hook.to_str().unwrap().into());
+
+        let unwraps = || fs::read_to_string(dir.path().join("unwraps")).unwrap_or_default();
+        let once = format!("{}\n", config.public_key);
+
+        {
+            config.call_unwrap_hook();
+            assert_eq!(unwraps(), once);
+        }
+
+        // Reusing an already-unwrapped key doesn't fire the hook again.
+        {
+            let shm_name = config.unwrapped_key_shm_name().unwrap();
+            let key = match open_or_attach_shm(&shm_name).unwrap() {
+                ShmHandle::Created(mut file) => {
+                    file.set_len(3).unwrap();
+                    file.write_all(b"key").unwrap();
+                    UnwrappedKey {
+                        file,
+                        shm_name: Some(shm_name),
+                    }
+                }
+                ShmHandle::Attached(_) => panic!("unwrapped key already present"),
+            };
+
+            let reused = config.open_unwrapped_key().unwrap();
+            assert!(config.unwrap_keyfile().is_err());
+            assert_eq!(unwraps(), once);
+
+            drop(reused);
+            drop(key);
+        }
+
+        // A failing hook is only a warning.
+        {
+            config.on_unwrap = Some("false".into());
+            config.call_unwrap_hook();
+            assert_eq!(unwraps(), once);
+        }
     }
 
     // TODO: Test Config::unwrap_keyfile.
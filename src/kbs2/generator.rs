@@ -1,9 +1,56 @@
 use anyhow::{anyhow, Result};
-use rand::Rng;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
 
 use crate::kbs2::config;
 use crate::kbs2::util;
 
+/// The maximum secret length that an internal generator may be configured with.
+pub const MAX_INTERNAL_LENGTH: u32 = 4096;
+
+/// The character classes that an internal generator's `weights` can refer to.
+pub static CHAR_CLASSES: &[&str] = &["lowercase", "uppercase", "digits", "symbols"];
+
+/// The prefix that marks an internal generator's `alphabet` as a reference to one of
+/// `ALPHABET_PRESETS`, e.g. `"preset:hex"`.
+pub static ALPHABET_PRESET_PREFIX: &str = "preset:";
+
+/// The named alphabets that an internal generator's `alphabet` can refer to.
+pub static ALPHABET_PRESETS: &[(&str, &str)] = &[
+    (
+        "alphanumeric",
+        "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+    ),
+    ("lower", "abcdefghijklmnopqrstuvwxyz"),
+    ("hex", "0123456789abcdef"),
+    // NOTE: Bitcoin's alphabet: alphanumerics without the lookalikes 0, O, I, and l.
+    (
+        "base58",
+        "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz",
+    ),
+    // NOTE: Every printable ASCII character except space, which is too easy to lose
+    // at either end of a secret.
+    (
+        "printable-ascii",
+        r##"!"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\]^_`abcdefghijklmnopqrstuvwxyz{|}~"##,
+    ),
+];
+
+/// Returns the character class (one of `CHAR_CLASSES`) that the given ASCII character
+/// belongs to.
+fn char_class(c: u8) -> &'static str {
+    if c.is_ascii_lowercase() {
+        "lowercase"
+    } else if c.is_ascii_uppercase() {
+        "uppercase"
+    } else if c.is_ascii_digit() {
+        "digits"
+    } else {
+        "symbols"
+    }
+}
+
 /// Represents the operations that all generators are capable of.
 pub trait Generator {
     /// Returns the name of the generator, e.g. `"default"`.
@@ -11,6 +58,39 @@ pub trait Generator {
 
     /// Returns a secret produced by the generator.
     fn secret(&self) -> Result<String>;
+
+    /// Returns a secret produced by the generator, using the given source of randomness.
+    ///
+    /// NOTE: The RNG is taken as a trait object rather than a generic parameter, so that
+    /// `Generator` stays object-safe (see `build_generator`). The default implementation
+    /// ignores it and calls `secret`, which suits generators that don't do their own
+    /// sampling (e.g. "command" generators).
+    fn secret_with(&self, _rng: &mut dyn RngCore) -> Result<String> {
+        self.secret()
+    }
+
+    /// Checks that the generator is configured well enough to produce secrets, without
+    /// actually producing one.
+    ///
+    /// NOTE: The default implementation performs no checks.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A specification for a generator: either an entry in `[[generators]]`, or one assembled
+/// ad hoc (e.g. from the flags given to `kbs2 generate`).
+pub type GeneratorSpec = config::GeneratorConfig;
+
+/// Builds an owned generator from the given specification.
+///
+/// Unlike `Config::get_generator`, the returned generator doesn't borrow from the
+/// configuration, so it can be built from a specification that's only needed briefly.
+pub fn build_generator(spec: &GeneratorSpec) -> Box<dyn Generator> {
+    match spec {
+        config::GeneratorConfig::Command(g) => Box::new(g.clone()),
+        config::GeneratorConfig::Internal(g) => Box::new(g.clone()),
+    }
 }
 
 impl Generator for config::GeneratorCommandConfig {
@@ -22,7 +102,22 @@ impl Generator for config::GeneratorCommandConfig {
         let (command, args) = util::parse_and_split_args(&self.command)?;
         let args = args.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
 
-        util::run_with_output(&command, &args)
+        util::run_with_output(&command, &args, self.max_output)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let (command, _) = util::parse_and_split_args(&self.command)
+            .map_err(|e| anyhow!("generator {}: {}", self.name, e))?;
+
+        if util::find_executable(&command).is_none() {
+            return Err(anyhow!(
+                "generator {}: command not found: {}",
+                self.name,
+                command
+            ));
+        }
+
+        Ok(())
     }
 }
 
@@ -32,29 +127,177 @@ impl Generator for config::GeneratorInternalConfig {
     }
 
     fn secret(&self) -> Result<String> {
+        self.secret_with(&mut rand::thread_rng())
+    }
+
+    fn secret_with(&self, rng: &mut dyn RngCore) -> Result<String> {
+        self.secret_with_rng(rng)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let alphabet = self.resolved_alphabet()?;
+
+        if !alphabet.is_ascii() {
+            return Err(anyhow!(
+                "generator {}: alphabet contains non-ascii characters",
+                self.name
+            ));
+        }
+
+        if alphabet.is_empty() {
+            return Err(anyhow!("generator {}: alphabet is empty", self.name));
+        }
+
+        self.check_length()?;
+        self.check_weights()
+    }
+}
+
+impl config::GeneratorInternalConfig {
+    /// Returns the generator's alphabet, expanding it if it names one of the
+    /// `ALPHABET_PRESETS` (e.g. `"preset:hex"`).
+    pub fn resolved_alphabet(&self) -> Result<&str> {
+        let preset = match self.alphabet.strip_prefix(ALPHABET_PRESET_PREFIX) {
+            Some(preset) => preset,
+            None => return Ok(&self.alphabet),
+        };
+
+        ALPHABET_PRESETS
+            .iter()
+            .find(|(name, _)| *name == preset)
+            .map(|(_, alphabet)| *alphabet)
+            .ok_or_else(|| {
+                anyhow!(
+                    "generator {}: unknown alphabet preset: {} (expected one of: {})",
+                    self.name,
+                    preset,
+                    ALPHABET_PRESETS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    /// Checks that the generator's configured length is within a sane range, i.e.
+    /// nonzero and no more than `MAX_INTERNAL_LENGTH`.
+    pub fn check_length(&self) -> Result<()> {
+        if self.length == 0 {
+            Err(anyhow!(
+                "generator {}: length must be at least 1",
+                self.name
+            ))
+        } else if self.length > MAX_INTERNAL_LENGTH {
+            Err(anyhow!(
+                "generator {}: length must be at most {} (got {})",
+                self.name,
+                MAX_INTERNAL_LENGTH,
+                self.length
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the generator's configured weights only refer to known character
+    /// classes (see `CHAR_CLASSES`).
+    pub fn check_weights(&self) -> Result<()> {
+        match self
+            .weights
+            .keys()
+            .find(|class| !CHAR_CLASSES.contains(&class.as_str()))
+        {
+            Some(class) => Err(anyhow!(
+                "generator {}: unknown character class in weights: {}",
+                self.name,
+                class
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the sampling weight of each character in the given (resolved) alphabet.
+    ///
+    /// Each character is weighted by its class's entry in `weights`, or 1 if its class
+    /// isn't listed. Sampling is therefore uniform within each class, and identical
+    /// weights for every class are equivalent to sampling the plain alphabet uniformly.
+    fn char_weights(&self, alphabet: &str) -> Vec<u32> {
+        alphabet
+            .bytes()
+            .map(|c| *self.weights.get(char_class(c)).unwrap_or(&1))
+            .collect()
+    }
+
+    /// Returns a secret produced by the generator, using the given source of randomness.
+    ///
+    /// NOTE: `Generator::secret` uses the thread-local RNG; this is exposed separately
+    /// so that a seeded RNG can be supplied for reproducible output (e.g., in tests).
+    pub fn secret_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<String> {
+        let alphabet = self.resolved_alphabet()?;
+
         // NOTE(ww): Disallow non-ASCII, to prevent gibberish indexing below.
-        if !self.alphabet.is_ascii() {
+        if !alphabet.is_ascii() {
             return Err(anyhow!("generator alphabet contains non-ascii characters"));
         }
 
-        let mut rng = rand::thread_rng();
-        let alphabet = self.alphabet.as_bytes();
-        let secret = (0..self.length)
-            .map(|_| alphabet[rng.gen_range(0, alphabet.len())] as char)
-            .collect::<String>();
+        self.check_length()?;
+        self.check_weights()?;
+
+        let weights = self.char_weights(alphabet);
+        let alphabet = alphabet.as_bytes();
 
-        Ok(secret)
+        if !self.weights.is_empty() {
+            if alphabet.is_empty() {
+                return Err(anyhow!("generator alphabet is empty"));
+            }
+
+            let dist = WeightedIndex::new(weights).map_err(|_| {
+                anyhow!(
+                    "generator {}: weights exclude every character in the alphabet",
+                    self.name
+                )
+            })?;
+
+            return Ok((0..self.length)
+                .map(|_| alphabet[dist.sample(rng)] as char)
+                .collect());
+        }
+
+        // NOTE: SliceRandom::choose samples uniformly (via rejection sampling, not
+        // modulo reduction), and its signature is stable across rand versions.
+        (0..self.length)
+            .map(|_| {
+                alphabet
+                    .choose(rng)
+                    .map(|&c| c as char)
+                    .ok_or_else(|| anyhow!("generator alphabet is empty"))
+            })
+            .collect::<Result<String>>()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::BTreeMap;
 
     fn dummy_command_generator(command: &str) -> Box<dyn Generator> {
         Box::new(config::GeneratorCommandConfig {
             name: "dummy-command".into(),
             command: command.into(),
+            max_output: util::DEFAULT_MAX_OUTPUT,
+        })
+    }
+
+    fn dummy_internal_generator_with_length(alphabet: &str, length: u32) -> Box<dyn Generator> {
+        Box::new(config::GeneratorInternalConfig {
+            name: "dummy-internal".into(),
+            alphabet: alphabet.into(),
+            length,
+            weights: BTreeMap::new(),
         })
     }
 
@@ -63,6 +306,7 @@ mod tests {
             name: "dummy-internal".into(),
             alphabet: alphabet.into(),
             length: 5,
+            weights: BTreeMap::new(),
         })
     }
 
@@ -79,6 +323,320 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate() {
+        {
+            let gen = dummy_command_generator("echo fake-password");
+            assert!(gen.validate().is_ok());
+        }
+
+        {
+            let gen = dummy_command_generator("/bin/sh -c 'echo fake-password'");
+            assert!(gen.validate().is_ok());
+        }
+
+        {
+            let gen = dummy_command_generator("");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-command: missing one or more arguments in command"
+            );
+        }
+
+        {
+            let gen = dummy_command_generator("echo 'unbalanced");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-command: failed to split command-line arguments (missing closing quote): echo 'unbalanced"
+            );
+        }
+
+        {
+            let gen = dummy_command_generator("kbs2-this-command-does-not-exist --length 16");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-command: command not found: kbs2-this-command-does-not-exist"
+            );
+        }
+
+        {
+            let gen = dummy_internal_generator("abc");
+            assert!(gen.validate().is_ok());
+        }
+
+        {
+            let gen = dummy_internal_generator("");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: alphabet is empty"
+            );
+        }
+
+        {
+            let gen = dummy_internal_generator("ⓓⓔⓕⓘⓝⓘⓣⓔⓛⓨ ⓝⓞⓣ ⓐⓢⓒⓘⓘ");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: alphabet contains non-ascii characters"
+            );
+        }
+
+        {
+            let gen = dummy_internal_generator_with_length("abc", 0);
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: length must be at least 1"
+            );
+        }
+    }
+
+    #[test]
+    fn test_alphabet_presets() {
+        let preset = |name: &str| config::GeneratorInternalConfig {
+            name: "dummy-internal".into(),
+            alphabet: format!("preset:{}", name),
+            length: 64,
+            weights: BTreeMap::new(),
+        };
+
+        let expected: &[(&str, &dyn Fn(char) -> bool)] = &[
+            ("alphanumeric", &|c| c.is_ascii_alphanumeric()),
+            ("lower", &|c| c.is_ascii_lowercase()),
+            ("hex", &|c| c.is_ascii_digit() || ('a'..='f').contains(&c)),
+            ("base58", &|c| {
+                c.is_ascii_alphanumeric() && !"0OIl".contains(c)
+            }),
+            ("printable-ascii", &|c| c.is_ascii_graphic()),
+        ];
+        assert_eq!(expected.len(), ALPHABET_PRESETS.len());
+
+        // Each preset holds exactly the characters it should, each exactly once.
+        for (name, in_preset) in expected {
+            let gen = preset(name);
+            let alphabet = gen.resolved_alphabet().unwrap();
+
+            let mut chars = alphabet.chars().collect::<Vec<_>>();
+            chars.sort_unstable();
+            chars.dedup();
+            assert_eq!(chars.len(), alphabet.len(), "{}", name);
+
+            let all = (0..=127u8).map(char::from).filter(|&c| in_preset(c));
+            assert!(all.eq(chars.into_iter()), "{}", name);
+
+            assert!(gen.validate().is_ok());
+            let secret = gen.secret().unwrap();
+            assert!(secret.chars().all(|c| alphabet.contains(c)));
+        }
+
+        // Inline alphabets are untouched, even if they resemble a preset's name.
+        {
+            let gen = dummy_internal_generator("hex");
+            assert!(gen.secret().unwrap().chars().all(|c| "hex".contains(c)));
+        }
+
+        {
+            let gen = preset("nonexistent");
+            let err = gen.validate().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: unknown alphabet preset: nonexistent (expected one of: alphanumeric, lower, hex, base58, printable-ascii)"
+            );
+            assert!(gen.secret().is_err());
+        }
+    }
+
+    #[test]
+    fn test_secret_with_rng() {
+        let gen = config::GeneratorInternalConfig {
+            name: "dummy-internal".into(),
+            alphabet: "abcdefghijklmnopqrstuvwxyz0123456789".into(),
+            length: 32,
+            weights: BTreeMap::new(),
+        };
+
+        {
+            let secret1 = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+            let secret2 = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+
+            assert_eq!(secret1.len(), 32);
+            assert_eq!(secret1, secret2);
+        }
+
+        {
+            let secret1 = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+            let secret2 = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(7331))
+                .unwrap();
+
+            assert_ne!(secret1, secret2);
+        }
+
+        {
+            let mut rng = StdRng::seed_from_u64(1337);
+            let secret1 = gen.secret_with_rng(&mut rng).unwrap();
+            let secret2 = gen.secret_with_rng(&mut rng).unwrap();
+
+            assert_ne!(secret1, secret2);
+        }
+    }
+
+    #[test]
+    fn test_build_generator() {
+        {
+            let spec = GeneratorSpec::Internal(config::GeneratorInternalConfig {
+                name: "ad-hoc".into(),
+                alphabet: "preset:hex".into(),
+                length: 24,
+                weights: BTreeMap::new(),
+            });
+            let gen = build_generator(&spec);
+            drop(spec);
+
+            assert_eq!(gen.name(), "ad-hoc");
+            assert!(gen.validate().is_ok());
+
+            let secret = gen.secret().unwrap();
+            assert_eq!(secret.len(), 24);
+            assert!(secret.chars().all(|c| c.is_ascii_hexdigit()));
+
+            // The trait object honors a supplied RNG, so seeded output is reproducible.
+            let secret1 = gen.secret_with(&mut StdRng::seed_from_u64(1337)).unwrap();
+            let secret2 = gen.secret_with(&mut StdRng::seed_from_u64(1337)).unwrap();
+            assert_eq!(secret1, secret2);
+        }
+
+        {
+            let spec = GeneratorSpec::Command(config::GeneratorCommandConfig {
+                name: "ad-hoc-command".into(),
+                command: "echo fake-password".into(),
+                max_output: util::DEFAULT_MAX_OUTPUT,
+            });
+            let gen = build_generator(&spec);
+
+            assert_eq!(gen.name(), "ad-hoc-command");
+            assert_eq!(
+                gen.secret_with(&mut StdRng::seed_from_u64(1337)).unwrap(),
+                "fake-password"
+            );
+        }
+
+        {
+            // Boxed generators of different kinds can be mixed freely.
+            let gens = vec![
+                build_generator(&GeneratorSpec::Internal(Default::default())),
+                dummy_command_generator("true"),
+            ];
+            assert_eq!(
+                gens.iter().map(|g| g.name()).collect::<Vec<_>>(),
+                vec!["default", "dummy-command"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_secret_uniform() {
+        let gen = config::GeneratorInternalConfig {
+            name: "dummy-internal".into(),
+            alphabet: "abc".into(),
+            length: MAX_INTERNAL_LENGTH,
+            weights: BTreeMap::new(),
+        };
+
+        let secret = gen
+            .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+            .unwrap();
+
+        // Each character should show up roughly a third of the time.
+        for c in "abc".chars() {
+            let count = secret.chars().filter(|&s| s == c).count();
+            assert!((1200..1530).contains(&count));
+        }
+    }
+
+    #[test]
+    fn test_secret_weighted() {
+        let weighted_gen = |weights: &[(&str, u32)]| config::GeneratorInternalConfig {
+            name: "dummy-internal".into(),
+            alphabet: "ab12".into(),
+            length: MAX_INTERNAL_LENGTH,
+            weights: weights.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        };
+
+        let counts = |secret: &str| {
+            "ab12"
+                .chars()
+                .map(|c| secret.chars().filter(|&s| s == c).count())
+                .collect::<Vec<_>>()
+        };
+
+        // Identical weights for every class should be indistinguishable from uniform.
+        {
+            let gen = weighted_gen(&[("lowercase", 5), ("digits", 5)]);
+            let secret = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+
+            for count in counts(&secret) {
+                assert!((900..1150).contains(&count));
+            }
+        }
+
+        // Digits are weighted 3:1, so each digit should show up roughly 3/8 of the time.
+        {
+            let gen = weighted_gen(&[("digits", 3)]);
+            let secret = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+
+            let counts = counts(&secret);
+            for count in &counts[..2] {
+                assert!((430..600).contains(count));
+            }
+            for count in &counts[2..] {
+                assert!((1400..1670).contains(count));
+            }
+        }
+
+        // A weight of zero excludes a class entirely.
+        {
+            let gen = weighted_gen(&[("lowercase", 0)]);
+            let secret = gen
+                .secret_with_rng(&mut StdRng::seed_from_u64(1337))
+                .unwrap();
+
+            assert!(secret.chars().all(|c| c.is_ascii_digit()));
+        }
+
+        {
+            let gen = weighted_gen(&[("lowercase", 0), ("digits", 0)]);
+            let err = gen.secret().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: weights exclude every character in the alphabet"
+            );
+        }
+
+        {
+            let gen = weighted_gen(&[("vowels", 2)]);
+            let err = gen.secret().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: unknown character class in weights: vowels"
+            );
+        }
+    }
+
     #[test]
     fn test_secret() {
         {
@@ -94,12 +652,43 @@ mod tests {
         {
             let gen = dummy_command_generator("false");
             let err = gen.secret().unwrap_err();
+            assert_eq!(err.to_string(), "command failed: false");
+        }
+
+        {
+            let gen = dummy_internal_generator("a");
+            assert_eq!(gen.secret().unwrap(), "aaaaa");
+        }
+
+        {
+            let gen = dummy_internal_generator_with_length("abc", 0);
+            let err = gen.secret().unwrap_err();
             assert_eq!(
                 err.to_string(),
-                "expected output from false, but none given"
+                "generator dummy-internal: length must be at least 1"
             );
         }
 
+        {
+            let gen = dummy_internal_generator_with_length("abc", MAX_INTERNAL_LENGTH);
+            assert_eq!(gen.secret().unwrap().len(), MAX_INTERNAL_LENGTH as usize);
+        }
+
+        {
+            let gen = dummy_internal_generator_with_length("abc", u32::MAX);
+            let err = gen.secret().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "generator dummy-internal: length must be at most 4096 (got 4294967295)"
+            );
+        }
+
+        {
+            let gen = dummy_internal_generator("");
+            let err = gen.secret().unwrap_err();
+            assert_eq!(err.to_string(), "generator alphabet is empty");
+        }
+
         {
             let gen = dummy_internal_generator("ⓓⓔⓕⓘⓝⓘⓣⓔⓛⓨ ⓝⓞⓣ ⓐⓢⓒⓘⓘ");
             let err = gen.secret().unwrap_err();
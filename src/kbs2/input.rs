@@ -1,15 +1,131 @@
 use anyhow::{anyhow, Result};
-use dialoguer::{Input, Password};
+use atty::Stream;
+use dialoguer::{Confirm, Input};
+use secrecy::{ExposeSecret, SecretString};
 
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
 use std::io::{self, Read};
 
 use crate::kbs2::config::Config;
 use crate::kbs2::generator::Generator;
+use crate::kbs2::record;
 use crate::kbs2::record::FieldKind::{self, *};
 
 /// The input separator used when input is gathered in "terse" mode.
 pub static TERSE_IFS: &str = "\x01";
 
+/// A source of interactive answers, e.g. the user's terminal.
+///
+/// NOTE: Anything that asks the user a question does so through this trait rather than the
+/// terminal directly, so that tests can substitute scripted answers (see `ScriptedPrompt`).
+pub trait Prompt {
+    /// Asks for a secret (e.g. a password) with the given prompt, without echoing it.
+    fn password(&self, prompt: &str) -> Result<SecretString>;
+
+    /// Asks for a line of text with the given prompt, offering `default` (if any) as the
+    /// answer for an empty line.
+    fn line(&self, prompt: &str, default: Option<&str>) -> Result<String>;
+
+    /// Asks a yes-or-no question, defaulting to "no".
+    fn confirm(&self, prompt: &str) -> Result<bool>;
+}
+
+/// Prompts the user on their terminal.
+pub struct TerminalPrompt;
+
+impl Prompt for TerminalPrompt {
+    fn password(&self, prompt: &str) -> Result<SecretString> {
+        // NOTE: This reads from the TTY rather than stdin, so that secrets can still be
+        // prompted for while stdin is redirected (e.g. with `kbs2 new --terse`).
+        rpassword::read_password_from_tty(Some(&format!("{}: ", prompt)))
+            .map(SecretString::new)
+            .map_err(|e| anyhow!("password prompt failed: {}", e.to_string()))
+    }
+
+    fn line(&self, prompt: &str, default: Option<&str>) -> Result<String> {
+        let mut input = Input::<String>::new();
+        input.with_prompt(prompt);
+        if let Some(default) = default {
+            input.default(default.into());
+        }
+
+        Ok(input.interact()?)
+    }
+
+    fn confirm(&self, prompt: &str) -> Result<bool> {
+        // NOTE: Without a terminal, we fail rather than guess at an answer.
+        if atty::isnt(Stream::Stdin) {
+            return Err(anyhow!("can't prompt without a terminal: {}", prompt));
+        }
+
+        Ok(Confirm::new()
+            .with_prompt(prompt)
+            .default(false)
+            .interact()?)
+    }
+}
+
+/// Answers prompts from a script, in order, for testing.
+///
+/// `password` and `line` consume an answer each (with `line` falling back on its default for
+/// an empty answer), while `confirm` consumes one and treats `"y"` as yes. Running out of
+/// answers is an error, as is a prompt that doesn't match the next expected one.
+#[cfg(test)]
+pub struct ScriptedPrompt {
+    answers: RefCell<VecDeque<(String, String)>>,
+}
+
+#[cfg(test)]
+impl ScriptedPrompt {
+    /// Creates a prompt that expects the given `(prompt, answer)` pairs, in order.
+    pub fn new(script: &[(&str, &str)]) -> ScriptedPrompt {
+        ScriptedPrompt {
+            answers: RefCell::new(
+                script
+                    .iter()
+                    .map(|(prompt, answer)| (prompt.to_string(), answer.to_string()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns whether every scripted answer has been used.
+    pub fn is_exhausted(&self) -> bool {
+        self.answers.borrow().is_empty()
+    }
+
+    fn answer(&self, prompt: &str) -> Result<String> {
+        match self.answers.borrow_mut().pop_front() {
+            Some((expected, answer)) if expected == prompt => Ok(answer),
+            Some((expected, _)) => Err(anyhow!("expected prompt {:?}, got {:?}", expected, prompt)),
+            None => Err(anyhow!("unexpected prompt: {}", prompt)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Prompt for ScriptedPrompt {
+    fn password(&self, prompt: &str) -> Result<SecretString> {
+        self.answer(prompt).map(SecretString::new)
+    }
+
+    fn line(&self, prompt: &str, default: Option<&str>) -> Result<String> {
+        let answer = self.answer(prompt)?;
+
+        match default {
+            Some(default) if answer.is_empty() => Ok(default.into()),
+            _ => Ok(answer),
+        }
+    }
+
+    fn confirm(&self, prompt: &str) -> Result<bool> {
+        Ok(self.answer(prompt)? == "y")
+    }
+}
+
 /// Given an array of field names and a potential generator, grabs the values for
 /// those fields in a terse manner (each separated by `TERSE_IFS`).
 ///
@@ -53,28 +169,47 @@ fn terse_fields(names: &[FieldKind], generator: Option<&dyn Generator>) -> Resul
     Ok(fields)
 }
 
-/// Given an array of field names and a potential generator, grabs the values for those
-/// fields by prompting the user for each.
+/// Given a record kind and a potential generator, grabs the values for that kind's
+/// fields by prompting the user for each, in the order configured by
+/// `commands.new.prompts` (see `NewConfig::prompts_for`).
 ///
 /// If a field is marked as sensitive **and** a generator is provided, the generator
 /// is used to provide that field and the user is **not** prompted.
+///
+/// The returned values are in the kind's usual field order, regardless of prompt order.
 fn interactive_fields(
-    names: &[FieldKind],
+    kind: &str,
     config: &Config,
     generator: Option<&dyn Generator>,
+    prompter: &dyn Prompt,
 ) -> Result<Vec<String>> {
-    let mut fields = vec![];
+    let names = record::kind_fields(kind)?;
+    let mut fields = vec![String::new(); names.len()];
 
-    for name in names {
-        let field = match name {
-            Sensitive(name) => {
+    for prompt in config.commands.new.prompts_for(kind)? {
+        // NOTE: prompts_for only returns prompts for this kind's fields.
+        let index = names
+            .iter()
+            .position(|name| name.name() == prompt.field)
+            .unwrap();
+        let text = match prompt.prompt {
+            Some(text) => text,
+            None => capitalize(&prompt.field),
+        };
+
+        fields[index] = match names[index] {
+            Sensitive(_) => {
                 if let Some(generator) = generator {
                     generator.secret()?
                 } else {
-                    let field = Password::new()
-                        .with_prompt(*name)
-                        .allow_empty_password(config.commands.new.generate_on_empty)
-                        .interact()?;
+                    // NOTE: An empty secret is only accepted when it's about to be
+                    // generated; otherwise, we ask again.
+                    let field = loop {
+                        let field = prompter.password(&text)?.expose_secret().clone();
+                        if !field.is_empty() || config.commands.new.generate_on_empty {
+                            break field;
+                        }
+                    };
 
                     if field.is_empty() && config.commands.new.generate_on_empty {
                         log::debug!("generate-on-empty with an empty field, generating a secret");
@@ -89,33 +224,139 @@ fn interactive_fields(
                     }
                 }
             }
-            Insensitive(name) => Input::<String>::new().with_prompt(*name).interact()?,
+            Insensitive(_) => prompter.line(&text, prompt.default.as_deref())?,
         };
-
-        fields.push(field);
     }
 
     Ok(fields)
 }
 
-/// Grabs the values for a set of field names from user input.
+/// Capitalizes the first character of the given field name, for use as a prompt.
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Grabs the values for a record kind's fields from user input.
+///
+/// NOTE: Terse input is always in the kind's usual field order, since it's meant for
+/// scripts; only interactive prompts follow `commands.new.prompts`.
 ///
 /// # Arguments
 ///
-/// * `names` - the set of field names to grab
+/// * `kind` - the kind of record to grab fields for, e.g. `login`
 /// * `terse` - whether or not to get fields tersely, i.e. by splitting on
 ///   `TERSE_IFS` instead of prompting for each
 /// * `config` - the active `Config`
 /// * `generator` - the generator, if any, to use for sensitive fields
+/// * `prompt` - the `Prompt` to ask for each field with, when not terse
 pub fn fields(
-    names: &[FieldKind],
+    kind: &str,
     terse: bool,
     config: &Config,
     generator: Option<&dyn Generator>,
+    prompt: &dyn Prompt,
 ) -> Result<Vec<String>> {
     if terse {
-        terse_fields(names, generator)
+        terse_fields(record::kind_fields(kind)?, generator)
     } else {
-        interactive_fields(names, config, generator)
+        interactive_fields(kind, config, generator, prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kbs2::config;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_capitalize() {
+        assert_eq!(capitalize("username"), "Username");
+        assert_eq!(capitalize(""), "");
+    }
+
+    #[test]
+    fn test_scripted_prompt() {
+        let prompt = ScriptedPrompt::new(&[("Name", ""), ("Name", "bob"), ("Sure?", "y")]);
+
+        assert_eq!(prompt.line("Name", Some("alice")).unwrap(), "alice");
+        assert_eq!(prompt.line("Name", Some("alice")).unwrap(), "bob");
+        assert!(prompt.confirm("Sure?").unwrap());
+        assert!(prompt.is_exhausted());
+
+        assert_eq!(
+            prompt.confirm("Really?").unwrap_err().to_string(),
+            "unexpected prompt: Really?"
+        );
+    }
+
+    #[test]
+    fn test_interactive_fields() {
+        let dir = tempdir().unwrap();
+        config::initialize(dir.path(), None, false, None).unwrap();
+        let mut config = config::load(dir.path(), None).unwrap();
+
+        {
+            // Empty secrets are asked for again.
+            let prompt = ScriptedPrompt::new(&[
+                ("Username", "alice"),
+                ("Password", ""),
+                ("Password", "hunter2"),
+            ]);
+            let fields = fields("login", false, &config, None, &prompt).unwrap();
+
+            assert_eq!(fields, vec!["alice", "hunter2"]);
+            assert!(prompt.is_exhausted());
+        }
+
+        {
+            // Sensitive fields aren't prompted for when there's a generator.
+            let prompt = ScriptedPrompt::new(&[("Variable", "API_KEY")]);
+            let generator = config.get_generator("default").unwrap();
+            let fields = fields("environment", false, &config, Some(generator), &prompt).unwrap();
+
+            assert_eq!(fields[0], "API_KEY");
+            assert!(!fields[1].is_empty());
+            assert!(prompt.is_exhausted());
+        }
+
+        {
+            // With generate-on-empty, an empty secret is generated instead.
+            config.commands.new.generate_on_empty = true;
+            let prompt = ScriptedPrompt::new(&[("Username", "bob"), ("Password", "")]);
+            let fields = fields("login", false, &config, None, &prompt).unwrap();
+
+            assert_eq!(fields[0], "bob");
+            assert!(!fields[1].is_empty());
+            assert!(prompt.is_exhausted());
+        }
+
+        {
+            // Prompts follow commands.new.prompts, with defaults for empty answers.
+            config.commands.new.prompts.insert(
+                "login".into(),
+                vec![
+                    config::FieldPrompt {
+                        field: "password".into(),
+                        prompt: Some("Secret".into()),
+                        default: None,
+                    },
+                    config::FieldPrompt {
+                        field: "username".into(),
+                        prompt: None,
+                        default: Some("carol".into()),
+                    },
+                ],
+            );
+            let prompt = ScriptedPrompt::new(&[("Secret", "s3cret"), ("Username", "")]);
+            let fields = fields("login", false, &config, None, &prompt).unwrap();
+
+            assert_eq!(fields, vec!["carol", "s3cret"]);
+            assert!(prompt.is_exhausted());
+        }
     }
 }
@@ -1,6 +1,12 @@
+/// Structures and routines for auditing the health of a store.
+pub mod audit;
+
 /// Structures and routines for interacting with age backends.
 pub mod backend;
 
+/// Structures and routines for serializing records before encryption.
+pub mod codec;
+
 /// Routines for the various `kbs2` subcommands.
 pub mod command;
 
@@ -10,9 +16,15 @@ pub mod config;
 /// Structures and routines for secret generators.
 pub mod generator;
 
+/// Structures and routines for importing records from other password managers.
+pub mod import;
+
 /// Routines for handling user input.
 pub mod input;
 
+/// Routines for formatting machine-readable output.
+pub mod output;
+
 /// Structures and routines for creating and managing individual `kbs2` records.
 pub mod record;
 
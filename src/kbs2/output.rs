@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::kbs2::record::Record;
+
+/// Formats the given value as a single line of JSON.
+///
+/// NOTE: All machine-readable output goes through this function, so that every
+/// command's `--json` output is formatted consistently.
+fn json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Formats the given record labels as a JSON array of strings.
+pub fn labels_json(labels: &[String]) -> Result<String> {
+    json(labels)
+}
+
+/// Formats the given record as a JSON object, including its label, timestamp, kind,
+/// and fields.
+pub fn record_json(record: &Record) -> Result<String> {
+    json(record)
+}
+
+/// Formats the given record's fields as shell commands that export each field as a
+/// `KBS2_<FIELD>` variable, e.g. for `eval "$(kbs2 dump --format env foo)"`.
+///
+/// Values are single-quoted, so the shell never expands or executes anything in them.
+pub fn record_env(record: &Record) -> String {
+    record
+        .body
+        .fields()
+        .iter()
+        .map(|(field, value)| format!("export {}={}\n", env_var(field), shell_quote(value)))
+        .collect()
+}
+
+/// Formats the given record's fields as `KBS2_<FIELD>` assignments in a `.env` file.
+///
+/// Values are double-quoted, with line breaks and anything that a dotenv parser might
+/// expand or unescape (backslashes, quotes, `$`, and backticks) escaped.
+pub fn record_dotenv(record: &Record) -> String {
+    record
+        .body
+        .fields()
+        .iter()
+        .map(|(field, value)| format!("{}={}\n", env_var(field), dotenv_quote(value)))
+        .collect()
+}
+
+#[doc(hidden)]
+fn env_var(field: &str) -> String {
+    format!("KBS2_{}", field.to_ascii_uppercase())
+}
+
+#[doc(hidden)]
+fn shell_quote(value: &str) -> String {
+    // NOTE: Nothing is special within single quotes except the single quote itself,
+    // which has to be closed, escaped, and reopened.
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[doc(hidden)]
+fn dotenv_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' | '`' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Replaces the value of the `secret` parameter in the given key URI (e.g. a TOTP
+/// `otpauth://` URI), leaving the rest of the URI intact.
+pub fn redact_uri_secret(uri: &str) -> String {
+    let (base, query) = match uri.find('?') {
+        Some(index) => (&uri[..index], &uri[index + 1..]),
+        None => return uri.into(),
+    };
+
+    let params = query
+        .split('&')
+        .map(|param| match param.find('=') {
+            Some(index) if param[..index].eq_ignore_ascii_case("secret") => {
+                format!("{}=REDACTED", &param[..index])
+            }
+            _ => param.into(),
+        })
+        .collect::<Vec<_>>();
+
+    format!("{}?{}", base, params.join("&"))
+}
+
+/// Renders the given data as a QR code, using Unicode block characters suitable for
+/// printing on a terminal.
+#[cfg(feature = "qr")]
+pub fn qr(data: &str) -> Result<String> {
+    use qrcode::render::unicode::Dense1x2;
+
+    let code = qrcode::QrCode::new(data.as_bytes())
+        .map_err(|e| anyhow!("unable to render QR code: {}", e))?;
+
+    // NOTE: Colors are inverted here, since most terminals are light-on-dark.
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// Renders the given data as a QR code.
+///
+/// NOTE: This build of `kbs2` doesn't include QR code support, so this always fails.
+/// Build with the `qr` feature to enable it.
+#[cfg(not(feature = "qr"))]
+pub fn qr(_data: &str) -> Result<String> {
+    Err(anyhow!(
+        "QR code support not available; rebuild kbs2 with the \"qr\" feature"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: These compare against golden files, since scripts depend on the exact
+    // shape of this output. Changing a golden file is a breaking change.
+
+    #[test]
+    fn test_labels_json() {
+        assert_eq!(labels_json(&[]).unwrap(), "[]");
+
+        let labels = vec!["foo".into(), "work/github".into(), "ünïcödé".into()];
+        assert_eq!(
+            labels_json(&labels).unwrap(),
+            include_str!("../../testdata/labels.json").trim_end()
+        );
+    }
+
+    #[test]
+    fn test_record_json() {
+        for (record, golden) in [
+            (
+                Record::login("foo", "bar", "baz"),
+                include_str!("../../testdata/login.json"),
+            ),
+            (
+                Record::environment("foo", "BAR", "baz"),
+                include_str!("../../testdata/environment.json"),
+            ),
+            (
+                Record::unstructured("foo", "bar\nbaz"),
+                include_str!("../../testdata/unstructured.json"),
+            ),
+        ] {
+            let record = Record {
+                timestamp: 1600000000,
+                ..record
+            };
+
+            assert_eq!(record_json(&record).unwrap(), golden.trim_end());
+        }
+    }
+
+    #[test]
+    fn test_record_env() {
+        let record = Record::login("foo", "alice smith", r#"it's "$(rm -rf ~)" `id` \n"#);
+
+        assert_eq!(
+            record_env(&record),
+            "export KBS2_USERNAME='alice smith'\n\
+             export KBS2_PASSWORD='it'\\''s \"$(rm -rf ~)\" `id` \\n'\n"
+        );
+        assert_eq!(
+            record_env(&Record::unstructured("foo", "")),
+            "export KBS2_CONTENTS=''\n"
+        );
+
+        // The shell should see exactly the original values.
+        for value in &[
+            "alice smith",
+            r#"it's "$(rm -rf ~)" `id` \n"#,
+            "'''",
+            "multiple\nlines",
+        ] {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(r#"eval "$1"; printf %s "$KBS2_PASSWORD""#)
+                .arg("sh")
+                .arg(record_env(&Record::login("foo", "bar", value)))
+                .output()
+                .unwrap();
+
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn test_record_dotenv() {
+        let record = Record::environment("foo", "API_KEY", "say \"hi\" to $USER\nand \\ `id`");
+
+        assert_eq!(
+            record_dotenv(&record),
+            "KBS2_VARIABLE=\"API_KEY\"\n\
+             KBS2_VALUE=\"say \\\"hi\\\" to \\$USER\\nand \\\\ \\`id\\`\"\n"
+        );
+    }
+
+    #[test]
+    fn test_redact_uri_secret() {
+        assert_eq!(
+            redact_uri_secret(
+                "otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example"
+            ),
+            "otpauth://totp/Example:alice?secret=REDACTED&issuer=Example"
+        );
+        assert_eq!(
+            redact_uri_secret("otpauth://totp/alice?digits=8&SECRET=JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/alice?digits=8&SECRET=REDACTED"
+        );
+        assert_eq!(
+            redact_uri_secret("otpauth://totp/alice"),
+            "otpauth://totp/alice"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "qr")]
+    fn test_qr() {
+        let code = qr("hunter2").unwrap();
+
+        // Every row of a rendered code should be the same (nonzero) width.
+        let widths = code.lines().map(|l| l.chars().count()).collect::<Vec<_>>();
+        assert!(widths[0] > 0);
+        assert!(widths.iter().all(|&w| w == widths[0]));
+        assert_ne!(qr("hunter3").unwrap(), code);
+    }
+
+    #[test]
+    #[cfg(not(feature = "qr"))]
+    fn test_qr() {
+        let err = qr("hunter2").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "QR code support not available; rebuild kbs2 with the \"qr\" feature"
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 use crate::kbs2::util;
 
@@ -6,6 +8,18 @@ use crate::kbs2::util;
 /// The stringified names of record kinds known to `kbs2`.
 pub static RECORD_KINDS: &[&str] = &["login", "environment", "unstructured"];
 
+/// The prefix of a TOTP secret in the key URI format used by authenticator apps.
+pub const TOTP_URI_PREFIX: &str = "otpauth://totp/";
+
+/// The period (in seconds) of a TOTP key URI that doesn't specify one, per RFC 6238.
+pub const TOTP_DEFAULT_PERIOD: u64 = 30;
+
+/// The prefix that names a record's metadata (rather than one of its fields) in `Record::set`.
+pub const EXTRA_PREFIX: &str = "extra.";
+
+/// The metadata key that `Record::append_note` appends to.
+pub const NOTES_KEY: &str = "notes";
+
 /// The kinds of fields known to `kbs2`.
 ///
 /// * "Insensitive" fields are accessed with terminal echo and cannot be generated.
@@ -16,10 +30,65 @@ pub enum FieldKind {
     Sensitive(&'static str),
 }
 
+impl FieldKind {
+    /// Returns the name of the field, e.g. `"username"`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldKind::Insensitive(name) | FieldKind::Sensitive(name) => name,
+        }
+    }
+}
+
+/// Returns the fields of the given record kind, in the same order as `RecordBody::fields`.
+pub fn kind_fields(kind: &str) -> Result<&'static [FieldKind]> {
+    use FieldKind::*;
+
+    match kind {
+        "login" => Ok(&[Insensitive("username"), Sensitive("password")]),
+        "environment" => Ok(&[Insensitive("variable"), Sensitive("value")]),
+        "unstructured" => Ok(&[Insensitive("contents")]),
+        _ => Err(anyhow!("unknown record kind: {}", kind)),
+    }
+}
+
+/// Returns the period (in seconds) of the given TOTP key URI, i.e. how long each of its
+/// codes is valid for.
+pub fn totp_period(uri: &str) -> Result<u64> {
+    let query = match uri.find('?') {
+        Some(index) => &uri[index + 1..],
+        None => return Ok(TOTP_DEFAULT_PERIOD),
+    };
+
+    for param in query.split('&') {
+        match param.find('=') {
+            Some(index) if param[..index].eq_ignore_ascii_case("period") => {
+                let period = &param[index + 1..];
+                return match period.parse::<u64>() {
+                    Ok(period) if period > 0 => Ok(period),
+                    _ => Err(anyhow!("invalid TOTP period: {}", period)),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    Ok(TOTP_DEFAULT_PERIOD)
+}
+
+/// Returns the TOTP time window that the given time (in seconds since the Unix epoch) falls
+/// in for the given period: the window's counter (`T` in RFC 6238), and the number of seconds
+/// until the next window begins.
+pub fn totp_window(time: u64, period: u64) -> (u64, u64) {
+    (time / period, period - time % period)
+}
+
 /// Represents the envelope of a `kbs2` record.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Record {
     /// When the record was created, as seconds since the Unix epoch.
+    ///
+    /// Legacy records written without a timestamp have a timestamp of 0.
+    #[serde(default)]
     pub timestamp: u64,
 
     /// The identifying label of the record.
@@ -27,10 +96,17 @@ pub struct Record {
 
     /// The type contents of the record.
     pub body: RecordBody,
+
+    /// Arbitrary metadata (e.g. `recovery-email`) attached to the record, beyond its fields.
+    ///
+    /// NOTE: This is a `BTreeMap` so that it's always serialized in the same order.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
 }
 
 /// Represents the core contents of a `kbs2` record.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(tag = "kind", content = "fields")]
 pub enum RecordBody {
     Login(LoginFields),
@@ -48,8 +124,32 @@ impl std::fmt::Display for RecordBody {
     }
 }
 
+impl RecordBody {
+    /// Returns the name and value of each of the record's fields, in order.
+    pub fn fields(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            RecordBody::Login(f) => vec![("username", &f.username), ("password", &f.password)],
+            RecordBody::Environment(f) => vec![("variable", &f.variable), ("value", &f.value)],
+            RecordBody::Unstructured(f) => vec![("contents", &f.contents)],
+        }
+    }
+
+    /// Returns a mutable reference to the value of the field with the given name, if the
+    /// record has one.
+    pub fn field_mut(&mut self, name: &str) -> Option<&mut String> {
+        match (self, name) {
+            (RecordBody::Login(f), "username") => Some(&mut f.username),
+            (RecordBody::Login(f), "password") => Some(&mut f.password),
+            (RecordBody::Environment(f), "variable") => Some(&mut f.variable),
+            (RecordBody::Environment(f), "value") => Some(&mut f.value),
+            (RecordBody::Unstructured(f), "contents") => Some(&mut f.contents),
+            _ => None,
+        }
+    }
+}
+
 /// Represents the fields of a login record.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct LoginFields {
     /// The username associated with the login.
     pub username: String,
@@ -59,7 +159,7 @@ pub struct LoginFields {
 }
 
 /// Represents the fields of an environment record.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct EnvironmentFields {
     /// The variable associated with the environment.
     pub variable: String,
@@ -69,7 +169,7 @@ pub struct EnvironmentFields {
 }
 
 /// Represents the fields of an unstructured record.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct UnstructuredFields {
     /// The contents associated with the record.
     pub contents: String,
@@ -85,6 +185,7 @@ impl Record {
                 username: username.to_owned(),
                 password: password.to_owned(),
             }),
+            extra: BTreeMap::new(),
         }
     }
 
@@ -97,6 +198,7 @@ impl Record {
                 variable: variable.to_owned(),
                 value: value.to_owned(),
             }),
+            extra: BTreeMap::new(),
         }
     }
 
@@ -108,7 +210,116 @@ impl Record {
             body: RecordBody::Unstructured(UnstructuredFields {
                 contents: contents.to_owned(),
             }),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the record's TOTP key URI (`otpauth://totp/...`), if it has one.
+    ///
+    /// NOTE: There's no dedicated TOTP record kind: a TOTP secret is stored as an
+    /// unstructured record whose contents are its key URI.
+    pub fn totp_uri(&self) -> Option<&str> {
+        match &self.body {
+            RecordBody::Unstructured(f) if f.contents.trim().starts_with(TOTP_URI_PREFIX) => {
+                Some(f.contents.trim())
+            }
+            _ => None,
+        }
+    }
+
+    /// Creates and returns a new record of the given kind, taking its fields in the
+    /// order given by `kind_fields`.
+    pub fn with_fields(kind: &str, label: &str, fields: &[String]) -> Result<Record> {
+        if fields.len() != kind_fields(kind)?.len() {
+            return Err(anyhow!(
+                "field count mismatch: expected {}, found {}",
+                kind_fields(kind)?.len(),
+                fields.len()
+            ));
         }
+
+        Ok(match kind {
+            "login" => Record::login(label, &fields[0], &fields[1]),
+            "environment" => Record::environment(label, &fields[0], &fields[1]),
+            _ => Record::unstructured(label, &fields[0]),
+        })
+    }
+
+    /// Returns whether the record's timestamp falls within the given (inclusive) bounds,
+    /// or `None` if the record is a legacy record without a timestamp to check.
+    pub fn is_within(&self, since: Option<u64>, until: Option<u64>) -> Option<bool> {
+        if self.timestamp == 0 {
+            return None;
+        }
+
+        Some(
+            !matches!(since, Some(since) if self.timestamp < since)
+                && !matches!(until, Some(until) if self.timestamp > until),
+        )
+    }
+
+    /// Returns the value of the field with the given name, e.g. `"username"` for a login
+    /// record.
+    pub fn field(&self, name: &str) -> Result<&str> {
+        let fields = self.body.fields();
+
+        match fields.iter().find(|(field, _)| *field == name) {
+            Some((_, value)) => Ok(value),
+            None => Err(self.no_such_field(name)),
+        }
+    }
+
+    /// Sets the field with the given name to the given value or, if the name is of the
+    /// form `extra.KEY`, sets the `KEY` metadata instead.
+    ///
+    /// The record's label, timestamp, and kind can't be set.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        if let Some(key) = name.strip_prefix(EXTRA_PREFIX) {
+            if key.is_empty() {
+                return Err(anyhow!("missing a metadata key after {}", EXTRA_PREFIX));
+            }
+
+            self.extra.insert(key.into(), value.into());
+            return Ok(());
+        }
+
+        if ["label", "timestamp", "kind"].contains(&name) {
+            return Err(anyhow!("a record's {} can't be changed", name));
+        }
+
+        match self.body.field_mut(name) {
+            Some(field) => {
+                *field = value.into();
+                Ok(())
+            }
+            None => Err(self.no_such_field(name)),
+        }
+    }
+
+    /// Appends the given note to the record's `notes` metadata, on a line of its own.
+    pub fn append_note(&mut self, note: &str) {
+        let notes = self.extra.entry(NOTES_KEY.into()).or_default();
+
+        if !notes.is_empty() && !notes.ends_with('\n') {
+            notes.push('\n');
+        }
+        notes.push_str(note);
+    }
+
+    #[doc(hidden)]
+    fn no_such_field(&self, name: &str) -> anyhow::Error {
+        anyhow!(
+            "{} record {} has no {} field (expected one of: {})",
+            self.body,
+            self.label,
+            name,
+            self.body
+                .fields()
+                .iter()
+                .map(|(field, _)| *field)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
     }
 }
 
@@ -156,4 +367,223 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_field() {
+        {
+            let record = Record::login("foo", "bar", "baz");
+
+            assert_eq!(record.field("username").unwrap(), "bar");
+            assert_eq!(record.field("password").unwrap(), "baz");
+
+            let err = record.field("value").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "login record foo has no value field (expected one of: username, password)"
+            );
+        }
+
+        {
+            let record = Record::environment("foo", "BAR", "baz");
+
+            assert_eq!(record.field("variable").unwrap(), "BAR");
+            assert_eq!(record.field("value").unwrap(), "baz");
+            assert!(record.field("password").is_err());
+        }
+
+        {
+            let record = Record::unstructured("foo", "bar");
+
+            assert_eq!(record.field("contents").unwrap(), "bar");
+            assert!(record.field("Contents").is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_within() {
+        let mut record = Record::login("foo", "bar", "baz");
+        record.timestamp = 1000;
+
+        assert_eq!(record.is_within(None, None), Some(true));
+        assert_eq!(record.is_within(Some(1000), None), Some(true));
+        assert_eq!(record.is_within(Some(1001), None), Some(false));
+        assert_eq!(record.is_within(None, Some(1000)), Some(true));
+        assert_eq!(record.is_within(None, Some(999)), Some(false));
+        assert_eq!(record.is_within(Some(500), Some(1500)), Some(true));
+        assert_eq!(record.is_within(Some(1500), Some(500)), Some(false));
+
+        record.timestamp = 0;
+        assert_eq!(record.is_within(Some(500), None), None);
+    }
+
+    #[test]
+    fn test_legacy_timestamp() {
+        let record: Record = toml::from_str(
+            r#"
+            label = "foo"
+            body = { kind = "Unstructured", fields = { contents = "bar" } }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(record.timestamp, 0);
+        assert_eq!(record.is_within(None, None), None);
+    }
+
+    #[test]
+    fn test_extra() {
+        let mut record = Record::login("foo", "bar", "baz");
+        record.timestamp = 1600000000;
+
+        // Records without metadata serialize exactly as they did before it existed.
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(!json.contains("extra"));
+        assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+
+        // Metadata is always serialized in the same (sorted) order, regardless of the order
+        // it was added in.
+        record
+            .extra
+            .insert("recovery-email".into(), "alice@example.com".into());
+        record
+            .extra
+            .insert("2fa-backup-codes-location".into(), "the safe".into());
+
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(json.ends_with(
+            r#""extra":{"2fa-backup-codes-location":"the safe","recovery-email":"alice@example.com"}}"#
+        ));
+        assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut record = Record::login("foo", "bar", "baz");
+
+        // Each assignment applies in order, so later ones win.
+        record.set("password", "hunter2").unwrap();
+        record.set("username", "alice").unwrap();
+        record.set("password", "hunter3").unwrap();
+        assert_eq!(record.field("username").unwrap(), "alice");
+        assert_eq!(record.field("password").unwrap(), "hunter3");
+
+        record
+            .set("extra.recovery-email", "alice@example.com")
+            .unwrap();
+        assert_eq!(record.extra["recovery-email"], "alice@example.com");
+
+        for name in &["label", "timestamp", "kind"] {
+            assert_eq!(
+                record.set(name, "x").unwrap_err().to_string(),
+                format!("a record's {} can't be changed", name)
+            );
+        }
+        assert_eq!(
+            record.set("contents", "x").unwrap_err().to_string(),
+            "login record foo has no contents field (expected one of: username, password)"
+        );
+        assert_eq!(
+            record.set("extra.", "x").unwrap_err().to_string(),
+            "missing a metadata key after extra."
+        );
+
+        let mut record = Record::environment("foo", "BAR", "baz");
+        record.set("value", "quux").unwrap();
+        assert_eq!(record.field("value").unwrap(), "quux");
+    }
+
+    #[test]
+    fn test_append_note() {
+        let mut record = Record::unstructured("foo", "bar");
+
+        record.append_note("first");
+        assert_eq!(record.extra[NOTES_KEY], "first");
+
+        record.append_note("second");
+        assert_eq!(record.extra[NOTES_KEY], "first\nsecond");
+
+        // Notes set some other way (e.g. with `kbs2 edit`) are appended to, too.
+        record.set("extra.notes", "edited\n").unwrap();
+        record.append_note("third");
+        assert_eq!(record.extra[NOTES_KEY], "edited\nthird");
+    }
+
+    #[test]
+    fn test_with_fields() {
+        for kind in RECORD_KINDS {
+            let fields = kind_fields(kind)
+                .unwrap()
+                .iter()
+                .map(|f| f.name().to_string())
+                .collect::<Vec<_>>();
+            let record = Record::with_fields(kind, "foo", &fields).unwrap();
+
+            assert_eq!(record.body.to_string(), *kind);
+            for field in &fields {
+                assert_eq!(record.field(field).unwrap(), field);
+            }
+        }
+
+        assert!(Record::with_fields("login", "foo", &["bar".into()]).is_err());
+        assert!(Record::with_fields("nonexistent", "foo", &[]).is_err());
+    }
+
+    #[test]
+    fn test_totp_uri() {
+        let uri = "otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example";
+
+        assert_eq!(Record::unstructured("foo", uri).totp_uri(), Some(uri));
+        assert_eq!(
+            Record::unstructured("foo", &format!("{}\n", uri)).totp_uri(),
+            Some(uri)
+        );
+        assert_eq!(Record::unstructured("foo", "bar").totp_uri(), None);
+        assert_eq!(
+            Record::unstructured("foo", "otpauth://hotp/Example:alice?secret=JBSWY3DP").totp_uri(),
+            None
+        );
+        assert_eq!(Record::login("foo", "bar", uri).totp_uri(), None);
+    }
+
+    #[test]
+    fn test_totp_period() {
+        assert_eq!(
+            totp_period("otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP").unwrap(),
+            TOTP_DEFAULT_PERIOD
+        );
+        assert_eq!(
+            totp_period("otpauth://totp/alice").unwrap(),
+            TOTP_DEFAULT_PERIOD
+        );
+        assert_eq!(
+            totp_period("otpauth://totp/alice?secret=JBSWY3DP&period=60").unwrap(),
+            60
+        );
+        assert_eq!(totp_period("otpauth://totp/alice?PERIOD=15").unwrap(), 15);
+
+        for period in &["0", "-30", "soon", ""] {
+            let err = totp_period(&format!("otpauth://totp/alice?period={}", period)).unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid TOTP period: {}", period));
+        }
+    }
+
+    #[test]
+    fn test_totp_window() {
+        // The counters for the test vectors in RFC 6238, Appendix B.
+        for (time, counter) in &[
+            (59, 0x1),
+            (1_111_111_109, 0x23523EC),
+            (1_111_111_111, 0x23523ED),
+            (1_234_567_890, 0x273EF07),
+            (2_000_000_000, 0x3F940AA),
+            (20_000_000_000, 0x27BC86AA),
+        ] {
+            assert_eq!(totp_window(*time, 30).0, *counter);
+        }
+
+        assert_eq!(totp_window(0, 30), (0, 30));
+        assert_eq!(totp_window(59, 30), (1, 1));
+        assert_eq!(totp_window(60, 30), (2, 30));
+        assert_eq!(totp_window(1_234_567_890, 60), (20_576_131, 30));
+    }
 }
@@ -1,12 +1,60 @@
 use anyhow::{anyhow, Result};
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
 
-use std::fs;
-use std::io;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::kbs2::audit;
 use crate::kbs2::backend;
 use crate::kbs2::config;
+use crate::kbs2::generator::Generator;
+use crate::kbs2::import::{self, CsvColumns, ImportOutcome};
 use crate::kbs2::record;
+use crate::kbs2::util;
+
+/// The directory, relative to the store, in which record attachments are stored.
+///
+/// NOTE: No record can be named after this directory (see `encode_label`), so it can't
+/// collide with a record.
+pub static ATTACHMENTS_DIRNAME: &str = ".attachments";
+
+/// The optional file, relative to the store, that lists gitignore-style globs of files that
+/// aren't records (e.g. sync metadata).
+pub static IGNORE_FILENAME: &str = ".kbs2ignore";
+
+/// The file, relative to the store, that records when each record was last accessed
+/// (see `Session::touch`).
+///
+/// NOTE: Access times are kept in this sidecar index rather than in the records themselves,
+/// so that reading a record never means re-encrypting it.
+pub static ACCESS_INDEX_FILENAME: &str = ".kbs2-access";
+
+/// The manifest at the head of a delta written by `Session::export_since`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeltaManifest {
+    /// The time that the delta covers changes since, in seconds since the Unix epoch.
+    pub since: u64,
+
+    /// The time that the delta was exported, in seconds since the Unix epoch. This is the
+    /// `since` to use for the next delta.
+    pub exported: u64,
+
+    /// The label of every record in the store when the delta was exported, changed or not.
+    /// Records missing from this list were deleted.
+    pub labels: Vec<String>,
+}
+
+#[doc(hidden)]
+#[derive(Deserialize, Serialize)]
+struct DeltaHeader {
+    manifest: DeltaManifest,
+}
 
 /// Encapsulates the context needed by `kbs2` to interact with records.
 pub struct Session {
@@ -17,217 +65,2987 @@ pub struct Session {
     pub config: config::Config,
 }
 
-impl Session {
-    /// Creates a new session, given a `Config`.
-    pub fn new(config: config::Config) -> Result<Session> {
-        fs::create_dir_all(&config.store)?;
+impl Session {
+    /// Creates a new session, given a `Config`.
+    ///
+    /// The session's key is checked against the keyfile's canary, if it has one (see
+    /// `verify_key`), so that a mismatched key is reported up front rather than as
+    /// failures to decrypt individual records.
+    pub fn new(config: config::Config) -> Result<Session> {
+        let backend = backend::RageLib::new(&config)?;
+        let session = Session::with_backend(Box::new(backend), config)?;
+
+        // NOTE: Keyfiles created before canaries existed (or given with --age-identity)
+        // don't have one, so there's nothing to check them against.
+        if session.config.canary_path().is_file() && !session.verify_key()? {
+            return Err(anyhow!(
+                "the loaded key doesn't match this config; if it was unlocked with \
+                 `kbs2 unlock` before the keyfile changed, run `kbs2 lock` and try again"
+            ));
+        }
+
+        // NOTE: This is only a sample, so failing to take it isn't worth reporting.
+        if let Ok(Some(warning)) = session.format_warning() {
+            util::warn(&warning);
+        }
+
+        Ok(session)
+    }
+
+    /// Creates a new session with the given backend, instead of the one described by the
+    /// given `Config`.
+    ///
+    /// The store isn't created if it doesn't exist; see `Config::open_store`. Any of the
+    /// configured `aliases` that would shadow an existing record is an error.
+    pub fn with_backend(
+        backend: Box<dyn backend::Backend>,
+        config: config::Config,
+    ) -> Result<Session> {
+        let session = Session { backend, config };
+
+        if let Some(alias) = session
+            .config
+            .aliases
+            .keys()
+            .find(|alias| session.has_record(alias))
+        {
+            return Err(anyhow!(
+                "aliases: {} is also the label of a record; rename one of them",
+                alias
+            ));
+        }
+
+        Ok(session)
+    }
+
+    /// Returns whether or not the session's key can decrypt the keyfile's canary, i.e.
+    /// whether it's the key that the configuration was initialized with.
+    ///
+    /// This never prompts, and it's cheap: the canary is a few bytes, and no records are
+    /// touched. A canary that can't be decrypted (or decrypts to the wrong plaintext)
+    /// means the wrong key; a missing canary is an error.
+    pub fn verify_key(&self) -> Result<bool> {
+        let canary_path = self.config.canary_path();
+        let canary = fs::read(&canary_path)
+            .map_err(|e| anyhow!("couldn't read {}: {}", canary_path.display(), e))?;
+
+        Ok(matches!(
+            self.backend.decrypt_bytes(&canary),
+            Ok(plaintext) if plaintext == backend::CANARY_PLAINTEXT
+        ))
+    }
+
+    /// Samples a record from the store, returning a warning if it's written in an age
+    /// format that the backend doesn't support (see `backend::format_warning`), e.g. by a
+    /// newer version of `kbs2`.
+    ///
+    /// Only one record is checked, since a store is normally written by a single version of
+    /// `kbs2` at a time. An empty store has nothing to check.
+    pub fn format_warning(&self) -> Result<Option<String>> {
+        let label = match self.record_labels()?.into_iter().next() {
+            Some(label) => label,
+            None => return Ok(None),
+        };
+
+        let record_contents = fs::read(self.record_path(&label)?)?;
+        Ok(
+            backend::format_warning(self.backend.as_ref(), &record_contents)
+                .map(|warning| format!("record {}: {}", label, warning)),
+        )
+    }
+
+    /// Returns the label of every record available in the store.
+    pub fn record_labels(&self) -> Result<Vec<String>> {
+        store_labels(&self.config)
+    }
+
+    /// Returns the number of records in the store, without decrypting any of them.
+    ///
+    /// Like `record_labels`, this skips any files excluded by the store's `.kbs2ignore`.
+    pub fn count(&self) -> Result<usize> {
+        Ok(self.record_labels()?.len())
+    }
+
+    /// Returns the total size, in bytes, of every record file in the store (as counted by
+    /// `count`). Attachments aren't included.
+    pub fn store_size_bytes(&self) -> Result<u64> {
+        self.record_labels()?.iter().try_fold(0, |size, label| {
+            Ok(size + fs::metadata(self.record_path(label)?)?.len())
+        })
+    }
+
+    /// Returns an iterator over every record in the store, decrypting each record only
+    /// when it's reached.
+    ///
+    /// Each item is its own `Result`, so a single unreadable record doesn't end the
+    /// iteration. Records are yielded in the same order as `record_labels`.
+    pub fn records(&self) -> Result<impl Iterator<Item = Result<record::Record>> + '_> {
+        let labels = self.record_labels()?;
+
+        Ok(labels.into_iter().map(move |label| self.get_record(&label)))
+    }
+
+    /// Decrypts every record in the store across multiple threads, applying `f`
+    /// to each record (or the error encountered while retrieving it).
+    ///
+    /// Results are returned in the same order as `record_labels`.
+    ///
+    /// # Arguments
+    ///
+    /// * `jobs` - the maximum number of threads to use, or `None` to use the
+    ///   available parallelism
+    /// * `f` - the function to apply to each retrieved record
+    pub fn par_map_records<T, F>(&self, jobs: Option<usize>, f: F) -> Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(&str, Result<record::Record>) -> T + Sync,
+    {
+        let labels = self.record_labels()?;
+        if labels.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let jobs = jobs
+            .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1)
+            .clamp(1, labels.len());
+        let chunk_size = labels.len().div_ceil(jobs);
+
+        log::debug!("decrypting {} records with {} jobs", labels.len(), jobs);
+
+        let f = &f;
+        thread::scope(|s| {
+            let handles = labels
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|label| f(label, self.get_record(label)))
+                            .collect::<Vec<T>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut results = Vec::with_capacity(labels.len());
+            for handle in handles {
+                results.extend(
+                    handle
+                        .join()
+                        .map_err(|_| anyhow!("record decryption thread panicked"))?,
+                );
+            }
+
+            Ok(results)
+        })
+    }
+
+    /// Returns the label and TOTP key URI of every record that has one (see
+    /// `Record::totp_uri`), in the same order as `record_labels`.
+    ///
+    /// Protected records are skipped with a warning, since their contents can't be read
+    /// without their extra passphrase. Any other unreadable record is an error, rather than
+    /// a silently incomplete export.
+    pub fn totp_uris(&self) -> Result<Vec<(String, String)>> {
+        let records = self.par_map_records(None, |label, record| (label.to_string(), record))?;
+
+        let mut uris = vec![];
+        for (label, record) in records {
+            let record = match record {
+                Ok(record) => record,
+                Err(_) if self.is_protected(&label)? => {
+                    util::warn(&format!("not exporting protected record: {}", label));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if let Some(uri) = record.totp_uri() {
+                uris.push((label, uri.to_string()));
+            }
+        }
+
+        Ok(uris)
+    }
+
+    /// Audits the store, reporting unreadable, weak, reused, and stale records along with
+    /// any store files that are accessible by other users.
+    ///
+    /// Every record is decrypted exactly once (see `par_map_records`). Record ages are
+    /// measured against `now`, in seconds since the Unix epoch.
+    pub fn audit(&self, now: u64) -> Result<audit::AuditReport> {
+        let records = self.par_map_records(None, |label, record| (label.to_string(), record))?;
+        let count = records.len();
+
+        let mut findings = vec![];
+        let mut secrets: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        if let Some(problem) = self.config.keyfile_perms_problem()? {
+            findings.push(audit::Finding {
+                // NOTE: Like check_keyfile_perms, a wrapped keyfile is still protected
+                // by the master password.
+                severity: if self.config.wrapped {
+                    audit::Severity::Warning
+                } else {
+                    audit::Severity::Critical
+                },
+                kind: audit::FindingKind::Permissions,
+                labels: vec![],
+                detail: problem,
+            });
+        }
+
+        if let Some(mode) = util::shared_mode(Path::new(&self.config.store))? {
+            findings.push(audit::Finding {
+                severity: audit::Severity::Warning,
+                kind: audit::FindingKind::Permissions,
+                labels: vec![],
+                detail: format!(
+                    "store {} is accessible by other users (mode {:o})",
+                    self.config.store, mode
+                ),
+            });
+        }
+
+        for (label, record) in records {
+            if let Some(mode) = util::shared_mode(&self.record_path(&label)?)? {
+                findings.push(audit::Finding {
+                    severity: audit::Severity::Warning,
+                    kind: audit::FindingKind::Permissions,
+                    labels: vec![label.clone()],
+                    detail: format!("record is accessible by other users (mode {:o})", mode),
+                });
+            }
+
+            let record = match record {
+                Ok(record) => record,
+                // NOTE: Protected records can't be read without their extra passphrase,
+                // so their contents go unaudited rather than being reported as unreadable.
+                Err(_) if self.is_protected(&label)? => {
+                    log::debug!("not auditing protected record: {}", label);
+                    continue;
+                }
+                Err(e) => {
+                    findings.push(audit::Finding {
+                        severity: audit::Severity::Critical,
+                        kind: audit::FindingKind::Unreadable,
+                        labels: vec![label],
+                        detail: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            let age = now.saturating_sub(record.timestamp);
+            if age >= audit::STALE_AGE_SECS {
+                findings.push(audit::Finding {
+                    severity: audit::Severity::Warning,
+                    kind: audit::FindingKind::Stale,
+                    labels: vec![label.clone()],
+                    detail: format!("unchanged for {} days", audit::days(age)),
+                });
+            }
+
+            let secret = match record.body {
+                record::RecordBody::Login(fields) => fields.password,
+                record::RecordBody::Environment(fields) => fields.value,
+                record::RecordBody::Unstructured(_) => continue,
+            };
+
+            let bits = audit::estimate_entropy(&secret);
+            if bits < self.config.min_entropy {
+                findings.push(audit::Finding {
+                    severity: audit::Severity::Critical,
+                    kind: audit::FindingKind::Weak,
+                    labels: vec![label.clone()],
+                    detail: format!("about {:.0} bits of estimated entropy", bits),
+                });
+            }
+
+            // NOTE: Empty secrets are already weak, and aren't really "shared".
+            if !secret.is_empty() {
+                secrets.entry(secret).or_default().push(label);
+            }
+        }
+
+        for mut labels in secrets.into_values() {
+            if labels.len() < 2 {
+                continue;
+            }
+
+            labels.sort();
+            findings.push(audit::Finding {
+                severity: audit::Severity::Critical,
+                kind: audit::FindingKind::Reused,
+                detail: format!("{} records share a secret", labels.len()),
+                labels,
+            });
+        }
+
+        Ok(audit::AuditReport::new(count, findings))
+    }
+
+    /// Returns the path within the store that the record with the given label
+    /// is (or would be) stored at.
+    ///
+    /// This function refuses to return a path that isn't directly within the store,
+    /// e.g. due to a label like `../../secret` or a record that's been replaced with a
+    /// symlink to a file outside of the store.
+    ///
+    /// With `label-case = "lower"`, a label without a record of its own falls back to its
+    /// lowercased form, so that lookups are case-insensitive.
+    pub fn record_path(&self, label: &str) -> Result<PathBuf> {
+        let record_path = self.exact_record_path(label)?;
+
+        if self.config.label_case == config::LabelCase::Lower && !record_path.exists() {
+            let lowered = label.to_lowercase();
+            if lowered != label {
+                return self.exact_record_path(&lowered);
+            }
+        }
+
+        Ok(record_path)
+    }
+
+    #[doc(hidden)]
+    fn exact_record_path(&self, label: &str) -> Result<PathBuf> {
+        let filename = encode_label(label, self.config.label_encoding)?;
+
+        // NOTE: encode_label should never produce anything other than a single normal
+        // path component, but we check anyways: a mistake here means reading or writing
+        // outside of the store.
+        if !is_single_component(&filename) {
+            return Err(anyhow!("refusing to use unsafe record label: {}", label));
+        }
+
+        let store = fs::canonicalize(&self.config.store)?;
+        let record_path = store.join(&filename);
+
+        if record_path.exists() {
+            let canonicalized = fs::canonicalize(&record_path)?;
+            if canonicalized.parent() != Some(&store) {
+                return Err(anyhow!(
+                    "refusing to use record outside of the store: {}",
+                    label
+                ));
+            }
+        }
+
+        Ok(record_path)
+    }
+
+    /// Returns whether or not the store contains a given record.
+    ///
+    /// This only checks for the record's file (see `record_path`) without decrypting it, so
+    /// it's cheap enough to call before any write. Labels that can't be represented in the
+    /// store are never present.
+    ///
+    /// NOTE: Every existence check should go through this function, rather than checking
+    /// `record_labels`, which skips ignored files (see `IGNORE_FILENAME`).
+    pub fn has_record(&self, label: &str) -> bool {
+        match self.record_path(label) {
+            Ok(record_path) => record_path.is_file(),
+            Err(_) => false,
+        }
+    }
+
+    /// Retrieves a record from the store by its label, or by one of its `aliases`.
+    pub fn get_record(&self, label: &str) -> Result<record::Record> {
+        let label = self.config.resolve_alias(label);
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        let record_path = self.record_path(label)?;
+        let record_contents = fs::read(&record_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
+            _ => e.into(),
+        })?;
+
+        if backend::wrapped_work_factor(&record_contents).is_ok() {
+            return Err(anyhow!(
+                "record {} is protected by an extra passphrase",
+                label
+            ));
+        }
+
+        match self.backend.decrypt(&record_contents) {
+            Ok(record) => Ok(record),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Retrieves the decrypted contents of the store entry with the given label (or alias),
+    /// as-is rather than decoded into a record.
+    ///
+    /// This reads anything that's encrypted to the store's key, including files that were
+    /// encrypted outside of `kbs2` (e.g. with `age -r <public-key>`) and placed in the store.
+    pub fn get_raw_record(&self, label: &str) -> Result<Vec<u8>> {
+        let label = self.config.resolve_alias(label);
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        let record_contents = fs::read(self.record_path(label)?)?;
+        self.backend.decrypt_bytes(&record_contents)
+    }
+
+    /// Records that the record with the given label (or alias) was just accessed, by
+    /// updating its entry in the store's access index (see `ACCESS_INDEX_FILENAME`).
+    ///
+    /// This does nothing on a read-only store, since reading a record shouldn't fail
+    /// just because its access time can't be written.
+    pub fn touch(&self, label: &str) -> Result<()> {
+        if self.config.check_store_writable().is_err() {
+            log::debug!("read-only store, not recording access: {}", label);
+            return Ok(());
+        }
+
+        let label = self.config.resolve_alias(label);
+        let mut index = self.access_index()?;
+        index.insert(label.into(), util::current_timestamp());
+
+        let contents = serde_json::to_vec(&index)?;
+        util::write_file_secure(
+            &Path::new(&self.config.store).join(ACCESS_INDEX_FILENAME),
+            &contents,
+        )
+    }
+
+    /// Returns when the record with the given label (or alias) was last accessed, in
+    /// seconds since the Unix epoch, or `None` if its access was never recorded.
+    pub fn last_accessed(&self, label: &str) -> Result<Option<u64>> {
+        let label = self.config.resolve_alias(label);
+        Ok(self.access_index()?.get(label).copied())
+    }
+
+    /// Returns the contents of the store's access index, which is empty if the store
+    /// doesn't have one yet.
+    #[doc(hidden)]
+    fn access_index(&self) -> Result<BTreeMap<String, u64>> {
+        let path = Path::new(&self.config.store).join(ACCESS_INDEX_FILENAME);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(anyhow!("unable to read {}: {}", ACCESS_INDEX_FILENAME, e)),
+        };
+
+        serde_json::from_slice(&contents)
+            .map_err(|e| anyhow!("malformed {}: {}", ACCESS_INDEX_FILENAME, e))
+    }
+
+    /// Returns whether the record with the given label is protected by an extra passphrase
+    /// (see `add_protected_record`). Like `get_record`, `label` may be an alias.
+    pub fn is_protected(&self, label: &str) -> Result<bool> {
+        let label = self.config.resolve_alias(label);
+        let record_contents =
+            fs::read(self.record_path(label)?).map_err(|_| anyhow!("no such record: {}", label))?;
+
+        // NOTE: A protected record's outermost age header has an scrypt stanza,
+        // which is how we mark it as needing the extra passphrase.
+        Ok(backend::wrapped_work_factor(&record_contents).is_ok())
+    }
+
+    /// Retrieves a record protected by an extra passphrase from the store by its label,
+    /// removing the passphrase before decrypting it as usual. Like `get_record`, `label`
+    /// may be an alias.
+    pub fn get_protected_record(
+        &self,
+        label: &str,
+        passphrase: SecretString,
+    ) -> Result<record::Record> {
+        let label = self.config.resolve_alias(label);
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        let record_contents = fs::read(self.record_path(label)?)?;
+        let unwrapped = backend::unwrap_with_passphrase(&record_contents, passphrase)
+            .map_err(|e| anyhow!("couldn't remove the extra passphrase from {}: {}", label, e))?;
+
+        self.backend.decrypt(&unwrapped)
+    }
+
+    /// Adds the given record to the store like `add_record`, but protected by the given
+    /// extra passphrase: after being encrypted as usual, the record is encrypted again
+    /// with the passphrase, so that reading it requires both the key *and* the passphrase.
+    pub fn add_protected_record(
+        &self,
+        record: &record::Record,
+        passphrase: SecretString,
+    ) -> Result<()> {
+        self.config.check_store_writable()?;
+
+        let record = self.apply_label_case(record)?;
+        let record_path = self.record_path(&record.label)?;
+
+        let record_contents = self.backend.encrypt(&record)?;
+        let record_contents = backend::wrap_with_passphrase(&record_contents, passphrase)?;
+        util::write_file_secure(&record_path, &record_contents)
+    }
+
+    /// Adds the given record to the store, replacing any existing record with the same label.
+    ///
+    /// The record is written atomically, so a failure never loses an existing record.
+    ///
+    /// The record's label is subject to the `label-case` policy: it's lowercased under
+    /// `"lower"`, and rejected under `"reject-collisions"` if it differs only by case from
+    /// an existing record's.
+    pub fn add_record(&self, record: &record::Record) -> anyhow::Result<()> {
+        self.config.check_store_writable()?;
+
+        let record = self.apply_label_case(record)?;
+        let record_path = self.record_path(&record.label)?;
+
+        let record_contents = self.backend.encrypt(&record)?;
+        util::write_file_secure(&record_path, &record_contents)
+    }
+
+    /// Applies the `label-case` policy to a record that's about to be written, returning
+    /// the record to write instead.
+    fn apply_label_case<'a>(&self, record: &'a record::Record) -> Result<Cow<'a, record::Record>> {
+        match self.config.label_case {
+            config::LabelCase::Preserve => {}
+            config::LabelCase::Lower => {
+                let lowered = record.label.to_lowercase();
+                if lowered != record.label {
+                    return Ok(Cow::Owned(record::Record {
+                        label: lowered,
+                        ..record.clone()
+                    }));
+                }
+            }
+            config::LabelCase::RejectCollisions => self.check_label_collision(&record.label)?,
+        }
+
+        Ok(Cow::Borrowed(record))
+    }
+
+    /// Checks that no record in the store has a label that differs from the given one
+    /// only by case.
+    ///
+    /// NOTE: This deliberately doesn't consult the filesystem for the label itself,
+    /// since a case-insensitive filesystem reports `github` as present when only `GitHub` is.
+    fn check_label_collision(&self, label: &str) -> Result<()> {
+        let lowered = label.to_lowercase();
+
+        match self
+            .record_labels()?
+            .into_iter()
+            .find(|existing| existing != label && existing.to_lowercase() == lowered)
+        {
+            Some(existing) => Err(anyhow!(
+                "label-case: {} collides with an existing record that differs only by case: {}",
+                label,
+                existing
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds each record read from the given JSON lines to the store. Each line holds one
+    /// record, in the format emitted by `kbs2 dump --json`; blank lines are skipped.
+    ///
+    /// Lines are handled independently, so an error on one line doesn't prevent the rest
+    /// from being added. The outcome of each line (its record's label, or the error
+    /// encountered) is returned in order, alongside its line number.
+    ///
+    /// Existing records are only replaced when `force` is `true`.
+    pub fn add_records_from_json<R: io::BufRead>(
+        &self,
+        reader: R,
+        force: bool,
+    ) -> Result<Vec<(usize, Result<String>)>> {
+        self.config.check_store_writable()?;
+
+        let mut results = vec![];
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            results.push((index + 1, self.add_record_from_json(&line, force)));
+        }
+
+        Ok(results)
+    }
+
+    #[doc(hidden)]
+    fn add_record_from_json(&self, json: &str, force: bool) -> Result<String> {
+        let record: record::Record =
+            serde_json::from_str(json).map_err(|e| anyhow!("malformed record: {}", e))?;
+
+        if self.has_record(&record.label) && !force {
+            return Err(anyhow!(
+                "refusing to overwrite a record without --force: {}",
+                record.label
+            ));
+        }
+
+        self.add_record(&record)?;
+        Ok(record.label)
+    }
+
+    /// Adds a login record for each row of the given CSV export (see
+    /// `import::logins_from_csv`), returning the outcome of each row in order, alongside
+    /// its line number.
+    ///
+    /// A row whose label is already taken, whether by an existing record or by an earlier
+    /// row, is skipped unless `overwrite` is `true` (in which case the last such row wins).
+    pub fn import_csv(
+        &self,
+        input: &str,
+        columns: &CsvColumns,
+        overwrite: bool,
+    ) -> Result<Vec<(usize, ImportOutcome)>> {
+        self.config.check_store_writable()?;
+
+        let mut outcomes = vec![];
+        for (line, login) in import::logins_from_csv(input, columns)? {
+            let outcome = match login {
+                Ok(record) if self.has_record(&record.label) && !overwrite => {
+                    ImportOutcome::Skipped(record.label)
+                }
+                Ok(record) => match self.add_record(&record) {
+                    Ok(()) => ImportOutcome::Imported(record.label),
+                    Err(e) => ImportOutcome::Failed(e),
+                },
+                Err(e) => ImportOutcome::Failed(e),
+            };
+
+            outcomes.push((line, outcome));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Writes a delta of the store to the given writer, containing only the records
+    /// changed after `when`, and returns the number of records written.
+    ///
+    /// A record counts as changed when its file was modified after `when`, so edits that
+    /// preserve the record's own timestamp are still included. Each record is written as a
+    /// JSON line in the format emitted by `kbs2 dump --json`, after a `DeltaManifest` line
+    /// that lists every current label so that deletions can be applied too (see
+    /// `apply_delta`).
+    ///
+    /// Protected records are skipped with a warning, since they can't be exported
+    /// without their extra passphrase. Attachments aren't included.
+    pub fn export_since<W: Write>(&self, when: SystemTime, mut writer: W) -> Result<usize> {
+        // NOTE: The export time is taken before anything is read, so that a record
+        // changed mid-export is included in the next delta rather than lost.
+        let exported = util::current_timestamp();
+        let labels = self.record_labels()?;
+
+        let mut changed = vec![];
+        for label in &labels {
+            if fs::metadata(self.record_path(label)?)?.modified()? > when {
+                changed.push(label);
+            }
+        }
+
+        let header = DeltaHeader {
+            manifest: DeltaManifest {
+                since: when
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                exported,
+                labels: labels.clone(),
+            },
+        };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+
+        let mut count = 0;
+        for label in changed {
+            let record = match self.get_record(label) {
+                Ok(record) => record,
+                Err(_) if self.is_protected(label)? => {
+                    util::warn(&format!("not exporting protected record: {}", label));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Applies a delta written by `export_since` to the store, returning the labels of the
+    /// records written and deleted (in that order).
+    ///
+    /// Every record in the delta is written, and every record that isn't listed in the
+    /// delta's manifest is deleted. The whole delta is read and checked before anything is
+    /// changed; existing records are only replaced or deleted when `force` is `true`.
+    pub fn apply_delta<R: io::BufRead>(
+        &self,
+        reader: R,
+        force: bool,
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        self.config.check_store_writable()?;
+
+        let mut manifest = None;
+        let mut records = vec![];
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if manifest.is_none() {
+                let header: DeltaHeader = serde_json::from_str(&line)
+                    .map_err(|e| anyhow!("malformed delta manifest: {}", e))?;
+                manifest = Some(header.manifest);
+                continue;
+            }
+
+            let record: record::Record = serde_json::from_str(&line)
+                .map_err(|e| anyhow!("malformed record on line {}: {}", index + 1, e))?;
+            records.push(record);
+        }
+
+        let manifest = manifest.ok_or_else(|| anyhow!("delta has no manifest"))?;
+        let manifest_labels = manifest
+            .labels
+            .iter()
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>();
+
+        let deletions = self
+            .record_labels()?
+            .into_iter()
+            .filter(|label| !manifest_labels.contains(label.as_str()))
+            .collect::<Vec<_>>();
+
+        for record in &records {
+            if !manifest_labels.contains(record.label.as_str()) {
+                return Err(anyhow!(
+                    "malformed delta: record isn't in its manifest: {}",
+                    record.label
+                ));
+            }
+
+            if !force && self.has_record(&record.label) {
+                return Err(anyhow!(
+                    "refusing to overwrite a record without --force: {}",
+                    record.label
+                ));
+            }
+        }
+
+        if !force && !deletions.is_empty() {
+            return Err(anyhow!(
+                "refusing to delete a record without --force: {}",
+                deletions[0]
+            ));
+        }
+
+        let mut written = vec![];
+        for record in records {
+            self.add_record(&record)?;
+            written.push(record.label);
+        }
+
+        for label in &deletions {
+            self.delete_record(label)?;
+        }
+
+        Ok((written, deletions))
+    }
+
+    /// Moves records (and their attachments) from one label to another, returning each
+    /// `(old, new)` pair of labels moved.
+    ///
+    /// A `source` ending in `/` moves every record beneath it, replacing the `source` prefix
+    /// of each label with `destination`. Otherwise, only the record labeled `source` is moved.
+    ///
+    /// Moves that would replace any existing record are rejected before anything is changed.
+    /// If writing a moved record fails, any records already written are removed again, leaving
+    /// the store as it was.
+    pub fn move_records(&self, source: &str, destination: &str) -> Result<Vec<(String, String)>> {
+        self.config.check_store_writable()?;
+
+        let labels = self.record_labels()?;
+
+        let moves = labels
+            .iter()
+            .filter_map(|label| {
+                if source.ends_with('/') {
+                    label
+                        .strip_prefix(source)
+                        .map(|rest| (label.clone(), format!("{}{}", destination, rest)))
+                } else if label == source {
+                    Some((label.clone(), destination.to_owned()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if moves.is_empty() {
+            return Err(anyhow!("no such record(s): {}", source));
+        }
+
+        for (old, new) in &moves {
+            if self.has_record(new) {
+                return Err(anyhow!(
+                    "refusing to move {} onto an existing record: {}",
+                    old,
+                    new
+                ));
+            }
+
+            // NOTE: This also rejects new labels that can't be represented in the store.
+            if self.attachments_dir(new)?.exists() {
+                return Err(anyhow!(
+                    "refusing to move {} onto existing attachments: {}",
+                    old,
+                    new
+                ));
+            }
+        }
+
+        // First, write every record under its new label, undoing everything on failure.
+        for (index, (old, new)) in moves.iter().enumerate() {
+            let result = self.get_record(old).and_then(|record| {
+                self.add_record(&record::Record {
+                    label: new.clone(),
+                    ..record
+                })
+            });
+
+            if let Err(e) = result {
+                for (_, new) in &moves[..index] {
+                    if let Err(e) = fs::remove_file(self.record_path(new)?) {
+                        log::debug!("couldn't roll back moved record {}: {}", new, e);
+                    }
+                }
+
+                return Err(e);
+            }
+        }
+
+        // Then, move any attachments over and remove the old records.
+        for (old, new) in &moves {
+            let old_attachments = self.attachments_dir(old)?;
+            if old_attachments.is_dir() {
+                fs::rename(&old_attachments, self.attachments_dir(new)?)?;
+            }
+
+            fs::remove_file(self.record_path(old)?)?;
+        }
+
+        Ok(moves)
+    }
+
+    /// Copies the record labeled `source` (or one of its `aliases`) to `destination`,
+    /// returning the new record.
+    ///
+    /// The copy is timestamped anew and, if a generator is given, has each of its sensitive
+    /// fields (e.g. a login's password) regenerated. Attachments aren't copied.
+    ///
+    /// An existing record at `destination` is only replaced when `force` is set.
+    pub fn copy_record(
+        &self,
+        source: &str,
+        destination: &str,
+        force: bool,
+        regenerate: Option<&dyn Generator>,
+    ) -> Result<record::Record> {
+        self.config.check_store_writable()?;
+
+        if !force && self.has_record(destination) {
+            return Err(anyhow!(
+                "refusing to overwrite an existing record without --force: {}",
+                destination
+            ));
+        }
+
+        let mut record = record::Record {
+            timestamp: util::current_timestamp(),
+            label: destination.into(),
+            ..self.get_record(source)?
+        };
+
+        if let Some(generator) = regenerate {
+            let sensitive = record::kind_fields(&record.body.to_string())?
+                .iter()
+                .filter(|field| matches!(field, record::FieldKind::Sensitive(_)))
+                .map(|field| field.name())
+                .collect::<Vec<_>>();
+
+            if sensitive.is_empty() {
+                return Err(anyhow!(
+                    "{} record {} has no fields to regenerate",
+                    record.body,
+                    source
+                ));
+            }
+
+            for name in sensitive {
+                record.set(name, &generator.secret()?)?;
+            }
+        }
+
+        self.add_record(&record)?;
+
+        Ok(record)
+    }
+
+    /// Returns the labels of the records matching the given glob (see `util::glob_match`),
+    /// sorted. Globs are matched against decoded labels, regardless of `label-encoding`.
+    ///
+    /// A pattern without any glob characters, or one that's the exact label of a record, is
+    /// returned as-is without checking that it exists. A glob that matches nothing is an error.
+    pub fn matching_labels(&self, pattern: &str) -> Result<Vec<String>> {
+        if !util::is_glob(pattern) || self.has_record(pattern) {
+            return Ok(vec![pattern.into()]);
+        }
+
+        let mut labels = self
+            .record_labels()?
+            .into_iter()
+            .filter(|label| util::glob_match(pattern, label))
+            .collect::<Vec<_>>();
+
+        if labels.is_empty() {
+            return Err(anyhow!("no records match: {}", pattern));
+        }
+
+        labels.sort();
+        Ok(labels)
+    }
+
+    /// Returns the single label matched by the given glob, like `matching_labels`. A glob
+    /// that matches more than one record is an error.
+    pub fn matching_label(&self, pattern: &str) -> Result<String> {
+        let mut labels = self.matching_labels(pattern)?;
+
+        if labels.len() > 1 {
+            return Err(anyhow!(
+                "{} matches {} records ({}); use a more specific label",
+                pattern,
+                labels.len(),
+                labels.join(", ")
+            ));
+        }
+
+        Ok(labels.remove(0))
+    }
+
+    /// Deletes every record matching the given glob (see `matching_labels`), along with
+    /// their attachments, returning their labels.
+    ///
+    /// When more than one record matches, `confirm` is asked whether to delete all of the
+    /// given labels, and declining is an error that leaves every record in place.
+    pub fn delete_matching<F>(&self, pattern: &str, confirm: F) -> Result<Vec<String>>
+    where
+        F: FnOnce(&[String]) -> Result<bool>,
+    {
+        let labels = self.matching_labels(pattern)?;
+
+        if labels.len() > 1 && !confirm(&labels)? {
+            return Err(anyhow!(
+                "not deleting the {} records matching {}",
+                labels.len(),
+                pattern
+            ));
+        }
+
+        for label in &labels {
+            self.delete_record(label)?;
+        }
+
+        Ok(labels)
+    }
+
+    /// Deletes a record from the store by label, along with any of its attachments.
+    pub fn delete_record(&self, label: &str) -> Result<()> {
+        self.config.check_store_writable()?;
+
+        let record_path = self.record_path(label)?;
+        let attachments_dir = self.attachments_dir(label)?;
+
+        std::fs::remove_file(&record_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
+            _ => e.into(),
+        })?;
+
+        if attachments_dir.is_dir() {
+            fs::remove_dir_all(&attachments_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the directory within the store that holds the attachments for the record
+    /// with the given label.
+    fn attachments_dir(&self, label: &str) -> Result<PathBuf> {
+        let record_path = self.record_path(label)?;
+
+        // NOTE: These unwraps are safe, since record_path always returns a path
+        // directly within the store.
+        Ok(record_path
+            .parent()
+            .unwrap()
+            .join(ATTACHMENTS_DIRNAME)
+            .join(record_path.file_name().unwrap()))
+    }
+
+    /// Returns the path within the store that the given record's attachment with the
+    /// given name is (or would be) stored at.
+    pub fn attachment_path(&self, label: &str, name: &str) -> Result<PathBuf> {
+        let filename = encode_label(name, self.config.label_encoding)
+            .map_err(|_| anyhow!("invalid attachment name: {}", name))?;
+
+        if !is_single_component(&filename) {
+            return Err(anyhow!("refusing to use unsafe attachment name: {}", name));
+        }
+
+        Ok(self.attachments_dir(label)?.join(filename))
+    }
+
+    /// Returns the name of every attachment on the record with the given label, sorted.
+    pub fn attachment_names(&self, label: &str) -> Result<Vec<String>> {
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        let attachments_dir = self.attachments_dir(label)?;
+        if !attachments_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![];
+        for entry in fs::read_dir(&attachments_dir)? {
+            let filename = entry?.file_name();
+
+            // NOTE: Like record labels, non-UTF-8 attachment names aren't supported.
+            match decode_label(filename.to_str().unwrap(), self.config.label_encoding) {
+                Ok(name) => names.push(name),
+                Err(e) => log::debug!("skipping undecodable attachment: {}", e),
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// Encrypts and stores the given contents as an attachment with the given name on the
+    /// record with the given label, replacing any existing attachment with that name.
+    ///
+    /// Attachments larger than the configured `max-attachment-size` are rejected.
+    pub fn attach(&self, label: &str, name: &str, contents: &[u8]) -> Result<()> {
+        self.config.check_store_writable()?;
+
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        if contents.len() as u64 > self.config.max_attachment_size {
+            return Err(anyhow!(
+                "attachment {} exceeds the maximum size of {} bytes",
+                name,
+                self.config.max_attachment_size
+            ));
+        }
+
+        let attachment_path = self.attachment_path(label, name)?;
+
+        // NOTE: This unwrap is safe, since attachment_path always returns a path
+        // within the record's attachment directory.
+        util::create_dir_secure(attachment_path.parent().unwrap())?;
+
+        let encrypted = self.backend.encrypt_bytes(contents)?;
+        util::write_file_secure(&attachment_path, &encrypted)
+    }
+
+    /// Retrieves and decrypts the attachment with the given name from the record with
+    /// the given label, streaming it to the given writer and returning its size.
+    ///
+    /// NOTE: The attachment is never held in memory in its entirety, so `writer` may
+    /// have received some plaintext even when this fails (see `Backend::decrypt_stream`).
+    pub fn read_attachment(&self, label: &str, name: &str, writer: &mut dyn Write) -> Result<u64> {
+        if !self.has_record(label) {
+            return Err(anyhow!("no such record: {}", label));
+        }
+
+        let attachment_path = self.attachment_path(label, name)?;
+        let encrypted = File::open(&attachment_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!("no such attachment: {}", name),
+            _ => e.into(),
+        })?;
+
+        self.backend
+            .decrypt_stream(&mut BufReader::new(encrypted), writer)
+    }
+}
+
+/// Returns whether or not the given filename is a single, normal path component
+/// (i.e., not `.`, `..`, or a path containing separators).
+fn is_single_component(filename: &str) -> bool {
+    let mut components = Path::new(filename).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// Returns whether or not the given character needs to be percent-encoded when
+/// it appears in a record label at the given (character) index.
+fn needs_encoding(index: usize, c: char) -> bool {
+    c == '%' || c == '/' || c == '\\' || c.is_control() || (index == 0 && c == '.')
+}
+
+/// Maps a record label onto the filename that it's stored under, according to
+/// the given encoding.
+///
+/// With `LabelEncoding::Percent`, characters that are hostile to filenames (path
+/// separators, control characters, `%` itself, and a leading `.`) are percent-encoded.
+/// Every other character (including non-ASCII) is passed through as-is, so ordinary
+/// labels map onto identical filenames.
+///
+/// With `LabelEncoding::Raw`, labels are used as filenames directly, and labels that
+/// can't be represented that way (e.g. `work/github` or `.hidden`) are rejected.
+pub fn encode_label(label: &str, encoding: config::LabelEncoding) -> Result<String> {
+    if label.is_empty() {
+        return Err(anyhow!("record labels can't be empty"));
+    }
+
+    match encoding {
+        config::LabelEncoding::Percent => {
+            let mut filename = String::with_capacity(label.len());
+            for (i, c) in label.chars().enumerate() {
+                if needs_encoding(i, c) {
+                    let mut buf = [0; 4];
+                    for byte in c.encode_utf8(&mut buf).bytes() {
+                        filename.push_str(&format!("%{:02X}", byte));
+                    }
+                } else {
+                    filename.push(c);
+                }
+            }
+
+            Ok(filename)
+        }
+        config::LabelEncoding::Raw => {
+            // NOTE: Raw labels are the filenames that stores used before percent-encoding
+            // existed, so only labels that can't name a record file directly within the
+            // store are rejected. That includes dotfiles, which are never records (see
+            // `is_ignored`), and therefore the store's own files.
+            if label.contains('/')
+                || label.contains('\0')
+                || label.starts_with('.')
+                || !is_single_component(label)
+            {
+                return Err(anyhow!(
+                    "record label can't be used as a filename without encoding: {}",
+                    label
+                ));
+            }
+
+            Ok(label.into())
+        }
+    }
+}
+
+/// Maps a filename in the store back onto the record label it represents, according
+/// to the given encoding. This is the inverse of `encode_label`.
+pub fn decode_label(filename: &str, encoding: config::LabelEncoding) -> Result<String> {
+    match encoding {
+        config::LabelEncoding::Percent => {
+            let bytes = filename.as_bytes();
+            let mut label = Vec::with_capacity(bytes.len());
+
+            let mut i = 0;
+            while i < bytes.len() {
+                if bytes[i] == b'%' {
+                    let byte = bytes
+                        .get(i + 1..i + 3)
+                        .and_then(|hex| std::str::from_utf8(hex).ok())
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| anyhow!("malformed record filename: {}", filename))?;
+
+                    label.push(byte);
+                    i += 3;
+                } else {
+                    label.push(bytes[i]);
+                    i += 1;
+                }
+            }
+
+            let label = String::from_utf8(label)
+                .map_err(|_| anyhow!("malformed record filename: {}", filename))?;
+
+            // NOTE: Every label has exactly one encoding, so a filename that decodes but
+            // doesn't re-encode to itself (e.g. `foo%41` or `.foo`) would be listed under a
+            // label that can't be used to open it.
+            if encode_label(&label, encoding)? != filename {
+                return Err(anyhow!("non-canonical record filename: {}", filename));
+            }
+
+            Ok(label)
+        }
+        config::LabelEncoding::Raw => Ok(filename.into()),
+    }
+}
+
+/// Returns the label of every record available in the store specified by the given
+/// `Config`.
+///
+/// NOTE: Unlike most store operations, this doesn't require a `Session` (and therefore
+/// doesn't require the private key), since no records are decrypted.
+pub fn store_labels(config: &config::Config) -> Result<Vec<String>> {
+    let store = Path::new(&config.store);
+
+    // NOTE: A missing store is only ever opened when `on-missing-store = "create"`
+    // lets a command that doesn't change it through, in which case it has no records.
+    if !store.exists() {
+        return Ok(vec![]);
+    }
+
+    if !store.is_dir() {
+        return Err(anyhow!("secret store is not a directory"));
+    }
+
+    let ignore_patterns = read_ignore_patterns(store)?;
+
+    let mut labels = vec![];
+    for entry in fs::read_dir(store)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            log::debug!("skipping non-file in store: {:?}", path);
+            continue;
+        }
+
+        // NOTE(ww): This unwrap is safe, since file_name always returns Some
+        // for non-directories.
+        // NOTE(ww): This one isn't safe, but we don't care. Non-UTF-8 labels aren't supported.
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        if is_ignored(filename, &ignore_patterns) {
+            log::debug!("skipping ignored file in store: {:?}", path);
+            continue;
+        }
+
+        match decode_label(filename, config.label_encoding) {
+            Ok(label) => labels.push(label),
+            Err(e) => util::warn(&format!("skipping file in store: {}", e)),
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Returns the patterns in the given store's `.kbs2ignore`, if it has one.
+#[doc(hidden)]
+fn read_ignore_patterns(store: &Path) -> Result<Vec<String>> {
+    let contents = match fs::read_to_string(store.join(IGNORE_FILENAME)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(anyhow!("unable to read {}: {}", IGNORE_FILENAME, e)),
+    };
+
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Into::into)
+        .collect())
+}
+
+/// Returns whether the file with the given name in the store's root is excluded by the
+/// given `.kbs2ignore` patterns.
+///
+/// Like gitignore, a leading `!` re-includes files excluded by an earlier pattern, a
+/// leading `/` is allowed (the store is flat, so every pattern is anchored anyways),
+/// and a trailing `/` only matches directories (which are never records).
+#[doc(hidden)]
+fn is_ignored(filename: &str, patterns: &[String]) -> bool {
+    // NOTE: Dotfiles (`.git`, `.kbs2ignore` itself, etc.) are always ignored. Record
+    // filenames never begin with `.`, under either label encoding (see `encode_label`).
+    if filename.starts_with('.') {
+        return true;
+    }
+
+    let mut ignored = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+
+        if pattern.ends_with('/') {
+            continue;
+        }
+
+        if util::glob_match(pattern.trim_start_matches('/'), filename) {
+            ignored = !negated;
+        }
+    }
+
+    ignored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::{tempdir, TempDir};
+
+    // NOTE: We pass store in here instead of creating it for lifetime reasons:
+    // the temp dir is destroyed when its TempDir object is destructed, so we need
+    // to keep it alive long enough for each unit test.
+    fn dummy_session(store: &TempDir) -> Session {
+        let backend = {
+            let key = age::SecretKey::generate();
+
+            Box::new(backend::RageLib {
+                pubkey: key.to_public(),
+                identities: vec![key.into()],
+                armor: true,
+                record_format: Default::default(),
+                recipients_file: None,
+                recipient_policy: vec![],
+            })
+        };
+
+        Session {
+            backend,
+            config: dummy_config(store),
+        }
+    }
+
+    fn dummy_config(store: &TempDir) -> config::Config {
+        config::Config {
+            config_dir: "/not/a/real/dir".into(),
+            profile: None,
+            config_file: None,
+            unwrapped_key: None,
+            // NOTE: Test sessions create their backends manually, so the public_key and
+            // keyfile here are dummy values that shouldn't need to be interacted with.
+            public_key: "not a real public key".into(),
+            recipients_file: None,
+            recipient_policy: vec![],
+            ssh_identity: None,
+            keyfile: "not a real private key file".into(),
+            wrapped: false,
+            read_only: false,
+            on_missing_store: Default::default(),
+            label_encoding: config::LabelEncoding::Percent,
+            label_case: Default::default(),
+            store_armor: true,
+            record_format: Default::default(),
+            max_attachment_size: config::DEFAULT_MAX_ATTACHMENT_SIZE,
+            min_entropy: audit::WEAK_ENTROPY_BITS,
+            strict_entropy: false,
+            log_level: Default::default(),
+            clock_check: None,
+            passphrase_entry: config::PassphraseEntry::Tty,
+            pinentry: config::DEFAULT_PINENTRY.into(),
+            passphrase_attempts: config::DEFAULT_PASSPHRASE_ATTEMPTS,
+            wrap_work_factor: config::DEFAULT_WRAP_WORK_FACTOR,
+            store: store.path().to_str().unwrap().into(),
+            unresolved_store: store.path().to_str().unwrap().into(),
+            pre_hook: None,
+            post_hook: None,
+            finally_hook: None,
+            on_unwrap: None,
+            reentrant_hooks: false,
+            background_hooks: false,
+            background_hook_log: false,
+            generators: vec![config::GeneratorConfig::Internal(Default::default())],
+            aliases: Default::default(),
+            commands: Default::default(),
+        }
+    }
+
+    /// A trivial (and utterly insecure) backend, for checking that sessions only
+    /// depend on the `Backend` trait.
+    struct FakeBackend;
+
+    impl FakeBackend {
+        const MAGIC: &'static [u8] = b"fake-encrypted:";
+    }
+
+    impl backend::Backend for FakeBackend {
+        fn create_keypair(_path: &Path) -> Result<String> {
+            Err(anyhow!("FakeBackend doesn't manage keyfiles"))
+        }
+
+        fn create_wrapped_keypair(
+            _path: &Path,
+            _password: SecretString,
+            _max_work_factor: Option<u8>,
+        ) -> Result<(String, u8)> {
+            Err(anyhow!("FakeBackend doesn't manage keyfiles"))
+        }
+
+        fn wrap_keyfile(_keyfile: &Path, _password: SecretString) -> Result<u8> {
+            Err(anyhow!("FakeBackend doesn't manage keyfiles"))
+        }
+
+        fn unwrap_to_disk(_keyfile: &Path, _password: SecretString) -> Result<()> {
+            Err(anyhow!("FakeBackend doesn't manage keyfiles"))
+        }
+
+        fn rewrap_keyfile(
+            _keyfile: &Path,
+            _old_password: SecretString,
+            _new_password: SecretString,
+        ) -> Result<u8> {
+            Err(anyhow!("FakeBackend doesn't manage keyfiles"))
+        }
+
+        fn encrypt_bytes(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+            let mut encrypted = FakeBackend::MAGIC.to_vec();
+            encrypted.extend(plaintext.iter().rev().map(|b| b ^ 0xff));
+            Ok(encrypted)
+        }
+
+        fn decrypt_bytes(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
+            match encrypted.strip_prefix(FakeBackend::MAGIC) {
+                Some(encrypted) => Ok(encrypted.iter().rev().map(|b| b ^ 0xff).collect()),
+                None => Err(anyhow!("not fake-encrypted")),
+            }
+        }
+    }
+
+    fn read_attachment(session: &Session, label: &str, name: &str) -> Result<Vec<u8>> {
+        let mut contents = vec![];
+        session.read_attachment(label, name, &mut contents)?;
+        Ok(contents)
+    }
+
+    // TODO: Figure out how to test Session::new. Doing so will require an interface for
+    // creating + initializing a config that doesn't unconditionally put the store directory
+    // within the user's data directory.
+
+    #[test]
+    fn test_backends() {
+        let store = tempdir().unwrap();
+        let sessions = vec![
+            dummy_session(&store),
+            Session::with_backend(Box::new(FakeBackend), dummy_config(&store)).unwrap(),
+        ];
+
+        for (i, session) in sessions.iter().enumerate() {
+            let record = record::Record::login("foo", "bar", "baz");
+            session.add_record(&record).unwrap();
+            assert_eq!(session.get_record("foo").unwrap(), record);
+
+            session.attach("foo", "codes", b"\x00\x01codes").unwrap();
+            assert_eq!(
+                read_attachment(&session, "foo", "codes").unwrap(),
+                b"\x00\x01codes"
+            );
+
+            // Each backend can only read its own records.
+            let encrypted = fs::read(session.record_path("foo").unwrap()).unwrap();
+            assert_eq!(
+                encrypted.starts_with(FakeBackend::MAGIC),
+                i == 1,
+                "session {}",
+                i
+            );
+            assert_eq!(
+                backend::Backend::decrypt(&FakeBackend, &encrypted).is_ok(),
+                i == 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_protected_records() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+        let passphrase = || SecretString::new("extra".into());
+
+        let record = record::Record::login("foo", "bar", "baz");
+        session.add_protected_record(&record, passphrase()).unwrap();
+        session
+            .add_record(&record::Record::login("plain", "bar", "baz"))
+            .unwrap();
+
+        assert!(session.is_protected("foo").unwrap());
+        assert!(!session.is_protected("plain").unwrap());
+        assert!(session.is_protected("nonexistent").is_err());
+
+        assert_eq!(
+            session.get_protected_record("foo", passphrase()).unwrap(),
+            record
+        );
+        assert!(session
+            .get_protected_record("foo", SecretString::new("wrong".into()))
+            .is_err());
+
+        // Protected records can't be read without the extra passphrase, including in bulk.
+        let err = session.get_record("foo").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record foo is protected by an extra passphrase"
+        );
+        assert_eq!(session.records().unwrap().filter(Result::is_err).count(), 1);
+    }
+
+    #[test]
+    fn test_verify_key() {
+        let store = tempdir().unwrap();
+        let session_with = |key: age::SecretKey| {
+            let backend = Box::new(backend::RageLib {
+                pubkey: key.to_public(),
+                identities: vec![key.into()],
+                armor: true,
+                record_format: Default::default(),
+                recipients_file: None,
+                recipient_policy: vec![],
+            });
+
+            let mut config = dummy_config(&store);
+            config.keyfile = store.path().join("key").to_str().unwrap().into();
+            Session::with_backend(backend, config).unwrap()
+        };
+
+        let key = age::SecretKey::generate();
+        let pubkey = key.to_public();
+        let session = session_with(key);
+
+        // There's nothing to verify against until the canary exists.
+        assert!(session.verify_key().is_err());
+
+        backend::write_canary(&session.config.canary_path(), vec![pubkey]).unwrap();
+        assert!(session.verify_key().unwrap());
+        assert!(!session_with(age::SecretKey::generate())
+            .verify_key()
+            .unwrap());
+
+        // A canary with the wrong plaintext doesn't count, even with the right key.
+        fs::write(
+            session.config.canary_path(),
+            session.backend.encrypt_bytes(b"not the canary").unwrap(),
+        )
+        .unwrap();
+        assert!(!session.verify_key().unwrap());
+    }
+
+    #[test]
+    fn test_record_labels() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let record = record::Record::login("foo", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+        }
+
+        // A missing store (as opened with `on-missing-store = "create"`) has no records.
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session.config.store = store.path().join("missing").to_str().unwrap().into();
+
+            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+            assert!(!session.has_record("foo"));
+        }
+    }
+
+    #[test]
+    fn test_record_labels_ignored() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login(".hidden", "bar", "baz"))
+                .unwrap();
+
+            // Dotfiles are ignored by default, even without a .kbs2ignore.
+            fs::create_dir(store.path().join(".git")).unwrap();
+            fs::write(store.path().join(".git/config"), "[core]").unwrap();
+            fs::write(store.path().join(".DS_Store"), "junk").unwrap();
+
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            assert_eq!(labels, vec![".hidden", "foo"]);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            for label in &["foo", "bar", "foo.sync", "keep.sync", "notes"] {
+                session
+                    .add_record(&record::Record::login(label, "bar", "baz"))
+                    .unwrap();
+            }
+
+            fs::write(
+                store.path().join(IGNORE_FILENAME),
+                "# sync metadata\n*.sync\n!keep.sync\n\n/notes\nfoo/\n",
+            )
+            .unwrap();
+
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            assert_eq!(labels, vec!["bar", "foo", "keep.sync"]);
+
+            // Bulk operations only see the remaining records.
+            assert_eq!(session.records().unwrap().count(), 3);
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            assert_eq!(session.count().unwrap(), 0);
+            assert_eq!(session.store_size_bytes().unwrap(), 0);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            for label in &["foo", "bar", "foo.sync"] {
+                session
+                    .add_record(&record::Record::login(label, "bar", "baz"))
+                    .unwrap();
+            }
+
+            // Neither ignored files nor dotfiles are counted, or included in the size.
+            fs::write(store.path().join(IGNORE_FILENAME), "*.sync\n").unwrap();
+            fs::write(store.path().join(".DS_Store"), "junk").unwrap();
+            fs::create_dir(store.path().join("subdir")).unwrap();
+
+            let size = ["foo", "bar"]
+                .iter()
+                .map(|label| fs::metadata(store.path().join(label)).unwrap().len())
+                .sum::<u64>();
+
+            assert_eq!(session.count().unwrap(), 2);
+            assert_eq!(session.store_size_bytes().unwrap(), size);
+            assert!(size > 0);
+
+            session.delete_record("bar").unwrap();
+            assert_eq!(session.count().unwrap(), 1);
+            assert!(session.store_size_bytes().unwrap() < size);
+        }
+    }
+
+    #[test]
+    fn test_records() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            assert_eq!(session.records().unwrap().count(), 0);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("a", "b", "c"))
+                .unwrap();
+
+            let mut records = session.records().unwrap();
+            let first = records.next().unwrap().unwrap();
+
+            // Records are only decrypted once they're reached, so removing the other record
+            // now surfaces as an error from the next item, rather than the first.
+            let other = if first.label == "foo" { "a" } else { "foo" };
+            session.delete_record(other).unwrap();
+
+            let err = records.next().unwrap().unwrap_err();
+            assert_eq!(err.to_string(), format!("no such record: {}", other));
+            assert!(records.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_par_map_records() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let results = session.par_map_records(None, |_, r| r.is_ok()).unwrap();
+            assert!(results.is_empty());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            for i in 0..32 {
+                let record = record::Record::login(&format!("record{}", i), "bar", "baz");
+                session.add_record(&record).unwrap();
+            }
+
+            let serial = session
+                .record_labels()
+                .unwrap()
+                .iter()
+                .map(|label| session.get_record(label).unwrap())
+                .collect::<Vec<_>>();
+
+            for jobs in &[None, Some(1), Some(3), Some(64)] {
+                let parallel = session.par_map_records(*jobs, |_, r| r.unwrap()).unwrap();
+
+                assert_eq!(serial, parallel);
+            }
+        }
+    }
+
+    #[test]
+    fn test_audit() {
+        const NOW: u64 = 1_600_000_000;
+        const DAY: u64 = 24 * 60 * 60;
+
+        {
+            let store = tempdir().unwrap();
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o700)).unwrap();
+            let session = dummy_session(&store);
+
+            let report = session.audit(NOW).unwrap();
+            assert_eq!(report.records, 0);
+            assert!(report.findings.is_empty());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o700)).unwrap();
+            let session = dummy_session(&store);
+
+            for (mut record, age) in vec![
+                (record::Record::login("bank", "foo", "hunter2"), 10 * DAY),
+                (record::Record::login("email", "foo", "hunter2"), 20 * DAY),
+                (
+                    record::Record::environment("old", "API_KEY", "Zq7!rT4^wY1*uI6%"),
+                    400 * DAY,
+                ),
+                (
+                    record::Record::login("strong", "foo", "xK9#mP2$vL5&nQ8@"),
+                    DAY,
+                ),
+                (record::Record::unstructured("notes", "abc"), 0),
+            ] {
+                record.timestamp = NOW - age;
+                session.add_record(&record).unwrap();
+            }
+
+            let report = session.audit(NOW).unwrap();
+            assert_eq!(report.to_string(), include_str!("../../testdata/audit.txt"));
+        }
+
+        {
+            let store = tempdir().unwrap();
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o700)).unwrap();
+            let session = dummy_session(&store);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "xK9#mP2$vL5&nQ8@"))
+                .unwrap();
+            util::write_file_secure(&session.record_path("broken").unwrap(), b"not a record")
+                .unwrap();
+            fs::set_permissions(
+                session.record_path("foo").unwrap(),
+                fs::Permissions::from_mode(0o644),
+            )
+            .unwrap();
+
+            let report = session.audit(util::current_timestamp()).unwrap();
+            assert_eq!(report.records, 2);
+            assert_eq!(report.critical(), 1);
+
+            assert_eq!(report.findings[0].kind, audit::FindingKind::Unreadable);
+            assert_eq!(report.findings[0].labels, vec!["broken"]);
+
+            assert_eq!(report.findings[1].kind, audit::FindingKind::Permissions);
+            assert_eq!(report.findings[1].labels, vec!["foo"]);
+            assert_eq!(
+                report.findings[1].detail,
+                "record is accessible by other users (mode 644)"
+            );
+        }
+    }
+
+    #[test]
+    fn test_totp_uris() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            assert!(session.totp_uris().unwrap().is_empty());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let uri = "otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example";
+
+            for record in &[
+                record::Record::login("login", "alice", uri),
+                record::Record::environment("env", "TOTP", uri),
+                record::Record::unstructured("notes", "not a totp secret"),
+                record::Record::unstructured("totp", &format!("{}\n", uri)),
+            ] {
+                session.add_record(record).unwrap();
+            }
+            session
+                .add_protected_record(
+                    &record::Record::unstructured("protected", uri),
+                    SecretString::new("extra".into()),
+                )
+                .unwrap();
+
+            assert_eq!(
+                session.totp_uris().unwrap(),
+                vec![("totp".to_string(), uri.to_string())]
+            );
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            fs::write(store.path().join("garbage"), "not a record").unwrap();
+            assert!(session.totp_uris().is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode_label() {
+        use config::LabelEncoding::*;
+
+        for (label, filename) in &[
+            ("foo", "foo"),
+            ("pets.com", "pets.com"),
+            ("with spaces", "with spaces"),
+            ("work/github", "work%2Fgithub"),
+            ("../../etc/passwd", "%2E.%2F..%2Fetc%2Fpasswd"),
+            (".", "%2E"),
+            ("..", "%2E."),
+            ("100%", "100%25"),
+            ("back\\slash", "back%5Cslash"),
+            ("new\nline", "new%0Aline"),
+            ("ünïcödé/🔑", "ünïcödé%2F🔑"),
+        ] {
+            assert_eq!(encode_label(label, Percent).unwrap(), *filename);
+            assert_eq!(decode_label(filename, Percent).unwrap(), *label);
+        }
+
+        assert_eq!(encode_label("foo", Raw).unwrap(), "foo");
+        assert_eq!(decode_label("foo%2F", Raw).unwrap(), "foo%2F");
+
+        assert!(encode_label("", Percent).is_err());
+        assert!(encode_label("", Raw).is_err());
+        assert!(encode_label("work/github", Raw).is_err());
+        assert!(encode_label("..", Raw).is_err());
+        assert!(encode_label(".kbs2ignore", Raw).is_err());
+        assert!(encode_label(".hidden", Raw).is_err());
+
+        // Stores from before percent-encoding can have records with any of these labels.
+        for label in &["100%", "back\\slash", "foo%41"] {
+            assert_eq!(encode_label(label, Raw).unwrap(), *label);
+        }
+
+        assert!(decode_label("foo%", Percent).is_err());
+        assert!(decode_label("foo%2", Percent).is_err());
+        assert!(decode_label("foo%ZZ", Percent).is_err());
+        assert!(decode_label("foo%FF", Percent).is_err());
+
+        for filename in &["foo%41", "foo%2f", ".hidden", "100%25%", "%2e."] {
+            assert!(decode_label(filename, Percent).is_err());
+        }
+        assert_eq!(
+            decode_label("foo%41", Percent).unwrap_err().to_string(),
+            "non-canonical record filename: foo%41"
+        );
+        assert_eq!(decode_label(".hidden", Raw).unwrap(), ".hidden");
+    }
+
+    #[test]
+    fn test_tricky_labels() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+
+        let labels = ["work/github", "../../escape", "ünïcödé", "100%", ".hidden"];
+        for label in &labels {
+            let record = record::Record::login(label, "bar", "baz");
+            session.add_record(&record).unwrap();
+
+            assert!(session.has_record(label));
+            assert_eq!(session.get_record(label).unwrap(), record);
+        }
+
+        // Every record should have landed directly within the store.
+        assert_eq!(fs::read_dir(store.path()).unwrap().count(), labels.len());
+
+        let mut listed = session.record_labels().unwrap();
+        listed.sort();
+        let mut expected = labels.to_vec();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        for label in &labels {
+            session.delete_record(label).unwrap();
+            assert!(!session.has_record(label));
+        }
+    }
+
+    #[test]
+    fn test_record_path() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let store_path = fs::canonicalize(store.path()).unwrap();
+
+            for label in &[
+                "foo",
+                "../foo",
+                "../../etc/passwd",
+                "..",
+                ".",
+                "/etc/passwd",
+            ] {
+                let path = session.record_path(label).unwrap();
+                assert_eq!(path.parent().unwrap(), store_path);
+            }
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session.config.label_encoding = config::LabelEncoding::Raw;
+
+            for label in &[
+                "../foo",
+                "../../etc/passwd",
+                "..",
+                ".",
+                "/etc/passwd",
+                "a/b",
+            ] {
+                assert!(session.record_path(label).is_err());
+                assert!(!session.has_record(label));
+                assert!(session.get_record(label).is_err());
+                assert!(session
+                    .add_record(&record::Record::login(label, "bar", "baz"))
+                    .is_err());
+                assert!(session.delete_record(label).is_err());
+            }
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let outside = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let outside_record = outside.path().join("foo");
+            fs::write(&outside_record, "not a record").unwrap();
+            std::os::unix::fs::symlink(&outside_record, store.path().join("foo")).unwrap();
+
+            let err = session.record_path("foo").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to use record outside of the store: foo"
+            );
+            assert!(!session.has_record("foo"));
+        }
+    }
+
+    #[test]
+    fn test_attachments() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+
+            assert_eq!(
+                session.attachment_names("foo").unwrap(),
+                Vec::<String>::new()
+            );
+
+            let payload = b"%PDF-1.4\x00\x01\x02\xff\xfe recovery codes";
+            session.attach("foo", "codes.pdf", payload).unwrap();
+            session.attach("foo", "keys/id_ed25519", b"key").unwrap();
+
+            assert_eq!(
+                read_attachment(&session, "foo", "codes.pdf").unwrap(),
+                payload
+            );
+            assert_eq!(
+                read_attachment(&session, "foo", "keys/id_ed25519").unwrap(),
+                b"key"
+            );
+            assert_eq!(
+                session.attachment_names("foo").unwrap(),
+                vec!["codes.pdf", "keys/id_ed25519"]
+            );
+
+            // Attachments are encrypted, and aren't records themselves.
+            let path = session.attachment_path("foo", "codes.pdf").unwrap();
+            assert_ne!(fs::read(&path).unwrap(), payload);
+            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+
+            // Replacing an attachment overwrites it.
+            session.attach("foo", "codes.pdf", b"new codes").unwrap();
+            assert_eq!(
+                read_attachment(&session, "foo", "codes.pdf").unwrap(),
+                b"new codes"
+            );
+
+            let err = read_attachment(&session, "foo", "nonexistent").unwrap_err();
+            assert_eq!(err.to_string(), "no such attachment: nonexistent");
+
+            // Deleting a record deletes its attachments.
+            session.delete_record("foo").unwrap();
+            assert!(!path.exists());
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            assert_eq!(
+                session.attachment_names("foo").unwrap(),
+                Vec::<String>::new()
+            );
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let err = session.attach("foo", "codes.pdf", b"codes").unwrap_err();
+            assert_eq!(err.to_string(), "no such record: foo");
+            assert!(read_attachment(&session, "foo", "codes.pdf").is_err());
+            assert!(session.attachment_names("foo").is_err());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session.config.max_attachment_size = 4;
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+
+            session.attach("foo", "small", b"1234").unwrap();
+
+            let err = session.attach("foo", "big", b"12345").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "attachment big exceeds the maximum size of 4 bytes"
+            );
+            assert_eq!(session.attachment_names("foo").unwrap(), vec!["small"]);
+        }
+    }
+
+    #[test]
+    fn test_has_record() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let record = record::Record::login("foo", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+            assert!(session.has_record("foo"));
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            assert!(!session.has_record("does-not-exist"));
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let labels = ["work/github", "with space", "ünïcödé", "100%", ".hidden"];
+
+            for label in &labels {
+                assert!(!session.has_record(label));
+                session
+                    .add_record(&record::Record::login(label, "bar", "baz"))
+                    .unwrap();
+                assert!(session.has_record(label));
+            }
+
+            // Only the exact (encoded) label is present.
+            assert!(!session.has_record("work"));
+            assert!(!session.has_record("work%2Fgithub"));
+            assert!(!session.has_record("100"));
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session.config.label_encoding = config::LabelEncoding::Raw;
+
+            // Labels that can't be represented in the store are never present.
+            assert!(!session.has_record("work/github"));
+            assert!(!session.has_record(".."));
+        }
+    }
+
+    #[test]
+    fn test_get_record() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            let record = record::Record::login("foo", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+
+            let retrieved_record = session.get_record("foo").unwrap();
+
+            assert_eq!(record, retrieved_record);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let err = session.get_record("foo").unwrap_err();
+            assert_eq!(err.to_string(), "no such record: foo");
+        }
+    }
+
+    #[test]
+    fn test_get_raw_record() {
+        let store = tempdir().unwrap();
+        let key = age::SecretKey::generate();
+        let pubkey = key.to_public();
+        let session = Session::with_backend(
+            Box::new(backend::RageLib {
+                pubkey: key.to_public(),
+                identities: vec![key.into()],
+                armor: true,
+                record_format: Default::default(),
+                recipients_file: None,
+                recipient_policy: vec![],
+            }),
+            dummy_config(&store),
+        )
+        .unwrap();
+
+        // The equivalent of `age -a -r <public-key> -o notes.txt`: arbitrary plaintext,
+        // encrypted without any of kbs2's record structure.
+        let plaintext = b"just some notes\n\x00\xffnot even UTF-8";
+        let mut encrypted = vec![];
+        {
+            let mut writer = age::Encryptor::with_recipients(vec![pubkey])
+                .wrap_output(&mut encrypted, age::Format::AsciiArmor)
+                .unwrap();
+            writer.write_all(plaintext).unwrap();
+            writer.finish().unwrap();
+        }
+        fs::write(session.record_path("notes.txt").unwrap(), &encrypted).unwrap();
+
+        assert_eq!(session.get_raw_record("notes.txt").unwrap(), plaintext);
+        assert!(session.get_record("notes.txt").is_err());
+
+        // A kbs2 record's raw contents are its serialized form.
+        let record = record::Record::login("foo", "bar", "baz");
+        session.add_record(&record).unwrap();
+        assert_eq!(
+            session.get_raw_record("foo").unwrap(),
+            serde_json::to_vec(&record).unwrap()
+        );
+
+        assert_eq!(
+            session
+                .get_raw_record("nonexistent")
+                .unwrap_err()
+                .to_string(),
+            "no such record: nonexistent"
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            let record = record::Record::login("github.com/personal", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+            session
+                .config
+                .aliases
+                .insert("gh".into(), "github.com/personal".into());
+            session
+                .config
+                .aliases
+                .insert("nope".into(), "missing".into());
+
+            assert_eq!(session.get_record("gh").unwrap(), record);
+            assert!(!session.is_protected("gh").unwrap());
+            assert_eq!(
+                session.get_record("nope").unwrap_err().to_string(),
+                "no such record: missing"
+            );
+
+            // Aliases are only resolved for lookups; they aren't records themselves.
+            assert!(!session.has_record("gh"));
+            assert_eq!(
+                session.record_labels().unwrap(),
+                vec!["github.com/personal"]
+            );
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("gh", "bar", "baz"))
+                .unwrap();
+
+            let mut config = dummy_config(&store);
+            config.aliases.insert("gh".into(), "github.com".into());
+
+            let err = Session::with_backend(session.backend, config)
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.to_string(),
+                "aliases: gh is also the label of a record; rename one of them"
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_record() {
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let record1 = record::Record::login("foo", "bar", "baz");
+            session.add_record(&record1).unwrap();
+
+            let record2 = record::Record::login("a", "b", "c");
+            session.add_record(&record2).unwrap();
+
+            // NOTE: record_labels() returns labels in a platform dependent order,
+            // which is why we don't compared against a fixed-order vec here or below.
+            assert_eq!(session.record_labels().unwrap().len(), 2);
+            assert!(session.record_labels().unwrap().contains(&"foo".into()));
+            assert!(session.record_labels().unwrap().contains(&"a".into()));
+
+            // Overwrite foo; still only two records.
+            let record3 = record::Record::login("foo", "quux", "zap");
+            session.add_record(&record3).unwrap();
 
-        let backend: Box<dyn backend::Backend> = Box::new(backend::RageLib::new(&config)?);
+            assert_eq!(session.record_labels().unwrap().len(), 2);
+            assert!(session.record_labels().unwrap().contains(&"foo".into()));
+            assert!(session.record_labels().unwrap().contains(&"a".into()));
+            assert_eq!(session.get_record("foo").unwrap(), record3);
 
-        Ok(Session { backend, config })
+            // No temporary files are left behind by the overwrite.
+            assert_eq!(fs::read_dir(store.path()).unwrap().count(), 2);
+        }
     }
 
-    /// Returns the label of every record available in the store.
-    pub fn record_labels(&self) -> Result<Vec<String>> {
-        let store = Path::new(&self.config.store);
+    #[test]
+    fn test_label_case() {
+        fn sorted_labels(session: &Session) -> Vec<String> {
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            labels
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session.config.label_case = config::LabelCase::Lower;
 
-        if !store.is_dir() {
-            return Err(anyhow!("secret store is not a directory"));
+            session
+                .add_record(&record::Record::login("GitHub", "user", "pass"))
+                .unwrap();
+            assert_eq!(sorted_labels(&session), vec!["github"]);
+
+            // Lookups fall back to the lowercased label.
+            assert!(session.has_record("GITHUB"));
+            assert_eq!(session.get_record("GitHub").unwrap().label, "github");
+
+            session
+                .add_record(&record::Record::login("GITHUB", "other", "pass"))
+                .unwrap();
+            assert_eq!(sorted_labels(&session), vec!["github"]);
         }
 
-        let mut labels = vec![];
-        for entry in fs::read_dir(store)? {
-            let path = entry?.path();
-            if !path.is_file() {
-                log::debug!("skipping non-file in store: {:?}", path);
-                continue;
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("GitHub", "user", "pass"))
+                .unwrap();
+            session.config.label_case = config::LabelCase::RejectCollisions;
+
+            // Replacing a record under its own label isn't a collision.
+            session
+                .add_record(&record::Record::login("GitHub", "other", "pass"))
+                .unwrap();
+
+            for label in &["github", "GITHUB"] {
+                let err = session
+                    .add_record(&record::Record::login(label, "user", "pass"))
+                    .unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "label-case: {} collides with an existing record that differs only by \
+                         case: GitHub",
+                        label
+                    )
+                );
             }
 
-            // NOTE(ww): This unwrap is safe, since file_name always returns Some
-            // for non-directories.
-            let label = path.file_name().unwrap();
+            let err = session
+                .copy_record("GitHub", "github", true, None)
+                .unwrap_err();
+            assert!(err.to_string().starts_with("label-case: github collides"));
 
-            // NOTE(ww): This one isn't safe, but we don't care. Non-UTF-8 labels aren't supported.
-            labels.push(label.to_str().unwrap().into());
+            session
+                .add_record(&record::Record::login("gitlab", "user", "pass"))
+                .unwrap();
+            assert_eq!(sorted_labels(&session), vec!["GitHub", "gitlab"]);
         }
-
-        Ok(labels)
     }
 
-    /// Returns whether or not the store contains a given record.
-    pub fn has_record(&self, label: &str) -> bool {
-        let record_path = Path::new(&self.config.store).join(label);
+    #[test]
+    fn test_add_records_from_json() {
+        let records = include_str!("../../testdata/records.jsonl");
 
-        record_path.is_file()
-    }
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
 
-    /// Retrieves a record from the store by its label.
-    pub fn get_record(&self, label: &str) -> Result<record::Record> {
-        if !self.has_record(label) {
-            return Err(anyhow!("no such record: {}", label));
+            let results = session
+                .add_records_from_json(records.as_bytes(), false)
+                .unwrap();
+
+            // The blank line is skipped, and the malformed line doesn't stop the rest.
+            assert_eq!(results.len(), 4);
+            assert_eq!(results[0].0, 1);
+            assert_eq!(results[0].1.as_ref().unwrap(), "foo");
+            assert_eq!(results[1].0, 2);
+            assert_eq!(results[1].1.as_ref().unwrap(), "env");
+            assert_eq!(results[2].0, 3);
+            assert!(results[2]
+                .1
+                .as_ref()
+                .unwrap_err()
+                .to_string()
+                .starts_with("malformed record: "));
+            assert_eq!(results[3].0, 5);
+            assert_eq!(results[3].1.as_ref().unwrap(), "notes");
+
+            assert_eq!(session.record_labels().unwrap().len(), 3);
+            assert_eq!(
+                session.get_record("foo").unwrap(),
+                record::Record {
+                    timestamp: 1600000000,
+                    ..record::Record::login("foo", "bar", "baz")
+                }
+            );
         }
 
-        let record_path = Path::new(&self.config.store).join(label);
-        let record_contents = fs::read_to_string(&record_path).map_err(|e| match e.kind() {
-            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
-            _ => e.into(),
-        })?;
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
 
-        match self.backend.decrypt(&record_contents) {
-            Ok(record) => Ok(record),
-            Err(e) => Err(e),
+            session
+                .add_record(&record::Record::login("foo", "old", "old"))
+                .unwrap();
+
+            // Existing records are left alone without force...
+            let results = session
+                .add_records_from_json(records.as_bytes(), false)
+                .unwrap();
+            assert_eq!(
+                results[0].1.as_ref().unwrap_err().to_string(),
+                "refusing to overwrite a record without --force: foo"
+            );
+            assert!(matches!(
+                session.get_record("foo").unwrap().body,
+                record::RecordBody::Login(ref fields) if fields.username == "old"
+            ));
+
+            // ...and replaced with it.
+            let results = session
+                .add_records_from_json(records.as_bytes(), true)
+                .unwrap();
+            assert_eq!(results[0].1.as_ref().unwrap(), "foo");
+            assert!(matches!(
+                session.get_record("foo").unwrap().body,
+                record::RecordBody::Login(ref fields) if fields.username == "bar"
+            ));
         }
     }
 
-    /// Adds the given record to the store.
-    pub fn add_record(&self, record: &record::Record) -> anyhow::Result<()> {
-        let record_path = Path::new(&self.config.store).join(&record.label);
+    #[test]
+    fn test_import_csv() {
+        let csv = include_str!("../../testdata/logins.csv");
+        let login = |session: &Session, label: &str| match session.get_record(label).unwrap().body {
+            record::RecordBody::Login(fields) => (fields.username, fields.password),
+            _ => panic!("expected a login record"),
+        };
 
-        let record_contents = self.backend.encrypt(record)?;
-        std::fs::write(&record_path, &record_contents)?;
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
 
-        Ok(())
-    }
+            let outcomes = session
+                .import_csv(csv, &CsvColumns::default(), false)
+                .unwrap();
+            let summary = outcomes
+                .iter()
+                .map(|(line, outcome)| match outcome {
+                    ImportOutcome::Imported(label) => format!("{}: imported {}", line, label),
+                    ImportOutcome::Skipped(label) => format!("{}: skipped {}", line, label),
+                    ImportOutcome::Failed(e) => format!("{}: failed: {}", line, e),
+                })
+                .collect::<Vec<_>>();
 
-    /// Deletes a record from the store by label.
-    pub fn delete_record(&self, label: &str) -> Result<()> {
-        let record_path = Path::new(&self.config.store).join(label);
+            // The blank line is ignored, the multi-line row is reported at its first line,
+            // and the duplicate host is skipped rather than overwriting the first row.
+            assert_eq!(
+                summary,
+                vec![
+                    "2: imported accounts.example.com",
+                    "3: imported foo.com",
+                    "5: skipped foo.com",
+                    "6: failed: couldn't find a host in URL: https:///nohost",
+                    "7: failed: expected 9 fields, found 2",
+                    "8: imported multi.example.org",
+                    "10: imported last.com",
+                ]
+            );
 
-        std::fs::remove_file(&record_path).map_err(|e| match e.kind() {
-            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
-            _ => e.into(),
-        })
-    }
-}
+            assert_eq!(session.record_labels().unwrap().len(), 4);
+            assert_eq!(
+                login(&session, "accounts.example.com"),
+                ("alice".into(), "p,ss\"word".into())
+            );
+            assert_eq!(login(&session, "foo.com"), ("bob".into(), "hunter2".into()));
+            assert_eq!(
+                login(&session, "multi.example.org"),
+                ("dave".into(), "line1\nline2".into())
+            );
+            assert_eq!(login(&session, "last.com"), ("eve".into(), "".into()));
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::{tempdir, TempDir};
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
 
-    // NOTE: We pass store in here instead of creating it for lifetime reasons:
-    // the temp dir is destroyed when its TempDir object is destructed, so we need
-    // to keep it alive long enough for each unit test.
-    fn dummy_session(store: &TempDir) -> Session {
-        let backend = {
-            let key = age::SecretKey::generate();
+            session
+                .add_record(&record::Record::login("last.com", "old", "old"))
+                .unwrap();
 
-            Box::new(backend::RageLib {
-                pubkey: key.to_public(),
-                identities: vec![key.into()],
-            })
-        };
+            // With overwrite, existing records are replaced and the last duplicate row wins.
+            let outcomes = session
+                .import_csv(csv, &CsvColumns::default(), true)
+                .unwrap();
+            assert!(
+                matches!(&outcomes[2], (5, ImportOutcome::Imported(label)) if label == "foo.com")
+            );
+            assert_eq!(
+                login(&session, "foo.com"),
+                ("bob2".into(), "duplicate".into())
+            );
+            assert_eq!(login(&session, "last.com"), ("eve".into(), "".into()));
+        }
 
-        let config = {
-            config::Config {
-                config_dir: "/not/a/real/dir".into(),
-                // NOTE: We create the backend above manually, so the public_key and keyfile
-                // here are dummy values that shouldn't need to be interacted with.
-                public_key: "not a real public key".into(),
-                keyfile: "not a real private key file".into(),
-                wrapped: false,
-                store: store.path().to_str().unwrap().into(),
-                pre_hook: None,
-                post_hook: None,
-                reentrant_hooks: false,
-                generators: vec![config::GeneratorConfig::Internal(Default::default())],
-                commands: Default::default(),
-            }
-        };
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
 
-        Session { backend, config }
+            // Remapped columns that don't exist fail the whole import.
+            let columns = CsvColumns::with_mappings(vec!["url=origin"]).unwrap();
+            assert_eq!(
+                session
+                    .import_csv(csv, &columns, false)
+                    .unwrap_err()
+                    .to_string(),
+                "CSV has no origin column"
+            );
+            assert!(session.record_labels().unwrap().is_empty());
+        }
     }
 
-    // TODO: Figure out how to test Session::new. Doing so will require an interface for
-    // creating + initializing a config that doesn't unconditionally put the store directory
-    // within the user's data directory.
-
     #[test]
-    fn test_record_labels() {
+    fn test_export_since() {
+        // Nothing changed since `when` means an empty delta, besides its manifest.
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            session
+                .add_record(&record::Record::unstructured("quux", "zap"))
+                .unwrap();
 
-            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+            let mut delta = vec![];
+            let count = session.export_since(SystemTime::now(), &mut delta).unwrap();
+            assert_eq!(count, 0);
+
+            let delta = String::from_utf8(delta).unwrap();
+            assert_eq!(delta.lines().count(), 1);
+
+            let header: DeltaHeader = serde_json::from_str(&delta).unwrap();
+            let mut labels = header.manifest.labels;
+            labels.sort();
+            assert_eq!(labels, vec!["foo", "quux"]);
         }
 
+        // Everything changed since the epoch.
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
-            let record = record::Record::login("foo", "bar", "baz");
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
 
-            session.add_record(&record).unwrap();
-            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+            let mut delta = vec![];
+            let count = session.export_since(UNIX_EPOCH, &mut delta).unwrap();
+            assert_eq!(count, 1);
+
+            let delta = String::from_utf8(delta).unwrap();
+            let lines = delta.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 2);
+
+            let header: DeltaHeader = serde_json::from_str(lines[0]).unwrap();
+            assert_eq!(header.manifest.since, 0);
+            assert_eq!(header.manifest.labels, vec!["foo"]);
+
+            let record: record::Record = serde_json::from_str(lines[1]).unwrap();
+            assert_eq!(record, session.get_record("foo").unwrap());
+        }
+
+        // An empty store exports just a manifest.
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let mut delta = vec![];
+            assert_eq!(session.export_since(UNIX_EPOCH, &mut delta).unwrap(), 0);
+            assert_eq!(String::from_utf8(delta).unwrap().lines().count(), 1);
         }
     }
 
     #[test]
-    fn test_has_record() {
+    fn test_apply_delta() {
+        let source_store = tempdir().unwrap();
+        let source = dummy_session(&source_store);
+
+        // Metadata travels with its record.
+        let mut foo = record::Record::login("foo", "bar", "baz");
+        foo.extra
+            .insert("recovery-email".into(), "alice@example.com".into());
+        source.add_record(&foo).unwrap();
+        source
+            .add_record(&record::Record::unstructured("quux", "zap"))
+            .unwrap();
+
+        let mut delta = vec![];
+        source.export_since(UNIX_EPOCH, &mut delta).unwrap();
+
+        // A delta applies cleanly to an empty store.
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
-            let record = record::Record::login("foo", "bar", "baz");
 
-            session.add_record(&record).unwrap();
+            let (mut written, deleted) = session.apply_delta(&delta[..], false).unwrap();
+            written.sort();
+            assert_eq!(written, vec!["foo", "quux"]);
+            assert!(deleted.is_empty());
+            assert_eq!(session.get_record("foo").unwrap(), foo);
+        }
+
+        // Records missing from the manifest are deleted, but only with `force`, and
+        // nothing is changed without it.
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("old", "a", "b"))
+                .unwrap();
+
+            let err = session.apply_delta(&delta[..], false).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to delete a record without --force: old"
+            );
+            assert!(session.has_record("old"));
+            assert!(!session.has_record("foo"));
+
+            let (_, deleted) = session.apply_delta(&delta[..], true).unwrap();
+            assert_eq!(deleted, vec!["old"]);
+            assert!(!session.has_record("old"));
             assert!(session.has_record("foo"));
+            assert!(session.has_record("quux"));
         }
 
+        // Existing records are only replaced with `force`.
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
+            session
+                .add_record(&record::Record::login("foo", "x", "y"))
+                .unwrap();
 
-            assert!(!session.has_record("does-not-exist"));
+            let err = session.apply_delta(&delta[..], false).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to overwrite a record without --force: foo"
+            );
+
+            session.apply_delta(&delta[..], true).unwrap();
+            assert_eq!(
+                session.get_record("foo").unwrap(),
+                source.get_record("foo").unwrap()
+            );
+        }
+
+        // Deltas need a manifest, and their records need to be in it.
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            let err = session.apply_delta(&b""[..], false).unwrap_err();
+            assert_eq!(err.to_string(), "delta has no manifest");
+
+            let record = serde_json::to_string(&record::Record::login("foo", "a", "b")).unwrap();
+            let err = session.apply_delta(record.as_bytes(), false).unwrap_err();
+            assert!(err.to_string().starts_with("malformed delta manifest"));
+
+            let delta = format!(
+                "{{\"manifest\":{{\"since\":0,\"exported\":0,\"labels\":[]}}}}\n{}",
+                record
+            );
+            let err = session.apply_delta(delta.as_bytes(), false).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "malformed delta: record isn't in its manifest: foo"
+            );
+            assert!(!session.has_record("foo"));
         }
     }
 
     #[test]
-    fn test_get_record() {
+    fn test_move_records() {
+        fn sorted_labels(session: &Session) -> Vec<String> {
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            labels
+        }
+
+        let populate = |session: &Session| {
+            for label in &["work/github", "work/aws/prod", "workshop", "personal/email"] {
+                session
+                    .add_record(&record::Record::login(label, "user", "pass"))
+                    .unwrap();
+            }
+            session
+                .attach("work/github", "recovery-codes", b"1234")
+                .unwrap();
+        };
+
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
-            let record = record::Record::login("foo", "bar", "baz");
+            populate(&session);
 
-            session.add_record(&record).unwrap();
+            let mut moves = session.move_records("work/", "archive/work/").unwrap();
+            moves.sort();
+            assert_eq!(
+                moves,
+                vec![
+                    ("work/aws/prod".into(), "archive/work/aws/prod".into()),
+                    ("work/github".into(), "archive/work/github".into()),
+                ]
+            );
 
-            let retrieved_record = session.get_record("foo").unwrap();
+            assert_eq!(
+                sorted_labels(&session),
+                vec![
+                    "archive/work/aws/prod",
+                    "archive/work/github",
+                    "personal/email",
+                    "workshop"
+                ]
+            );
 
-            assert_eq!(record, retrieved_record);
+            let record = session.get_record("archive/work/github").unwrap();
+            assert_eq!(record.label, "archive/work/github");
+            assert_eq!(
+                read_attachment(&session, "archive/work/github", "recovery-codes").unwrap(),
+                b"1234"
+            );
         }
 
         {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
+            populate(&session);
 
-            let err = session.get_record("foo").unwrap_err();
-            assert_eq!(err.to_string(), "no such record: foo");
+            // Without a trailing /, only the exact label is moved.
+            session
+                .move_records("workshop", "hobbies/workshop")
+                .unwrap();
+            assert!(session.has_record("hobbies/workshop"));
+            assert!(!session.has_record("workshop"));
+            assert!(session.has_record("work/github"));
+
+            assert!(session.move_records("nothing/", "else/").is_err());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            populate(&session);
+            session
+                .add_record(&record::Record::login("personal/github", "user", "pass"))
+                .unwrap();
+
+            // Collisions are rejected without changing anything.
+            let before = sorted_labels(&session);
+            let err = session.move_records("work/", "personal/").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to move work/github onto an existing record: personal/github"
+            );
+            assert_eq!(sorted_labels(&session), before);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+            populate(&session);
+            session
+                .add_record(&record::Record::login("archived", "user", "pass"))
+                .unwrap();
+            fs::write(store.path().join(IGNORE_FILENAME), "archived\n").unwrap();
+
+            // Ignored records are still protected from being overwritten.
+            let err = session.move_records("workshop", "archived").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to move workshop onto an existing record: archived"
+            );
         }
     }
 
     #[test]
-    fn test_add_record() {
+    fn test_copy_record() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+
+        let mut original = record::Record::login("foo", "user", "hunter2");
+        original.timestamp = 1;
+        original
+            .extra
+            .insert("url".into(), "https://foo.com".into());
+        session.add_record(&original).unwrap();
+        session.attach("foo", "recovery-codes", b"1234").unwrap();
+
+        {
+            let copy = session.copy_record("foo", "bar", false, None).unwrap();
+            assert_eq!(copy.label, "bar");
+            assert!(copy.timestamp > original.timestamp);
+            assert_eq!(copy.body, original.body);
+            assert_eq!(copy.extra, original.extra);
+
+            assert_eq!(session.get_record("bar").unwrap(), copy);
+            assert_eq!(session.get_record("foo").unwrap(), original);
+            assert!(session.attachment_names("bar").unwrap().is_empty());
+        }
+
+        {
+            let generator = session.config.get_generator("default").unwrap();
+            let copy = session
+                .copy_record("foo", "baz", false, Some(generator))
+                .unwrap();
+
+            match &copy.body {
+                record::RecordBody::Login(fields) => {
+                    assert_eq!(fields.username, "user");
+                    assert_ne!(fields.password, "hunter2");
+                    assert!(!fields.password.is_empty());
+                }
+                _ => panic!("expected a login record"),
+            }
+            assert_eq!(session.get_record("baz").unwrap(), copy);
+        }
+
+        {
+            let err = session.copy_record("foo", "bar", false, None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "refusing to overwrite an existing record without --force: bar"
+            );
+
+            let generator = session.config.get_generator("default").unwrap();
+            let copy = session
+                .copy_record("foo", "bar", true, Some(generator))
+                .unwrap();
+            assert_eq!(session.get_record("bar").unwrap(), copy);
+        }
+
+        {
+            session
+                .add_record(&record::Record::unstructured("notes", "hello"))
+                .unwrap();
+
+            let generator = session.config.get_generator("default").unwrap();
+            let err = session
+                .copy_record("notes", "notes2", false, Some(generator))
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "unstructured record notes has no fields to regenerate"
+            );
+            assert!(!session.has_record("notes2"));
+
+            assert_eq!(
+                session
+                    .copy_record("nonexistent", "other", false, None)
+                    .unwrap_err()
+                    .to_string(),
+                "no such record: nonexistent"
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
         {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            let record = record::Record::login("foo", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+            session.attach("foo", "notes", b"hello").unwrap();
+            session.config.read_only = true;
+
+            // Mutating operations are rejected before touching the store...
+            let errs = vec![
+                session.add_record(&record::Record::login("new", "bar", "baz")),
+                session.add_protected_record(&record, SecretString::new("extra".into())),
+                session
+                    .add_records_from_json(io::Cursor::new("{}"), true)
+                    .map(|_| ()),
+                session.move_records("foo", "moved").map(|_| ()),
+                session.delete_record("foo"),
+                session.attach("foo", "more-notes", b"hello"),
+            ];
+            for err in errs {
+                assert_eq!(err.unwrap_err().to_string(), "store is read-only");
+            }
+            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+            assert!(!session.is_protected("foo").unwrap());
+
+            // ...while reads work as usual.
+            assert_eq!(session.get_record("foo").unwrap(), record);
+            assert_eq!(session.records().unwrap().count(), 1);
+            assert_eq!(read_attachment(&session, "foo", "notes").unwrap(), b"hello");
+        }
+
+        // NOTE: root can write to the store regardless of its permissions, so this
+        // can only be observed as an unprivileged user.
+        if !nix::unistd::geteuid().is_root() {
             let store = tempdir().unwrap();
             let session = dummy_session(&store);
 
-            let record1 = record::Record::login("foo", "bar", "baz");
-            session.add_record(&record1).unwrap();
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o500)).unwrap();
+            let err = session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap_err();
+            fs::set_permissions(store.path(), fs::Permissions::from_mode(0o700)).unwrap();
 
-            let record2 = record::Record::login("a", "b", "c");
-            session.add_record(&record2).unwrap();
+            assert_eq!(err.to_string(), "store is read-only");
+        }
+    }
 
-            // NOTE: record_labels() returns labels in a platform dependent order,
-            // which is why we don't compared against a fixed-order vec here or below.
-            assert_eq!(session.record_labels().unwrap().len(), 2);
-            assert!(session.record_labels().unwrap().contains(&"foo".into()));
-            assert!(session.record_labels().unwrap().contains(&"a".into()));
+    #[test]
+    fn test_touch() {
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+            let record = record::Record::login("foo", "bar", "baz");
 
-            // Overwrite foo; still only two records.
-            let record3 = record::Record::login("foo", "quux", "zap");
-            session.add_record(&record3).unwrap();
+            session.add_record(&record).unwrap();
+            session.config.aliases.insert("f".into(), "foo".into());
 
-            assert_eq!(session.record_labels().unwrap().len(), 2);
-            assert!(session.record_labels().unwrap().contains(&"foo".into()));
-            assert!(session.record_labels().unwrap().contains(&"a".into()));
+            // Records that were never read have no access time, and no index is created
+            // until one is.
+            assert_eq!(session.last_accessed("foo").unwrap(), None);
+            assert!(!store.path().join(ACCESS_INDEX_FILENAME).exists());
+
+            let before = util::current_timestamp();
+            session.touch("f").unwrap();
+            let accessed = session.last_accessed("foo").unwrap().unwrap();
+            assert!(accessed >= before && accessed <= util::current_timestamp());
+            assert_eq!(session.last_accessed("f").unwrap(), Some(accessed));
+
+            // The index lives alongside the records, but isn't one of them.
+            let index: BTreeMap<String, u64> = serde_json::from_slice(
+                &fs::read(store.path().join(ACCESS_INDEX_FILENAME)).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(index.get("foo"), Some(&accessed));
+            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+            assert_eq!(session.get_record("foo").unwrap(), record);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut session = dummy_session(&store);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            session.config.read_only = true;
+
+            // Reads on a read-only store aren't recorded, but don't fail either.
+            session.touch("foo").unwrap();
+            assert_eq!(session.last_accessed("foo").unwrap(), None);
+            assert!(!store.path().join(ACCESS_INDEX_FILENAME).exists());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let session = dummy_session(&store);
+
+            fs::write(store.path().join(ACCESS_INDEX_FILENAME), "not json").unwrap();
+            assert!(session
+                .last_accessed("foo")
+                .unwrap_err()
+                .to_string()
+                .starts_with("malformed .kbs2-access: "));
         }
     }
 
@@ -267,4 +3085,109 @@ mod tests {
             assert_eq!(err.to_string(), "no such record: does-not-exist");
         }
     }
+
+    #[test]
+    fn test_format_warning() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+
+        // Nothing to sample in an empty store.
+        assert_eq!(session.format_warning().unwrap(), None);
+
+        session
+            .add_record(&record::Record::login("foo", "bar", "baz"))
+            .unwrap();
+        assert_eq!(session.format_warning().unwrap(), None);
+
+        // Pretend that foo was written by a future kbs2, with a newer version of age.
+        let path = session.record_path("foo").unwrap();
+        let encrypted = session.backend.encrypt_bytes(b"whatever").unwrap();
+        let newer = String::from_utf8_lossy(&encrypted)
+            .replacen("age-encryption.org/v1", "age-encryption.org/v2", 1)
+            .into_bytes();
+        fs::write(&path, newer).unwrap();
+
+        assert!(session
+            .format_warning()
+            .unwrap()
+            .unwrap()
+            .starts_with("record foo: written in a newer age format (age-encryption.org/v2)"));
+    }
+
+    #[test]
+    fn test_matching_labels() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+        for label in &["temp/b", "temp/a", "temp2", "keep/temp/c", "odd*label"] {
+            session
+                .add_record(&record::Record::login(label, "bar", "baz"))
+                .unwrap();
+        }
+
+        assert_eq!(
+            session.matching_labels("temp/*").unwrap(),
+            vec!["temp/a", "temp/b"]
+        );
+        assert_eq!(session.matching_labels("temp?").unwrap(), vec!["temp2"]);
+        assert_eq!(
+            session.matching_labels("*temp*").unwrap(),
+            vec!["keep/temp/c", "temp/a", "temp/b", "temp2"]
+        );
+
+        // Exact labels (even ones that look like globs) and plain labels are used as-is.
+        assert_eq!(
+            session.matching_labels("odd*label").unwrap(),
+            vec!["odd*label"]
+        );
+        assert_eq!(session.matching_labels("nope").unwrap(), vec!["nope"]);
+
+        assert_eq!(
+            session.matching_labels("nope/*").unwrap_err().to_string(),
+            "no records match: nope/*"
+        );
+
+        assert_eq!(session.matching_label("temp?").unwrap(), "temp2");
+        assert_eq!(
+            session.matching_label("temp/*").unwrap_err().to_string(),
+            "temp/* matches 2 records (temp/a, temp/b); use a more specific label"
+        );
+    }
+
+    #[test]
+    fn test_delete_matching() {
+        let store = tempdir().unwrap();
+        let session = dummy_session(&store);
+        for label in &["temp/a", "temp/b", "temp2", "keep"] {
+            session
+                .add_record(&record::Record::login(label, "bar", "baz"))
+                .unwrap();
+        }
+
+        // A single match doesn't need confirmation.
+        assert_eq!(
+            session
+                .delete_matching("temp?", |_| panic!("unexpected confirmation"))
+                .unwrap(),
+            vec!["temp2"]
+        );
+
+        // Declining to delete multiple matches leaves every one of them in place.
+        let err = session
+            .delete_matching("temp/*", |labels| {
+                assert_eq!(labels, ["temp/a", "temp/b"]);
+                Ok(false)
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not deleting the 2 records matching temp/*"
+        );
+        assert!(session.has_record("temp/a") && session.has_record("temp/b"));
+
+        assert_eq!(
+            session.delete_matching("temp/*", |_| Ok(true)).unwrap(),
+            vec!["temp/a", "temp/b"]
+        );
+        assert_eq!(session.record_labels().unwrap(), vec!["keep"]);
+    }
 }
@@ -1,17 +1,38 @@
 use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::libc::c_void;
+use nix::sys::mman;
 use pinentry::PassphraseInput;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::kbs2::config::PassphraseEntry;
+use crate::kbs2::input::Prompt;
+
 /// Given an input string formatted according to shell quoting rules,
 /// split it into its command and argument parts and return each.
+///
+/// Single and double quotes and backslash escapes are honored (as by a POSIX shell),
+/// but nothing is expanded: variables, globs, and `~` are passed through literally.
 pub fn parse_and_split_args(argv: &str) -> Result<(String, Vec<String>)> {
     let args = match shell_words::split(argv) {
         Ok(args) => args,
-        Err(_) => return Err(anyhow!("failed to split command-line arguments: {}", argv)),
+        Err(e) => {
+            return Err(anyhow!(
+                "failed to split command-line arguments ({}): {}",
+                e,
+                argv
+            ))
+        }
     };
 
     let (command, args) = args
@@ -22,23 +43,197 @@ pub fn parse_and_split_args(argv: &str) -> Result<(String, Vec<String>)> {
     Ok((command, args))
 }
 
+/// Calls the given syscall wrapper, retrying it for as long as it fails with `EINTR`
+/// (i.e., for as long as it's interrupted by a signal before completing).
+pub fn retry_eintr<T>(mut syscall: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    loop {
+        match syscall() {
+            Err(nix::Error::Sys(Errno::EINTR)) => log::debug!("syscall interrupted, retrying"),
+            result => return result,
+        }
+    }
+}
+
+/// Locks the given memory into RAM, so that it's never written to swap, and (on Linux)
+/// excludes it from core dumps. Returns whether the memory was locked.
+///
+/// Both are best-effort hardening: failing to lock the memory (e.g. because it would exceed
+/// `RLIMIT_MEMLOCK`) is only a warning. The lock lasts for as long as the memory is mapped.
+pub fn lock_memory(memory: &[u8]) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        // NOTE: This only matters if we crash, so a failure isn't worth a warning.
+        if let Err(e) = unsafe {
+            mman::madvise(
+                memory.as_ptr() as *mut c_void,
+                memory.len(),
+                mman::MmapAdvise::MADV_DONTDUMP,
+            )
+        } {
+            log::debug!("couldn't exclude memory from core dumps: {}", e);
+        }
+    }
+
+    lock_memory_with(memory, |addr, len| unsafe { mman::mlock(addr, len) })
+}
+
+#[doc(hidden)]
+fn lock_memory_with<F>(memory: &[u8], mut mlock: F) -> bool
+where
+    F: FnMut(*const c_void, usize) -> nix::Result<()>,
+{
+    match retry_eintr(|| mlock(memory.as_ptr() as *const c_void, memory.len())) {
+        Ok(()) => true,
+        Err(e) => {
+            let hint = match e {
+                nix::Error::Sys(Errno::ENOMEM) | nix::Error::Sys(Errno::EPERM) => {
+                    " (try raising the locked memory limit with `ulimit -l`)"
+                }
+                _ => "",
+            };
+            warn(&format!(
+                "couldn't lock memory, so it may be written to swap: {}{}",
+                e, hint
+            ));
+            false
+        }
+    }
+}
+
+/// Find the given command, either directly (if it contains a `/`) or by searching each
+/// directory in `$PATH`, returning the path to it if it's an executable file.
+pub fn find_executable(command: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if command.contains('/') {
+        return Some(PathBuf::from(command)).filter(|path| is_executable(path));
+    }
+
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(command))
+            .find(|path| is_executable(path))
+    })
+}
+
+/// Returns whether the given text matches the given glob pattern, in which `*` matches any
+/// run of characters (including `/`) and `?` matches any single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // NOTE: On a mismatch, we backtrack to the most recent `*` and let it consume
+    // one more character. Earlier `*`s never need revisiting, so this is linear-ish.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns whether the given text contains any of the special characters of a glob pattern
+/// (see `glob_match`).
+pub fn is_glob(text: &str) -> bool {
+    text.contains(&['*', '?'][..])
+}
+
+/// The default maximum number of bytes accepted from a command's standard output
+/// by `run_with_output`.
+pub const DEFAULT_MAX_OUTPUT: usize = 4096;
+
 /// Given a command and its arguments, run the command and capture the resulting
 /// standard output.
 ///
-/// NOTE: The command is run with no standard input or standard error.
-pub fn run_with_output(command: &str, args: &[&str]) -> Result<String> {
-    let output = Command::new(command)
+/// At most `max_output` bytes of output are accepted; if the command produces more
+/// than that, it's killed and an error is returned.
+///
+/// NOTE: The command is run with no standard input. Its standard error is captured
+/// and included in the returned error if the command exits unsuccessfully.
+pub fn run_with_output(command: &str, args: &[&str], max_output: usize) -> Result<String> {
+    let mut child = Command::new(command)
         .args(args)
         .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .output()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .map_err(|_| anyhow!("failed to execute command: {}", command))?;
 
-    if output.stdout.is_empty() {
+    // NOTE: stderr gets drained on its own thread, so that a chatty command can't
+    // deadlock us by filling up its stderr pipe while we're waiting on stdout.
+    // We only hold onto a bounded amount of it.
+    let stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = vec![];
+        let mut stderr = stderr.take(max_output as u64);
+        let _ = stderr.read_to_end(&mut buf);
+        let _ = io::copy(&mut stderr.into_inner(), &mut io::sink());
+        buf
+    });
+
+    // Read one byte past the limit, so that we can tell an exactly-full buffer
+    // apart from an overflowing one.
+    let mut stdout = vec![];
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .take(max_output as u64 + 1)
+        .read_to_end(&mut stdout)?;
+
+    if stdout.len() > max_output {
+        child.kill()?;
+        child.wait()?;
+        return Err(anyhow!(
+            "output from {} exceeded the limit of {} bytes",
+            command,
+            max_output
+        ));
+    }
+
+    let status = child.wait()?;
+    let stderr = stderr_reader
+        .join()
+        .map_err(|_| anyhow!("failed to collect stderr from {}", command))?;
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let stderr = stderr.trim();
+
+        if stderr.is_empty() {
+            return Err(anyhow!("command failed: {}", command));
+        } else {
+            return Err(anyhow!("command failed: {}: {}", command, stderr));
+        }
+    }
+
+    if stdout.is_empty() {
         return Err(anyhow!("expected output from {}, but none given", command));
     }
 
-    let mut output = String::from_utf8(output.stdout)?;
+    let mut output = String::from_utf8(stdout)?;
     if output.ends_with('\n') {
         output.pop();
     }
@@ -46,27 +241,266 @@ pub fn run_with_output(command: &str, args: &[&str]) -> Result<String> {
     Ok(output)
 }
 
+/// Given a command and its arguments, run the command with the given input on its standard
+/// input, waiting for it to exit.
+///
+/// NOTE: The command inherits our standard output and error. The input is never placed on
+/// its command line, so it isn't visible to other processes (e.g. via `ps`).
+pub fn run_with_input(command: &str, args: &[String], input: &[u8]) -> Result<()> {
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|_| anyhow!("failed to execute command: {}", command))?;
+
+    // NOTE: A command that exits without reading all of its input isn't an error
+    // by itself; its exit status decides.
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        match stdin.write_all(input) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+
+    if !child.wait()?.success() {
+        return Err(anyhow!("command failed: {}", command));
+    }
+
+    Ok(())
+}
+
 /// Securely retrieve a password from the user.
 ///
-/// NOTE: This function currently uses pinentry internally, which
-/// will delegate to the appropriate pinentry binary on the user's
-/// system.
-pub fn get_password() -> Result<SecretString> {
-    if let Some(mut input) = PassphraseInput::with_default_binary() {
-        input
-            .with_description("Enter your master kbs2 password")
-            .with_prompt("Password:")
-            .interact()
-            .map_err(|e| anyhow!("pinentry failed: {}", e.to_string()))
-    } else {
-        log::debug!("no pinentry binary, falling back on rpassword");
+/// # Arguments
+///
+/// * `entry` - the mechanism to prompt with
+/// * `pinentry` - the pinentry binary to use, unless `entry` is `PassphraseEntry::Tty`
+/// * `prompt` - the `Prompt` to ask with, when prompting on the TTY
+///
+/// NOTE: When `pinentry` can't be found, this function falls back on a TTY prompt. It
+/// only warns about doing so when pinentry was requested explicitly, i.e. with
+/// `PassphraseEntry::Pinentry`.
+pub fn get_password(
+    entry: PassphraseEntry,
+    pinentry: &str,
+    prompt: &dyn Prompt,
+) -> Result<SecretString> {
+    prompt_password(
+        entry,
+        pinentry,
+        prompt,
+        "Enter your master kbs2 password",
+        false,
+    )
+}
+
+/// Securely retrieve a new password from the user, prompting a second time for
+/// confirmation.
+///
+/// Arguments and fallback behavior are as with `get_password`.
+pub fn get_new_password(
+    entry: PassphraseEntry,
+    pinentry: &str,
+    prompt: &dyn Prompt,
+) -> Result<SecretString> {
+    prompt_password(
+        entry,
+        pinentry,
+        prompt,
+        "Enter your new master kbs2 password",
+        true,
+    )
+}
+
+/// Securely retrieve the extra passphrase protecting the record with the given label,
+/// optionally prompting a second time for confirmation (e.g. when setting it).
+///
+/// Arguments and fallback behavior are as with `get_password`.
+pub fn get_record_passphrase(
+    entry: PassphraseEntry,
+    pinentry: &str,
+    prompt: &dyn Prompt,
+    label: &str,
+    confirm: bool,
+) -> Result<SecretString> {
+    let description = format!("Enter the extra passphrase for {}", label);
+    prompt_password(entry, pinentry, prompt, &description, confirm)
+}
+
+#[doc(hidden)]
+fn prompt_password(
+    entry: PassphraseEntry,
+    pinentry: &str,
+    prompt: &dyn Prompt,
+    description: &str,
+    confirm: bool,
+) -> Result<SecretString> {
+    if entry != PassphraseEntry::Tty {
+        if let Some(mut input) = PassphraseInput::with_binary(pinentry) {
+            input.with_description(description).with_prompt("Password:");
+            if confirm {
+                input.with_confirmation("Confirm:", "Passwords don't match");
+            }
+
+            return input
+                .interact()
+                .map_err(|e| anyhow!("pinentry failed: {}", e.to_string()));
+        }
+
+        let message = format!(
+            "couldn't find pinentry binary ({}), falling back on the TTY",
+            pinentry
+        );
+        if entry == PassphraseEntry::Pinentry {
+            warn(&message);
+        } else {
+            log::debug!("{}", message);
+        }
+    }
+
+    let password = prompt.password("Password")?;
+
+    if confirm {
+        let confirmation = prompt.password("Confirm")?;
+
+        if password.expose_secret() != confirmation.expose_secret() {
+            return Err(anyhow!("passwords don't match"));
+        }
+    }
+
+    Ok(password)
+}
+
+/// Create the given directory (and any missing parents) with at most owner-only (`0700`)
+/// permissions.
+///
+/// NOTE: The process's umask still applies, so this never creates a directory that's more
+/// permissive than the umask allows. Directories that already exist are left untouched.
+pub fn create_dir_secure(path: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .map_err(|e| anyhow!("unable to create {}: {}", path.display(), e))
+}
+
+/// Atomically replace the file at the given path with the given contents, with at most
+/// owner-only (`0600`) permissions.
+///
+/// The contents are written to a temporary file in the same directory (created with
+/// owner-only permissions, further restricted by the umask) and then renamed over the
+/// original, so that a failure partway through never leaves a truncated file behind.
+pub fn write_file_secure(path: &Path, contents: &[u8]) -> Result<()> {
+    write_file_secure_with(path, |file| Ok(file.write_all(contents)?))
+}
+
+/// Like `write_file_secure`, but with the contents written by the given function.
+///
+/// If the function fails, the file at the given path is left untouched and nothing it
+/// wrote is kept.
+pub fn write_file_secure_with<T>(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<T>,
+) -> Result<T> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
 
-        rpassword::read_password_from_tty(Some("Password: "))
-            .map(SecretString::new)
-            .map_err(|e| anyhow!("password prompt failed: {}", e.to_string()))
+    // NOTE: The temporary file is removed when dropped, including on early returns.
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    let result = write(&mut file)?;
+    file.as_file().sync_all()?;
+    file.persist(path)
+        .map_err(|e| anyhow!("unable to replace {}: {}", path.display(), e))?;
+
+    Ok(result)
+}
+
+/// Return the permission bits of the file or directory at the given path if it's accessible
+/// by users other than its owner, or `None` if it isn't (or doesn't exist).
+pub fn shared_mode(path: &Path) -> Result<Option<u32>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mode = metadata.permissions().mode() & 0o777;
+    Ok(Some(mode).filter(|mode| mode & 0o077 != 0))
+}
+
+/// The maximum number of base64-encoded bytes that `osc52_sequence` will produce.
+///
+/// NOTE: OSC 52 itself has no limit, but terminals do: xterm and hterm (among others)
+/// ignore sequences with more than 100,000 bytes of payload.
+pub const OSC52_MAX_ENCODED: usize = 100_000;
+
+/// GNU screen truncates device control strings longer than this, so OSC 52 sequences
+/// passed through it are split into chunks of (at most) this many bytes.
+const SCREEN_CHUNK_SIZE: usize = 76;
+
+/// The terminal multiplexers that OSC 52 sequences need to be wrapped for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Multiplexer {
+    Tmux,
+    Screen,
+}
+
+impl Multiplexer {
+    /// Returns the multiplexer that we're running under, if any.
+    pub fn detect() -> Option<Multiplexer> {
+        if env::var_os("TMUX").is_some() {
+            Some(Multiplexer::Tmux)
+        } else if env::var_os("STY").is_some() {
+            Some(Multiplexer::Screen)
+        } else {
+            None
+        }
     }
 }
 
+/// Return the OSC 52 escape sequence that sets the terminal's clipboard to the given
+/// contents. Empty contents produce a sequence that clears the clipboard.
+///
+/// When running under a multiplexer, the sequence is wrapped in the multiplexer's
+/// passthrough sequence so that it reaches the outer terminal.
+pub fn osc52_sequence(contents: &str, multiplexer: Option<Multiplexer>) -> Result<String> {
+    let encoded = base64::encode(contents);
+    if encoded.len() > OSC52_MAX_ENCODED {
+        return Err(anyhow!(
+            "secret is too large for the osc52 clipboard ({} bytes encoded, max {})",
+            encoded.len(),
+            OSC52_MAX_ENCODED
+        ));
+    }
+
+    let sequence = format!("\x1b]52;c;{}\x07", encoded);
+
+    Ok(match multiplexer {
+        None => sequence,
+        // NOTE: tmux requires every ESC within a passthrough sequence to be doubled.
+        Some(Multiplexer::Tmux) => {
+            format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+        }
+        // NOTE: The sequence is entirely ASCII, so chunking its bytes is safe.
+        Some(Multiplexer::Screen) => sequence
+            .as_bytes()
+            .chunks(SCREEN_CHUNK_SIZE)
+            .map(|chunk| format!("\x1bP{}\x1b\\", String::from_utf8_lossy(chunk)))
+            .collect(),
+    })
+}
+
+/// Clear the terminal's clipboard by writing the empty OSC 52 sequence to the given terminal.
+pub fn clear_osc52(tty: &mut dyn Write, multiplexer: Option<Multiplexer>) -> Result<()> {
+    tty.write_all(osc52_sequence("", multiplexer)?.as_bytes())?;
+    tty.flush()?;
+
+    Ok(())
+}
+
 /// Return the current timestamp as seconds since the UNIX epoch.
 pub fn current_timestamp() -> u64 {
     // NOTE(ww): This unwrap should be safe, since every time should be
@@ -77,9 +511,187 @@ pub fn current_timestamp() -> u64 {
         .as_secs()
 }
 
-/// Print the given message on `stderr` with a warning prefix.
+/// Parses a relative duration (like `7d` or `1h30m`) into seconds.
+///
+/// A duration is one or more whole numbers, each followed by a unit: `s` (seconds),
+/// `m` (minutes), `h` (hours), `d` (days), or `w` (weeks).
+pub fn parse_relative_duration(duration: &str) -> Result<u64> {
+    let invalid = || anyhow!("invalid duration: {}", duration);
+
+    if duration.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+
+        // NOTE: An empty number (i.e., a unit without a count) fails to parse here.
+        let count = number.parse::<u64>().map_err(|_| invalid())?;
+        total = count
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    // A trailing number without a unit is ambiguous, so we reject it.
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(total)
+}
+
+/// Parses an RFC 3339 timestamp (like `2020-05-23T18:30:00Z` or
+/// `2020-05-23T14:30:00.5-04:00`) into seconds since the Unix epoch.
+///
+/// Fractional seconds are truncated, and timestamps before the epoch are rejected.
+pub fn parse_rfc3339(timestamp: &str) -> Result<u64> {
+    let invalid = || anyhow!("invalid RFC 3339 timestamp: {}", timestamp);
+
+    let bytes = timestamp.as_bytes();
+    if bytes.len() < 20 || !timestamp.is_ascii() {
+        return Err(invalid());
+    }
+
+    let number = |start: usize, end: usize| -> Result<i64> {
+        let digits = &timestamp[start..end];
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        digits.parse::<i64>().map_err(|_| invalid())
+    };
+
+    if bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return Err(invalid());
+    }
+
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        _ => return Err(invalid()),
+    };
+    if !(1..=month_days).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid());
+    }
+
+    let mut rest = &timestamp[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return Err(invalid());
+        }
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest.as_bytes() {
+        b"Z" | b"z" => 0,
+        [sign, _, _, b':', _, _] if *sign == b'+' || *sign == b'-' => {
+            let offset_at = timestamp.len() - 5;
+            let (hours, minutes) = (
+                number(offset_at, offset_at + 2)?,
+                number(offset_at + 3, offset_at + 5)?,
+            );
+            if hours > 23 || minutes > 59 {
+                return Err(invalid());
+            }
+
+            let offset = hours * 60 * 60 + minutes * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    // NOTE: This is the "days from civil" algorithm, with years starting in March
+    // so that leap days fall at the end of each year.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60 + second - offset;
+    if seconds < 0 {
+        return Err(anyhow!("timestamp is before the Unix epoch: {}", timestamp));
+    }
+
+    Ok(seconds as u64)
+}
+
+/// Parses the given time, either as seconds since the Unix epoch, as an RFC 3339 timestamp,
+/// or as a relative duration (see `parse_relative_duration`) before `now`, into seconds
+/// since the Unix epoch.
+pub fn parse_time(time: &str, now: u64) -> Result<u64> {
+    // NOTE: Relative durations always end with a unit, and never contain a date or
+    // time separator.
+    if let Ok(seconds) = time.parse::<u64>() {
+        Ok(seconds)
+    } else if time.contains(&['-', ':'][..]) {
+        parse_rfc3339(time)
+    } else {
+        parse_relative_duration(time).map(|duration| now.saturating_sub(duration))
+    }
+}
+
+/// Whether `warn` is silenced, i.e. whether `kbs2` was run with `--quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silence (or un-silence) every subsequent `warn`.
+///
+/// NOTE: This only affects advisory warnings; errors are still returned (and reported)
+/// as usual.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Print the given message on `stderr` with a warning prefix, unless warnings have been
+/// silenced with `set_quiet`.
 pub fn warn(msg: &str) {
-    eprintln!("Warn: {}", msg);
+    write_warning(&mut io::stderr(), msg);
+}
+
+#[doc(hidden)]
+fn write_warning<W: Write>(out: &mut W, msg: &str) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // NOTE: Like eprintln!, a warning that can't be written is dropped.
+    writeln!(out, "Warn: {}", msg).ok();
 }
 
 /// Retrieve the current user's home directory.
@@ -119,11 +731,45 @@ mod tests {
             assert_eq!(args, vec!["one arg", "another arg", ""]);
         }
 
+        {
+            let (cmd, args) = parse_and_split_args("mygen --charset \"a b c\" -n 3").unwrap();
+
+            assert_eq!(cmd, "mygen");
+            assert_eq!(args, vec!["--charset", "a b c", "-n", "3"]);
+        }
+
+        {
+            let (cmd, args) = parse_and_split_args(r"my\ gen one\ arg --x=a\ b").unwrap();
+
+            assert_eq!(cmd, "my gen");
+            assert_eq!(args, vec!["one arg", "--x=a b"]);
+        }
+
+        {
+            let (cmd, args) =
+                parse_and_split_args(r#"foo "it's" 'say "hi"' "a \"quoted\" word" $HOME ~"#)
+                    .unwrap();
+
+            assert_eq!(cmd, "foo");
+            assert_eq!(
+                args,
+                vec!["it's", "say \"hi\"", "a \"quoted\" word", "$HOME", "~"]
+            );
+        }
+
         {
             let err = parse_and_split_args("some 'bad {syntax").unwrap_err();
             assert_eq!(
                 err.to_string(),
-                "failed to split command-line arguments: some 'bad {syntax"
+                "failed to split command-line arguments (missing closing quote): some 'bad {syntax"
+            );
+        }
+
+        {
+            let err = parse_and_split_args("foo \"bar").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to split command-line arguments (missing closing quote): foo \"bar"
             );
         }
 
@@ -131,22 +777,130 @@ mod tests {
             let err = parse_and_split_args("").unwrap_err();
             assert_eq!(err.to_string(), "missing one or more arguments in command");
         }
+
+        {
+            let err = parse_and_split_args(" \t ").unwrap_err();
+            assert_eq!(err.to_string(), "missing one or more arguments in command");
+        }
+    }
+
+    #[test]
+    fn test_find_executable() {
+        assert!(find_executable("sh").is_some());
+        assert_eq!(find_executable("/bin/sh"), Some(PathBuf::from("/bin/sh")));
+        assert!(find_executable("kbs2-not-a-real-command").is_none());
+        assert!(find_executable("/not/a/real/command").is_none());
+
+        // Directories and non-executable files aren't executables.
+        assert!(find_executable("/").is_none());
+        assert!(find_executable("/etc/passwd").is_none());
+    }
+
+    #[test]
+    fn test_lock_memory() {
+        {
+            let memory = memmap::MmapMut::map_anon(4096).unwrap();
+            assert!(lock_memory(&memory));
+        }
+
+        {
+            let memory = [0u8; 32];
+            let mut calls = vec![];
+            assert!(lock_memory_with(&memory, |addr, len| {
+                calls.push((addr, len));
+                Ok(())
+            }));
+            assert_eq!(calls, vec![(memory.as_ptr() as *const c_void, 32)]);
+        }
+
+        // Hitting RLIMIT_MEMLOCK (or any other failure) is a warning, not an error.
+        for errno in &[Errno::ENOMEM, Errno::EPERM, Errno::EINVAL] {
+            let mut calls = 0;
+            assert!(!lock_memory_with(&[0u8; 32], |_, _| {
+                calls += 1;
+                Err(nix::Error::Sys(*errno))
+            }));
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn test_retry_eintr() {
+        {
+            let mut calls = 0;
+            let result = retry_eintr(|| {
+                calls += 1;
+                if calls < 3 {
+                    Err(nix::Error::Sys(Errno::EINTR))
+                } else {
+                    Ok(calls)
+                }
+            });
+            assert_eq!(result.unwrap(), 3);
+        }
+
+        {
+            let mut calls = 0;
+            let result: nix::Result<()> = retry_eintr(|| {
+                calls += 1;
+                Err(nix::Error::Sys(Errno::ENOENT))
+            });
+            assert!(matches!(result, Err(nix::Error::Sys(Errno::ENOENT))));
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, text, matches) in &[
+            ("", "", true),
+            ("", "foo", false),
+            ("*", "", true),
+            ("*", "team-a/foo", true),
+            ("foo", "foo", true),
+            ("foo", "foobar", false),
+            ("team-a/*", "team-a/foo", true),
+            ("team-a/*", "team-a/foo/bar", true),
+            ("team-a/*", "team-b/foo", false),
+            ("team-a/*", "team-a", false),
+            ("*/prod", "team-a/prod", true),
+            ("*/prod", "team-a/prod/db", false),
+            ("*a*b*", "xxaxxbxx", true),
+            ("*a*b", "xxaxxbxxa", false),
+            ("f?o", "foo", true),
+            ("f?o", "fo", false),
+            ("ü*", "ünïcödé", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern, text),
+                *matches,
+                "{} vs. {}",
+                pattern,
+                text
+            );
+        }
     }
 
     #[test]
     fn test_run_with_output() {
         {
-            let output = run_with_output("echo", &["-n", "foo"]).unwrap();
+            let output = run_with_output("echo", &["-n", "foo"], DEFAULT_MAX_OUTPUT).unwrap();
+            assert_eq!(output, "foo");
+        }
+
+        {
+            let output = run_with_output("echo", &["foo"], DEFAULT_MAX_OUTPUT).unwrap();
             assert_eq!(output, "foo");
         }
 
         {
-            let output = run_with_output("echo", &["foo"]).unwrap();
+            let output = run_with_output("echo", &["-n", "foo"], 3).unwrap();
             assert_eq!(output, "foo");
         }
 
         {
-            let err = run_with_output("this-command-should-not-exist", &[]).unwrap_err();
+            let err = run_with_output("this-command-should-not-exist", &[], DEFAULT_MAX_OUTPUT)
+                .unwrap_err();
             assert_eq!(
                 err.to_string(),
                 "failed to execute command: this-command-should-not-exist"
@@ -154,14 +908,241 @@ mod tests {
         }
 
         {
-            let err = run_with_output("true", &[]).unwrap_err();
+            let err = run_with_output("true", &[], DEFAULT_MAX_OUTPUT).unwrap_err();
             assert_eq!(err.to_string(), "expected output from true, but none given");
         }
 
+        {
+            let err = run_with_output("false", &[], DEFAULT_MAX_OUTPUT).unwrap_err();
+            assert_eq!(err.to_string(), "command failed: false");
+        }
+
+        {
+            let err = run_with_output("sh", &["-c", "echo oops >&2; exit 1"], DEFAULT_MAX_OUTPUT)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "command failed: sh: oops");
+        }
+
+        {
+            let err = run_with_output("echo", &["-n", "foo"], 2).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "output from echo exceeded the limit of 2 bytes"
+            );
+        }
+
+        {
+            let err = run_with_output("yes", &[], DEFAULT_MAX_OUTPUT).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "output from yes exceeded the limit of 4096 bytes"
+            );
+        }
+
         // TODO: Small error test here for the case where the output isn't UTF-8.
     }
 
-    // TODO: Figure out a good way to test util::get_password.
+    #[test]
+    fn test_run_with_input() {
+        let sh = |script: &str| vec!["-c".to_string(), script.to_string()];
+
+        {
+            assert!(run_with_input("sh", &sh(r#"test "$(cat)" = hunter2"#), b"hunter2").is_ok());
+        }
+
+        {
+            let err =
+                run_with_input("sh", &sh(r#"test "$(cat)" = hunter2"#), b"wrong").unwrap_err();
+            assert_eq!(err.to_string(), "command failed: sh");
+        }
+
+        // Commands don't have to read their input.
+        {
+            assert!(run_with_input("true", &[], &[b'x'; 1024 * 1024]).is_ok());
+        }
+
+        {
+            let err = run_with_input("this-command-should-not-exist", &[], b"").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to execute command: this-command-should-not-exist"
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_password() {
+        use crate::kbs2::input::ScriptedPrompt;
+
+        // NOTE: A nonexistent pinentry binary falls back on the TTY, i.e. the prompt.
+        for entry in &[
+            PassphraseEntry::Tty,
+            PassphraseEntry::Auto,
+            PassphraseEntry::Pinentry,
+        ] {
+            let prompt = ScriptedPrompt::new(&[("Password", "hunter2")]);
+            let password = get_password(*entry, "this-pinentry-does-not-exist", &prompt).unwrap();
+            assert_eq!(password.expose_secret(), "hunter2");
+            assert!(prompt.is_exhausted());
+        }
+
+        {
+            let prompt = ScriptedPrompt::new(&[("Password", "hunter2"), ("Confirm", "hunter2")]);
+            let password = get_new_password(PassphraseEntry::Tty, "", &prompt).unwrap();
+            assert_eq!(password.expose_secret(), "hunter2");
+            assert!(prompt.is_exhausted());
+        }
+
+        {
+            let prompt = ScriptedPrompt::new(&[("Password", "hunter2"), ("Confirm", "hunter3")]);
+            let err =
+                get_record_passphrase(PassphraseEntry::Tty, "", &prompt, "foo", true).unwrap_err();
+            assert_eq!(err.to_string(), "passwords don't match");
+        }
+
+        {
+            let prompt = ScriptedPrompt::new(&[]);
+            assert!(get_password(PassphraseEntry::Tty, "", &prompt).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_file_secure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        write_file_secure(&path, b"foo").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"foo");
+
+        write_file_secure(&path, b"bar").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"bar");
+
+        // No temporary files should be left behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        assert!(write_file_secure(&dir.path().join("missing/file"), b"foo").is_err());
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+    }
+
+    #[test]
+    fn test_write_file_secure_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        let written = write_file_secure_with(&path, |file| {
+            file.write_all(b"foo")?;
+            Ok(3)
+        })
+        .unwrap();
+        assert_eq!(written, 3);
+        assert_eq!(std::fs::read(&path).unwrap(), b"foo");
+
+        // A failed write leaves the existing file alone, and leaves nothing else behind.
+        let err = write_file_secure_with(&path, |file| -> Result<()> {
+            file.write_all(b"partial")?;
+            Err(anyhow!("oops"))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "oops");
+        assert_eq!(std::fs::read(&path).unwrap(), b"foo");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_create_dir_secure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a/b");
+
+        create_dir_secure(&path).unwrap();
+        assert!(path.is_dir());
+
+        for path in &[dir.path().join("a"), path.clone()] {
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+
+        // Existing directories are fine, and aren't modified.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        create_dir_secure(&path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+    }
+
+    #[test]
+    fn test_shared_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        assert_eq!(shared_mode(&path).unwrap(), None);
+
+        write_file_secure(&path, b"foo").unwrap();
+        assert_eq!(shared_mode(&path).unwrap(), None);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(shared_mode(&path).unwrap(), Some(0o640));
+    }
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(
+            osc52_sequence("hunter2", None).unwrap(),
+            "\x1b]52;c;aHVudGVyMg==\x07"
+        );
+        assert_eq!(osc52_sequence("", None).unwrap(), "\x1b]52;c;\x07");
+        assert_eq!(
+            osc52_sequence("ünïcödé", None).unwrap(),
+            "\x1b]52;c;w7xuw69jw7Zkw6k=\x07"
+        );
+
+        assert_eq!(
+            osc52_sequence("hunter2", Some(Multiplexer::Tmux)).unwrap(),
+            "\x1bPtmux;\x1b\x1b]52;c;aHVudGVyMg==\x07\x1b\\"
+        );
+
+        {
+            let contents = "a".repeat(100);
+            let sequence = osc52_sequence(&contents, Some(Multiplexer::Screen)).unwrap();
+
+            // Unwrapping each chunk gives back the plain sequence.
+            let chunks = sequence
+                .split_terminator("\x1b\\")
+                .map(|chunk| chunk.strip_prefix("\x1bP").unwrap())
+                .collect::<Vec<_>>();
+            assert!(chunks.len() > 1);
+            assert!(chunks.iter().all(|chunk| chunk.len() <= SCREEN_CHUNK_SIZE));
+            assert_eq!(chunks.concat(), osc52_sequence(&contents, None).unwrap());
+        }
+
+        {
+            // 75,000 bytes encode to exactly 100,000 bytes of base64.
+            assert!(osc52_sequence(&"a".repeat(75_000), None).is_ok());
+
+            let err = osc52_sequence(&"a".repeat(75_001), None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "secret is too large for the osc52 clipboard (100004 bytes encoded, max 100000)"
+            );
+        }
+    }
+
+    #[test]
+    fn test_clear_osc52() {
+        {
+            let mut tty = vec![];
+            clear_osc52(&mut tty, None).unwrap();
+            assert_eq!(tty, b"\x1b]52;c;\x07");
+        }
+
+        {
+            let mut tty = vec![];
+            clear_osc52(&mut tty, Some(Multiplexer::Tmux)).unwrap();
+            assert_eq!(tty, b"\x1bPtmux;\x1b\x1b]52;c;\x07\x1b\\");
+        }
+    }
 
     #[test]
     fn test_current_timestamp() {
@@ -178,8 +1159,112 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_relative_duration() {
+        assert_eq!(parse_relative_duration("0s").unwrap(), 0);
+        assert_eq!(parse_relative_duration("45s").unwrap(), 45);
+        assert_eq!(parse_relative_duration("30m").unwrap(), 30 * 60);
+        assert_eq!(parse_relative_duration("12h").unwrap(), 12 * 60 * 60);
+        assert_eq!(parse_relative_duration("7d").unwrap(), 7 * 24 * 60 * 60);
+        assert_eq!(parse_relative_duration("2w").unwrap(), 14 * 24 * 60 * 60);
+        assert_eq!(parse_relative_duration("1h30m").unwrap(), 90 * 60);
+        assert_eq!(parse_relative_duration("1d1d").unwrap(), 2 * 24 * 60 * 60);
+
+        for duration in &[
+            "",
+            "7",
+            "d",
+            "7x",
+            "1h30",
+            "-7d",
+            "7 d",
+            "99999999999999999999w",
+        ] {
+            assert_eq!(
+                parse_relative_duration(duration).unwrap_err().to_string(),
+                format!("invalid duration: {}", duration)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_rfc3339("2020-05-23T23:51:40Z").unwrap(), 1590277900);
+        assert_eq!(parse_rfc3339("2020-05-23t23:51:40z").unwrap(), 1590277900);
+        assert_eq!(parse_rfc3339("2020-05-23 23:51:40Z").unwrap(), 1590277900);
+        assert_eq!(
+            parse_rfc3339("2020-05-23T23:51:40.999Z").unwrap(),
+            1590277900
+        );
+        assert_eq!(
+            parse_rfc3339("2020-05-23T19:51:40-04:00").unwrap(),
+            1590277900
+        );
+        assert_eq!(
+            parse_rfc3339("2020-05-24T05:21:40+05:30").unwrap(),
+            1590277900
+        );
+        assert_eq!(parse_rfc3339("2020-02-29T00:00:00Z").unwrap(), 1582934400);
+        assert_eq!(parse_rfc3339("2000-03-01T00:00:00Z").unwrap(), 951868800);
+
+        for timestamp in &[
+            "",
+            "2020-05-23",
+            "2020-05-23T23:51:40",
+            "2020-05-23T23:51:40+0400",
+            "2020-05-23T23:51:40.Z",
+            "2020-13-01T00:00:00Z",
+            "2020-04-31T00:00:00Z",
+            "2019-02-29T00:00:00Z",
+            "2020-05-23T24:00:00Z",
+            "2020-05-23T23:60:00Z",
+            "2020-05-23T23:51:40+24:00",
+            "+020-05-23T23:51:40Z",
+            "2020/05/23T23:51:40Z",
+        ] {
+            assert_eq!(
+                parse_rfc3339(timestamp).unwrap_err().to_string(),
+                format!("invalid RFC 3339 timestamp: {}", timestamp)
+            );
+        }
+
+        assert_eq!(
+            parse_rfc3339("1969-12-31T23:59:59Z")
+                .unwrap_err()
+                .to_string(),
+            "timestamp is before the Unix epoch: 1969-12-31T23:59:59Z"
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("7d", 1590277900).unwrap(),
+            1590277900 - 7 * 86400
+        );
+        assert_eq!(parse_time("7d", 60).unwrap(), 0);
+        assert_eq!(parse_time("2020-05-23T23:51:40Z", 0).unwrap(), 1590277900);
+        assert_eq!(parse_time("1590277900", 0).unwrap(), 1590277900);
+        assert!(parse_time("yesterday", 1590277900).is_err());
+    }
+
     // TODO: Figure out a good way to test util::warn.
 
+    #[test]
+    fn test_warn() {
+        let mut out = vec![];
+        write_warning(&mut out, "loud");
+        assert_eq!(out, b"Warn: loud\n");
+
+        set_quiet(true);
+        let mut out = vec![];
+        write_warning(&mut out, "quiet");
+        set_quiet(false);
+
+        assert!(out.is_empty());
+    }
+
     #[test]
     fn test_home_dir() {
         let dir = home_dir().unwrap();
@@ -1,13 +1,16 @@
 use anyhow::{anyhow, Result};
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use clap_generate::{generate, generators};
 
+use std::env;
 use std::io;
 use std::path::Path;
 use std::process::{self, Command};
 
 mod kbs2;
 
+use kbs2::input::Prompt;
+
 fn app<'a>() -> App<'a> {
     // TODO(ww): Put this in a separate file, or switch to YAML.
     // The latter probably won't work with env!, though.
@@ -25,6 +28,62 @@ fn app<'a>() -> App<'a> {
                 .takes_value(true)
                 .env("KBS2_CONFIG_DIR"),
         )
+        .arg(
+            Arg::new("config")
+                .about("use the specified config file (or - for stdin), instead of one in the config directory")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .env("KBS2_CONFIG"),
+        )
+        .arg(
+            Arg::new("profile")
+                .about("use the specified profile's config within the config directory")
+                .long("profile")
+                .value_name("PROFILE")
+                .takes_value(true)
+                .env("KBS2_PROFILE"),
+        )
+        .arg(
+            Arg::new("store")
+                .about("use the specified store, overriding the config")
+                .short('s')
+                .long("store")
+                .value_name("DIR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("on-missing-store")
+                .about("what to do when the store doesn't exist, overriding the config")
+                .long("on-missing-store")
+                .value_name("POLICY")
+                .takes_value(true)
+                .possible_values(&["error", "create", "prompt"]),
+        )
+        .arg(
+            Arg::new("age-identity")
+                .about("use the specified age identity (wrapped or not), overriding the keyfile")
+                .long("age-identity")
+                .value_name("PATH")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("verbose")
+                .about("log more verbosely (may be repeated)")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("quiet")
+                .about("don't print warnings (errors are still printed)")
+                .long("quiet"),
+        )
+        .arg(
+            Arg::new("insecure-keyfile-perms")
+                .about("allow an unwrapped keyfile that other users can access")
+                .long("insecure-keyfile-perms"),
+        )
         .arg(
             Arg::new("completions")
                 .about("emit shell tab completions")
@@ -33,6 +92,14 @@ fn app<'a>() -> App<'a> {
                 .takes_value(true)
                 .possible_values(&["bash", "zsh", "fish"]),
         )
+        .arg(
+            Arg::new("print-config")
+                .about("print the effective config (after includes, migrations, and overrides) and exit")
+                .long("print-config")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["toml", "json"]),
+        )
         .subcommand(
             App::new("init")
                 .about("initialize kbs2 with a new config and keypair")
@@ -46,10 +113,40 @@ fn app<'a>() -> App<'a> {
                     Arg::new("insecure-not-wrapped")
                         .about("don't wrap the keypair with a master password")
                         .long("insecure-not-wrapped"),
+                )
+                .arg(
+                    Arg::new("wrap-work-factor")
+                        .about("the largest scrypt work factor to wrap the keypair with")
+                        .long("wrap-work-factor")
+                        .takes_value(true)
+                        .conflicts_with("insecure-not-wrapped"),
                 ),
         )
         .subcommand(App::new("unlock").about("unwrap the private key for use"))
         .subcommand(App::new("lock").about("remove the unwrapped key, if any, from shared memory"))
+        .subcommand(
+            App::new("wrap-key").about("wrap an unwrapped private key with a master password"),
+        )
+        .subcommand(
+            App::new("unwrap-key")
+                .about("permanently remove the master password from a wrapped private key"),
+        )
+        .subcommand(
+            App::new("rewrap-key").about("change the master password on a wrapped private key"),
+        )
+        .subcommand(
+            App::new("bench-kdf")
+                .about("time scrypt at several work factors, and recommend a wrap-work-factor")
+                .arg(
+                    Arg::new("target-ms")
+                        .about("the unwrap latency to target, in milliseconds")
+                        .short('t')
+                        .long("target-ms")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("500"),
+                ),
+        )
         .subcommand(
             App::new("new")
                 .about("create a new record")
@@ -57,7 +154,7 @@ fn app<'a>() -> App<'a> {
                     Arg::new("label")
                         .about("the record's label")
                         .index(1)
-                        .required(true),
+                        .required_unless_present("from-stdin"),
                 )
                 .arg(
                     Arg::new("kind")
@@ -93,6 +190,24 @@ fn app<'a>() -> App<'a> {
                         .long("generator")
                         .takes_value(true)
                         .default_value("default"),
+                )
+                .arg(
+                    Arg::new("from-stdin")
+                        .about("create records from JSON lines on stdin, one per line")
+                        .long("from-stdin")
+                        .conflicts_with_all(&["label", "terse", "generate"]),
+                )
+                .arg(
+                    Arg::new("delta")
+                        .about("apply a delta from `kbs2 dump --since` on stdin, deleting records")
+                        .long("delta")
+                        .requires("from-stdin"),
+                )
+                .arg(
+                    Arg::new("extra-passphrase")
+                        .about("also protect the record with its own passphrase")
+                        .long("extra-passphrase")
+                        .conflicts_with("from-stdin"),
                 ),
         )
         .subcommand(
@@ -111,15 +226,147 @@ fn app<'a>() -> App<'a> {
                         .long("kind")
                         .takes_value(true)
                         .possible_values(kbs2::record::RECORD_KINDS),
+                )
+                .arg(
+                    Arg::new("since")
+                        .about("list only records created or modified at or after TIME (RFC 3339, or relative like 7d)")
+                        .long("since")
+                        .value_name("TIME")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("until")
+                        .about("list only records created or modified at or before TIME (RFC 3339, or relative like 7d)")
+                        .long("until")
+                        .value_name("TIME")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("json")
+                        .about("list record labels as a JSON array")
+                        .short('j')
+                        .long("json")
+                        .conflicts_with("details"),
+                )
+                .arg(
+                    Arg::new("aliases")
+                        .about("print any configured aliases alongside each record's label")
+                        .short('a')
+                        .long("aliases")
+                        .conflicts_with("json"),
+                )
+                .arg(
+                    Arg::new("stats")
+                        .about("print the number of records and their total size, instead of listing them")
+                        .short('s')
+                        .long("stats")
+                        .conflicts_with_all(&["details", "kind", "since", "until", "json", "aliases"]),
+                ),
+        )
+        .subcommand(
+            App::new("rm")
+                .about("remove a record, or every record matching a glob")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label, or a glob (e.g. 'temp/*')")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .about("don't ask before removing more than one record")
+                        .short('y')
+                        .long("yes"),
                 ),
         )
         .subcommand(
-            App::new("rm").about("remove a record").arg(
-                Arg::new("label")
-                    .about("the record's label")
-                    .index(1)
-                    .required(true),
-            ),
+            App::new("mv")
+                .about("move a record, or every record beneath a label ending in /")
+                .alias("move")
+                .arg(
+                    Arg::new("source")
+                        .about("the record's current label, or a prefix ending in /")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("destination")
+                        .about("the record's new label, or a new prefix")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("cp")
+                .about("copy a record to a new label")
+                .alias("copy")
+                .arg(
+                    Arg::new("source")
+                        .about("the label of the record to copy")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("destination")
+                        .about("the label of the copy")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("regen")
+                        .about("regenerate the copy's sensitive fields with the given generator")
+                        .long("regen")
+                        .value_name("GENERATOR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("force")
+                        .about("overwrite the destination if it already exists")
+                        .short('f')
+                        .long("force"),
+                ),
+        )
+        .subcommand(
+            App::new("attach")
+                .about("attach a file to a record")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("file")
+                        .about("the file to attach")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("name")
+                        .about("the attachment's name (default: the file's name)")
+                        .short('n')
+                        .long("name")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("attachment")
+                .about("get an attachment from a record, or list its attachments")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(Arg::new("name").about("the attachment's name").index(2))
+                .arg(
+                    Arg::new("output")
+                        .about("write the attachment to this file instead of stdout")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .requires("name"),
+                ),
         )
         .subcommand(
             App::new("dump")
@@ -128,13 +375,54 @@ fn app<'a>() -> App<'a> {
                     Arg::new("label")
                         .about("the record's label")
                         .index(1)
-                        .required(true),
+                        .required_unless_present("all"),
                 )
                 .arg(
                     Arg::new("json")
                         .about("dump in JSON format")
                         .short('j')
                         .long("json"),
+                )
+                .arg(
+                    Arg::new("all")
+                        .about("dump every record in the store")
+                        .short('a')
+                        .long("all")
+                        .conflicts_with("label"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .about("dump the record's fields as KBS2_<FIELD> variables")
+                        .short('f')
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["env", "dotenv"])
+                        .conflicts_with_all(&["json", "all"]),
+                )
+                .arg(
+                    Arg::new("extra")
+                        .about("dump only the value of the record's KEY metadata")
+                        .short('e')
+                        .long("extra")
+                        .value_name("KEY")
+                        .takes_value(true)
+                        .conflicts_with_all(&["json", "all", "format"]),
+                )
+                .arg(
+                    Arg::new("raw")
+                        .about("dump the record's decrypted contents as-is, e.g. for files encrypted with the age CLI")
+                        .short('r')
+                        .long("raw")
+                        .conflicts_with_all(&["json", "all", "format", "extra"]),
+                )
+                .arg(
+                    Arg::new("since")
+                        .about("dump a delta of the records changed since TIME (Unix seconds, RFC 3339, or relative like 7d)")
+                        .long("since")
+                        .value_name("TIME")
+                        .takes_value(true)
+                        .requires("all")
+                        .conflicts_with("json"),
                 ),
         )
         .subcommand(
@@ -142,15 +430,67 @@ fn app<'a>() -> App<'a> {
                 .about("get the password in a login record")
                 .arg(
                     Arg::new("label")
-                        .about("the record's label")
+                        .about("the record's label, or a glob matching exactly one record")
                         .index(1)
-                        .required(true),
+                        .required_unless_present("clear"),
+                )
+                .arg(
+                    Arg::new("clear")
+                        .about("clear the clipboard now, instead of getting a password")
+                        .long("clear")
+                        .conflicts_with_all(&[
+                            "label",
+                            "clipboard",
+                            "no-clipboard",
+                            "peek",
+                            "qr",
+                            "exec",
+                        ]),
                 )
                 .arg(
                     Arg::new("clipboard")
                         .about("copy the password to the clipboard")
                         .short('c')
-                        .long("clipboard"),
+                        .long("clipboard")
+                        .overrides_with("no-clipboard"),
+                )
+                .arg(
+                    Arg::new("no-clipboard")
+                        .about("write the password to stdout, overriding --clipboard")
+                        .short('C')
+                        .long("no-clipboard")
+                        .overrides_with("clipboard"),
+                )
+                .arg(
+                    Arg::new("peek")
+                        .about("print the password on the terminal, then erase it")
+                        .short('p')
+                        .long("peek")
+                        .conflicts_with("clipboard"),
+                )
+                .arg(
+                    Arg::new("qr")
+                        .about("print the password as a QR code on the terminal")
+                        .short('q')
+                        .long("qr")
+                        .conflicts_with_all(&["clipboard", "peek"]),
+                )
+                .arg(
+                    Arg::new("exec")
+                        .about("run CMD with the password on its stdin")
+                        .short('e')
+                        .long("exec")
+                        .value_name("CMD")
+                        .takes_value(true)
+                        .conflicts_with_all(&["clipboard", "peek", "qr"]),
+                )
+                .arg(
+                    Arg::new("field")
+                        .about("the field to get, e.g. username")
+                        .short('f')
+                        .long("field")
+                        .takes_value(true)
+                        .default_value("password"),
                 ),
         )
         .subcommand(
@@ -189,6 +529,34 @@ fn app<'a>() -> App<'a> {
                         .about("don't update the record's timestamp")
                         .short('p')
                         .long("preserve-timestamp"),
+                )
+                .arg(
+                    Arg::new("set")
+                        .about("set a field (or extra.KEY metadata) to VALUE instead of opening an editor; may be repeated")
+                        .short('s')
+                        .long("set")
+                        .value_name("KEY=VALUE")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("append-note")
+                        .about("append NOTE to the record's notes metadata instead of opening an editor")
+                        .long("append-note")
+                        .value_name("NOTE")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("__complete")
+                .about("emit completion data for shells")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::new("kind")
+                        .about("the kind of completion data to emit")
+                        .index(1)
+                        .required(true)
+                        .possible_values(&["labels", "generators"]),
                 ),
         )
         .subcommand(
@@ -199,6 +567,70 @@ fn app<'a>() -> App<'a> {
                         .about("the generator to use")
                         .index(1)
                         .default_value("default"),
+                )
+                .arg(
+                    Arg::new("alphabet")
+                        .about("generate with an ad-hoc generator that samples from ALPHABET")
+                        .short('a')
+                        .long("alphabet")
+                        .value_name("ALPHABET")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("length")
+                        .about("generate with an ad-hoc generator that produces LENGTH characters")
+                        .short('l')
+                        .long("length")
+                        .value_name("LENGTH")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(App::new("audit").about("report weak, reused, and stale records"))
+        .subcommand(
+            App::new("totp-export")
+                .about("export the TOTP key URI of every record that has one")
+                .arg(
+                    Arg::new("qr")
+                        .about("print each key URI as a QR code on the terminal")
+                        .short('q')
+                        .long("qr"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .about("print TOTP secrets even when stdout isn't a terminal")
+                        .short('f')
+                        .long("force"),
+                )
+                .arg(
+                    Arg::new("window")
+                        .about("print each record's current time window instead of its key URI")
+                        .short('w')
+                        .long("window")
+                        .conflicts_with("qr"),
+                ),
+        )
+        .subcommand(
+            App::new("import")
+                .about("import login records from a CSV export (e.g. from a browser)")
+                .arg(
+                    Arg::new("file")
+                        .about("the CSV file to import, or - for stdin")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("column")
+                        .about("read FIELD (url, username, or password) from the column named HEADER")
+                        .short('c')
+                        .long("column")
+                        .value_name("FIELD=HEADER")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("overwrite")
+                        .about("overwrite records that already exist, instead of skipping them")
+                        .long("overwrite"),
                 ),
         )
 }
@@ -223,15 +655,117 @@ fn run() -> Result<()> {
         return Ok(());
     }
 
-    let config_dir = match matches.value_of("config-dir") {
-        Some(path) => Path::new(path).to_path_buf(),
-        None => kbs2::config::find_config_dir()?,
+    // NOTE: RUST_LOG takes precedence over --verbose, which takes precedence over
+    // the config's log-level. The latter only applies once the config has been loaded.
+    let env_log_level = env::var_os("RUST_LOG").is_some();
+    let verbose_log_level =
+        kbs2::config::LogLevel::from_verbosity(matches.occurrences_of("verbose"));
+    if let (false, Some(level)) = (env_log_level, verbose_log_level) {
+        log::set_max_level(level.into());
+    }
+    let log_level_override = env_log_level || verbose_log_level.is_some();
+
+    kbs2::util::set_quiet(matches.is_present("quiet"));
+
+    // NOTE: A config read from stdin has no directory of its own, so its relative paths
+    // are resolved against the current directory unless --config-dir says otherwise.
+    let config_dir = match (matches.value_of("config-dir"), matches.value_of("config")) {
+        (Some(path), _) => Path::new(path).to_path_buf(),
+        (None, Some("-")) => env::current_dir()?,
+        (None, _) => kbs2::config::find_config_dir()?,
     };
 
     log::debug!("config dir: {:?}", config_dir);
-    std::fs::create_dir_all(&config_dir)?;
+    kbs2::util::create_dir_secure(&config_dir)?;
+
+    // NOTE: The store override is applied immediately after loading, so that every
+    // subcommand sees the same store. Only `kbs2 new` is allowed to create it.
+    let profile = matches.value_of("profile");
+    // NOTE: KBS2_STORE is also exported to custom commands, so we don't treat it as an
+    // override within one: a nested kbs2 would otherwise be stuck with its parent's store,
+    // even when given a different config.
+    let store_env = match env::var_os("KBS2_SUBCOMMAND") {
+        Some(_) => None,
+        None => env::var("KBS2_STORE").ok(),
+    };
+    let store_override = matches.value_of("store").or_else(|| store_env.as_deref());
+    let create_store = matches.subcommand_name() == Some("new");
+    let insecure_keyfile_perms = matches.is_present("insecure-keyfile-perms");
+    let on_missing_store = matches
+        .value_of("on-missing-store")
+        .map(str::parse::<kbs2::config::OnMissingStore>)
+        .transpose()?;
+
+    // NOTE: Overriding the identity for a command that rewrites the keyfile (and the
+    // config's `wrapped` setting) would leave the config describing the wrong key.
+    let identity_override = matches.value_of("age-identity");
+    if let (Some(_), Some(cmd)) = (identity_override, matches.subcommand_name()) {
+        if ["init", "wrap-key", "unwrap-key", "rewrap-key"].contains(&cmd) {
+            return Err(anyhow!("--age-identity can't be used with kbs2 {}", cmd));
+        }
+    }
+
+    // NOTE: An explicit config file replaces both the config directory and the profile,
+    // and can't be created by `kbs2 init` (which only writes to the config directory).
+    let config_file = matches.value_of("config");
+    if config_file.is_some() && profile.is_some() {
+        return Err(anyhow!("--config can't be used with --profile"));
+    }
+    if let (Some(_), Some("init")) = (config_file, matches.subcommand_name()) {
+        return Err(anyhow!("--config can't be used with kbs2 init"));
+    }
+
+    // NOTE: A config read from stdin has no file to rewrite, and leaves no stdin behind for
+    // commands that read records, answers, or an editor session from it. Both kinds of
+    // command are rejected before anything is read.
+    if let (Some("-"), Some((cmd, sub_matches))) = (config_file, matches.subcommand()) {
+        let rejected = match cmd {
+            "wrap-key" | "unwrap-key" | "rewrap-key" | "new" | "edit" => true,
+            "rm" => !sub_matches.is_present("yes"),
+            "import" => sub_matches.value_of("file") == Some("-"),
+            _ => false,
+        };
+
+        if rejected {
+            return Err(anyhow!("--config - can't be used with kbs2 {}", cmd));
+        }
+    }
+
+    let load_config = || -> Result<kbs2::config::Config> {
+        let mut config = match config_file {
+            Some("-") => kbs2::config::load_reader(io::stdin().lock(), &config_dir)?,
+            Some(config_file) => kbs2::config::load_file(Path::new(config_file))?,
+            None => kbs2::config::load(&config_dir, profile)?,
+        };
+        if !log_level_override {
+            log::set_max_level(config.log_level.into());
+        }
+
+        if let Some(identity) = identity_override {
+            config.override_identity(identity)?;
+        }
+
+        config.check_keyfile_perms(insecure_keyfile_perms)?;
+
+        if let Some(store) = store_override {
+            config.override_store(store, create_store)?;
+        }
+
+        if let Some(on_missing_store) = on_missing_store {
+            config.on_missing_store = on_missing_store;
+        }
+
+        Ok(config)
+    };
+
+    // NOTE: --print-config takes precedence over any subcommand, so that it shows the
+    // config that the subcommand would have run with.
+    if let Some(format) = matches.value_of("print-config") {
+        let config = load_config()?;
+        return kbs2::command::print_config(format, &config);
+    }
 
-    // Subcommand dispatch happens here. All subcommands take a `Session`, with four exceptions:
+    // Subcommand dispatch happens here. All subcommands take a `Session`, with seven exceptions:
     //
     // * The empty subcommand (i.e., just `kbs2`) does nothing besides printing help.
     //
@@ -244,79 +778,204 @@ fn run() -> Result<()> {
     //
     // * `kbs2 lock` exists to remove the shared memory object created by `kbs2 unlock`. Taking
     //   a session would mean that it would attempt to pointlessly unlock the key before re-locking.
+    //
+    // * `kbs2 wrap-key`, `kbs2 unwrap-key`, and `kbs2 rewrap-key` modify the keyfile itself,
+    //   and so can't depend on a session that has already loaded it.
+    //
+    // * `kbs2 __complete` emits data for shell completions, and needs to do so quickly and
+    //   without prompting for the master password.
+    //
+    // * `kbs2 bench-kdf` only measures this machine, and so doesn't need a config at all.
     if matches.subcommand().is_none() {
         app.clone()
             .write_long_help(&mut io::stdout())
             .map_err(|_| anyhow!("failed to print help"))
     } else if let Some(("init", matches)) = matches.subcommand() {
-        kbs2::command::init(&matches, &config_dir)
+        kbs2::command::init(matches, &config_dir, profile)
+    } else if let Some(("bench-kdf", matches)) = matches.subcommand() {
+        kbs2::command::bench_kdf(matches)
     } else if let Some(("unlock", matches)) = matches.subcommand() {
-        let config = kbs2::config::load(&config_dir)?;
-        kbs2::command::unlock(&matches, &config)
+        let mut config = load_config()?;
+        kbs2::command::unlock(&matches, &mut config)
     } else if let Some(("lock", matches)) = matches.subcommand() {
-        let config = kbs2::config::load(&config_dir)?;
+        let config = load_config()?;
         kbs2::command::lock(&matches, &config)
+    } else if let Some(("wrap-key", matches)) = matches.subcommand() {
+        let mut config = load_config()?;
+        kbs2::command::wrap_key(matches, &mut config)
+    } else if let Some(("unwrap-key", matches)) = matches.subcommand() {
+        let mut config = load_config()?;
+        kbs2::command::unwrap_key(matches, &mut config)
+    } else if let Some(("rewrap-key", matches)) = matches.subcommand() {
+        let mut config = load_config()?;
+        kbs2::command::rewrap_key(matches, &mut config)
+    } else if let Some(("__complete", matches)) = matches.subcommand() {
+        let config = load_config()?;
+        kbs2::command::complete(matches, &config)
     } else {
-        let config = kbs2::config::load(&config_dir)?;
+        let config = load_config()?;
         log::debug!("loaded config: {:?}", config);
 
+        // NOTE: Commands that change the store check that it's writable before
+        // anything else, so that a read-only store doesn't surface mid-change
+        // (e.g. after every field has been prompted for).
+        if let Some((cmd, _)) = matches.subcommand() {
+            if ["new", "rm", "mv", "cp", "attach", "edit", "import"].contains(&cmd) {
+                config.check_store_writable()?;
+            }
+
+            // NOTE: External commands might change the store, so only the builtin
+            // commands that never do are allowed to see a missing store as an empty one.
+            let mutating = ![
+                "list",
+                "attachment",
+                "dump",
+                "pass",
+                "env",
+                "generate",
+                "audit",
+                "totp-export",
+            ]
+            .contains(&cmd);
+            config.open_store(mutating, |store| {
+                if config_file == Some("-") {
+                    return Err(anyhow!(
+                        "store {} doesn't exist (can't prompt to create it with --config -)",
+                        store.display()
+                    ));
+                }
+
+                kbs2::input::TerminalPrompt.confirm(&format!(
+                    "store {} doesn't exist; create it?",
+                    store.display()
+                ))
+            })?;
+        }
+
         let session = kbs2::session::Session::new(config)?;
 
-        if let Some(pre_hook) = &session.config.pre_hook {
-            log::debug!("pre-hook: {}", pre_hook);
-            session.config.call_hook(pre_hook, &[])?;
+        // NOTE: The finally-hooks run however the subcommand ends, so everything from
+        // the pre-hook onwards is collected into a single result for them. A failed external
+        // command still exits with its own status, but only after the finally-hooks have run.
+        let mut external_failed = false;
+        let result = run_subcommand(
+            &matches,
+            &session,
+            profile,
+            config_file,
+            &mut external_failed,
+        );
+
+        let result = session
+            .config
+            .call_finally_hooks(matches.subcommand_name().unwrap(), result);
+        if external_failed {
+            process::exit(2);
         }
 
-        match matches.subcommand() {
-            Some(("new", matches)) => kbs2::command::new(&matches, &session)?,
-            Some(("list", matches)) => kbs2::command::list(&matches, &session)?,
-            Some(("rm", matches)) => kbs2::command::rm(&matches, &session)?,
-            Some(("dump", matches)) => kbs2::command::dump(&matches, &session)?,
-            Some(("pass", matches)) => kbs2::command::pass(&matches, &session)?,
-            Some(("env", matches)) => kbs2::command::env(&matches, &session)?,
-            Some(("edit", matches)) => kbs2::command::edit(&matches, &session)?,
-            Some(("generate", matches)) => kbs2::command::generate(&matches, &session)?,
-            Some((cmd, matches)) => {
-                let cmd = format!("kbs2-{}", cmd);
-
-                let ext_args: Vec<&str> = match matches.values_of("") {
-                    Some(values) => values.collect(),
-                    None => vec![],
-                };
-
-                log::debug!("external command requested: {} (args: {:?})", cmd, ext_args);
-
-                let status = Command::new(&cmd)
-                    .args(&ext_args)
-                    .env("KBS2_CONFIG_DIR", &config_dir)
-                    .env("KBS2_STORE", &session.config.store)
-                    .env("KBS2_SUBCOMMAND", "1")
-                    .status()
-                    .map_or(None, |s| Some(s.success()));
-
-                match status {
-                    Some(true) => (),
-                    Some(false) => process::exit(2),
-                    None => return Err(anyhow!("no such command: {}", cmd)),
-                }
+        result
+    }
+}
+
+/// Runs a subcommand that takes a `Session`, along with the global pre- and post-hooks.
+///
+/// `external_failed` is set when the subcommand is an external command that ran but failed.
+fn run_subcommand(
+    matches: &ArgMatches,
+    session: &kbs2::session::Session,
+    profile: Option<&str>,
+    config_file: Option<&str>,
+    external_failed: &mut bool,
+) -> Result<()> {
+    if let Some(pre_hook) = &session.config.pre_hook {
+        log::debug!("pre-hook: {}", pre_hook);
+        session
+            .config
+            .call_hook(pre_hook, &[], &Default::default())?;
+    }
+
+    match matches.subcommand() {
+        Some(("new", matches)) => kbs2::command::new(&matches, session)?,
+        Some(("list", matches)) => kbs2::command::list(&matches, session)?,
+        Some(("rm", matches)) => kbs2::command::rm(&matches, session)?,
+        Some(("mv", matches)) => kbs2::command::mv(&matches, session)?,
+        Some(("cp", matches)) => kbs2::command::cp(&matches, session)?,
+        Some(("attach", matches)) => kbs2::command::attach(&matches, session)?,
+        Some(("attachment", matches)) => kbs2::command::attachment(&matches, session)?,
+        Some(("dump", matches)) => kbs2::command::dump(&matches, session)?,
+        Some(("pass", matches)) => kbs2::command::pass(&matches, session)?,
+        Some(("env", matches)) => kbs2::command::env(&matches, session)?,
+        Some(("edit", matches)) => kbs2::command::edit(&matches, session)?,
+        Some(("generate", matches)) => kbs2::command::generate(&matches, session)?,
+        Some(("audit", matches)) => kbs2::command::audit(&matches, session)?,
+        Some(("totp-export", matches)) => kbs2::command::totp_export(&matches, session)?,
+        Some(("import", matches)) => kbs2::command::import(&matches, session)?,
+        Some((cmd, matches)) => {
+            // NOTE: External commands load the config themselves, but a config read from
+            // stdin is already gone by the time they'd read it.
+            if config_file == Some("-") {
+                return Err(anyhow!("--config - can't be used with external commands"));
             }
-            _ => unreachable!(),
-        }
 
-        if let Some(post_hook) = &session.config.post_hook {
-            log::debug!("post-hook: {}", post_hook);
-            session.config.call_hook(post_hook, &[])?;
+            let cmd = format!("kbs2-{}", cmd);
+
+            let ext_args: Vec<&str> = match matches.values_of("") {
+                Some(values) => values.collect(),
+                None => vec![],
+            };
+
+            log::debug!("external command requested: {} (args: {:?})", cmd, ext_args);
+
+            let status = Command::new(&cmd)
+                .args(&ext_args)
+                .env("KBS2_CONFIG_DIR", &session.config.config_dir)
+                .envs(profile.map(|p| ("KBS2_PROFILE", p)))
+                .envs(config_file.map(|f| ("KBS2_CONFIG", f)))
+                .env("KBS2_STORE", &session.config.store)
+                .env("KBS2_SUBCOMMAND", "1")
+                .status()
+                .map_or(None, |s| Some(s.success()));
+
+            match status {
+                Some(true) => (),
+                Some(false) => {
+                    *external_failed = true;
+                    return Err(anyhow!("external command failed: {}", cmd));
+                }
+                None => return Err(anyhow!("no such command: {}", cmd)),
+            }
         }
+        _ => unreachable!(),
+    }
 
-        Ok(())
+    if let Some(post_hook) = &session.config.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session
+            .config
+            .call_post_hook(post_hook, &[], &Default::default(), None)?;
     }
+
+    Ok(())
 }
 
 fn main() {
-    env_logger::init();
+    // NOTE: Unless RUST_LOG is set, the logger itself accepts every level and `run`
+    // picks the effective level with `log::set_max_level`.
+    if env::var_os("RUST_LOG").is_some() {
+        env_logger::init();
+    } else {
+        env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Trace)
+            .init();
+        log::set_max_level(log::LevelFilter::Error);
+    }
 
     process::exit(match run() {
         Ok(()) => 0,
+        Err(e) if e.is::<kbs2::config::HookCancelled>() => {
+            log::info!("{}", e);
+            0
+        }
         Err(e) => {
             eprintln!("Fatal: {}", e);
             1